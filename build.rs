@@ -1,4 +1,11 @@
-use std::path::Path;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Directory whose contents end up in the initial ramdisk.
+const INITRD_DIR: &str = "initrd";
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -11,10 +18,16 @@ fn main() {
 
     let config = bootloader::BootConfig::default();
 
+    // pack the initial ramdisk
+    let initrd_path = out_dir.join("initrd.tar");
+    pack_initrd(Path::new(INITRD_DIR), &initrd_path).expect("failed to pack the initrd");
+    println!("cargo:rerun-if-changed={}", INITRD_DIR);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
         .set_boot_config(&config)
+        .set_ramdisk(&initrd_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
@@ -22,6 +35,7 @@ fn main() {
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
         .set_boot_config(&config)
+        .set_ramdisk(&initrd_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -29,3 +43,72 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Writes every file below `dir` into a ustar archive at `out`.
+fn pack_initrd(dir: &Path, out: &Path) -> io::Result<()> {
+    let mut archive = Vec::new();
+    if dir.is_dir() {
+        pack_dir(dir, "", &mut archive)?;
+    }
+    // an archive ends with two zero blocks
+    archive.resize(archive.len() + 1024, 0);
+    fs::write(out, archive)
+}
+
+fn pack_dir(dir: &Path, prefix: &str, archive: &mut Vec<u8>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    // keep the archive reproducible
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+        if entry.file_type()?.is_dir() {
+            write_tar_header(archive, &format!("{}/", name), 0, b'5', 0o755)?;
+            pack_dir(&entry.path(), &format!("{}/", name), archive)?;
+        } else {
+            let data = fs::read(entry.path())?;
+            write_tar_header(archive, &name, data.len() as u64, b'0', 0o644)?;
+            archive.extend_from_slice(&data);
+            let padding = (512 - data.len() % 512) % 512;
+            archive.resize(archive.len() + padding, 0);
+        }
+    }
+    Ok(())
+}
+
+fn write_tar_header(
+    archive: &mut Vec<u8>,
+    name: &str,
+    size: u64,
+    kind: u8,
+    mode: u32,
+) -> io::Result<()> {
+    let mut header = [0u8; 512];
+    // names longer than the name field are split into prefix and name at a `/`
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        let split = name[..name.len().min(155)]
+            .rfind('/')
+            .filter(|&split| name.len() - split - 1 <= 100)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, name.to_string()))?;
+        (&name[..split], &name[split + 1..])
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    (&mut header[100..108]).write_all(format!("{:07o}\0", mode).as_bytes())?;
+    (&mut header[108..116]).write_all(b"0000000\0")?; // uid
+    (&mut header[116..124]).write_all(b"0000000\0")?; // gid
+    (&mut header[124..136]).write_all(format!("{:011o}\0", size).as_bytes())?;
+    (&mut header[136..148]).write_all(b"00000000000\0")?; // mtime
+    header[156] = kind;
+    (&mut header[257..265]).write_all(b"ustar\000")?;
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    (&mut header[148..156]).write_all(format!("{:06o}\0 ", checksum).as_bytes())?;
+
+    archive.extend_from_slice(&header);
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Minimal ACPI table discovery: just enough to find the tables the kernel
+//! needs and to read the processor and interrupt controller list from the MADT.
+
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr, slice};
+
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+pub static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+pub struct Acpi {
+    tables: Vec<PhysAddr>,
+    pub madt: Option<Madt>,
+}
+
+impl Acpi {
+    /// Returns the physical address of the first table with the given signature.
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<PhysAddr> {
+        self.tables
+            .iter()
+            .copied()
+            .find(|&addr| unsafe { read_header(addr) }.signature == *signature)
+    }
+}
+
+unsafe fn read_header(addr: PhysAddr) -> SdtHeader {
+    ptr::read_unaligned(memory::phys_to_virt(addr).as_ptr::<SdtHeader>())
+}
+
+/// Returns the bytes of the table at `addr`, header included.
+///
+/// ## Safety
+/// `addr` must point to an ACPI table.
+pub unsafe fn table_bytes(addr: PhysAddr) -> &'static [u8] {
+    let header = read_header(addr);
+    slice::from_raw_parts(
+        memory::phys_to_virt(addr).as_ptr::<u8>(),
+        header.length as usize,
+    )
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Parses the RSDP handed over by the bootloader and the tables it points to.
+pub fn init(rsdp_addr: u64) -> Result<(), &'static str> {
+    let rsdp_addr = PhysAddr::new(rsdp_addr);
+    let rsdp = unsafe { ptr::read_unaligned(memory::phys_to_virt(rsdp_addr).as_ptr::<Rsdp>()) };
+    if &rsdp.signature != b"RSD PTR " {
+        return Err("invalid RSDP signature");
+    }
+    let rsdp_bytes =
+        unsafe { slice::from_raw_parts(memory::phys_to_virt(rsdp_addr).as_ptr::<u8>(), 20) };
+    if !checksum_ok(rsdp_bytes) {
+        return Err("invalid RSDP checksum");
+    }
+
+    // prefer the XSDT with 64-bit pointers if the firmware provides one
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+    let root_bytes = unsafe { table_bytes(root) };
+    if !checksum_ok(root_bytes) {
+        return Err("invalid root system description table checksum");
+    }
+
+    let mut tables = Vec::new();
+    let mut offset = size_of::<SdtHeader>();
+    while offset + entry_size <= root_bytes.len() {
+        let addr = if entry_size == 8 {
+            read_u64(root_bytes, offset)
+        } else {
+            read_u32(root_bytes, offset) as u64
+        };
+        tables.push(PhysAddr::new(addr));
+        offset += entry_size;
+    }
+
+    let mut acpi = Acpi { tables, madt: None };
+    if let Some(madt) = acpi.find_table(b"APIC") {
+        acpi.madt = Some(parse_madt(unsafe { table_bytes(madt) }));
+    }
+    ACPI.init_once(|| acpi);
+    Ok(())
+}
+
+fn parse_madt(bytes: &[u8]) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: read_u32(bytes, 36) as u64,
+        ..Default::default()
+    };
+
+    let mut offset = size_of::<SdtHeader>() + 8;
+    while offset + 2 <= bytes.len() {
+        let entry_type = bytes[offset];
+        let length = bytes[offset + 1] as usize;
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+        match entry_type {
+            // Processor Local APIC
+            0 => madt.processors.push(Processor {
+                processor_id: entry[2] as u32,
+                apic_id: entry[3] as u32,
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            // I/O APIC
+            1 => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            // Interrupt Source Override
+            2 => madt.overrides.push(InterruptSourceOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            // Local APIC Address Override
+            5 => madt.local_apic_address = read_u64(entry, 4),
+            // Processor Local x2APIC
+            9 => madt.processors.push(Processor {
+                processor_id: read_u32(entry, 12),
+                apic_id: read_u32(entry, 4),
+                enabled: read_u32(entry, 8) & 1 != 0,
+            }),
+            _ => {}
+        }
+        offset += length;
+    }
+    madt
+}
@@ -0,0 +1,306 @@
+//! Per-process page tables.
+//!
+//! Every address space shares the kernel's upper half (level 4 entries
+//! 256..512) and owns its lower half, which is where user code lives. The
+//! kernel's level 4 entries are all allocated at boot and never change, so
+//! copying them once keeps an address space in sync with later kernel
+//! mappings.
+
+use core::ptr;
+
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    elf::{USER_STACK_SIZE, USER_STACK_TOP},
+    memory::{self, FRAME_ALLOCATOR, MAPPER},
+    sync::IrqSpinLock,
+};
+
+/// First level 4 entry belonging to the kernel.
+pub const KERNEL_PML4_START: usize = 256;
+
+/// Highest address (exclusive) user mappings may use.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// Highest address (exclusive) user pages are actually mapped at. The last
+/// page below [`USER_END`] is never mapped, so no user instruction can end
+/// right at the non-canonical boundary.
+const USER_MAP_END: u64 = USER_END - 4096;
+/// Where [`AddressSpace::map_anonymous`] places mappings without a fixed address.
+const MMAP_START: u64 = 0x0000_1000_0000_0000;
+/// End (exclusive) of the area used by [`AddressSpace::map_anonymous`],
+/// leaving a guard page below the user stack.
+const MMAP_END: u64 = USER_STACK_TOP - USER_STACK_SIZE - 4096;
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// Serializes changes to the user half.
+    user_half: IrqSpinLock<UserHalf>,
+}
+
+struct UserHalf {
+    mmap_next: u64,
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    pub fn new() -> Option<Self> {
+        let level_4_frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate_frame()?;
+        let table = unsafe { table_mut(level_4_frame) };
+        table.zero();
+
+        let mut mapper = MAPPER.get().unwrap().lock();
+        let kernel_table = mapper.level_4_table();
+        for index in KERNEL_PML4_START..512 {
+            table[index] = kernel_table[index].clone();
+        }
+        Some(AddressSpace {
+            level_4_frame,
+            user_half: IrqSpinLock::new(UserHalf {
+                mmap_next: MMAP_START,
+            }),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        let offset = memory::phys_to_virt(PhysAddr::new(0));
+        unsafe { OffsetPageTable::new(table_mut(self.level_4_frame), offset) }
+    }
+
+    /// Backs `page` with a fresh zeroed frame, accessible from user mode.
+    pub fn map_user(
+        &self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let _user_half = self.user_half.lock();
+        self.map_user_locked(page, flags)
+    }
+
+    fn map_user_locked(
+        &self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert!(
+            page.start_address().as_u64() < USER_MAP_END,
+            "user mapping in the kernel half"
+        );
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            ptr::write_bytes(
+                memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                4096,
+            );
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let result = unsafe {
+            self.mapper()
+                .map_to(page, frame, flags, &mut *frame_allocator)
+        };
+        match result {
+            // the address space may not be active; switching to it flushes the TLB anyway
+            Ok(flush) => flush.ignore(),
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(err);
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Unmaps `page` and frees the frame backing it, if any.
+    fn unmap_user_locked(&self, page: Page<Size4KiB>) {
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        if let Ok((frame, flush)) = self.mapper().unmap(page) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            if self.is_active() {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+    }
+
+    /// Maps every page overlapping `start..start + size` with [`AddressSpace::map_user`].
+    ///
+    /// Either all pages are mapped or, on failure, none of them.
+    pub fn map_user_range(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let _user_half = self.user_half.lock();
+        self.map_user_range_locked(start, size, flags)
+    }
+
+    fn map_user_range_locked(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        if size == 0 {
+            return Ok(());
+        }
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + size - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            if let Err(err) = self.map_user_locked(page, flags) {
+                for mapped in Page::range(first, page) {
+                    self.unmap_user_locked(mapped);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps `size` bytes of zeroed memory at a free address and returns it.
+    pub fn map_anonymous(
+        &self,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
+        let mut user_half = self.user_half.lock();
+        // `size` comes straight from user space, so it may be anything
+        let size = size
+            .checked_add(4095)
+            .ok_or(MapToError::FrameAllocationFailed)?
+            & !4095;
+        if size == 0 || size > MMAP_END.saturating_sub(user_half.mmap_next) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let start = VirtAddr::new(user_half.mmap_next);
+        self.map_user_range_locked(start, size, flags)?;
+        // leave an unmapped guard page between mappings
+        user_half.mmap_next += size + 4096;
+        Ok(start)
+    }
+
+    /// Changes the permissions of an already mapped user page.
+    ///
+    /// Only the calling CPU's TLB is flushed, so this is meant for address
+    /// spaces that are not running yet.
+    pub fn protect(
+        &self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let _user_half = self.user_half.lock();
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { self.mapper().update_flags(page, flags)?.flush() };
+        Ok(())
+    }
+
+    /// Flags of the page containing `addr`, if it is mapped.
+    pub fn page_flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// Physical address `addr` is mapped to in this address space.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { frame, offset, .. } => {
+                Some(frame.start_address() + offset)
+            }
+            _ => None,
+        }
+    }
+
+    /// Copies `data` to `addr` in this address space, which need not be active.
+    ///
+    /// Fails with the first target address whose page is not mapped.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), VirtAddr> {
+        let mut written = 0;
+        while written < data.len() {
+            let target = addr + written;
+            let phys = self.translate(target).ok_or(target)?;
+            let chunk = (4096 - usize::from(target.page_offset())).min(data.len() - written);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    memory::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches the calling CPU to this address space.
+    pub fn activate(&self) {
+        if !self.is_active() {
+            unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+        }
+    }
+}
+
+/// Switches the calling CPU back to the kernel's own page table.
+pub fn activate_kernel() {
+    let kernel = memory::kernel_page_table();
+    if Cr3::read().0 != kernel {
+        unsafe { Cr3::write(kernel, Cr3Flags::empty()) };
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user half, including the page tables themselves.
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        let level_4 = unsafe { table_mut(self.level_4_frame) };
+        for entry in level_4.iter().take(KERNEL_PML4_START) {
+            if let Ok(frame) = entry.frame() {
+                free_table(frame, 3, &mut *frame_allocator);
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Frees the page table in `frame` at the given level and everything mapped below it.
+fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let table = unsafe { table_mut(frame) };
+    for entry in table.iter() {
+        // user mappings are only ever made with 4 KiB pages
+        if let Ok(child) = entry.frame() {
+            if level > 1 {
+                free_table(child, level - 1, frame_allocator);
+            } else {
+                unsafe { frame_allocator.deallocate_frame(child) };
+            }
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
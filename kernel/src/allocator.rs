@@ -1,8 +1,12 @@
-pub const HEAP_START: usize = 0x4400_0000_0000;
+// in the upper half, which is shared by all address spaces
+pub const HEAP_START: usize = 0xFFFF_8800_0000_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024 * 32; // 32 MiB since we're using GUI
 
+use core::alloc::{GlobalAlloc, Layout};
+
 use linked_list_allocator::LockedHeap;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, Mapper, Page, PageTableFlags, Size4KiB,
     },
@@ -11,8 +15,23 @@ use x86_64::{
 
 use crate::memory::BootInfoFrameAllocator;
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: InterruptSafeHeap = InterruptSafeHeap(LockedHeap::empty());
+
+/// Heap whose lock is only ever held with interrupts disabled, so a thread
+/// can never be preempted while holding it and the scheduler may allocate
+/// from inside the timer interrupt.
+struct InterruptSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for InterruptSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -36,7 +55,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
     }
 
     Ok(())
@@ -0,0 +1,160 @@
+//! Local APIC driver: per-CPU timer, end-of-interrupt and inter-processor interrupts.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+use crate::{memory, time};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REG_ID: usize = 0x020;
+const REG_TPR: usize = 0x080;
+const REG_EOI: usize = 0x0B0;
+const REG_SVR: usize = 0x0F0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DEST_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Vector of the spurious interrupt. Its low four bits must be set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Period of the per-CPU scheduler timer.
+pub const TIMER_PERIOD_MS: u64 = 10;
+
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base as usize + reg) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base as usize + reg) as *mut u32, value) };
+}
+
+/// Maps the local APIC registers. Called once on the BSP; all CPUs see their
+/// own local APIC at the same physical address.
+pub fn init() {
+    let apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let value = unsafe { apic_base.read() };
+    let phys = PhysAddr::new(value & 0x000F_FFFF_FFFF_F000);
+    let virt = memory::map_mmio(phys, 4096);
+    LAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+}
+
+/// Enables the local APIC of the calling CPU.
+pub fn init_local() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    unsafe {
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn is_initialized() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// APIC ID of the calling CPU.
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+fn wait_for_delivery() {
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn send_icr(apic_id: u32, low: u32) {
+    write(REG_ICR_HIGH, apic_id << 24);
+    write(REG_ICR_LOW, low);
+    wait_for_delivery();
+}
+
+/// Sends a fixed interrupt with the given vector to one CPU.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send_icr(apic_id, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends a fixed interrupt with the given vector to every CPU but the calling one.
+pub fn send_ipi_all_excluding_self(vector: u8) {
+    write(REG_ICR_HIGH, 0);
+    write(
+        REG_ICR_LOW,
+        ICR_DEST_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32,
+    );
+    wait_for_delivery();
+}
+
+pub fn send_init(apic_id: u32) {
+    send_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI; the CPU starts executing real-mode code at `page * 0x1000`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_icr(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+/// Measures the local APIC timer frequency against the PIT. Requires
+/// interrupts to be enabled so that the system clock advances.
+pub fn calibrate_timer() {
+    const CALIBRATION_TICKS: u64 = 5;
+
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+
+    // start right at a tick boundary
+    let start = time::ticks();
+    while time::ticks() == start {
+        core::hint::spin_loop();
+    }
+    write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+    let start = time::ticks();
+    while time::ticks() < start + CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT_COUNT);
+    write(REG_TIMER_INITIAL_COUNT, 0);
+
+    let elapsed_ms = CALIBRATION_TICKS * 1000 / time::TICKS_PER_SECOND;
+    TIMER_TICKS_PER_MS.store(elapsed as u64 / elapsed_ms, Ordering::Relaxed);
+}
+
+/// Starts the periodic scheduler timer of the calling CPU.
+pub fn start_timer(vector: u8) {
+    let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(
+        REG_TIMER_INITIAL_COUNT,
+        (ticks_per_ms * TIMER_PERIOD_MS).min(u32::MAX as u64) as u32,
+    );
+}
@@ -0,0 +1,530 @@
+//! SATA disks behind an AHCI controller, PCI class 01:06.
+//!
+//! Every port uses a single command slot. A command runs with its port
+//! locked and the calling thread sleeps until the port interrupts; data
+//! moves through bounce frames the port owns. Without MSI the command slot
+//! is polled instead.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+
+use x86_64::VirtAddr;
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    colors, interrupts, log_info, log_warn,
+    memory::DmaFrame,
+    pci::{self, DeviceMatch, PciDevice, PciDriver},
+    sync::{Mutex, WaitQueue},
+    time,
+};
+
+/// The BAR holding the controller registers.
+const ABAR: usize = 5;
+
+// generic host control registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+
+const CAP_S64A: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const MAX_PORTS: usize = 32;
+
+// port registers
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0C;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const IS_DHRS: u32 = 1 << 0;
+const IS_PSS: u32 = 1 << 1;
+const IS_DPS: u32 = 1 << 2;
+const IS_IFS: u32 = 1 << 27;
+const IS_HBDS: u32 = 1 << 28;
+const IS_HBFS: u32 = 1 << 29;
+const IS_TFES: u32 = 1 << 30;
+const IS_ERRORS: u32 = IS_IFS | IS_HBDS | IS_HBFS | IS_TFES;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Device detection in the SATA status: present with the link up.
+const SSTS_DET_MASK: u32 = 0xF;
+const SSTS_DET_PRESENT: u32 = 3;
+
+/// Signature of a plain ATA disk, as opposed to ATAPI drives and port
+/// multipliers.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Marks a host to device FIS as carrying a command.
+const FIS_COMMAND: u8 = 0x80;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// The command list takes the first kilobyte of the port's frame, the
+/// FISes the device sends are received after it.
+const RECEIVED_FIS_OFFSET: u64 = 1024;
+/// Offset of the physical region descriptors in a command table.
+const PRDT_OFFSET: u64 = 0x80;
+const PRD_INTERRUPT: u32 = 1 << 31;
+/// Length of a host to device register FIS in dwords.
+const FIS_H2D_DWORDS: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+
+const SECTOR_SIZE: usize = 512;
+const FRAME_SIZE: usize = 4096;
+/// Bounce frames of every port, which is also the largest command.
+const MAX_FRAMES: usize = 32;
+const TIMEOUT_MS: u64 = 5000;
+/// How often the command slot is checked without interrupts.
+const POLL_MS: u64 = 1;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[DeviceMatch::Class {
+        class: 0x01,
+        subclass: 0x06,
+    }],
+    probe,
+};
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+fn read(addr: VirtAddr) -> u32 {
+    unsafe { ptr::read_volatile(addr.as_ptr()) }
+}
+
+fn write(addr: VirtAddr, value: u32) {
+    unsafe { ptr::write_volatile(addr.as_mut_ptr(), value) }
+}
+
+struct Controller {
+    regs: VirtAddr,
+    ports: Vec<Arc<Port>>,
+}
+
+impl Controller {
+    /// Collects the interrupt status of every port that raised one.
+    fn handle_interrupt(&self) {
+        let pending = read(self.regs + HBA_IS);
+        for port in &self.ports {
+            if pending & (1 << port.index) != 0 {
+                let status = port.read(PX_IS);
+                port.write(PX_IS, status);
+                port.status.fetch_or(status, Ordering::Release);
+                port.waiters.notify_all();
+            }
+        }
+        write(self.regs + HBA_IS, pending);
+    }
+}
+
+/// What the port hands to the controller, only touched with the port locked.
+struct PortMemory {
+    /// The command list followed by the received FIS area.
+    list: DmaFrame,
+    /// The command table of slot 0.
+    table: DmaFrame,
+    buffers: Vec<DmaFrame>,
+}
+
+struct Port {
+    index: usize,
+    regs: VirtAddr,
+    /// Held while a command runs.
+    memory: Mutex<PortMemory>,
+    /// Interrupt status bits collected since the current command started.
+    status: AtomicU32,
+    waiters: WaitQueue,
+    /// Whether the controller interrupts, or the port has to be polled.
+    interrupts: AtomicBool,
+}
+
+impl Port {
+    fn read(&self, register: u64) -> u32 {
+        read(self.regs + register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write(self.regs + register, value)
+    }
+
+    /// Waits until none of `bits` is set in `register`.
+    fn wait_clear(&self, register: u64, bits: u32) -> Result<(), BlockError> {
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        while self.read(register) & bits != 0 {
+            if time::uptime_ms() >= deadline {
+                return Err(BlockError::Io);
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        self.wait_clear(PX_CMD, CMD_CR)?;
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        self.wait_clear(PX_CMD, CMD_FR)
+    }
+
+    fn start(&self) -> Result<(), BlockError> {
+        self.write(PX_SERR, u32::MAX);
+        self.write(PX_IS, u32::MAX);
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE | CMD_SUD | CMD_POD);
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// Stops the port and points it at freshly allocated memory.
+    fn new(index: usize, regs: VirtAddr, dma64: bool) -> Result<Port, &'static str> {
+        let memory = PortMemory {
+            list: DmaFrame::new().ok_or("out of memory")?,
+            table: DmaFrame::new().ok_or("out of memory")?,
+            buffers: (0..MAX_FRAMES)
+                .map(|_| DmaFrame::new())
+                .collect::<Option<_>>()
+                .ok_or("out of memory")?,
+        };
+        let reachable = |frame: &DmaFrame| dma64 || frame.phys().as_u64() >> 32 == 0;
+        if !(reachable(&memory.list)
+            && reachable(&memory.table)
+            && memory.buffers.iter().all(reachable))
+        {
+            return Err("controller cannot reach memory above 4 GiB");
+        }
+        let list = memory.list.phys().as_u64();
+        let fis = list + RECEIVED_FIS_OFFSET;
+
+        let port = Port {
+            index,
+            regs,
+            memory: Mutex::new(memory),
+            status: AtomicU32::new(0),
+            waiters: WaitQueue::new(),
+            interrupts: AtomicBool::new(false),
+        };
+        port.stop().map_err(|_| "port does not stop")?;
+        port.write(PX_CLB, list as u32);
+        port.write(PX_CLBU, (list >> 32) as u32);
+        port.write(PX_FB, fis as u32);
+        port.write(PX_FBU, (fis >> 32) as u32);
+        port.start().map_err(|_| "port does not start")?;
+        // PIO commands end with a PIO Setup FIS rather than a register FIS
+        port.write(PX_IE, IS_DHRS | IS_PSS | IS_DPS | IS_ERRORS);
+        Ok(port)
+    }
+
+    /// Runs `command` on `count` sectors from `lba` in slot 0, moving `len`
+    /// bytes through the bounce frames.
+    fn run(
+        &self,
+        memory: &mut PortMemory,
+        command: u8,
+        lba: u64,
+        count: u16,
+        len: usize,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+
+        let table = memory.table.as_mut_slice();
+        table[..PRDT_OFFSET as usize].fill(0);
+        let lba = lba.to_le_bytes();
+        let count = count.to_le_bytes();
+        table[..14].copy_from_slice(&[
+            FIS_TYPE_REG_H2D,
+            FIS_COMMAND,
+            command,
+            0,
+            lba[0],
+            lba[1],
+            lba[2],
+            DEVICE_LBA,
+            lba[3],
+            lba[4],
+            lba[5],
+            0,
+            count[0],
+            count[1],
+        ]);
+        let regions = len.div_ceil(FRAME_SIZE);
+        for i in 0..regions {
+            let size = (len - i * FRAME_SIZE).min(FRAME_SIZE) as u32;
+            let flags = if i + 1 == regions { PRD_INTERRUPT } else { 0 };
+            let address = memory.buffers[i].phys().as_u64();
+            let entry = PRDT_OFFSET as usize + i * 16;
+            table[entry..entry + 4].copy_from_slice(&(address as u32).to_le_bytes());
+            table[entry + 4..entry + 8].copy_from_slice(&((address >> 32) as u32).to_le_bytes());
+            table[entry + 8..entry + 12].fill(0);
+            table[entry + 12..entry + 16].copy_from_slice(&((size - 1) | flags).to_le_bytes());
+        }
+
+        let table = memory.table.phys().as_u64();
+        let mut header = FIS_H2D_DWORDS | (regions as u32) << 16;
+        if write {
+            header |= HEADER_WRITE;
+        }
+        let list = memory.list.as_mut_slice();
+        list[..32].fill(0);
+        list[0..4].copy_from_slice(&header.to_le_bytes());
+        list[8..12].copy_from_slice(&(table as u32).to_le_bytes());
+        list[12..16].copy_from_slice(&((table >> 32) as u32).to_le_bytes());
+
+        let interrupts = self.interrupts.load(Ordering::Relaxed);
+        if !interrupts {
+            self.write(PX_IS, u32::MAX);
+        }
+        self.status.store(0, Ordering::Relaxed);
+        // the command has to be in memory before the controller fetches it
+        fence(Ordering::SeqCst);
+        self.write(PX_CI, 1);
+
+        // polled ports never have their interrupt status collected
+        let status = || self.status.load(Ordering::Acquire) | self.read(PX_IS);
+        let busy = || self.read(PX_CI) & 1 != 0 && status() & IS_ERRORS == 0;
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        loop {
+            let step = if interrupts { TIMEOUT_MS } else { POLL_MS };
+            if self.waiters.wait_while_timeout_uninterruptible(step, busy) {
+                break;
+            }
+            if time::uptime_ms() >= deadline {
+                self.recover();
+                return Err(BlockError::Io);
+            }
+        }
+
+        if status() & IS_ERRORS != 0 || self.read(PX_TFD) & TFD_ERR != 0 {
+            self.recover();
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    /// Restarts the port after a failed command, which clears the error.
+    fn recover(&self) {
+        if self.stop().and_then(|_| self.start()).is_err() {
+            log_warn!("ahci: port {} does not recover", self.index);
+        }
+    }
+
+    fn identify(&self) -> Result<(String, u64), BlockError> {
+        let mut memory = self.memory.lock();
+        self.run(&mut memory, CMD_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+        let identify = &memory.buffers[0].as_slice()[..SECTOR_SIZE];
+        let word =
+            |index: usize| u16::from_le_bytes([identify[index * 2], identify[index * 2 + 1]]);
+
+        // the model string has the bytes of every word swapped
+        let model: Vec<u8> = (27..47)
+            .flat_map(|index| word(index).to_be_bytes())
+            .collect();
+        let model = String::from_utf8_lossy(&model).trim().into();
+        if word(83) & (1 << 10) == 0 {
+            log_warn!("ahci: {} does not support LBA48", model);
+            return Err(BlockError::Io);
+        }
+        let sectors = (100..104)
+            .rev()
+            .fold(0, |sectors, index| sectors << 16 | word(index) as u64);
+        Ok((model, sectors))
+    }
+}
+
+pub struct AhciDisk {
+    port: Arc<Port>,
+    model: String,
+    sectors: u64,
+}
+
+impl AhciDisk {
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let mut memory = self.port.memory.lock();
+        let mut lba = start;
+        for chunk in buf.chunks_mut(MAX_FRAMES * FRAME_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.port.run(
+                &mut memory,
+                CMD_READ_DMA_EXT,
+                lba,
+                count,
+                chunk.len(),
+                false,
+            )?;
+            for (part, frame) in chunk.chunks_mut(FRAME_SIZE).zip(&memory.buffers) {
+                part.copy_from_slice(&frame.as_slice()[..part.len()]);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let mut memory = self.port.memory.lock();
+        let mut lba = start;
+        for chunk in buf.chunks(MAX_FRAMES * FRAME_SIZE) {
+            for (part, frame) in chunk.chunks(FRAME_SIZE).zip(&mut memory.buffers) {
+                frame.as_mut_slice()[..part.len()].copy_from_slice(part);
+            }
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.port.run(
+                &mut memory,
+                CMD_WRITE_DMA_EXT,
+                lba,
+                count,
+                chunk.len(),
+                true,
+            )?;
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut memory = self.port.memory.lock();
+        self.port
+            .run(&mut memory, CMD_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let regs = device.map_bar(ABAR).ok_or("no AHCI registers")?;
+    device.enable_bus_master();
+    write(regs + HBA_GHC, read(regs + HBA_GHC) | GHC_AE);
+    let dma64 = read(regs + HBA_CAP) & CAP_S64A != 0;
+    let implemented = read(regs + HBA_PI);
+
+    let mut ports = Vec::new();
+    for index in 0..MAX_PORTS {
+        let port_regs = regs + PORT_BASE + index as u64 * PORT_SIZE;
+        if implemented & (1 << index) == 0
+            || read(port_regs + PX_SSTS) & SSTS_DET_MASK != SSTS_DET_PRESENT
+        {
+            continue;
+        }
+        if read(port_regs + PX_SIG) != SIGNATURE_ATA {
+            log_info!(
+                "ahci: port {} has no disk (signature {:#x})",
+                index,
+                read(port_regs + PX_SIG)
+            );
+            continue;
+        }
+        match Port::new(index, port_regs, dma64) {
+            Ok(port) => ports.push(Arc::new(port)),
+            Err(err) => {
+                log_warn!("ahci: cannot use port {}: {}", index, err);
+            }
+        }
+    }
+    if ports.is_empty() {
+        return Err("no disks attached");
+    }
+
+    let controller = Arc::new(Controller { regs, ports });
+    let handler = controller.clone();
+    let vector = interrupts::allocate_vector(Arc::new(move || handler.handle_interrupt()));
+    let msi = match vector {
+        Some(vector) => match device.enable_msi(vector) {
+            Ok(()) => true,
+            Err(_) => {
+                interrupts::free_vector(vector);
+                false
+            }
+        },
+        None => false,
+    };
+    if msi {
+        write(regs + HBA_IS, u32::MAX);
+        write(regs + HBA_GHC, read(regs + HBA_GHC) | GHC_IE);
+    } else {
+        log_warn!("ahci: no MSI, polling PCI {}", device.address);
+    }
+
+    for port in &controller.ports {
+        port.interrupts.store(msi, Ordering::Relaxed);
+        let (model, sectors) = match port.identify() {
+            Ok(identity) => identity,
+            Err(_) => {
+                log_warn!("ahci: port {} does not identify", port.index);
+                continue;
+            }
+        };
+        let name = format!(
+            "sd{}",
+            (b'a' + NEXT_DISK.fetch_add(1, Ordering::Relaxed) as u8) as char
+        );
+        log_info!(
+            "{}: {} (AHCI port {}, {} MiB)",
+            name,
+            model,
+            port.index,
+            (sectors * SECTOR_SIZE as u64) >> 20
+        );
+        let disk = AhciDisk {
+            port: port.clone(),
+            model,
+            sectors,
+        };
+        if let Err(err) = super::register_disk(&name, Arc::new(disk)) {
+            log_warn!("Cannot register {}: {:?}", name, err);
+        }
+    }
+    Ok(())
+}
+
+/// Registers the driver, which takes every AHCI controller on the PCI bus
+/// and registers its disks as `sda`, `sdb` and so on. Returns how many
+/// controllers there were.
+pub fn init() -> usize {
+    pci::register_driver(&DRIVER)
+}
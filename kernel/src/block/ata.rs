@@ -0,0 +1,556 @@
+//! ATA disks and ATAPI drives on the two legacy IDE channels, driven by
+//! programmed I/O.
+//!
+//! Drives are identified by polling with their interrupts off. Afterwards a
+//! command is issued with its channel locked and the calling thread sleeps
+//! until the drive raises its interrupt for every block of data.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use x86_64::instructions::port::Port;
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    colors,
+    interrupts::{self, InterruptIndex},
+    log_info, log_warn,
+    sync::{Mutex, WaitQueue},
+    time,
+};
+
+pub const PRIMARY: usize = 0;
+pub const SECONDARY: usize = 1;
+
+// registers relative to the command block base
+const REG_DATA: u16 = 0;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Device control bits: interrupts off and software reset.
+const CONTROL_NIEN: u8 = 1 << 1;
+const CONTROL_SRST: u8 = 1 << 2;
+
+const DRIVE_LBA: u8 = 0xE0;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xA0;
+const CMD_IDENTIFY_PACKET: u8 = 0xA1;
+const CMD_FLUSH_CACHE: u8 = 0xE7;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xA8;
+
+/// Signatures left in the LBA mid and high registers by packet devices.
+const ATAPI_SIGNATURES: [(u8, u8); 2] = [(0x14, 0xEB), (0x69, 0x96)];
+
+const SECTOR_SIZE: usize = 512;
+/// Most sectors a single LBA28 or LBA48 command transfers.
+const LBA28_MAX_SECTORS: usize = 256;
+const LBA48_MAX_SECTORS: usize = 65536;
+/// Sectors a drive can address with LBA28.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How long a drive may take for one block or to become ready.
+const TIMEOUT_MS: u64 = 5000;
+
+/// One IDE channel with up to two drives, which share its registers and interrupt.
+struct Channel {
+    base: u16,
+    control: u16,
+    irq: InterruptIndex,
+    /// Held while a command runs.
+    lock: Mutex<()>,
+    /// Set by the interrupt handler, together with the status it read.
+    interrupted: AtomicBool,
+    status: AtomicU8,
+    waiters: WaitQueue,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new(0x1F0, 0x3F6, InterruptIndex::PrimaryATAHardDisk),
+    Channel::new(0x170, 0x376, InterruptIndex::SecondaryATAHardDisk),
+];
+
+/// Called by the interrupt handler of `channel`. Reading the status register
+/// acknowledges the interrupt.
+pub fn handle_interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    channel
+        .status
+        .store(channel.read(REG_STATUS), Ordering::Relaxed);
+    channel.interrupted.store(true, Ordering::Release);
+    channel.waiters.notify_all();
+}
+
+impl Channel {
+    const fn new(base: u16, control: u16, irq: InterruptIndex) -> Self {
+        Channel {
+            base,
+            control,
+            irq,
+            lock: Mutex::new(()),
+            interrupted: AtomicBool::new(false),
+            status: AtomicU8::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// The status without acknowledging an interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        unsafe { Port::new(self.control).write(value) }
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, data: &[u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in data.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    /// Gives the drive the 400 ns it needs to put its status on the bus.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, drive: u8, bits: u8) {
+        self.write(REG_DRIVE, bits | drive << 4);
+        self.delay();
+    }
+
+    /// Polls until the drive is no longer busy and returns its status.
+    fn wait_ready(&self) -> Result<u8, BlockError> {
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if time::uptime_ms() >= deadline {
+                return Err(BlockError::Io);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Polls until the drive wants to transfer data.
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        let status = self.wait_ready()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    /// Forgets about earlier interrupts; called before issuing a command.
+    fn arm(&self) {
+        self.interrupted.store(false, Ordering::Release);
+    }
+
+    /// Sleeps until the drive raises its interrupt and returns the status.
+    fn wait_interrupt(&self) -> Result<u8, BlockError> {
+        let done = self
+            .waiters
+            .wait_while_timeout_uninterruptible(TIMEOUT_MS, || {
+                !self.interrupted.load(Ordering::Acquire)
+            });
+        if !done {
+            log_warn!("ATA channel {:#X} timed out", self.base);
+            self.reset();
+            return Err(BlockError::Io);
+        }
+        self.arm();
+        let status = self.status.load(Ordering::Relaxed);
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(status)
+    }
+
+    /// Resets both drives, e.g. after one stopped answering.
+    fn reset(&self) {
+        self.set_control(CONTROL_SRST);
+        self.delay();
+        self.set_control(0);
+        let _ = self.wait_ready();
+    }
+
+    /// Loads the task file for a transfer of `count` sectors at `lba`.
+    fn set_lba(&self, drive: u8, lba: u64, count: usize, lba48: bool) {
+        if lba48 {
+            self.select(drive, 0x40);
+            // high bytes first, they are latched by the second write
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(drive, DRIVE_LBA | (lba >> 24) as u8 & 0x0F);
+        }
+        // a count of 0 means the maximum
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+    }
+
+    /// Sends the command packet of an ATAPI command and reads the data it
+    /// returns into `buf`. Returns how many bytes arrived.
+    fn packet(&self, drive: u8, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize, BlockError> {
+        self.select(drive, 0xA0);
+        self.wait_ready()?;
+        self.write(REG_FEATURES, 0);
+        // the most bytes the drive may transfer per interrupt
+        let limit = buf.len().min(0xFFFE) as u16;
+        self.write(REG_LBA_MID, limit as u8);
+        self.write(REG_LBA_HIGH, (limit >> 8) as u8);
+        self.arm();
+        self.write(REG_COMMAND, CMD_PACKET);
+        self.wait_drq()?;
+        self.write_data(packet);
+
+        let mut done = 0;
+        loop {
+            let status = self.wait_interrupt()?;
+            if status & STATUS_DRQ == 0 {
+                return Ok(done);
+            }
+            let len = self.read(REG_LBA_MID) as usize | (self.read(REG_LBA_HIGH) as usize) << 8;
+            if done + len > buf.len() || !len.is_multiple_of(2) {
+                self.reset();
+                return Err(BlockError::Io);
+            }
+            self.read_data(&mut buf[done..done + len]);
+            done += len;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveKind {
+    Ata,
+    Atapi,
+}
+
+/// A drive on one of the channels.
+pub struct AtaDrive {
+    channel: &'static Channel,
+    /// 0 for the master, 1 for the slave.
+    drive: u8,
+    kind: DriveKind,
+    model: String,
+    sectors: u64,
+    sector_size: usize,
+    lba48: bool,
+}
+
+impl AtaDrive {
+    pub fn kind(&self) -> DriveKind {
+        self.kind
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Looks for a drive and reads its identification, with the channel's
+    /// interrupts off.
+    fn probe(channel: &'static Channel, drive: u8) -> Option<AtaDrive> {
+        channel.select(drive, 0xA0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.write(REG_COMMAND, CMD_IDENTIFY);
+        if channel.alt_status() == 0 {
+            return None;
+        }
+        channel.wait_ready().ok()?;
+
+        let signature = (channel.read(REG_LBA_MID), channel.read(REG_LBA_HIGH));
+        let kind = if signature == (0, 0) {
+            DriveKind::Ata
+        } else if ATAPI_SIGNATURES.contains(&signature) {
+            channel.write(REG_COMMAND, CMD_IDENTIFY_PACKET);
+            DriveKind::Atapi
+        } else {
+            return None;
+        };
+        channel.wait_drq().ok()?;
+        let mut identify = [0; SECTOR_SIZE];
+        channel.read_data(&mut identify);
+        let word =
+            |index: usize| u16::from_le_bytes([identify[index * 2], identify[index * 2 + 1]]);
+
+        // the model string has the bytes of every word swapped
+        let model: Vec<u8> = (27..47)
+            .flat_map(|index| word(index).to_be_bytes())
+            .collect();
+        let model = String::from_utf8_lossy(&model).trim().into();
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (100..104)
+                .rev()
+                .fold(0, |sectors, index| sectors << 16 | word(index) as u64)
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+        if kind == DriveKind::Ata && word(49) & (1 << 9) == 0 {
+            log_warn!("ATA drive {} only supports CHS addressing", model);
+            return None;
+        }
+        Some(AtaDrive {
+            channel,
+            drive,
+            kind,
+            model,
+            sectors,
+            sector_size: SECTOR_SIZE,
+            lba48,
+        })
+    }
+
+    /// Asks an ATAPI drive for the size of its medium.
+    fn read_capacity(&mut self) -> Result<(), BlockError> {
+        let mut packet = [0; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+        let mut capacity = [0; 8];
+        let _lock = self.channel.lock.lock();
+        if self.channel.packet(self.drive, &packet, &mut capacity)? != capacity.len() {
+            return Err(BlockError::Io);
+        }
+        let last = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap()) as usize;
+        if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE) {
+            return Err(BlockError::Io);
+        }
+        self.sectors = last as u64 + 1;
+        self.sector_size = block_size;
+        Ok(())
+    }
+
+    fn max_sectors(&self, lba48: bool) -> usize {
+        if lba48 {
+            LBA48_MAX_SECTORS
+        } else {
+            LBA28_MAX_SECTORS
+        }
+    }
+
+    /// Transfers sectors with PIO commands of at most the size the
+    /// addressing mode allows.
+    fn transfer(
+        &self,
+        start: u64,
+        len: usize,
+        mut chunk: impl FnMut(u64, usize, usize, bool) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let count = len / SECTOR_SIZE;
+        let mut done = 0;
+        while done < count {
+            let lba = start + done as u64;
+            let lba48 = self.lba48 && lba + (count - done) as u64 > LBA28_LIMIT;
+            let sectors = (count - done).min(self.max_sectors(lba48));
+            chunk(lba, done * SECTOR_SIZE, sectors, lba48)?;
+            done += sectors;
+        }
+        Ok(())
+    }
+
+    fn read_ata(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        self.transfer(start, buf.len(), |lba, offset, sectors, lba48| {
+            channel.wait_ready()?;
+            channel.set_lba(self.drive, lba, sectors, lba48);
+            channel.arm();
+            let command = if lba48 {
+                CMD_READ_SECTORS_EXT
+            } else {
+                CMD_READ_SECTORS
+            };
+            channel.write(REG_COMMAND, command);
+            // one interrupt for every sector
+            for sector in buf[offset..offset + sectors * SECTOR_SIZE].chunks_exact_mut(SECTOR_SIZE)
+            {
+                if channel.wait_interrupt()? & STATUS_DRQ == 0 {
+                    return Err(BlockError::Io);
+                }
+                channel.read_data(sector);
+            }
+            Ok(())
+        })
+    }
+
+    fn write_ata(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        self.transfer(start, data.len(), |lba, offset, sectors, lba48| {
+            channel.wait_ready()?;
+            channel.set_lba(self.drive, lba, sectors, lba48);
+            channel.arm();
+            let command = if lba48 {
+                CMD_WRITE_SECTORS_EXT
+            } else {
+                CMD_WRITE_SECTORS
+            };
+            channel.write(REG_COMMAND, command);
+            // the first sector goes out without an interrupt, each one after
+            // that once the drive has taken the previous one
+            channel.wait_drq()?;
+            for sector in data[offset..offset + sectors * SECTOR_SIZE].chunks_exact(SECTOR_SIZE) {
+                channel.write_data(sector);
+                channel.wait_interrupt()?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_atapi(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        // stay below the 64 KiB a single data phase can describe
+        let per_command = (0xFFFE / self.sector_size).max(1);
+        for (i, chunk) in buf.chunks_mut(per_command * self.sector_size).enumerate() {
+            let lba = (start + (i * per_command) as u64) as u32;
+            let count = (chunk.len() / self.sector_size) as u32;
+            let mut packet = [0; 12];
+            packet[0] = SCSI_READ_12;
+            packet[2..6].copy_from_slice(&lba.to_be_bytes());
+            packet[6..10].copy_from_slice(&count.to_be_bytes());
+            if channel.packet(self.drive, &packet, chunk)? != chunk.len() {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        match self.kind {
+            DriveKind::Ata => self.read_ata(start, buf),
+            DriveKind::Atapi => self.read_atapi(start, buf),
+        }
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        match self.kind {
+            DriveKind::Ata => self.write_ata(start, buf),
+            DriveKind::Atapi => Err(BlockError::ReadOnly),
+        }
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.kind == DriveKind::Atapi {
+            return Ok(());
+        }
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        channel.wait_ready()?;
+        channel.select(self.drive, DRIVE_LBA);
+        channel.arm();
+        let command = if self.lba48 {
+            CMD_FLUSH_CACHE_EXT
+        } else {
+            CMD_FLUSH_CACHE
+        };
+        channel.write(REG_COMMAND, command);
+        channel.wait_interrupt()?;
+        Ok(())
+    }
+}
+
+/// Finds the drives on both channels and registers them as `hda` to `hdd`.
+/// Returns how many were registered.
+pub fn init() -> usize {
+    let mut found = 0;
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        // a floating bus reads as all ones
+        if channel.alt_status() == 0xFF {
+            continue;
+        }
+        channel.set_control(CONTROL_NIEN);
+        let drives: Vec<AtaDrive> = (0..2)
+            .filter_map(|drive| AtaDrive::probe(channel, drive))
+            .collect();
+        if drives.is_empty() {
+            continue;
+        }
+        channel.arm();
+        channel.set_control(0);
+        interrupts::enable_irq(channel.irq);
+
+        for mut drive in drives {
+            let name = format!("hd{}", (b'a' + (index * 2) as u8 + drive.drive) as char);
+            if drive.kind == DriveKind::Atapi && drive.read_capacity().is_err() {
+                log_info!("{}: {} (no medium)", name, drive.model);
+                continue;
+            }
+            log_info!(
+                "{}: {} ({:?}, {} MiB{})",
+                name,
+                drive.model,
+                drive.kind,
+                (drive.sectors * drive.sector_size as u64) >> 20,
+                if drive.lba48 { ", LBA48" } else { "" }
+            );
+            match super::register_disk(&name, Arc::new(drive)) {
+                Ok(()) => found += 1,
+                Err(err) => {
+                    log_warn!("Cannot register {}: {:?}", name, err);
+                }
+            }
+        }
+    }
+    found
+}
@@ -0,0 +1,394 @@
+//! The buffer cache all disks share.
+//!
+//! Blocks are kept by device and block number and the least recently used
+//! clean one goes first when the cache is full. Writes only reach the cache;
+//! the dirty blocks are written back by a background thread, when too many
+//! of them pile up, or on [`sync`].
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    allocator::HEAP_SIZE,
+    colors, log_info, log_warn, memory,
+    sync::Mutex,
+    thread::{self, Priority},
+};
+
+/// The cache takes this share of physical memory...
+const MEMORY_SHARE: u64 = 16;
+/// ...but blocks live on the kernel heap, which they may only fill to a quarter.
+const MAX_BYTES: usize = HEAP_SIZE / 4;
+const MIN_BYTES: usize = 256 * 1024;
+/// Writers have to write back their device once dirty blocks take up more
+/// than this share of the cache.
+const DIRTY_SHARE: usize = 2;
+const WRITEBACK_INTERVAL_MS: u64 = 5000;
+/// Largest run of adjacent blocks written back in one request.
+const MAX_RUN_BYTES: usize = 128 * 1024;
+
+/// A device id and a block number.
+type Key = (u64, u64);
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Bumped by every write, so that write-back can tell whether the block
+    /// changed while it was being written.
+    version: u64,
+    last_use: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Blocks written back to their device.
+    pub writebacks: u64,
+    pub blocks: usize,
+    pub dirty_blocks: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+struct Cache {
+    entries: BTreeMap<Key, Entry>,
+    /// Keys by last use, the least recently used first.
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    dirty_bytes: usize,
+    stats: CacheStats,
+}
+
+impl Cache {
+    const fn new() -> Self {
+        Cache {
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            dirty_bytes: 0,
+            stats: CacheStats {
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                writebacks: 0,
+                blocks: 0,
+                dirty_blocks: 0,
+                bytes: 0,
+                capacity: MIN_BYTES,
+            },
+        }
+    }
+
+    fn touch(&mut self, key: Key) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.last_use);
+        self.clock += 1;
+        entry.last_use = self.clock;
+        self.lru.insert(self.clock, key);
+        Some(entry)
+    }
+
+    fn get(&mut self, key: Key) -> Option<&[u8]> {
+        if self.entries.contains_key(&key) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        self.touch(key).map(|entry| &*entry.data)
+    }
+
+    fn insert(&mut self, key: Key, data: &[u8], dirty: bool) {
+        self.clock += 1;
+        self.lru.insert(self.clock, key);
+        self.entries.insert(
+            key,
+            Entry {
+                data: data.into(),
+                dirty,
+                version: 0,
+                last_use: self.clock,
+            },
+        );
+        self.stats.bytes += data.len();
+        if dirty {
+            self.dirty_bytes += data.len();
+        }
+        self.evict();
+    }
+
+    /// Caches a block just read from the device, unless a newer version was
+    /// written to the cache meanwhile. Returns the cached data in that case.
+    fn insert_clean(&mut self, key: Key, data: &[u8]) -> Option<&[u8]> {
+        if self.entries.contains_key(&key) {
+            return self.touch(key).map(|entry| &*entry.data);
+        }
+        self.insert(key, data, false);
+        None
+    }
+
+    fn write(&mut self, key: Key, data: &[u8]) {
+        let Some(entry) = self.touch(key) else {
+            self.insert(key, data, true);
+            return;
+        };
+        entry.data.copy_from_slice(data);
+        entry.version += 1;
+        if !entry.dirty {
+            entry.dirty = true;
+            self.dirty_bytes += data.len();
+        }
+    }
+
+    /// Marks a written back block clean, unless it was written again since.
+    fn mark_clean(&mut self, key: Key, version: u64) {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if entry.dirty && entry.version == version {
+                entry.dirty = false;
+                self.dirty_bytes -= entry.data.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.lru.remove(&entry.last_use);
+            self.stats.bytes -= entry.data.len();
+            if entry.dirty {
+                self.dirty_bytes -= entry.data.len();
+            }
+        }
+    }
+
+    /// Drops clean blocks until the cache fits its capacity again. Dirty
+    /// blocks stay until they are written back.
+    fn evict(&mut self) {
+        while self.stats.bytes > self.stats.capacity {
+            let victim = self
+                .lru
+                .values()
+                .find(|key| !self.entries[*key].dirty)
+                .copied();
+            let Some(key) = victim else {
+                return;
+            };
+            self.remove(key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn too_dirty(&self) -> bool {
+        self.dirty_bytes > self.stats.capacity / DIRTY_SHARE
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache::new());
+/// Every cached device, for the write-back thread.
+static DEVICES: spin::Mutex<Vec<Weak<CachedDevice>>> = spin::Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A device whose blocks go through the cache.
+pub struct CachedDevice {
+    id: u64,
+    device: Arc<dyn BlockDevice>,
+    /// Held while blocks are read from the device or written back to it, so
+    /// a read never caches data older than what was just written back.
+    io: Mutex<()>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        let cached = Arc::new(CachedDevice {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            device,
+            io: Mutex::new(()),
+        });
+        let mut devices = DEVICES.lock();
+        devices.retain(|device| device.strong_count() > 0);
+        devices.push(Arc::downgrade(&cached));
+        cached
+    }
+
+    fn key(&self, block: u64) -> Key {
+        (self.id, block)
+    }
+
+    /// Writes all dirty blocks of the device back, runs of adjacent blocks in
+    /// one request each. Blocks the device refuses are dropped from the
+    /// cache.
+    pub fn write_back(&self) -> Result<(), BlockError> {
+        let _io = self.io.lock();
+        let dirty: Vec<(u64, u64, Box<[u8]>)> = CACHE
+            .lock()
+            .entries
+            .range(self.key(0)..=self.key(u64::MAX))
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&(_, block), entry)| (block, entry.version, entry.data.clone()))
+            .collect();
+
+        let block_size = self.block_size();
+        let mut result = Ok(());
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len()
+                && dirty[end].0 == dirty[end - 1].0 + 1
+                && (end - start) * block_size < MAX_RUN_BYTES
+            {
+                end += 1;
+            }
+            let run = &dirty[start..end];
+            let data: Vec<u8> = run
+                .iter()
+                .flat_map(|(_, _, data)| data.iter().copied())
+                .collect();
+            let written = self.device.write_blocks(run[0].0, &data);
+            let mut cache = CACHE.lock();
+            for &(block, version, _) in run {
+                match written {
+                    Ok(()) => cache.mark_clean(self.key(block), version),
+                    Err(_) => cache.remove(self.key(block)),
+                }
+            }
+            match written {
+                Ok(()) => cache.stats.writebacks += run.len() as u64,
+                Err(err) => {
+                    log_warn!("Lost {} blocks at {}: {:?}", run.len(), run[0].0, err);
+                    result = Err(err);
+                }
+            }
+            start = end;
+        }
+        result
+    }
+}
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        let _ = self.write_back();
+        let mut cache = CACHE.lock();
+        let keys: Vec<Key> = cache
+            .entries
+            .range(self.key(0)..=self.key(u64::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            cache.remove(key);
+        }
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let block_size = self.block_size();
+        let mut missing = Vec::new();
+        {
+            let mut cache = CACHE.lock();
+            for (index, chunk) in buf.chunks_exact_mut(block_size).enumerate() {
+                match cache.get(self.key(start + index as u64)) {
+                    Some(data) => chunk.copy_from_slice(data),
+                    None => missing.push(index),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let _io = self.io.lock();
+        let mut run_start = 0;
+        while run_start < missing.len() {
+            // read each run of missing blocks in one request
+            let mut run_end = run_start + 1;
+            while run_end < missing.len() && missing[run_end] == missing[run_end - 1] + 1 {
+                run_end += 1;
+            }
+            let first = missing[run_start];
+            let last = missing[run_end - 1];
+            let run = &mut buf[first * block_size..(last + 1) * block_size];
+            self.device.read_blocks(start + first as u64, run)?;
+            let mut cache = CACHE.lock();
+            for (i, chunk) in run.chunks_exact_mut(block_size).enumerate() {
+                let key = self.key(start + (first + i) as u64);
+                if let Some(newer) = cache.insert_clean(key, chunk) {
+                    chunk.copy_from_slice(newer);
+                }
+            }
+            run_start = run_end;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let too_dirty = {
+            let mut cache = CACHE.lock();
+            for (index, chunk) in buf.chunks_exact(self.block_size()).enumerate() {
+                cache.write(self.key(start + index as u64), chunk);
+            }
+            cache.too_dirty()
+        };
+        if too_dirty {
+            self.write_back()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back()?;
+        self.device.flush()
+    }
+}
+
+fn devices() -> Vec<Arc<CachedDevice>> {
+    DEVICES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Writes back the dirty blocks of every device and flushes them. Returns
+/// the first error, after trying all of them.
+pub fn sync() -> Result<(), BlockError> {
+    devices()
+        .iter()
+        .map(|device| device.flush())
+        .fold(Ok(()), Result::and)
+}
+
+pub fn stats() -> CacheStats {
+    let cache = CACHE.lock();
+    CacheStats {
+        blocks: cache.entries.len(),
+        dirty_blocks: cache.entries.values().filter(|entry| entry.dirty).count(),
+        ..cache.stats
+    }
+}
+
+/// Sizes the cache after the amount of physical memory and starts the
+/// write-back thread.
+pub fn init() {
+    let capacity = ((memory::usable_memory() / MEMORY_SHARE) as usize).clamp(MIN_BYTES, MAX_BYTES);
+    CACHE.lock().stats.capacity = capacity;
+    log_info!("Block cache of {} KiB", capacity / 1024);
+    thread::spawn_with("writeback", Priority::Low, || loop {
+        thread::sleep(WRITEBACK_INTERVAL_MS);
+        for device in devices() {
+            // failures are logged by the write-back itself
+            let _ = device.write_back();
+        }
+    });
+}
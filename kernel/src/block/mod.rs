@@ -0,0 +1,317 @@
+//! Block devices: disks and anything else addressed in fixed-size blocks.
+//!
+//! Drivers register the devices they find under a name, which also makes
+//! them show up in the devfs.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    colors, log_info, log_warn,
+    vfs::{self, ext2::Ext2Fs, fat::FatFs, FileType, Filesystem, Inode, Metadata, VfsError},
+};
+
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod virtio_blk;
+
+pub use self::cache::CachedDevice;
+
+static DEVICES: spin::Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> =
+    spin::Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    Misaligned,
+    ReadOnly,
+    /// The device reported an error or did not answer.
+    Io,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Size of a block in bytes, usually 512.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads `buf.len() / block_size()` blocks starting at block `start`.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / block_size()` blocks starting at block `start`.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far has reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Checks that `len` bytes starting at block `start` fit the device.
+    fn check_request(&self, start: u64, len: usize) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        if !len.is_multiple_of(block_size) {
+            return Err(BlockError::Misaligned);
+        }
+        let end = start
+            .checked_add((len / block_size) as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        if end > self.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of `device`, which need not be
+/// aligned to blocks.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = alloc::vec![0; block_size];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let index = pos / block_size as u64;
+        let within = (pos % block_size as u64) as usize;
+        let remaining = buf.len() - done;
+        if within == 0 && remaining >= block_size {
+            // whole blocks go straight into the buffer
+            let len = remaining / block_size * block_size;
+            device.read_blocks(index, &mut buf[done..done + len])?;
+            done += len;
+        } else {
+            let len = remaining.min(block_size - within);
+            device.read_blocks(index, &mut block)?;
+            buf[done..done + len].copy_from_slice(&block[within..within + len]);
+            done += len;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `data` at byte `offset` of `device`, reading back the blocks that
+/// are only partly overwritten.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, data: &[u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = alloc::vec![0; block_size];
+    let mut done = 0;
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let index = pos / block_size as u64;
+        let within = (pos % block_size as u64) as usize;
+        let remaining = data.len() - done;
+        if within == 0 && remaining >= block_size {
+            let len = remaining / block_size * block_size;
+            device.write_blocks(index, &data[done..done + len])?;
+            done += len;
+        } else {
+            let len = remaining.min(block_size - within);
+            device.read_blocks(index, &mut block)?;
+            block[within..within + len].copy_from_slice(&data[done..done + len]);
+            device.write_blocks(index, &block)?;
+            done += len;
+        }
+    }
+    Ok(())
+}
+
+/// Makes `device` available as `name`, both here and in the devfs.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), VfsError> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    vfs::devfs::register(
+        name,
+        Arc::new(DeviceNode {
+            device: device.clone(),
+            inode: devices.len() as u64 + DEVICE_NODE_BASE,
+        }),
+    )?;
+    devices.insert(name.to_string(), device);
+    Ok(())
+}
+
+/// Registers a whole disk as `name`, then each of its partitions as `name`
+/// followed by the partition number. Filesystems the kernel knows on the
+/// partitions, or on the disk itself if it has none, are mounted under
+/// `/mnt`. All of them go through the block cache.
+pub fn register_disk(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), VfsError> {
+    let device: Arc<dyn BlockDevice> = CachedDevice::new(device);
+    register(name, device.clone())?;
+    let partitions = partition::scan(&device).unwrap_or_else(|err| {
+        log_warn!("{}: cannot read the partition table: {:?}", name, err);
+        Vec::new()
+    });
+    if partitions.is_empty() {
+        automount(name, device);
+    }
+    for partition in partitions {
+        let partition_name = format!("{}{}", name, partition.number);
+        log_info!(
+            "{}: {} ({} MiB){}{}",
+            partition_name,
+            partition.kind,
+            (partition.blocks * partition.block_size() as u64) >> 20,
+            if partition.name.is_empty() { "" } else { " " },
+            partition.name
+        );
+        let partition: Arc<dyn BlockDevice> = Arc::new(partition);
+        match register(&partition_name, partition.clone()) {
+            Ok(()) => automount(&partition_name, partition),
+            Err(err) => {
+                log_warn!("Cannot register {}: {:?}", partition_name, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mounts the filesystem on `device` at `/mnt/<name>`, if there is one the
+/// kernel knows.
+fn automount(name: &str, device: Arc<dyn BlockDevice>) {
+    let filesystem: Arc<dyn Filesystem> = if let Ok(ext2) = Ext2Fs::new(device.clone()) {
+        Arc::new(ext2)
+    } else if let Ok(fat) = FatFs::new(device) {
+        Arc::new(fat)
+    } else {
+        return;
+    };
+    let path = format!("/mnt/{}", name);
+    match vfs::mount(&path, filesystem.clone()) {
+        Ok(()) => {
+            log_info!("Mounted {} ({}) at {}", name, filesystem.name(), path);
+        }
+        Err(err) => {
+            log_warn!("Cannot mount {} at {}: {:?}", name, path, err);
+        }
+    }
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// All registered devices, ordered by name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Inode numbers of device nodes start here, after the devfs's own devices.
+const DEVICE_NODE_BASE: u64 = 0x100;
+
+/// A block device as a file in the devfs, readable and writable at any offset.
+struct DeviceNode {
+    device: Arc<dyn BlockDevice>,
+    inode: u64,
+}
+
+impl DeviceNode {
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+
+    /// Shortens a request at `offset` to what is left of the device.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        self.size().saturating_sub(offset).min(len as u64) as usize
+    }
+}
+
+impl Inode for DeviceNode {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Ok(Metadata {
+            file_type: FileType::BlockDevice,
+            size: self.size(),
+            inode: self.inode,
+            mode: 0o600,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let len = self.clamp(offset, buf.len());
+        read_bytes(&*self.device, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        write_bytes(&*self.device, offset, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        // opening with `TRUNCATE` must not fail on a device
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(self.device.flush()?)
+    }
+}
+
+impl From<BlockError> for VfsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::ReadOnly => VfsError::ReadOnly,
+            BlockError::OutOfRange | BlockError::Misaligned | BlockError::Io => VfsError::Io,
+        }
+    }
+}
+
+/// Disk in memory that unit tests put their images on.
+#[cfg(test)]
+pub(crate) struct RamDisk {
+    blocks: spin::Mutex<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl RamDisk {
+    pub(crate) fn new(image: Vec<u8>) -> Arc<Self> {
+        assert!(image.len().is_multiple_of(512));
+        Arc::new(RamDisk {
+            blocks: spin::Mutex::new(image),
+        })
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.blocks.lock().len() / 512) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let start = start as usize * 512;
+        buf.copy_from_slice(&self.blocks.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let start = start as usize * 512;
+        self.blocks.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
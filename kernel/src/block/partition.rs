@@ -0,0 +1,487 @@
+//! Partition tables: MBR with its chain of extended boot records, and GPT.
+//!
+//! Every partition found becomes a block device of its own, a window onto
+//! its part of the disk.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use super::{BlockDevice, BlockError};
+use crate::{colors, log_warn};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Logical partitions inside an extended one are numbered from 5.
+const FIRST_LOGICAL: usize = 5;
+/// Most logical partitions followed, in case the chain loops.
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Largest partition entry array accepted, 128 entries of 128 bytes being usual.
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+const GPT_NAME_OFFSET: usize = 56;
+const GPT_NAME_LEN: usize = 72;
+
+/// A GUID as stored on disk, with its first three fields little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr { system_id: u8 },
+    Gpt { type_guid: Guid, guid: Guid },
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionKind::Mbr { system_id } => write!(f, "MBR type {:#04x}", system_id),
+            PartitionKind::Gpt { type_guid, .. } => write!(f, "GPT type {}", type_guid),
+        }
+    }
+}
+
+/// A partition of a disk, addressed in the disk's blocks.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    /// First block on the disk.
+    pub start: u64,
+    pub blocks: u64,
+    /// 1 to 4 for primary MBR partitions and from 5 for logical ones; the
+    /// entry index plus one on GPT.
+    pub number: usize,
+    pub kind: PartitionKind,
+    /// Name of a GPT partition, empty on MBR.
+    pub name: String,
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        self.device.read_blocks(self.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        self.device.write_blocks(self.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// CRC-32 as used by GPT, the reflected IEEE 802.3 polynomial.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_block(device: &dyn BlockDevice, block: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0; device.block_size()];
+    device.read_blocks(block, &mut buf)?;
+    Ok(buf)
+}
+
+struct MbrEntry {
+    system_id: u8,
+    start: u64,
+    blocks: u64,
+}
+
+/// The four entries of a boot record, or `None` if `sector` does not hold
+/// a partition table.
+fn mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector.len() < 512 || sector[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    let entries = [0, 1, 2, 3].map(|i| {
+        let entry = &sector[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (
+            entry[0],
+            MbrEntry {
+                system_id: entry[4],
+                start: read_u32(entry, 8) as u64,
+                blocks: read_u32(entry, 12) as u64,
+            },
+        )
+    });
+    // FAT boot sectors carry the signature too, but have code where the
+    // table would be, which rarely passes for boot flags
+    if entries.iter().any(|(status, _)| *status & 0x7F != 0) {
+        return None;
+    }
+    Some(entries.map(|(_, entry)| entry))
+}
+
+/// Finds the partitions on `device`. A disk without a partition table has
+/// none.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let sector = read_block(&**device, 0)?;
+    let Some(entries) = mbr_entries(&sector) else {
+        return Ok(Vec::new());
+    };
+    if entries
+        .iter()
+        .any(|entry| entry.system_id == MBR_PROTECTIVE)
+    {
+        return scan_gpt(device);
+    }
+
+    let mut partitions = Vec::new();
+    let mut add = |number, system_id, start: u64, blocks: u64| {
+        if blocks == 0 || start + blocks > device.block_count() {
+            log_warn!("Partition {} lies outside the disk", number);
+            return;
+        }
+        partitions.push(Partition {
+            device: device.clone(),
+            start,
+            blocks,
+            number,
+            kind: PartitionKind::Mbr { system_id },
+            name: String::new(),
+        });
+    };
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.system_id == 0 {
+            continue;
+        }
+        if !MBR_EXTENDED.contains(&entry.system_id) {
+            add(index + 1, entry.system_id, entry.start, entry.blocks);
+            continue;
+        }
+
+        // each extended boot record describes one logical partition,
+        // relative to itself, and where the next record is, relative to
+        // the extended partition
+        let mut record = entry.start;
+        for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+            if record >= device.block_count() {
+                log_warn!("Extended partition chain leaves the disk");
+                break;
+            }
+            let Some([logical, next, ..]) = mbr_entries(&read_block(&**device, record)?) else {
+                break;
+            };
+            if logical.system_id != 0 {
+                add(
+                    number,
+                    logical.system_id,
+                    record + logical.start,
+                    logical.blocks,
+                );
+            }
+            if !MBR_EXTENDED.contains(&next.system_id) || next.start == 0 {
+                break;
+            }
+            record = entry.start + next.start;
+        }
+    }
+    Ok(partitions)
+}
+
+/// Reads the GPT header at `lba` and the entries it points to, checking
+/// both against their CRCs. Returns the entries and the size of each.
+fn read_gpt(device: &dyn BlockDevice, lba: u64) -> Result<Option<(Vec<u8>, usize)>, BlockError> {
+    let mut header = read_block(device, lba)?;
+    if header.len() < GPT_HEADER_SIZE || &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = read_u32(&header, 12) as usize;
+    if !(GPT_HEADER_SIZE..=header.len()).contains(&header_size) || read_u64(&header, 24) != lba {
+        return Ok(None);
+    }
+    let header_crc = read_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Ok(None);
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let size = count.saturating_mul(entry_size);
+    if entry_size < GPT_MIN_ENTRY_SIZE
+        || !entry_size.is_multiple_of(8)
+        || size > GPT_MAX_ENTRIES_SIZE
+    {
+        return Ok(None);
+    }
+    // a bad pointer is damage like a bad checksum, leaving the other copy
+    let blocks = size.div_ceil(device.block_size()) as u64;
+    if entries_lba
+        .checked_add(blocks)
+        .is_none_or(|end| end > device.block_count())
+    {
+        return Ok(None);
+    }
+    let mut entries = vec![0; size];
+    let offset = entries_lba * device.block_size() as u64;
+    super::read_bytes(device, offset, &mut entries)?;
+    if crc32(&entries) != read_u32(&header, 88) {
+        return Ok(None);
+    }
+    Ok(Some((entries, entry_size)))
+}
+
+fn scan_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let last = device.block_count().saturating_sub(1);
+    let (entries, entry_size) = match read_gpt(&**device, 1)? {
+        Some(entries) => entries,
+        None => match read_gpt(&**device, last)? {
+            Some(entries) => {
+                log_warn!("Primary GPT header is damaged, using the backup");
+                entries
+            }
+            None => {
+                log_warn!("Disk has a protective MBR but no valid GPT");
+                return Ok(Vec::new());
+            }
+        },
+    };
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero() {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        let end = read_u64(entry, 40);
+        if end < first || end > last {
+            log_warn!("GPT partition {} lies outside the disk", index + 1);
+            continue;
+        }
+        let name: Vec<u16> = entry[GPT_NAME_OFFSET..GPT_NAME_OFFSET + GPT_NAME_LEN]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        partitions.push(Partition {
+            device: device.clone(),
+            start: first,
+            blocks: end - first + 1,
+            number: index + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                guid: Guid(entry[16..32].try_into().unwrap()),
+            },
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn set_mbr_entry(sector: &mut [u8], index: usize, system_id: u8, start: u32, blocks: u32) {
+        let entry = &mut sector[MBR_TABLE_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = system_id;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    }
+
+    fn boot_record(image: &mut [u8], block: usize) -> &mut [u8] {
+        let sector = &mut image[block * 512..][..512];
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+        sector
+    }
+
+    fn disk(image: Vec<u8>) -> Arc<dyn BlockDevice> {
+        RamDisk::new(image)
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn logical_partitions_follow_the_ebr_chain() {
+        let mut image = vec![0; 256 * 512];
+        let mbr = boot_record(&mut image, 0);
+        set_mbr_entry(mbr, 0, 0x83, 1, 50);
+        set_mbr_entry(mbr, 1, 0x05, 100, 100);
+        // logical partitions start relative to their record, the next
+        // record relative to the extended partition
+        let ebr = boot_record(&mut image, 100);
+        set_mbr_entry(ebr, 0, 0x83, 2, 10);
+        set_mbr_entry(ebr, 1, 0x05, 30, 21);
+        let ebr = boot_record(&mut image, 130);
+        set_mbr_entry(ebr, 0, 0x07, 1, 20);
+        image[102 * 512] = 0xAB;
+
+        let partitions = scan(&disk(image)).unwrap();
+        let found: Vec<_> = partitions
+            .iter()
+            .map(|partition| (partition.number, partition.start, partition.blocks))
+            .collect();
+        assert_eq!(found, [(1, 1, 50), (5, 102, 10), (6, 131, 20)]);
+        assert_eq!(partitions[2].kind, PartitionKind::Mbr { system_id: 0x07 });
+
+        let mut block = [0; 512];
+        partitions[1].read_blocks(0, &mut block).unwrap();
+        assert_eq!(block[0], 0xAB);
+        assert_eq!(
+            partitions[1].read_blocks(10, &mut block),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn disk_without_a_table_has_no_partitions() {
+        assert!(scan(&disk(vec![0; 8 * 512])).unwrap().is_empty());
+    }
+
+    /// Disk of 64 blocks with a GPT of four entries, the second one in use.
+    fn gpt_image() -> Vec<u8> {
+        let mut image = vec![0; 64 * 512];
+        set_mbr_entry(boot_record(&mut image, 0), 0, MBR_PROTECTIVE, 1, 63);
+
+        let entries = &mut image[2 * 512..][..4 * 128];
+        let entry = &mut entries[128..256];
+        entry[0..16].copy_from_slice(&[0x11; 16]);
+        entry[16..32].copy_from_slice(&[0x22; 16]);
+        entry[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&62u64.to_le_bytes());
+        for (i, unit) in "data".encode_utf16().enumerate() {
+            entry[GPT_NAME_OFFSET + i * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+        }
+        set_gpt_header(&mut image, 1, 2);
+        image
+    }
+
+    /// Writes a GPT header at `lba` for the entries of [`gpt_image`],
+    /// saying they are at `entries_lba`.
+    fn set_gpt_header(image: &mut [u8], lba: u64, entries_lba: u64) {
+        let entries_crc = crc32(&image[2 * 512..][..4 * 128]);
+        let header = &mut image[lba as usize * 512..][..512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[16..20].fill(0);
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let partitions = scan(&disk(gpt_image())).unwrap();
+        assert_eq!(partitions.len(), 1);
+        let partition = &partitions[0];
+        assert_eq!(partition.number, 2);
+        assert_eq!((partition.start, partition.blocks), (34, 29));
+        assert_eq!(partition.name, "data");
+        assert_eq!(
+            partition.kind,
+            PartitionKind::Gpt {
+                type_guid: Guid([0x11; 16]),
+                guid: Guid([0x22; 16]),
+            }
+        );
+    }
+
+    #[test]
+    fn gpt_checksums_are_checked() {
+        let device = disk(gpt_image());
+        assert!(read_gpt(&*device, 1).unwrap().is_some());
+
+        let mut image = gpt_image();
+        image[2 * 512 + 200] ^= 1;
+        assert_eq!(read_gpt(&*disk(image), 1).unwrap(), None);
+
+        let mut image = gpt_image();
+        image[512 + 8] ^= 1;
+        assert_eq!(read_gpt(&*disk(image), 1).unwrap(), None);
+    }
+
+    #[test]
+    fn entries_outside_the_disk_fall_back_to_the_backup() {
+        let mut image = gpt_image();
+        set_gpt_header(&mut image, 1, 64);
+        assert_eq!(read_gpt(&*disk(image.clone()), 1).unwrap(), None);
+        set_gpt_header(&mut image, 1, u64::MAX);
+        assert_eq!(read_gpt(&*disk(image.clone()), 1).unwrap(), None);
+
+        // which is what `scan` goes on to read
+        set_gpt_header(&mut image, 63, 2);
+        let (entries, entry_size) = read_gpt(&*disk(image), 63).unwrap().unwrap();
+        assert_eq!((entries.len(), entry_size), (4 * 128, 128));
+    }
+
+    #[test]
+    fn guid_display_is_mixed_endian() {
+        let guid = Guid([
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B,
+        ]);
+        assert_eq!(
+            alloc::format!("{}", guid),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+    }
+}
@@ -0,0 +1,410 @@
+//! Disks on virtio-blk, the paravirtualized block device of QEMU and friends.
+//!
+//! A caller queues its requests and sleeps until they are done. Whoever gets
+//! to the queue first hands the waiting requests to the device, merging
+//! requests for adjacent sectors into one, and collects the ones the device
+//! has finished after it interrupts.
+
+use alloc::{
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    colors, interrupts, log_info,
+    memory::DmaFrame,
+    pci::{self, DeviceMatch, PciDevice, PciDriver},
+    sync::{Mutex, WaitQueue},
+    virtio::{self, Buffer, Transport, Virtqueue},
+};
+
+const DEVICE_ID_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_ID_MODERN: u16 = 0x1042;
+
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// device configuration
+const CONFIG_CAPACITY: u64 = 0;
+const CONFIG_SEG_MAX: u64 = 12;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+const REQ_GET_ID: u32 = 8;
+
+const STATUS_OK: u8 = 0;
+const STATUS_IOERR: u8 = 1;
+/// Status of a request the device has not finished yet.
+const STATUS_PENDING: u8 = 0xFF;
+
+/// Offset of the status byte in the header frame of a request.
+const STATUS_OFFSET: u64 = 16;
+const ID_LEN: usize = 20;
+
+const SECTOR_SIZE: usize = 512;
+const FRAME_SIZE: usize = 4096;
+const QUEUE_SIZE: u16 = 128;
+/// Largest piece a transfer is split into, in frames.
+const MAX_REQUEST_FRAMES: usize = 32;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: DEVICE_ID_TRANSITIONAL,
+        },
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: DEVICE_ID_MODERN,
+        },
+    ],
+    probe,
+};
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+struct Request {
+    kind: u32,
+    sector: u64,
+    len: usize,
+    /// The request header, followed by the status byte.
+    header: DmaFrame,
+    data: Vec<DmaFrame>,
+    status: AtomicU8,
+}
+
+impl Request {
+    fn new(kind: u32, sector: u64, len: usize) -> Result<Arc<Self>, BlockError> {
+        let mut header = DmaFrame::new().ok_or(BlockError::Io)?;
+        let data = (0..len.div_ceil(FRAME_SIZE))
+            .map(|_| DmaFrame::new())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(BlockError::Io)?;
+        let bytes = header.as_mut_slice();
+        bytes[0..4].copy_from_slice(&kind.to_le_bytes());
+        bytes[8..16].copy_from_slice(&sector.to_le_bytes());
+        Ok(Arc::new(Request {
+            kind,
+            sector,
+            len,
+            header,
+            data,
+            status: AtomicU8::new(STATUS_PENDING),
+        }))
+    }
+
+    fn end_sector(&self) -> u64 {
+        self.sector + (self.len / SECTOR_SIZE) as u64
+    }
+
+    fn data_buffers(&self) -> impl Iterator<Item = Buffer> + '_ {
+        let writable = self.kind != REQ_OUT;
+        self.data.iter().enumerate().map(move |(i, frame)| Buffer {
+            addr: frame.phys(),
+            len: (self.len - i * FRAME_SIZE).min(FRAME_SIZE) as u32,
+            writable,
+        })
+    }
+
+    fn fill(&mut self, buf: &[u8]) {
+        for (frame, chunk) in self.data.iter_mut().zip(buf.chunks(FRAME_SIZE)) {
+            frame.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    fn copy_to(&self, buf: &mut [u8]) {
+        for (frame, chunk) in self.data.iter().zip(buf.chunks_mut(FRAME_SIZE)) {
+            chunk.copy_from_slice(&frame.as_slice()[..chunk.len()]);
+        }
+    }
+
+    fn result(&self) -> Option<Result<(), BlockError>> {
+        match self.status.load(Ordering::Acquire) {
+            STATUS_PENDING => None,
+            STATUS_OK => Some(Ok(())),
+            _ => Some(Err(BlockError::Io)),
+        }
+    }
+}
+
+struct Queue {
+    virtqueue: Virtqueue,
+    /// Requests waiting for free descriptors.
+    pending: Vec<Arc<Request>>,
+    /// Requests the device is working on, by the head of their chain. All
+    /// of them share the header of the first.
+    in_flight: Vec<Vec<Arc<Request>>>,
+}
+
+impl Queue {
+    /// Hands `batch`, a run of requests for adjacent sectors, to the device
+    /// as one request. Gives the batch back if the queue is too full.
+    fn dispatch(&mut self, batch: Vec<Arc<Request>>) -> Result<(), Vec<Arc<Request>>> {
+        let first = &batch[0];
+        let mut buffers = Vec::new();
+        buffers.push(Buffer {
+            addr: first.header.phys(),
+            len: STATUS_OFFSET as u32,
+            writable: false,
+        });
+        for request in &batch {
+            buffers.extend(request.data_buffers());
+        }
+        buffers.push(Buffer {
+            addr: first.header.phys() + STATUS_OFFSET,
+            len: 1,
+            writable: true,
+        });
+        match self.virtqueue.add(&buffers) {
+            Some(head) => {
+                self.in_flight[head as usize] = batch;
+                Ok(())
+            }
+            None => Err(batch),
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: Mutex<Queue>,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    /// Most data buffers the device takes in one request.
+    max_segments: usize,
+    /// Bumped by every interrupt, so that a waiter can tell whether there
+    /// may be finished requests it has not collected yet.
+    interrupts: AtomicU64,
+    completions: WaitQueue,
+}
+
+impl VirtioBlk {
+    fn handle_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Release);
+        self.completions.notify_all();
+    }
+
+    /// Collects finished requests and hands waiting ones to the device.
+    fn process(&self) {
+        let mut queue = self.queue.lock();
+        while let Some((head, _)) = queue.virtqueue.pop_used() {
+            let batch = core::mem::take(&mut queue.in_flight[head as usize]);
+            let Some(first) = batch.first() else {
+                continue;
+            };
+            let status = match first.header.as_slice()[STATUS_OFFSET as usize] {
+                STATUS_OK => STATUS_OK,
+                _ => STATUS_IOERR,
+            };
+            for request in &batch {
+                request.status.store(status, Ordering::Release);
+            }
+        }
+
+        let mut pending = core::mem::take(&mut queue.pending).into_iter().peekable();
+        let mut submitted = false;
+        while let Some(first) = pending.next() {
+            let mut segments = first.data.len();
+            let mut batch = alloc::vec![first];
+            let kind = batch[0].kind;
+            while let Some(next) = pending.peek() {
+                let mergeable = matches!(kind, REQ_IN | REQ_OUT)
+                    && next.kind == kind
+                    && next.sector == batch[batch.len() - 1].end_sector()
+                    && segments + next.data.len() <= self.max_segments;
+                if !mergeable {
+                    break;
+                }
+                segments += next.data.len();
+                batch.extend(pending.next());
+            }
+            if let Err(batch) = queue.dispatch(batch) {
+                queue.pending.extend(batch);
+                break;
+            }
+            submitted = true;
+        }
+        queue.pending.extend(pending);
+        if submitted {
+            queue.virtqueue.notify();
+        }
+    }
+
+    /// Queues `requests` and waits until the device has finished all of them.
+    fn run(&self, requests: &[Arc<Request>]) -> Result<(), BlockError> {
+        {
+            let mut queue = self.queue.lock();
+            queue.pending.extend(requests.iter().cloned());
+            // a stable sort keeps requests for the same sectors in the order
+            // they were made
+            queue.pending.sort_by_key(|request| request.sector);
+        }
+        let mut result = Ok(());
+        for request in requests {
+            loop {
+                let seen = self.interrupts.load(Ordering::Acquire);
+                self.process();
+                if let Some(status) = request.result() {
+                    result = result.and(status);
+                    break;
+                }
+                self.completions
+                    .wait_while_uninterruptible(|| self.interrupts.load(Ordering::Acquire) == seen);
+            }
+        }
+        result
+    }
+
+    /// Splits a transfer of `len` bytes starting at `sector` into requests
+    /// small enough for the device.
+    fn requests(
+        &self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+    ) -> Result<Vec<Arc<Request>>, BlockError> {
+        let chunk = self.max_segments.min(MAX_REQUEST_FRAMES) * FRAME_SIZE;
+        (0..len)
+            .step_by(chunk)
+            .map(|offset| {
+                let sector = sector + (offset / SECTOR_SIZE) as u64;
+                Request::new(kind, sector, chunk.min(len - offset))
+            })
+            .collect()
+    }
+
+    /// The serial number the device reports, if any.
+    fn serial(&self) -> Option<String> {
+        let request = Request::new(REQ_GET_ID, 0, ID_LEN).ok()?;
+        self.run(core::slice::from_ref(&request)).ok()?;
+        let mut id = [0; ID_LEN];
+        request.copy_to(&mut id);
+        let len = id.iter().position(|&b| b == 0).unwrap_or(ID_LEN);
+        let serial: String = String::from_utf8_lossy(&id[..len]).trim().into();
+        (!serial.is_empty()).then_some(serial)
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(start, buf.len())?;
+        let requests = self.requests(REQ_IN, start, buf.len())?;
+        self.run(&requests)?;
+        let mut offset = 0;
+        for request in &requests {
+            request.copy_to(&mut buf[offset..offset + request.len]);
+            offset += request.len;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_request(start, buf.len())?;
+        let mut requests = self.requests(REQ_OUT, start, buf.len())?;
+        let mut offset = 0;
+        for request in &mut requests {
+            // nobody else holds the request before it is queued
+            let request = Arc::get_mut(request).unwrap();
+            request.fill(&buf[offset..offset + request.len]);
+            offset += request.len;
+        }
+        self.run(&requests)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush || self.read_only {
+            return Ok(());
+        }
+        self.run(&[Request::new(REQ_FLUSH, 0, 0)?])
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(F_SEG_MAX | F_RO | F_FLUSH)?;
+    let virtqueue = transport
+        .setup_queue(0, QUEUE_SIZE)
+        .inspect_err(|_| transport.fail())?;
+
+    // every request needs a descriptor for its header and its status too
+    if virtqueue.size() < 3 {
+        transport.fail();
+        return Err("virtqueue too small for a request");
+    }
+    let mut max_segments = virtqueue.size() as usize - 2;
+    if features & F_SEG_MAX != 0 {
+        let seg_max: u32 = transport.read_config(CONFIG_SEG_MAX);
+        max_segments = max_segments.min(seg_max.max(1) as usize);
+    }
+    let sectors = transport.read_config_u64(CONFIG_CAPACITY);
+    let disk = Arc::new(VirtioBlk {
+        queue: Mutex::new(Queue {
+            in_flight: (0..virtqueue.size()).map(|_| Vec::new()).collect(),
+            virtqueue,
+            pending: Vec::new(),
+        }),
+        transport,
+        sectors,
+        read_only: features & F_RO != 0,
+        can_flush: features & F_FLUSH != 0,
+        max_segments,
+        interrupts: AtomicU64::new(0),
+        completions: WaitQueue::new(),
+    });
+
+    let weak: Weak<VirtioBlk> = Arc::downgrade(&disk);
+    let vector = interrupts::allocate_vector(Arc::new(move || {
+        if let Some(disk) = weak.upgrade() {
+            disk.handle_interrupt();
+        }
+    }))
+    .ok_or("out of interrupt vectors")
+    .inspect_err(|_| disk.transport.fail())?;
+    let started = device
+        .enable_msix(&[vector])
+        .and_then(|_| disk.transport.enable_queue(0, 0));
+    if let Err(err) = started {
+        interrupts::free_vector(vector);
+        disk.transport.fail();
+        return Err(err);
+    }
+    disk.transport.driver_ok();
+
+    let name = format!(
+        "vd{}",
+        (b'a' + NEXT_DISK.fetch_add(1, Ordering::Relaxed) as u8) as char
+    );
+    log_info!(
+        "{}: {} ({} MiB{})",
+        name,
+        disk.serial().unwrap_or_else(|| "virtio disk".into()),
+        (sectors * SECTOR_SIZE as u64) >> 20,
+        if disk.read_only { ", read-only" } else { "" }
+    );
+    super::register_disk(&name, disk).map_err(|_| "cannot register the disk")
+}
+
+/// Registers the driver, which takes every virtio-blk device on the PCI bus
+/// as `vda`, `vdb` and so on. Returns how many there were.
+pub fn init() -> usize {
+    pci::register_driver(&DRIVER)
+}
@@ -0,0 +1,336 @@
+//! Loader for statically linked ELF64 executables.
+//!
+//! Only `ET_EXEC` images are accepted since nothing applies relocations;
+//! Rust programs have to be built with `-C relocation-model=static`.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::address_space::{AddressSpace, USER_END};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// The user stack ends just below the top of the user half.
+pub const USER_STACK_TOP: u64 = USER_END - 0x1000_0000;
+pub const USER_STACK_SIZE: u64 = 4096 * 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    /// Not a little-endian 64 bit x86_64 executable.
+    Unsupported,
+    /// Needs a dynamic linker.
+    Interpreter,
+    BadSegment,
+    /// Arguments and environment do not fit on the user stack.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let field = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let field = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let field = bytes.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(field.try_into().unwrap()))
+}
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8], offset: usize) -> Result<Self, ElfError> {
+        Ok(ProgramHeader {
+            kind: read_u32(bytes, offset)?,
+            flags: read_u32(bytes, offset + 4)?,
+            offset: read_u64(bytes, offset + 8)?,
+            vaddr: read_u64(bytes, offset + 16)?,
+            file_size: read_u64(bytes, offset + 32)?,
+            mem_size: read_u64(bytes, offset + 40)?,
+        })
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A validated executable.
+pub struct ElfFile<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    program_headers: Vec<ProgramHeader>,
+    program_header_offset: u64,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64
+            || bytes[5] != ELFDATA2LSB
+            || bytes[6] != EV_CURRENT
+            || read_u16(bytes, 16)? != ET_EXEC
+            || read_u16(bytes, 18)? != EM_X86_64
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = read_u64(bytes, 24)?;
+        let program_header_offset = read_u64(bytes, 32)?;
+        let entry_size = read_u16(bytes, 54)? as usize;
+        let entry_count = read_u16(bytes, 56)? as usize;
+        if entry_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+
+        let mut program_headers = Vec::with_capacity(entry_count);
+        for index in 0..entry_count {
+            let offset = (program_header_offset as usize)
+                .checked_add(index * entry_size)
+                .ok_or(ElfError::Truncated)?;
+            let header = ProgramHeader::parse(bytes, offset)?;
+            match header.kind {
+                PT_INTERP => return Err(ElfError::Interpreter),
+                PT_LOAD => Self::validate_segment(bytes, &header)?,
+                _ => {}
+            }
+            program_headers.push(header);
+        }
+        if !program_headers.iter().any(|header| {
+            header.kind == PT_LOAD
+                && header.flags & PF_X != 0
+                && (header.vaddr..header.vaddr + header.mem_size).contains(&entry)
+        }) {
+            return Err(ElfError::BadSegment);
+        }
+
+        Ok(ElfFile {
+            bytes,
+            entry,
+            program_headers,
+            program_header_offset,
+        })
+    }
+
+    fn validate_segment(bytes: &[u8], header: &ProgramHeader) -> Result<(), ElfError> {
+        let file_end = header
+            .offset
+            .checked_add(header.file_size)
+            .ok_or(ElfError::BadSegment)?;
+        let mem_end = header
+            .vaddr
+            .checked_add(header.mem_size)
+            .ok_or(ElfError::BadSegment)?;
+        if file_end > bytes.len() as u64
+            || header.file_size > header.mem_size
+            || header.vaddr < 4096
+            || mem_end > USER_STACK_TOP - USER_STACK_SIZE
+        {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    fn loadable(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|header| header.kind == PT_LOAD && header.mem_size > 0)
+    }
+
+    /// Address of the program headers in the loaded image, for `AT_PHDR`.
+    fn program_headers_addr(&self) -> u64 {
+        if let Some(header) = self.program_headers.iter().find(|h| h.kind == PT_PHDR) {
+            return header.vaddr;
+        }
+        self.loadable()
+            .find(|header| {
+                (header.offset..header.offset + header.file_size)
+                    .contains(&self.program_header_offset)
+            })
+            .map(|header| header.vaddr + self.program_header_offset - header.offset)
+            .unwrap_or(0)
+    }
+
+    /// Maps the loadable segments into `address_space` and copies their contents.
+    pub fn load(&self, address_space: &AddressSpace) -> Result<(), ElfError> {
+        for header in self.loadable() {
+            let flags = header.page_flags();
+            let first = Page::containing_address(VirtAddr::new(header.vaddr));
+            let last = Page::containing_address(VirtAddr::new(header.vaddr + header.mem_size - 1));
+            for page in Page::range_inclusive(first, last) {
+                // segments may share their first or last page
+                match address_space.page_flags(page.start_address()) {
+                    Some(existing) => address_space
+                        .protect(page, merge_flags(existing, flags))
+                        .map_err(|_| ElfError::BadSegment)?,
+                    None => address_space
+                        .map_user(page, flags)
+                        .map(|_| ())
+                        .map_err(|_| ElfError::OutOfMemory)?,
+                }
+            }
+            // the rest of the segment stays zero, the frames are zeroed when mapped
+            let data = &self.bytes[header.offset as usize..(header.offset + header.file_size) as usize];
+            address_space
+                .write(VirtAddr::new(header.vaddr), data)
+                .map_err(|_| ElfError::BadSegment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Permissions of a page used by two segments: the union of both.
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let mut flags = (a | b) & PageTableFlags::WRITABLE;
+    if a.contains(PageTableFlags::NO_EXECUTE) && b.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Maps the user stack and fills it with the initial process state expected
+/// by the System V ABI: `argc`, the `argv` and `envp` arrays and the
+/// auxiliary vector, followed by the strings they point to.
+///
+/// Returns the initial user stack pointer.
+fn setup_stack(
+    address_space: &AddressSpace,
+    elf: &ElfFile,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space
+        .map_user_range(
+            VirtAddr::new(stack_bottom),
+            USER_STACK_SIZE,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(|_| ElfError::OutOfMemory)?;
+
+    // strings go to the very top
+    let mut sp = USER_STACK_TOP;
+    let mut push_str = |s: &str| -> Result<u64, ElfError> {
+        let len = s.len() as u64 + 1;
+        if sp - stack_bottom < len {
+            return Err(ElfError::ArgumentsTooLong);
+        }
+        sp -= len;
+        let mut bytes = Vec::with_capacity(len as usize);
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+        address_space
+            .write(VirtAddr::new(sp), &bytes)
+            .map_err(|_| ElfError::OutOfMemory)?;
+        Ok(sp)
+    };
+    let argv_ptrs = argv.iter().map(|arg| push_str(arg)).collect::<Result<Vec<_>, _>>()?;
+    let envp_ptrs = envp.iter().map(|env| push_str(env)).collect::<Result<Vec<_>, _>>()?;
+
+    let auxv = [
+        (AT_PHDR, elf.program_headers_addr()),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_headers.len() as u64),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, elf.entry),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = vec![argv_ptrs.len() as u64];
+    words.extend(&argv_ptrs);
+    words.push(0);
+    words.extend(&envp_ptrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // `rsp` has to be 16 byte aligned at the entry point, pointing at `argc`
+    let size = words.len() as u64 * 8;
+    let rsp = (sp - size) & !0xf;
+    if rsp < stack_bottom + 4096 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space
+        .write(VirtAddr::new(rsp), &bytes)
+        .map_err(|_| ElfError::OutOfMemory)?;
+    Ok(VirtAddr::new(rsp))
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct LoadedProgram {
+    pub address_space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the executable in `image` into a fresh address space.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, ElfError> {
+    let elf = ElfFile::parse(image)?;
+    let address_space = AddressSpace::new().ok_or(ElfError::OutOfMemory)?;
+    elf.load(&address_space)?;
+    let stack_pointer = setup_stack(&address_space, &elf, argv, envp)?;
+    Ok(LoadedProgram {
+        address_space: Arc::new(address_space),
+        entry: elf.entry(),
+        stack_pointer,
+    })
+}
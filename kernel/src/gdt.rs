@@ -1,51 +1,99 @@
-use conquer_once::spin::Lazy;
+use alloc::{boxed::Box, vec};
+use core::ptr::addr_of_mut;
+
+use conquer_once::spin::OnceCell;
 use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        let stack_end = stack_start + STACK_SIZE;
-        stack_end
-    };
-    tss
-});
+// The BSP's tables are set up before the heap exists; every AP allocates its own.
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+static mut BSP_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+static SELECTORS: OnceCell<Selectors> = OnceCell::uninit();
 
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 
-static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
+/// The segment selectors are the same on every CPU since all GDTs share one layout.
+///
+/// The order of the entries is dictated by `SYSCALL`/`SYSRET`, which derive
+/// the kernel and user selectors from a single base each: kernel data must
+/// follow kernel code, and user code must follow user data.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+fn build_gdt(gdt: &mut GlobalDescriptorTable, tss: &'static TaskStateSegment) -> Selectors {
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-    (
-        gdt,
-        Selectors {
-            code_selector,
-            tss_selector,
-        },
-    )
-});
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    Selectors {
+        code_selector,
+        data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    }
 }
 
-pub fn init() {
+/// Segment selectors of the GDT layout shared by all CPUs.
+pub fn selectors() -> Selectors {
+    *SELECTORS.get().expect("GDT not initialized")
+}
+
+unsafe fn load(gdt: &GlobalDescriptorTable, selectors: Selectors) {
     use x86_64::instructions::{
         segmentation::{Segment, CS, SS},
         tables::load_tss,
     };
 
-    GDT.0.load();
+    gdt.load_unsafe();
+    CS::set_reg(selectors.code_selector);
+    SS::set_reg(selectors.data_selector);
+    load_tss(selectors.tss_selector);
+}
+
+/// Loads the GDT and TSS of the bootstrap processor.
+pub fn init() {
+    static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(SegmentSelector(0));
-        load_tss(GDT.1.tss_selector);
+        let tss = &mut *addr_of_mut!(BSP_TSS);
+        let stack_start = VirtAddr::from_ptr(addr_of_mut!(STACK));
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_start + DOUBLE_FAULT_STACK_SIZE;
+
+        let gdt = &mut *addr_of_mut!(BSP_GDT);
+        let selectors = build_gdt(gdt, &*addr_of_mut!(BSP_TSS));
+        SELECTORS.init_once(|| selectors);
+        load(gdt, selectors);
     }
 }
+
+/// TSS of the bootstrap processor.
+pub fn bsp_tss() -> *mut TaskStateSegment {
+    addr_of_mut!(BSP_TSS)
+}
+
+/// Allocates and loads a GDT and TSS for the calling application processor.
+pub fn init_ap() -> *mut TaskStateSegment {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss: *mut TaskStateSegment = tss;
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    unsafe {
+        let selectors = build_gdt(gdt, &*tss);
+        load(gdt, selectors);
+    }
+    tss
+}
@@ -149,7 +149,7 @@ impl Painter {
                             break;
                         }
                         for x in 0..width {
-                            if (framebuffer[y * width + x].a as f32) == 0.0
+                            if framebuffer[y * width + x].a == 0.0
                                 || x_pos + x >= self.info.width
                             {
                                 continue;
@@ -169,7 +169,7 @@ impl Painter {
                             break;
                         }
                         for x in 0..width {
-                            if (framebuffer[y * width + x].a as f32) == 0.0
+                            if framebuffer[y * width + x].a == 0.0
                                 || x_pos + x >= self.info.width
                             {
                                 continue;
@@ -233,7 +233,7 @@ impl Painter {
                         }
                         for x in r_layer_x0..r_layer_x1 {
                             let screen_x = x + layer_x;
-                            if (framebuffer[y * layer_width + x].a as f32) == 0.0
+                            if framebuffer[y * layer_width + x].a == 0.0
                                 || screen_x >= self.info.width
                                 || rendered
                                     [(screen_y - render_y) * render_width + (screen_x - render_x)]
@@ -264,7 +264,7 @@ impl Painter {
                         }
                         for x in r_layer_x0..r_layer_x1 {
                             let screen_x = x + layer_x;
-                            if (framebuffer[y * layer_width + x].a as f32) == 0.0
+                            if framebuffer[y * layer_width + x].a == 0.0
                                 || screen_x >= self.info.width
                                 || rendered
                                     [(screen_y - render_y) * render_width + (screen_x - render_x)]
@@ -338,7 +338,7 @@ pub fn draw_char(x: u32, y: u32, c: char, color: Color) -> u32 {
             }
             return glyph_width;
         }
-        return 0;
+        0
     })
 }
 
@@ -367,7 +367,7 @@ fn draw_line_low(x0: u32, y0: u32, x1: u32, y1: u32, color: Color) {
     for x in x0..=x1 {
         painter.draw_pixel(x, y, color);
         if d > 0 {
-            if inverse == true {
+            if inverse {
                 y -= 1;
             } else {
                 y += 1;
@@ -397,7 +397,7 @@ fn draw_line_high(x0: u32, y0: u32, x1: u32, y1: u32, color: Color) {
     for y in y0..=y1 {
         painter.draw_pixel(x, y, color);
         if d > 0 {
-            if inverse == true {
+            if inverse {
                 x -= 1;
             } else {
                 x += 1;
@@ -412,8 +412,8 @@ fn draw_line_high(x0: u32, y0: u32, x1: u32, y1: u32, color: Color) {
 pub fn draw_line(x0: u32, y0: u32, x1: u32, y1: u32, color: Color) {
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        let dx = if x0 > x1 { x0 - x1 } else { x1 - x0 };
-        let dy = if y0 > y1 { y0 - y1 } else { y1 - y0 };
+        let dx = x0.abs_diff(x1);
+        let dy = y0.abs_diff(y1);
         if x0 == x1 {
             draw_rect(x0, y0, 1, dy + 1, color);
         } else if y0 == y1 {
@@ -448,7 +448,7 @@ pub fn get_char_width(c: char) -> u32 {
     if let Some(glyph) = unifont::get_glyph(c) {
         return glyph.get_width() as u32;
     }
-    return 0;
+    0
 }
 
 pub fn draw_bitmap(x: u32, y: u32, width: u32, height: u32, bitmap: &[Color]) {
@@ -0,0 +1,202 @@
+//! The initial ramdisk: a ustar archive the bootloader loads next to the kernel.
+//!
+//! It is exposed as a read-only tree of files. The archive stays where the
+//! bootloader put it, so file contents are handed out without copying.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{slice, str};
+
+use conquer_once::spin::OnceCell;
+
+const BLOCK_SIZE: usize = 512;
+
+static INITRD: OnceCell<Initrd> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub data: &'static [u8],
+}
+
+pub struct Initrd {
+    /// Entries by path, without leading or trailing slashes. The root is `""`.
+    entries: BTreeMap<String, Entry>,
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Parses an octal number field, which may be terminated by NUL or space.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+    let mut value = 0usize;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((digit - b'0') as usize)?;
+    }
+    Some(value)
+}
+
+fn parse_str(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).ok()
+}
+
+impl Initrd {
+    fn parse(archive: &'static [u8]) -> Result<Self, &'static str> {
+        let mut initrd = Initrd {
+            entries: BTreeMap::new(),
+        };
+        initrd.insert_dir("");
+
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= archive.len() {
+            let header = &archive[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" {
+                return Err("initrd is not a ustar archive");
+            }
+            let checksum = parse_octal(&header[148..156]).ok_or("bad tar checksum field")?;
+            let sum: usize = header
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte } as usize)
+                .sum();
+            if sum != checksum {
+                return Err("tar header checksum mismatch");
+            }
+
+            let name = parse_str(&header[0..100]).ok_or("bad file name")?;
+            let prefix = parse_str(&header[345..500]).ok_or("bad file name")?;
+            let path = if prefix.is_empty() {
+                normalize(name).to_string()
+            } else {
+                alloc::format!("{}/{}", normalize(prefix), normalize(name))
+            };
+            let size = parse_octal(&header[124..136]).ok_or("bad file size")?;
+            let data_start = offset + BLOCK_SIZE;
+            let data = archive
+                .get(data_start..data_start + size)
+                .ok_or("truncated initrd")?;
+
+            match header[156] {
+                b'0' | 0 => initrd.insert_file(&path, data),
+                b'5' => initrd.insert_dir(&path),
+                // links and special files are not supported
+                _ => {}
+            }
+            offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+        Ok(initrd)
+    }
+
+    fn insert_dir(&mut self, path: &str) {
+        self.entries.insert(
+            path.to_string(),
+            Entry {
+                kind: EntryKind::Directory,
+                data: &[],
+            },
+        );
+    }
+
+    fn insert_file(&mut self, path: &str, data: &'static [u8]) {
+        // archives need not list every parent directory
+        let mut parent = path;
+        while let Some(split) = parent.rfind('/') {
+            parent = &parent[..split];
+            if !self.entries.contains_key(parent) {
+                self.insert_dir(parent);
+            }
+        }
+        self.entries.insert(
+            path.to_string(),
+            Entry {
+                kind: EntryKind::File,
+                data,
+            },
+        );
+    }
+
+    pub fn entry(&self, path: &str) -> Option<Entry> {
+        self.entries.get(normalize(path)).copied()
+    }
+
+    /// Contents of the file at `path`.
+    pub fn read(&self, path: &str) -> Option<&'static [u8]> {
+        self.entry(path)
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| entry.data)
+    }
+
+    /// Names of the entries directly inside the directory at `path`.
+    pub fn read_dir(&self, path: &str) -> Option<Vec<&str>> {
+        let dir = normalize(path);
+        if self.entry(dir)?.kind != EntryKind::Directory {
+            return None;
+        }
+        let children = self
+            .entries
+            .keys()
+            .filter_map(|entry| {
+                let name = if dir.is_empty() {
+                    entry.as_str()
+                } else {
+                    entry.strip_prefix(dir)?.strip_prefix('/')?
+                };
+                (!name.is_empty() && !name.contains('/')).then_some(name)
+            })
+            .collect();
+        Some(children)
+    }
+
+    /// A number identifying the entry at `path`, stable while the system runs.
+    pub fn entry_number(&self, path: &str) -> Option<u64> {
+        let path = normalize(path);
+        self.entries
+            .keys()
+            .position(|entry| entry == path)
+            .map(|index| index as u64 + 1)
+    }
+
+    pub fn entry_count(&self) -> usize {
+        // the root does not count
+        self.entries.len() - 1
+    }
+}
+
+/// Parses the ramdisk the bootloader loaded at `addr` and returns the number of entries in it.
+pub fn init(addr: u64, len: u64) -> Result<usize, &'static str> {
+    let archive = unsafe { slice::from_raw_parts(addr as *const u8, len as usize) };
+    let initrd = Initrd::parse(archive)?;
+    let entries = initrd.entry_count();
+    INITRD.init_once(|| initrd);
+    Ok(entries)
+}
+
+pub fn get() -> Option<&'static Initrd> {
+    INITRD.get()
+}
+
+/// Contents of the file at `path` in the initrd, if there is one.
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    get()?.read(path)
+}
@@ -1,13 +1,53 @@
+use alloc::{sync::Arc, vec::Vec};
+
 use conquer_once::spin::Lazy;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
 
-use crate::{colors, gdt, keyboard, log_error, log_panic, log_warn, serial_println, graphics::PAINTER};
+use crate::{
+    apic, block::ata, colors, gdt, keyboard, log_error, log_panic, log_warn, serial_println,
+    graphics::PAINTER,
+    mouse, percpu, process, smp,
+    sync::IrqSpinLock,
+    thread::{self, context},
+    time,
+};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Per-CPU scheduler timer of the local APIC.
+pub const LAPIC_TIMER_VECTOR: u8 = 0x30;
+/// IPI telling an idle CPU that a thread became runnable on it.
+pub const RESCHEDULE_VECTOR: u8 = 0x31;
+/// IPI asking a CPU to flush the TLB entries described by `smp::tlb_shootdown`.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0x32;
+/// First of the vectors handed out to devices signalling message interrupts (MSI).
+pub const DEVICE_VECTOR_BASE: u8 = 0x40;
+const DEVICE_VECTORS: usize = 32;
+
+type DeviceHandler = Option<Arc<dyn Fn() + Send + Sync>>;
+const NO_HANDLER: DeviceHandler = None;
+
+/// Handlers of the device vectors, indexed from [`DEVICE_VECTOR_BASE`].
+static DEVICE_HANDLERS: IrqSpinLock<[DeviceHandler; DEVICE_VECTORS]> =
+    IrqSpinLock::new([NO_HANDLER; DEVICE_VECTORS]);
+
+/// Legacy interrupt lines the firmware routes PCI interrupt pins to.
+pub const PCI_LINES: [u8; 4] = [5, 9, 10, 11];
+
+type LineHandlers = Vec<Arc<dyn Fn() + Send + Sync>>;
+const NO_LINE_HANDLERS: LineHandlers = Vec::new();
+
+/// Handlers of the PCI interrupt lines, indexed by line. Devices share the
+/// lines, so each handler has to check whether its device interrupted.
+static LINE_HANDLERS: IrqSpinLock<[LineHandlers; 16]> =
+    IrqSpinLock::new([NO_LINE_HANDLERS; 16]);
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -51,9 +91,39 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
     idt.page_fault.set_handler_fn(page_fault_handler);
-    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(pit_interrupt_handler);
+    unsafe {
+        idt[LAPIC_TIMER_VECTOR as usize]
+            .set_handler_addr(VirtAddr::new(context::timer_interrupt_entry as *const () as u64));
+        idt[RESCHEDULE_VECTOR as usize]
+            .set_handler_addr(VirtAddr::new(context::reschedule_interrupt_entry as *const () as u64));
+        idt[thread::YIELD_VECTOR as usize]
+            .set_handler_addr(VirtAddr::new(context::yield_interrupt_entry as *const () as u64));
+    }
+    idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb_shootdown_handler);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
     idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
     idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+    idt[InterruptIndex::PrimaryATAHardDisk.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+    idt[InterruptIndex::SecondaryATAHardDisk.as_usize()]
+        .set_handler_fn(secondary_ata_interrupt_handler);
+    macro_rules! device_vectors {
+        ($($index:literal)*) => {
+            $(idt[DEVICE_VECTOR_BASE as usize + $index]
+                .set_handler_fn(device_interrupt_handler::<$index>);)*
+        };
+    }
+    device_vectors!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+    macro_rules! pci_lines {
+        ($($line:literal)*) => {
+            $(idt[(PIC_1_OFFSET + $line) as usize]
+                .set_handler_fn(line_interrupt_handler::<$line>);)*
+        };
+    }
+    pci_lines!(5 9 10 11);
     idt
 });
 
@@ -61,16 +131,104 @@ pub fn idt_init() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Unmasks the legacy interrupt line behind `index` (and the cascade for lines of the second PIC).
+pub fn enable_irq(index: InterruptIndex) {
+    unmask_line(index.as_u8() - PIC_1_OFFSET);
+}
+
+fn unmask_line(line: u8) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut primary, mut secondary] = pics.read_masks();
+        if line < 8 {
+            primary &= !(1 << line);
+        } else {
+            primary &= !(1 << (InterruptIndex::PIC2.as_u8() - PIC_1_OFFSET));
+            secondary &= !(1 << (line - 8));
+        }
+        pics.write_masks(primary, secondary);
+    }
+}
+
+/// Reserves a device vector that runs `handler` whenever it is raised.
+/// Returns `None` once all of them are taken.
+pub fn allocate_vector(handler: Arc<dyn Fn() + Send + Sync>) -> Option<u8> {
+    let mut handlers = DEVICE_HANDLERS.lock();
+    let index = handlers.iter().position(Option::is_none)?;
+    handlers[index] = Some(handler);
+    Some(DEVICE_VECTOR_BASE + index as u8)
+}
+
+pub fn free_vector(vector: u8) {
+    if let Some(handler) = DEVICE_HANDLERS
+        .lock()
+        .get_mut(vector.wrapping_sub(DEVICE_VECTOR_BASE) as usize)
+    {
+        *handler = None;
+    }
+}
+
+/// Runs `handler` on every interrupt of the legacy line `line`, for PCI
+/// devices that cannot signal message interrupts, and unmasks the line.
+/// Fails for lines not in [`PCI_LINES`].
+pub fn add_line_handler(
+    line: u8,
+    handler: Arc<dyn Fn() + Send + Sync>,
+) -> Result<(), &'static str> {
+    if !PCI_LINES.contains(&line) {
+        return Err("interrupt line is not routed to PCI");
+    }
+    LINE_HANDLERS.lock()[line as usize].push(handler);
+    unmask_line(line);
+    Ok(())
+}
+
+/// The PIT only drives the system clock; it is wired to the BSP alone.
+extern "x86-interrupt" fn pit_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
     // way too noisy
     // log_trace!("Timer interrupt");
+    let now = time::tick();
+    thread::scheduler::wake_sleepers(now);
+    keyboard::tick();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Called by `timer_interrupt_entry` with the stack pointer of the
+/// interrupted thread; returns the stack pointer of the thread to resume.
+#[no_mangle]
+extern "C" fn timer_interrupt_switch(rsp: u64) -> u64 {
+    let _irq = percpu::IrqGuard::enter();
+    apic::eoi();
+    thread::scheduler::timer_tick(rsp)
+}
+
+/// Called by `reschedule_interrupt_entry`, like `timer_interrupt_switch`.
+#[no_mangle]
+extern "C" fn reschedule_interrupt_switch(rsp: u64) -> u64 {
+    let _irq = percpu::IrqGuard::enter();
+    apic::eoi();
+    thread::scheduler::reschedule(rsp)
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
+    smp::handle_shootdown_ipi();
+    apic::eoi();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts must not be acknowledged
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
     use x86_64::instructions::port::Port;
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
@@ -82,11 +240,13 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
-extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn mouse_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
     use x86_64::instructions::port::Port;
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    keyboard::add_mouse_scancode(scancode);
+    mouse::add_byte(scancode);
 
     unsafe {
         PICS.lock()
@@ -94,7 +254,58 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+extern "x86-interrupt" fn primary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
+    ata::handle_interrupt(ata::PRIMARY);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryATAHardDisk.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
+    ata::handle_interrupt(ata::SECONDARY);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryATAHardDisk.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn device_interrupt_handler<const INDEX: usize>(
+    stack_frame: InterruptStackFrame,
+) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
+    // the handler may take locks of its own, so it runs without ours
+    let handler = DEVICE_HANDLERS.lock()[INDEX].clone();
+    if let Some(handler) = handler {
+        handler();
+    }
+    apic::eoi();
+}
+
+extern "x86-interrupt" fn line_interrupt_handler<const LINE: u8>(
+    stack_frame: InterruptStackFrame,
+) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    let _irq = percpu::IrqGuard::enter();
+    let handlers = LINE_HANDLERS.lock()[LINE as usize].clone();
+    for handler in handlers {
+        handler();
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + LINE);
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
     serial_println!(
         "CPU Exception:    BREAKPOINT (int 0x3)
 
@@ -143,12 +354,64 @@ use x86_64::structures::idt::PageFaultErrorCode;
 
 use crate::hlt_loop;
 
+/// Ends the process whose user code caused an exception. The handler's frame
+/// is abandoned along with the rest of the thread's kernel stack.
+fn terminate_faulting_process() -> ! {
+    x86_64::instructions::interrupts::enable();
+    process::exit(process::FAULT_STATUS);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    if stack_frame.code_segment & 3 == 3 {
+        log_warn!(
+            "General protection fault ({:#x}) in user mode, rip {:?}",
+            error_code,
+            stack_frame.instruction_pointer
+        );
+        terminate_faulting_process();
+    }
+    serial_println!(
+        "CPU Exception:    #GP GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code,
+        stack_frame
+    );
+    panic!("general protection fault in the kernel");
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
+    if stack_frame.code_segment & 3 == 3 {
+        log_warn!(
+            "Invalid opcode in user mode, rip {:?}",
+            stack_frame.instruction_pointer
+        );
+        terminate_faulting_process();
+    }
+    serial_println!("CPU Exception:    #UD INVALID OPCODE\n{:#?}", stack_frame);
+    panic!("invalid opcode in the kernel");
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let _gs = percpu::KernelGsGuard::enter(&stack_frame);
     use x86_64::registers::control::Cr2;
 
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        log_warn!(
+            "Page fault at {:?} ({:?}) in user mode, rip {:?}",
+            Cr2::read(),
+            error_code,
+            stack_frame.instruction_pointer
+        );
+        terminate_faulting_process();
+    }
+
     serial_println!(
         "CPU Exception:    #PF PAGE FAULT
 Accessed Address: {:?}
@@ -0,0 +1,512 @@
+//! Keyboard layouts: which character each key types, and how dead keys and
+//! the compose key combine characters into accented ones.
+
+use pc_keyboard::KeyCode;
+
+use super::Modifiers;
+
+// Dead keys type the combining form of their accent; the next character
+// typed decides what the accent turns into.
+const GRAVE: char = '\u{300}';
+const ACUTE: char = '\u{301}';
+const CIRCUMFLEX: char = '\u{302}';
+const TILDE: char = '\u{303}';
+const DIAERESIS: char = '\u{308}';
+const CEDILLA: char = '\u{327}';
+
+/// The keys typing characters, in the order of [`Table::plain`] and
+/// [`Table::shifted`]: the number row, then the three letter rows, each
+/// from left to right.
+const KEYS: [KeyCode; 48] = [
+    KeyCode::Oem8,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Key0,
+    KeyCode::OemMinus,
+    KeyCode::OemPlus,
+    KeyCode::Q,
+    KeyCode::W,
+    KeyCode::E,
+    KeyCode::R,
+    KeyCode::T,
+    KeyCode::Y,
+    KeyCode::U,
+    KeyCode::I,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Oem4,
+    KeyCode::Oem6,
+    KeyCode::Oem7,
+    KeyCode::A,
+    KeyCode::S,
+    KeyCode::D,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::Oem1,
+    KeyCode::Oem3,
+    KeyCode::Oem5,
+    KeyCode::Z,
+    KeyCode::X,
+    KeyCode::C,
+    KeyCode::V,
+    KeyCode::B,
+    KeyCode::N,
+    KeyCode::M,
+    KeyCode::OemComma,
+    KeyCode::OemPeriod,
+    KeyCode::Oem2,
+];
+
+struct Table {
+    plain: &'static str,
+    shifted: &'static str,
+    /// Keys typing something with AltGr, without and with shift. `'\0'`
+    /// where they type nothing.
+    alt_gr: &'static [(KeyCode, char, char)],
+    /// What the keypad's decimal point types.
+    decimal: char,
+}
+
+const US: Table = Table {
+    plain: concat!(
+        "`1234567890-=",
+        "qwertyuiop[]\\",
+        "asdfghjkl;'",
+        "\\zxcvbnm,./"
+    ),
+    shifted: concat!(
+        "~!@#$%^&*()_+",
+        "QWERTYUIOP{}|",
+        "ASDFGHJKL:\"",
+        "|ZXCVBNM<>?"
+    ),
+    alt_gr: &[],
+    decimal: '.',
+};
+
+const UK: Table = Table {
+    plain: concat!(
+        "`1234567890-=",
+        "qwertyuiop[]#",
+        "asdfghjkl;'",
+        "\\zxcvbnm,./"
+    ),
+    shifted: concat!(
+        "¬!\"£$%^&*()_+",
+        "QWERTYUIOP{}~",
+        "ASDFGHJKL:@",
+        "|ZXCVBNM<>?"
+    ),
+    alt_gr: &[
+        (KeyCode::Oem8, '¦', '\0'),
+        (KeyCode::Key4, '€', '\0'),
+        (KeyCode::A, 'á', 'Á'),
+        (KeyCode::E, 'é', 'É'),
+        (KeyCode::I, 'í', 'Í'),
+        (KeyCode::O, 'ó', 'Ó'),
+        (KeyCode::U, 'ú', 'Ú'),
+    ],
+    decimal: '.',
+};
+
+const GERMAN: Table = Table {
+    plain: concat!(
+        "\u{302}1234567890ß\u{301}",
+        "qwertzuiopü+#",
+        "asdfghjklöä",
+        "<yxcvbnm,.-"
+    ),
+    shifted: concat!(
+        "°!\"§$%&/()=?\u{300}",
+        "QWERTZUIOPÜ*'",
+        "ASDFGHJKLÖÄ",
+        ">YXCVBNM;:_"
+    ),
+    alt_gr: &[
+        (KeyCode::Key2, '²', '\0'),
+        (KeyCode::Key3, '³', '\0'),
+        (KeyCode::Key7, '{', '\0'),
+        (KeyCode::Key8, '[', '\0'),
+        (KeyCode::Key9, ']', '\0'),
+        (KeyCode::Key0, '}', '\0'),
+        (KeyCode::OemMinus, '\\', '\0'),
+        (KeyCode::Q, '@', '\0'),
+        (KeyCode::E, '€', '\0'),
+        (KeyCode::Oem6, '~', '\0'),
+        (KeyCode::Oem5, '|', '\0'),
+        (KeyCode::M, 'µ', '\0'),
+    ],
+    decimal: ',',
+};
+
+const FRENCH: Table = Table {
+    plain: concat!(
+        "²&é\"'(-è_çà)=",
+        "azertyuiop\u{302}$*",
+        "qsdfghjklmù",
+        "<wxcvbn,;:!"
+    ),
+    shifted: concat!(
+        "³1234567890°+",
+        "AZERTYUIOP\u{308}£µ",
+        "QSDFGHJKLM%",
+        ">WXCVBN?./§"
+    ),
+    alt_gr: &[
+        (KeyCode::Key2, '\u{303}', '\0'),
+        (KeyCode::Key3, '#', '\0'),
+        (KeyCode::Key4, '{', '\0'),
+        (KeyCode::Key5, '[', '\0'),
+        (KeyCode::Key6, '|', '\0'),
+        (KeyCode::Key7, '\u{300}', '\0'),
+        (KeyCode::Key8, '\\', '\0'),
+        (KeyCode::Key9, '^', '\0'),
+        (KeyCode::Key0, '@', '\0'),
+        (KeyCode::OemMinus, ']', '\0'),
+        (KeyCode::OemPlus, '}', '\0'),
+        (KeyCode::E, '€', '\0'),
+        (KeyCode::Oem6, '¤', '\0'),
+    ],
+    decimal: '.',
+};
+
+const DVORAK: Table = Table {
+    plain: concat!(
+        "`1234567890[]",
+        "',.pyfgcrl/=\\",
+        "aoeuidhtns-",
+        "\\;qjkxbmwvz"
+    ),
+    shifted: concat!(
+        "~!@#$%^&*(){}",
+        "\"<>PYFGCRL?+|",
+        "AOEUIDHTNS_",
+        "|:QJKXBMWVZ"
+    ),
+    alt_gr: &[],
+    decimal: '.',
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    German,
+    French,
+    Dvorak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [
+        Layout::Us,
+        Layout::Uk,
+        Layout::German,
+        Layout::French,
+        Layout::Dvorak,
+    ];
+
+    /// The short name the layout is selected by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::German => "de",
+            Layout::French => "fr",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    fn table(self) -> &'static Table {
+        match self {
+            Layout::Us => &US,
+            Layout::Uk => &UK,
+            Layout::German => &GERMAN,
+            Layout::French => &FRENCH,
+            Layout::Dvorak => &DVORAK,
+        }
+    }
+
+    /// The character `key` types with `modifiers`. Dead keys type the
+    /// combining form of their accent; see [`is_dead`].
+    pub fn map(self, key: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        let table = self.table();
+        if let Some(character) = special(key, modifiers, table.decimal) {
+            return Some(character);
+        }
+
+        if modifiers.alt_gr() {
+            let &(_, plain, shifted) = table.alt_gr.iter().find(|entry| entry.0 == key)?;
+            let character = if modifiers.shift() { shifted } else { plain };
+            return (character != '\0').then_some(character);
+        }
+
+        let index = KEYS.iter().position(|&known| known == key)?;
+        let plain = table.plain.chars().nth(index)?;
+        let shifted = table.shifted.chars().nth(index)?;
+        // caps lock only reaches keys typing the two cases of a letter
+        let letter = plain.is_lowercase() && plain.to_uppercase().eq(core::iter::once(shifted));
+        if modifiers.shift() != (modifiers.caps_lock && letter) {
+            Some(shifted)
+        } else {
+            Some(plain)
+        }
+    }
+}
+
+/// What the keys outside the main block type, which is the same on every
+/// layout.
+fn special(key: KeyCode, modifiers: &Modifiers, decimal: char) -> Option<char> {
+    let character = match key {
+        KeyCode::Spacebar => ' ',
+        KeyCode::Tab => '\t',
+        KeyCode::Return | KeyCode::NumpadEnter => '\n',
+        KeyCode::Backspace => '\u{8}',
+        KeyCode::Escape => '\u{1b}',
+        KeyCode::Delete => '\u{7f}',
+        KeyCode::NumpadDivide => '/',
+        KeyCode::NumpadMultiply => '*',
+        KeyCode::NumpadSubtract => '-',
+        KeyCode::NumpadAdd => '+',
+        // without num lock, the rest of the keypad moves the cursor
+        _ if !modifiers.num_lock => return None,
+        KeyCode::Numpad0 => '0',
+        KeyCode::Numpad1 => '1',
+        KeyCode::Numpad2 => '2',
+        KeyCode::Numpad3 => '3',
+        KeyCode::Numpad4 => '4',
+        KeyCode::Numpad5 => '5',
+        KeyCode::Numpad6 => '6',
+        KeyCode::Numpad7 => '7',
+        KeyCode::Numpad8 => '8',
+        KeyCode::Numpad9 => '9',
+        KeyCode::NumpadPeriod => decimal,
+        _ => return None,
+    };
+    Some(character)
+}
+
+/// Accents with their spacing form, the letters they go on and what they
+/// turn those into.
+const ACCENTS: [(char, char, &str, &str); 6] = [
+    (GRAVE, '`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    (ACUTE, '´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    (CIRCUMFLEX, '^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    (TILDE, '~', "anoANO", "ãñõÃÑÕ"),
+    (DIAERESIS, '¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+    (CEDILLA, '¸', "cC", "çÇ"),
+];
+
+/// Pairs typed after the compose key that are not an accent and a letter.
+const LIGATURES: [(char, char, char); 12] = [
+    ('a', 'e', 'æ'),
+    ('A', 'E', 'Æ'),
+    ('o', 'e', 'œ'),
+    ('O', 'E', 'Œ'),
+    ('s', 's', 'ß'),
+    ('o', '/', 'ø'),
+    ('O', '/', 'Ø'),
+    ('a', 'a', 'å'),
+    ('A', 'A', 'Å'),
+    ('e', '=', '€'),
+    ('c', 'o', '©'),
+    ('r', 'o', '®'),
+];
+
+/// Whether `character` comes from a dead key.
+pub fn is_dead(character: char) -> bool {
+    ACCENTS.iter().any(|accent| accent.0 == character)
+}
+
+/// The spacing form of the accent a dead key types, which is what the dead
+/// key types on its own.
+pub fn spacing(accent: char) -> char {
+    ACCENTS
+        .iter()
+        .find(|entry| entry.0 == accent)
+        .map_or(accent, |entry| entry.1)
+}
+
+/// `base` with the accent a dead key typed.
+pub fn accented(accent: char, base: char) -> Option<char> {
+    let (_, _, bases, results) = ACCENTS.iter().find(|entry| entry.0 == accent)?;
+    let index = bases.chars().position(|candidate| candidate == base)?;
+    results.chars().nth(index)
+}
+
+/// The accent a character typed after the compose key stands for: a dead
+/// key itself, the accent's spacing form or the ASCII character resembling
+/// it.
+fn accent_of(character: char) -> Option<char> {
+    let accent = match character {
+        '\'' => ACUTE,
+        '"' => DIAERESIS,
+        ',' => CEDILLA,
+        _ => {
+            ACCENTS
+                .iter()
+                .find(|entry| entry.0 == character || entry.1 == character)?
+                .0
+        }
+    };
+    Some(accent)
+}
+
+/// What the compose key followed by `first` and `second` types, in either
+/// order.
+pub fn compose(first: char, second: char) -> Option<char> {
+    let ordered = |first: char, second: char| {
+        let ligature = LIGATURES
+            .iter()
+            .find(|entry| entry.0 == first && entry.1 == second);
+        match ligature {
+            Some(&(_, _, result)) => Some(result),
+            None => accented(accent_of(first)?, second),
+        }
+    };
+    ordered(first, second).or_else(|| ordered(second, first))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modifiers(shift: bool, alt_gr: bool, caps_lock: bool) -> Modifiers {
+        Modifiers {
+            left_shift: shift,
+            right_alt: alt_gr,
+            caps_lock,
+            ..Modifiers::new()
+        }
+    }
+
+    #[test]
+    fn tables_cover_every_key() {
+        for layout in Layout::ALL {
+            let table = layout.table();
+            assert_eq!(table.plain.chars().count(), KEYS.len(), "{layout:?}");
+            assert_eq!(table.shifted.chars().count(), KEYS.len(), "{layout:?}");
+        }
+    }
+
+    #[test]
+    fn map_with_shift_and_caps_lock() {
+        let plain = modifiers(false, false, false);
+        let shift = modifiers(true, false, false);
+        let caps_lock = modifiers(false, false, true);
+        let both = modifiers(true, false, true);
+        assert_eq!(Layout::Us.map(KeyCode::A, &plain), Some('a'));
+        assert_eq!(Layout::Us.map(KeyCode::A, &shift), Some('A'));
+        assert_eq!(Layout::Us.map(KeyCode::A, &caps_lock), Some('A'));
+        assert_eq!(Layout::Us.map(KeyCode::A, &both), Some('a'));
+        // caps lock leaves keys that are not letters alone
+        assert_eq!(Layout::Us.map(KeyCode::Key1, &caps_lock), Some('1'));
+        assert_eq!(Layout::German.map(KeyCode::Oem1, &caps_lock), Some('Ö'));
+        assert_eq!(Layout::German.map(KeyCode::OemMinus, &caps_lock), Some('ß'));
+
+        assert_eq!(Layout::German.map(KeyCode::Y, &plain), Some('z'));
+        assert_eq!(Layout::French.map(KeyCode::Q, &plain), Some('a'));
+        assert_eq!(Layout::Dvorak.map(KeyCode::S, &plain), Some('o'));
+        assert_eq!(Layout::Uk.map(KeyCode::Key3, &shift), Some('£'));
+        assert_eq!(Layout::Uk.map(KeyCode::Oem7, &plain), Some('#'));
+        assert_eq!(Layout::Uk.map(KeyCode::Oem5, &plain), Some('\\'));
+    }
+
+    #[test]
+    fn map_alt_gr() {
+        let alt_gr = modifiers(false, true, false);
+        assert_eq!(Layout::German.map(KeyCode::Q, &alt_gr), Some('@'));
+        assert_eq!(Layout::German.map(KeyCode::Oem5, &alt_gr), Some('|'));
+        assert_eq!(
+            Layout::Uk.map(KeyCode::E, &modifiers(true, true, false)),
+            Some('É')
+        );
+        assert_eq!(
+            Layout::German.map(KeyCode::Q, &modifiers(true, true, false)),
+            None
+        );
+        assert_eq!(Layout::German.map(KeyCode::W, &alt_gr), None);
+        assert_eq!(Layout::Us.map(KeyCode::A, &alt_gr), None);
+    }
+
+    #[test]
+    fn map_keypad() {
+        let num_lock = modifiers(false, false, false);
+        let no_num_lock = Modifiers {
+            num_lock: false,
+            ..num_lock
+        };
+        assert_eq!(Layout::Us.map(KeyCode::Numpad7, &num_lock), Some('7'));
+        assert_eq!(Layout::Us.map(KeyCode::Numpad7, &no_num_lock), None);
+        assert_eq!(Layout::Us.map(KeyCode::NumpadAdd, &no_num_lock), Some('+'));
+        assert_eq!(Layout::Us.map(KeyCode::NumpadPeriod, &num_lock), Some('.'));
+        assert_eq!(
+            Layout::German.map(KeyCode::NumpadPeriod, &num_lock),
+            Some(',')
+        );
+    }
+
+    #[test]
+    fn dead_keys() {
+        let plain = modifiers(false, false, false);
+        let shift = modifiers(true, false, false);
+        let circumflex = Layout::German.map(KeyCode::Oem8, &plain).unwrap();
+        let grave = Layout::German.map(KeyCode::OemPlus, &shift).unwrap();
+        let diaeresis = Layout::French.map(KeyCode::Oem4, &shift).unwrap();
+        assert!(is_dead(circumflex) && is_dead(grave) && is_dead(diaeresis));
+        assert!(!is_dead('^') && !is_dead('a'));
+
+        assert_eq!(accented(circumflex, 'e'), Some('ê'));
+        assert_eq!(accented(grave, 'A'), Some('À'));
+        assert_eq!(accented(diaeresis, 'y'), Some('ÿ'));
+        assert_eq!(accented(CEDILLA, 'C'), Some('Ç'));
+        assert_eq!(accented(TILDE, 'e'), None);
+        assert_eq!(accented('x', 'e'), None);
+
+        assert_eq!(spacing(circumflex), '^');
+        assert_eq!(spacing(ACUTE), '´');
+        assert_eq!(spacing('x'), 'x');
+    }
+
+    #[test]
+    fn accents_have_a_result_for_every_base() {
+        for (accent, _, bases, results) in ACCENTS {
+            assert_eq!(bases.chars().count(), results.chars().count(), "{accent:?}");
+        }
+    }
+
+    #[test]
+    fn compose_sequences() {
+        assert_eq!(compose('a', 'e'), Some('æ'));
+        assert_eq!(compose('s', 's'), Some('ß'));
+        assert_eq!(compose('e', '='), Some('€'));
+        // an accent in its ASCII look-alike, spacing or combining form
+        assert_eq!(compose('\'', 'e'), Some('é'));
+        assert_eq!(compose('"', 'u'), Some('ü'));
+        assert_eq!(compose(',', 'c'), Some('ç'));
+        assert_eq!(compose('^', 'o'), Some('ô'));
+        assert_eq!(compose('`', 'a'), Some('à'));
+        assert_eq!(compose(TILDE, 'n'), Some('ñ'));
+        // either order works
+        assert_eq!(compose('e', '\''), Some('é'));
+        assert_eq!(compose('/', 'O'), Some('Ø'));
+        assert_eq!(compose('o', 'c'), Some('©'));
+
+        assert_eq!(compose('x', 'y'), None);
+        assert_eq!(compose('\'', 'x'), None);
+    }
+}
@@ -0,0 +1,830 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{KeyState, ScancodeSet, ScancodeSet1, ScancodeSet2};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub use self::layout::Layout;
+pub use pc_keyboard::KeyCode;
+
+use crate::{colors, log, log_warn, mouse, print, time};
+
+pub mod layout;
+
+pub static KEYBOARD_SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+static KEY_DECODER: OnceCell<Mutex<KeyDecoder>> = OnceCell::uninit();
+
+/// How long a key is held down before it starts repeating.
+static REPEAT_DELAY_MS: AtomicU64 = AtomicU64::new(500);
+/// Repeats per second, 0 if keys do not repeat.
+static REPEAT_RATE: AtomicU64 = AtomicU64::new(30);
+/// Uptime at which the held key repeats next, 0 if none does.
+static REPEAT_AT: AtomicU64 = AtomicU64::new(0);
+
+static KEYBOARD_WAKER: AtomicWaker = AtomicWaker::new();
+
+pub fn add_keyboard_scancode(scancode: u8) {
+    if let Ok(queue) = KEYBOARD_SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_err() {
+            log_warn!(
+                "Keyboard scancode queue full; dropping keyboard input {:#X}",
+                scancode
+            );
+        } else {
+            KEYBOARD_WAKER.wake();
+        }
+    } else {
+        log_warn!("Keyboard scancode queue uninitialized");
+    }
+}
+
+pub fn scancode_queues_empty() -> bool {
+    KEYBOARD_SCANCODE_QUEUE
+        .try_get()
+        .ok()
+        .map(|queue| queue.is_empty())
+        .unwrap_or(true)
+        && mouse::byte_queue_empty()
+}
+
+pub fn get_keyboard_scancode() -> Option<u8> {
+    KEYBOARD_SCANCODE_QUEUE
+        .try_get()
+        .ok()
+        .and_then(|queue| queue.pop().ok())
+}
+
+pub fn init() {
+    KEYBOARD_SCANCODE_QUEUE
+        .try_init_once(|| ArrayQueue::new(64))
+        .expect("Keyboard scancode queue already initialized");
+    KEY_DECODER
+        .try_init_once(|| Mutex::new(KeyDecoder::new()))
+        .expect("Key decoder already initialized");
+}
+
+/// An endless stream of raw keyboard scancodes, woken by the keyboard interrupt.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        // fast path
+        if let Some(scancode) = get_keyboard_scancode() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // register before re-checking so that a scancode pushed in between is not missed
+        KEYBOARD_WAKER.register(cx.waker());
+        match get_keyboard_scancode() {
+            Some(scancode) => {
+                KEYBOARD_WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// An endless stream of key presses and releases decoded from the keyboard
+/// scancodes.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+}
+
+impl KeyEventStream {
+    pub fn new() -> Self {
+        KeyEventStream {
+            scancodes: ScancodeStream::new(),
+        }
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        let mut decoder = KEY_DECODER.get().unwrap().lock();
+        // most scancodes are only part of a key event, so keep feeding them
+        // until one completes or the queue runs dry
+        loop {
+            if let Some(event) = decoder.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match self.scancodes.poll_next_unpin(cx) {
+                Poll::Ready(Some(scancode)) => decoder.feed(scancode),
+                _ => break,
+            }
+        }
+        // the timer wakes the stream when a repeat is due
+        decoder.repeat();
+        match decoder.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Task printing the characters typed to the logger.
+pub async fn print_keypresses() {
+    let mut events = KeyEventStream::new();
+    while let Some(event) = events.next().await {
+        if let Some(character) = event.unicode {
+            log::set_color(colors::YELLOW);
+            print!("{}", character);
+        }
+    }
+}
+
+/// A key going down or up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    /// `false` if the key was released. Presses repeat while the key is
+    /// held down; see [`set_repeat`]. Pause is only ever pressed.
+    pub pressed: bool,
+    /// The modifiers and locks once this event is taken into account.
+    pub modifiers: Modifiers,
+    /// The character the press types, if any. Releases type nothing, and
+    /// neither do dead keys and the compose key (Menu): the character they
+    /// make comes with the press completing the sequence.
+    pub unicode: Option<char>,
+}
+
+/// Which modifier keys are held down and which locks are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    /// The right Alt key, which is AltGr on many layouts.
+    pub right_alt: bool,
+    pub left_meta: bool,
+    pub right_meta: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Self {
+        Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            left_meta: false,
+            right_meta: false,
+            caps_lock: false,
+            // like the PC BIOS, start with the keypad typing digits
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    pub fn alt_gr(&self) -> bool {
+        self.right_alt
+    }
+
+    pub fn meta(&self) -> bool {
+        self.left_meta || self.right_meta
+    }
+
+    /// The argument of [`KEYCMD_SET_LEDS`] lighting the locks that are on.
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+/// The current modifiers and locks.
+pub fn modifiers() -> Modifiers {
+    KEY_DECODER
+        .try_get()
+        .map_or(Modifiers::new(), |decoder| decoder.lock().modifiers)
+}
+
+/// The layout keys are typed with.
+pub fn layout() -> Layout {
+    KEY_DECODER
+        .try_get()
+        .map_or(Layout::Us, |decoder| decoder.lock().layout)
+}
+
+/// Switches to `layout`, dropping any dead key or compose sequence in
+/// progress.
+pub fn set_layout(layout: Layout) {
+    let mut decoder = KEY_DECODER.get().unwrap().lock();
+    decoder.layout = layout;
+    decoder.composing = Composing::Idle;
+}
+
+/// How long a key is held down before it starts repeating, in
+/// milliseconds, and how many times a second it then repeats.
+pub fn repeat() -> (u64, u64) {
+    (
+        REPEAT_DELAY_MS.load(Ordering::Relaxed),
+        REPEAT_RATE.load(Ordering::Relaxed),
+    )
+}
+
+/// Sets how keys held down repeat. A `rate` of 0 turns repeating off.
+/// Rates beyond the timer's resolution repeat once per tick.
+pub fn set_repeat(delay_ms: u64, rate: u64) {
+    REPEAT_DELAY_MS.store(delay_ms, Ordering::Relaxed);
+    REPEAT_RATE.store(rate, Ordering::Relaxed);
+    if rate == 0 {
+        REPEAT_AT.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Wakes the [`KeyEventStream`] when the held key is due to repeat. Called
+/// from the timer interrupt.
+pub fn tick() {
+    let due = REPEAT_AT.load(Ordering::Relaxed);
+    if due != 0 && time::uptime_ms() >= due {
+        KEYBOARD_WAKER.wake();
+    }
+}
+
+enum Scancodes {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl Scancodes {
+    fn advance_state(
+        &mut self,
+        scancode: u8,
+    ) -> Result<Option<pc_keyboard::KeyEvent>, pc_keyboard::Error> {
+        match self {
+            Scancodes::Set1(set) => set.advance_state(scancode),
+            Scancodes::Set2(set) => set.advance_state(scancode),
+        }
+    }
+
+    /// How many bytes follow [`SCANCODE_PAUSE`] when Pause is pressed.
+    fn pause_len(&self) -> u8 {
+        match self {
+            // E1 1D 45 E1 9D C5
+            Scancodes::Set1(_) => 5,
+            // E1 14 77 E1 F0 14 F0 77
+            Scancodes::Set2(_) => 7,
+        }
+    }
+
+    /// Whether `sequence` is one of the shift presses and releases that
+    /// keyboards wrap around Print Screen and the navigation keys, so that
+    /// old software sees them as their keypad counterparts.
+    fn is_fake_shift(&self, sequence: &[u8]) -> bool {
+        match self {
+            Scancodes::Set1(_) => matches!(sequence, [0xe0, 0x2a | 0xaa | 0x36 | 0xb6]),
+            Scancodes::Set2(_) => {
+                matches!(sequence, [0xe0, 0x12 | 0x59] | [0xe0, 0xf0, 0x12 | 0x59])
+            }
+        }
+    }
+}
+
+/// Where the keyboard is in taking a [`KEYCMD_SET_LEDS`] command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedCommand {
+    Idle,
+    AwaitingCommandAck,
+    AwaitingLedsAck,
+}
+
+/// What the keys typed after a dead key or the compose key will make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Composing {
+    Idle,
+    /// A dead key typed this combining accent.
+    Dead(char),
+    /// The compose key was pressed.
+    Compose,
+    /// The compose key was pressed, then this character typed.
+    ComposeFirst(char),
+}
+
+/// Turns keyboard scancodes into [`KeyEvent`]s, repeats held keys and
+/// keeps the lock LEDs in line with the lock state.
+struct KeyDecoder {
+    scancodes: Scancodes,
+    layout: Layout,
+    modifiers: Modifiers,
+    composing: Composing,
+    /// Decoded events not yet taken by a [`KeyEventStream`].
+    events: VecDeque<KeyEvent>,
+    /// Keys held down, whose presses the keyboard repeats by itself.
+    held: Vec<KeyCode>,
+    /// The press repeated while its key is held down.
+    repeating: Option<KeyEvent>,
+    /// Prefix bytes of an extended key received so far.
+    prefix: [u8; 2],
+    prefix_len: usize,
+    /// Bytes of the Pause sequence still to come.
+    pause_left: u8,
+    led_command: LedCommand,
+    /// The LEDs being sent.
+    leds: u8,
+    led_retries: u8,
+    led_sent_at: u64,
+}
+
+impl KeyDecoder {
+    fn new() -> Self {
+        let scancodes = if KBC_MODE & KBC_MODE_TRANSLATE != 0 {
+            Scancodes::Set1(ScancodeSet1::new())
+        } else {
+            Scancodes::Set2(ScancodeSet2::new())
+        };
+        KeyDecoder {
+            scancodes,
+            layout: Layout::Us,
+            modifiers: Modifiers::new(),
+            composing: Composing::Idle,
+            events: VecDeque::new(),
+            held: Vec::new(),
+            repeating: None,
+            prefix: [0; 2],
+            prefix_len: 0,
+            pause_left: 0,
+            led_command: LedCommand::Idle,
+            leds: 0,
+            led_retries: 0,
+            led_sent_at: 0,
+        }
+    }
+
+    /// Feeds one byte from the keyboard into the decoder, queueing the
+    /// events it completes.
+    fn feed(&mut self, scancode: u8) {
+        if self.pause_left > 0 {
+            self.pause_left -= 1;
+            if self.pause_left == 0 {
+                self.event(pc_keyboard::KeyEvent {
+                    code: KeyCode::PauseBreak,
+                    state: KeyState::SingleShot,
+                });
+            }
+            return;
+        }
+
+        if self.prefix_len == 0 {
+            match scancode {
+                KEYBOARD_ACK => return self.led_acked(),
+                KEYBOARD_RESEND => return self.led_resend(),
+                KEYBOARD_ECHO | KEYBOARD_OVERRUN_SET1 | KEYBOARD_OVERRUN_SET2 => return,
+                // a scancode in set 1, so only a reset keyboard in set 2
+                KEYBOARD_SELF_TEST_PASSED if matches!(self.scancodes, Scancodes::Set2(_)) => return,
+                SCANCODE_PAUSE => {
+                    self.pause_left = self.scancodes.pause_len();
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        // hold on to extended prefixes until the sequence is complete, so
+        // that fake shifts never reach the scancode set
+        if scancode == SCANCODE_EXTENDED {
+            self.prefix = [SCANCODE_EXTENDED, 0];
+            self.prefix_len = 1;
+            return;
+        }
+        if scancode == SCANCODE_RELEASE && self.prefix_len == 1 {
+            self.prefix[1] = SCANCODE_RELEASE;
+            self.prefix_len = 2;
+            return;
+        }
+        let mut sequence = [0; 3];
+        let len = self.prefix_len + 1;
+        sequence[..self.prefix_len].copy_from_slice(&self.prefix[..self.prefix_len]);
+        sequence[self.prefix_len] = scancode;
+        self.prefix_len = 0;
+        if self.scancodes.is_fake_shift(&sequence[..len]) {
+            return;
+        }
+
+        let mut event = None;
+        for &scancode in &sequence[..len] {
+            match self.scancodes.advance_state(scancode) {
+                Ok(Some(decoded)) => event = Some(decoded),
+                Ok(None) => {}
+                // keys the scancode set does not know are of no use to anyone
+                Err(_) => return,
+            }
+        }
+        if let Some(event) = event {
+            self.event(event);
+        }
+    }
+
+    fn event(&mut self, event: pc_keyboard::KeyEvent) {
+        let key = event.code;
+        let pressed = event.state != KeyState::Up;
+        if pressed && self.held.contains(&key) {
+            // the keyboard repeating the key by itself; held keys are
+            // repeated in software instead
+            return;
+        }
+        if event.state == KeyState::Down {
+            self.held.push(key);
+        } else {
+            self.held.retain(|&held| held != key);
+        }
+
+        let modifiers = &mut self.modifiers;
+        let flag = match key {
+            KeyCode::LShift => Some(&mut modifiers.left_shift),
+            KeyCode::RShift => Some(&mut modifiers.right_shift),
+            KeyCode::LControl => Some(&mut modifiers.left_ctrl),
+            KeyCode::RControl => Some(&mut modifiers.right_ctrl),
+            KeyCode::LAlt => Some(&mut modifiers.left_alt),
+            KeyCode::RAltGr => Some(&mut modifiers.right_alt),
+            KeyCode::LWin => Some(&mut modifiers.left_meta),
+            KeyCode::RWin => Some(&mut modifiers.right_meta),
+            _ => None,
+        };
+        let modifier = flag.is_some();
+        if let Some(flag) = flag {
+            *flag = pressed;
+        }
+        let lock = match key {
+            KeyCode::CapsLock => Some(&mut modifiers.caps_lock),
+            KeyCode::NumpadLock => Some(&mut modifiers.num_lock),
+            KeyCode::ScrollLock => Some(&mut modifiers.scroll_lock),
+            _ => None,
+        };
+        let is_lock = lock.is_some();
+        if let Some(lock) = lock.filter(|_| pressed) {
+            *lock ^= true;
+            self.update_leds();
+        }
+
+        if !pressed {
+            if self.repeating.is_some_and(|repeating| repeating.key == key) {
+                self.stop_repeat();
+            }
+            self.push(key, false, None);
+            return;
+        }
+        if modifier || is_lock || key == KeyCode::PauseBreak {
+            self.push(key, true, None);
+            return;
+        }
+
+        let (accent, unicode) = match self.layout.map(key, &self.modifiers) {
+            Some(character) => self.compose(character),
+            None if key == KeyCode::Apps => {
+                self.composing = Composing::Compose;
+                self.push(key, true, None);
+                return;
+            }
+            None => {
+                // moving the cursor gives up on composing
+                self.composing = Composing::Idle;
+                (None, None)
+            }
+        };
+        if let Some(accent) = accent {
+            self.push(key, true, Some(accent));
+        }
+        let event = self.push(key, true, unicode);
+        let swallowed = unicode.is_none() && self.composing != Composing::Idle;
+        if swallowed {
+            self.stop_repeat();
+        } else {
+            self.start_repeat(event);
+        }
+    }
+
+    fn push(&mut self, key: KeyCode, pressed: bool, unicode: Option<char>) -> KeyEvent {
+        let event = KeyEvent {
+            key,
+            pressed,
+            modifiers: self.modifiers,
+            unicode,
+        };
+        self.events.push_back(event);
+        event
+    }
+
+    /// Runs `typed` through the dead key or compose sequence in progress.
+    /// Returns a lone accent to type first, and the character to type.
+    fn compose(&mut self, typed: char) -> (Option<char>, Option<char>) {
+        match mem::replace(&mut self.composing, Composing::Idle) {
+            Composing::Idle if layout::is_dead(typed) => {
+                self.composing = Composing::Dead(typed);
+                (None, None)
+            }
+            Composing::Idle => (None, Some(typed)),
+            Composing::Dead(accent) if typed == ' ' || typed == accent => {
+                (None, Some(layout::spacing(accent)))
+            }
+            Composing::Dead(accent) if layout::is_dead(typed) => {
+                self.composing = Composing::Dead(typed);
+                (None, Some(layout::spacing(accent)))
+            }
+            Composing::Dead(accent) => match layout::accented(accent, typed) {
+                Some(character) => (None, Some(character)),
+                None => (Some(layout::spacing(accent)), Some(typed)),
+            },
+            Composing::Compose => {
+                self.composing = Composing::ComposeFirst(typed);
+                (None, None)
+            }
+            // sequences that make nothing type nothing
+            Composing::ComposeFirst(first) => (None, layout::compose(first, typed)),
+        }
+    }
+
+    fn start_repeat(&mut self, event: KeyEvent) {
+        let delay = REPEAT_DELAY_MS.load(Ordering::Relaxed);
+        if REPEAT_RATE.load(Ordering::Relaxed) == 0 {
+            return self.stop_repeat();
+        }
+        self.repeating = Some(event);
+        REPEAT_AT.store(time::uptime_ms() + delay, Ordering::Relaxed);
+    }
+
+    fn stop_repeat(&mut self) {
+        self.repeating = None;
+        REPEAT_AT.store(0, Ordering::Relaxed);
+    }
+
+    /// Queues the next repeat of the held key if it is due.
+    fn repeat(&mut self) {
+        let due = REPEAT_AT.load(Ordering::Relaxed);
+        let now = time::uptime_ms();
+        let rate = REPEAT_RATE.load(Ordering::Relaxed);
+        let Some(event) = self
+            .repeating
+            .filter(|_| due != 0 && now >= due && rate != 0)
+        else {
+            return;
+        };
+        // keep to the rate, but never catch up on repeats missed while the
+        // stream was not polled
+        let interval = 1000 / rate;
+        let next = if due + interval > now {
+            due + interval
+        } else {
+            now + interval
+        };
+        REPEAT_AT.store(next, Ordering::Relaxed);
+        self.events.push_back(KeyEvent {
+            modifiers: self.modifiers,
+            ..event
+        });
+    }
+
+    /// Starts telling the keyboard which LEDs to light. If it is still busy
+    /// with an earlier change, the new state is sent once it is done.
+    fn update_leds(&mut self) {
+        let stuck = time::uptime_ms().saturating_sub(self.led_sent_at) > LED_TIMEOUT_MS;
+        if self.led_command == LedCommand::Idle || stuck {
+            self.led_retries = 0;
+            self.send_led_command(LedCommand::AwaitingCommandAck);
+        }
+    }
+
+    fn send_led_command(&mut self, next: LedCommand) {
+        let byte = match next {
+            LedCommand::AwaitingCommandAck => KEYCMD_SET_LEDS,
+            _ => self.leds,
+        };
+        self.led_command = next;
+        self.led_sent_at = time::uptime_ms();
+        send_to_keyboard(byte);
+    }
+
+    fn led_acked(&mut self) {
+        match self.led_command {
+            LedCommand::Idle => {}
+            LedCommand::AwaitingCommandAck => {
+                self.leds = self.modifiers.leds();
+                self.send_led_command(LedCommand::AwaitingLedsAck);
+            }
+            LedCommand::AwaitingLedsAck => {
+                self.led_command = LedCommand::Idle;
+                if self.leds != self.modifiers.leds() {
+                    self.update_leds();
+                }
+            }
+        }
+    }
+
+    fn led_resend(&mut self) {
+        if self.led_command == LedCommand::Idle {
+            return;
+        }
+        self.led_retries += 1;
+        if self.led_retries > LED_RETRIES {
+            log_warn!("Keyboard keeps rejecting the LED command; giving up");
+            self.led_command = LedCommand::Idle;
+            return;
+        }
+        self.send_led_command(self.led_command);
+    }
+}
+
+const PORT_KEYDAT: u16 = 0x0060;
+const PORT_KEYSTA: u16 = 0x0064;
+const PORT_KEYCMD: u16 = 0x0064;
+const KEYCMD_WRITE_MODE: u8 = 0x60;
+const KEYCMD_SENDTO_MOUSE: u8 = 0xd4;
+const KEYCMD_SET_LEDS: u8 = 0xed;
+
+const KBC_MODE: u8 = 0x47; // mode enabling PS/2 mouse
+/// Bit of [`KBC_MODE`] making the controller translate what the keyboard
+/// sends into scancode set 1.
+const KBC_MODE_TRANSLATE: u8 = 1 << 6;
+
+// bytes the keyboard sends that are not scancodes
+const KEYBOARD_ACK: u8 = 0xfa;
+const KEYBOARD_RESEND: u8 = 0xfe;
+const KEYBOARD_ECHO: u8 = 0xee;
+const KEYBOARD_SELF_TEST_PASSED: u8 = 0xaa;
+const KEYBOARD_OVERRUN_SET1: u8 = 0xff;
+const KEYBOARD_OVERRUN_SET2: u8 = 0x00;
+
+const SCANCODE_EXTENDED: u8 = 0xe0;
+const SCANCODE_PAUSE: u8 = 0xe1;
+const SCANCODE_RELEASE: u8 = 0xf0;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+/// How often a rejected LED command is sent again.
+const LED_RETRIES: u8 = 3;
+/// How long the keyboard may take to acknowledge an LED command before a
+/// new one is sent anyway.
+const LED_TIMEOUT_MS: u64 = 1000;
+
+fn wait_kbc_isready() {
+    let mut port = Port::new(PORT_KEYSTA);
+    loop {
+        let status: u8 = unsafe { port.read() };
+        if (status & 0b10) == 0 {
+            break;
+        }
+    }
+}
+
+pub fn init_kbc() {
+    let mut port = Port::new(PORT_KEYCMD);
+    unsafe {
+        port.write(KEYCMD_WRITE_MODE);
+    }
+    wait_kbc_isready();
+    let mut port = Port::new(PORT_KEYDAT);
+    unsafe {
+        port.write(KBC_MODE);
+    }
+    wait_kbc_isready();
+
+    // the keyboard comes up with all LEDs off, which does not match the
+    // initial num lock
+    KEY_DECODER.get().unwrap().lock().update_leds();
+}
+
+fn send_to_keyboard(byte: u8) {
+    wait_kbc_isready();
+    let mut port = Port::new(PORT_KEYDAT);
+    unsafe { port.write(byte) };
+}
+
+/// Sends `byte` to the mouse rather than the keyboard. The mouse answers
+/// through the mouse interrupt.
+pub fn send_to_mouse(byte: u8) {
+    wait_kbc_isready();
+    let mut port = Port::new(PORT_KEYCMD);
+    unsafe { port.write(KEYCMD_SENDTO_MOUSE) };
+    send_to_keyboard(byte);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // scancode set 1 presses; the release adds 0x80
+    const GRAVE_KEY: &[u8] = &[0x29];
+    const EQUALS_KEY: &[u8] = &[0x0d];
+    const A: &[u8] = &[0x1e];
+    const E: &[u8] = &[0x12];
+    const X: &[u8] = &[0x2d];
+    const SPACE: &[u8] = &[0x39];
+    const APPS: &[u8] = &[0xe0, 0x5d];
+    const LEFT: &[u8] = &[0xe0, 0x4b];
+
+    /// The characters typed by pressing and releasing `keys` in turn.
+    fn type_keys(layout: Layout, keys: &[&[u8]]) -> Vec<char> {
+        let mut decoder = KeyDecoder::new();
+        decoder.layout = layout;
+        for key in keys {
+            for &scancode in *key {
+                decoder.feed(scancode);
+            }
+            let (last, prefix) = key.split_last().unwrap();
+            for &scancode in prefix {
+                decoder.feed(scancode);
+            }
+            decoder.feed(last | 0x80);
+        }
+        decoder
+            .events
+            .iter()
+            .filter_map(|event| event.unicode)
+            .collect()
+    }
+
+    #[test]
+    fn dead_key_accents_the_next_letter() {
+        // the German ^ key is dead, and so is ´ next to ß
+        assert_eq!(type_keys(Layout::German, &[GRAVE_KEY, E]), ['ê']);
+        assert_eq!(type_keys(Layout::German, &[EQUALS_KEY, A]), ['á']);
+        assert_eq!(type_keys(Layout::Us, &[GRAVE_KEY, E]), ['`', 'e']);
+    }
+
+    #[test]
+    fn dead_key_without_a_letter() {
+        assert_eq!(type_keys(Layout::German, &[GRAVE_KEY, SPACE]), ['^']);
+        assert_eq!(type_keys(Layout::German, &[GRAVE_KEY, GRAVE_KEY]), ['^']);
+        assert_eq!(type_keys(Layout::German, &[GRAVE_KEY, X]), ['^', 'x']);
+        // another dead key types the first accent and takes its place
+        assert_eq!(
+            type_keys(Layout::German, &[GRAVE_KEY, EQUALS_KEY, E]),
+            ['^', 'é']
+        );
+    }
+
+    #[test]
+    fn compose_key() {
+        assert_eq!(type_keys(Layout::Us, &[APPS, A, E]), ['æ']);
+        assert_eq!(type_keys(Layout::Us, &[APPS, E, A]), ['æ']);
+        assert_eq!(type_keys(Layout::German, &[APPS, GRAVE_KEY, A]), ['â']);
+        assert_eq!(type_keys(Layout::Us, &[APPS, X, X, A]), ['a']);
+        // moving the cursor gives up on the sequence
+        assert_eq!(type_keys(Layout::Us, &[APPS, LEFT, A]), ['a']);
+    }
+}
@@ -131,6 +131,16 @@ impl LayerController {
         self.layers.remove(layer.lock().index as usize)
     }
 
+    /// Removes `layer` wherever it is in the stack.
+    pub fn remove(&mut self, layer: &Arc<Mutex<Layer>>) {
+        if let Some(position) = self.layers.iter().position(|l| Arc::ptr_eq(l, layer)) {
+            self.layers.remove(position);
+            for (index, layer) in self.layers.iter().enumerate().skip(position) {
+                layer.lock().index = index as u32;
+            }
+        }
+    }
+
     pub fn get_layer_count(&self) -> usize {
         self.layers.len()
     }
@@ -142,11 +152,11 @@ impl LayerController {
     }
 
     pub fn render(&self) {
-        graphics::layer_controller_render(&self);
+        graphics::layer_controller_render(self);
     }
 
     pub fn render_partial(&self, x: u32, y: u32, width: u32, height: u32) {
-        graphics::layer_controller_render_partial(&self, x, y, width, height);
+        graphics::layer_controller_render_partial(self, x, y, width, height);
     }
 
     pub fn get_layers_iter(&self) -> impl Iterator<Item = &Arc<Mutex<Layer>>> {
@@ -1,8 +1,8 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
-#![feature(alloc_error_handler)] // at the top of the file
-#![feature(once_cell)]
+#![cfg_attr(not(test), feature(alloc_error_handler))] // at the top of the file
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("alloc error: {:?}", layout)
@@ -11,18 +11,38 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 extern crate alloc;
 
 use x86_64::instructions;
+pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod apic;
 pub mod bitmap;
+pub mod block;
 pub mod colors;
+pub mod elf;
 pub mod gdt;
 pub mod graphics;
+pub mod initrd;
 pub mod interrupts;
 pub mod keyboard;
 pub mod layer;
 pub mod log;
 pub mod memory;
+pub mod mouse;
+pub mod net;
+pub mod pci;
+pub mod percpu;
+pub mod process;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod time;
 pub mod unifont;
+pub mod usermode;
+pub mod vfs;
+pub mod virtio;
 pub mod gui;
 
 pub fn hlt_loop() -> ! {
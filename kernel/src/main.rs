@@ -3,28 +3,38 @@
 
 extern crate alloc;
 
-use core::{panic::PanicInfo, u32::MAX};
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
 
 use bootloader_api::{
     config::{BootloaderConfig, Mapping},
     entry_point, BootInfo,
 };
+use futures_util::stream::StreamExt;
 use kernel::{
-    allocator, bitmap, colors, gdt, graphics, interrupts,
-    keyboard::{self, MousePhase, MOUSE_STATUS},
+    acpi, allocator, apic, bitmap, block, colors, gdt, graphics, initrd, interrupts, keyboard,
     layer::{self, Layer, LAYER_CONTROLLER},
-    log, log_info, log_ok, log_panic, log_trace,
+    log, log_info, log_ok, log_panic, log_trace, log_warn,
     memory::{self, BootInfoFrameAllocator},
-    print, serial_println
+    mouse::{self, MouseEvent, MouseEventStream},
+    net, pci, percpu, process, serial_println, smp, syscall,
+    task::{executor::Executor, Task},
+    thread, time, vfs,
 };
-use x86_64::{instructions, VirtAddr};
+use spin::Mutex;
+use x86_64::VirtAddr;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // keep everything the bootloader maps out of the lower half, which belongs to user space
+    config.mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
     config
 };
 
+/// Program started as the first process if the root filesystem contains it.
+const INIT_PATH: &str = "/bin/init";
+
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
@@ -57,6 +67,8 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
         unsafe { interrupts::PICS.lock().initialize() };
         log_info!("PICs initialized");
+        time::init();
+        log_info!("PIT initialized");
         x86_64::instructions::interrupts::enable();
         log_info!("Interrupts enabled");
 
@@ -72,13 +84,82 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         allocator::init_heap(&mut mapper, &mut frame_allocator)
             .expect("heap initialization failed");
         log_info!("Heap initialized");
+        memory::init(phys_mem_offset, mapper, frame_allocator);
+
+        match boot_info.ramdisk_addr.into_option() {
+            Some(ramdisk_addr) => match initrd::init(ramdisk_addr, boot_info.ramdisk_len) {
+                Ok(entries) => {
+                    log_info!("Initrd loaded ({} entries)", entries);
+                }
+                Err(err) => {
+                    log_warn!("Cannot read initrd: {}", err);
+                }
+            },
+            None => {
+                log_warn!("No initrd provided by the bootloader");
+            }
+        }
+
+        match boot_info.rsdp_addr.into_option() {
+            Some(rsdp_addr) => match acpi::init(rsdp_addr) {
+                Ok(()) => {
+                    log_info!("ACPI tables parsed");
+                }
+                Err(err) => {
+                    log_warn!("Cannot parse ACPI tables: {}", err);
+                }
+            },
+            None => {
+                log_warn!("No RSDP provided by the bootloader");
+            }
+        }
+
+        apic::init();
+        apic::init_local();
+        percpu::init_bsp();
+        log_info!("Local APIC initialized");
+        syscall::init();
+        log_info!("System calls enabled");
+
+        thread::init();
+        apic::calibrate_timer();
+        apic::start_timer(interrupts::LAPIC_TIMER_VECTOR);
+        log_info!("Scheduler initialized");
+
+        smp::init();
+        log_info!("{} CPUs online", percpu::cpu_count());
+
+        let pci_devices = pci::init();
+        log_info!("PCI bus scanned ({} devices)", pci_devices);
+
+        if let Some(initrd) = initrd::get() {
+            vfs::mount("/", Arc::new(vfs::initrdfs::InitrdFs::new(initrd))).unwrap();
+        }
+        vfs::devfs::init();
+        vfs::mount("/dev", Arc::new(vfs::devfs::DevFs)).unwrap();
+        log_info!("Filesystems mounted");
+
+        block::cache::init();
+        let drives = block::ata::init();
+        log_info!("ATA initialized ({} drives)", drives);
+        let disks = block::virtio_blk::init();
+        log_info!("virtio-blk initialized ({} disks)", disks);
+        let controllers = block::ahci::init();
+        log_info!("AHCI initialized ({} controllers)", controllers);
+
+        let nics = net::virtio_net::init();
+        log_info!("virtio-net initialized ({} NICs)", nics);
+        let nics = net::e1000::init();
+        log_info!("e1000 initialized ({} NICs)", nics);
+        net::init();
+        log_info!("Network stack started");
 
         keyboard::init();
         keyboard::init_kbc();
         log_info!("Keyboard initialized");
 
-        keyboard::enable_mouse();
-        log_info!("Mouse initialized");
+        let kind = mouse::init();
+        log_info!("Mouse initialized ({:?})", kind);
 
         layer::init();
         log_info!("Layer manager initialized");
@@ -88,6 +169,9 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     log_ok!("Welcome to Micfong OS!");
     serial_println!("\n\nWelcome to Micfong OS!");
+    if let Ok(motd) = vfs::read_to_end("/etc/motd") {
+        serial_println!("{}", core::str::from_utf8(&motd).unwrap_or_default());
+    }
 
     let screen_width = graphics::get_width();
     let screen_height = graphics::get_height();
@@ -102,7 +186,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     );
     background_layer.draw_rect(20, 20, 120, 120, colors::ORANGE);
 
-    let mut mouse_cursor_layer = Layer::new(13, 19, screen_width / 2, screen_height / 2, MAX);
+    let mut mouse_cursor_layer = Layer::new(13, 19, screen_width / 2, screen_height / 2, u32::MAX);
     mouse_cursor_layer.draw_bitmap(0, 0, 13, 19, &bitmap::MOUSE_CURSOR);
 
     let mut test_window_layer = Layer::new(200, 100, 120, 80, 1);
@@ -112,88 +196,36 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     layer::add_layer(test_window_layer);
     let mouse_cursor_layer = layer::add_layer(mouse_cursor_layer);
 
-    let layer_controller = LAYER_CONTROLLER.get().unwrap().lock();
+    LAYER_CONTROLLER.get().unwrap().lock().render();
 
-    layer_controller.render();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(mouse_cursor(mouse_cursor_layer)));
 
-    loop {
-        instructions::interrupts::disable();
-        if keyboard::scancode_queues_empty() {
-            instructions::interrupts::enable_and_hlt();
-        } else {
-            if let Some(scancode) = keyboard::get_keyboard_scancode() {
-                instructions::interrupts::enable();
-                log::set_color(colors::YELLOW);
-                print!("{:02X} ", scancode);
-            }
-            if let Some(scancode) = keyboard::get_mouse_scancode() {
-                instructions::interrupts::enable();
-
-                let mut mouse_status = MOUSE_STATUS.get().unwrap().lock();
-                match mouse_status.phase {
-                    MousePhase::Ack => {
-                        if scancode == 0xfa {
-                            mouse_status.phase = MousePhase::Byte1;
-                        }
-                    }
-                    MousePhase::Byte1 => {
-                        // Check if this is a valid byte
-                        if scancode & 0b1100_1000 == 0b0000_1000 {
-                            mouse_status.buffer[0] = scancode;
-                            mouse_status.phase = MousePhase::Byte2;
-                        }
-                    }
-                    MousePhase::Byte2 => {
-                        mouse_status.buffer[1] = scancode;
-                        mouse_status.phase = MousePhase::Byte3;
-                    }
-                    MousePhase::Byte3 => {
-                        mouse_status.buffer[2] = scancode;
-                        mouse_status.phase = MousePhase::Byte1;
-
-                        mouse_status.left_button = mouse_status.buffer[0] & 0b0000_0001 != 0;
-                        mouse_status.right_button = mouse_status.buffer[0] & 0b0000_0010 != 0;
-                        mouse_status.middle_button = mouse_status.buffer[0] & 0b0000_0100 != 0;
-
-                        mouse_status.x_delta = mouse_status.buffer[1] as i32;
-                        mouse_status.y_delta = mouse_status.buffer[2] as i32;
-                        if mouse_status.buffer[0] & 0b0001_0000 != 0 {
-                            mouse_status.x_delta -= 256;
-                        }
-                        if mouse_status.buffer[0] & 0b0010_0000 != 0 {
-                            mouse_status.y_delta -= 256;
-                        }
-                        mouse_status.y_delta = -mouse_status.y_delta;
-
-                        let old_x = mouse_status.x_pos as u32;
-                        let old_y = mouse_status.y_pos as u32;
-
-                        mouse_status.x_pos += mouse_status.x_delta;
-                        mouse_status.y_pos += mouse_status.y_delta;
-                        mouse_status.x_pos = mouse_status
-                            .x_pos
-                            .max(0)
-                            .min((graphics::get_width() - 1) as i32);
-                        mouse_status.y_pos = mouse_status
-                            .y_pos
-                            .max(0)
-                            .min((graphics::get_height() - 1) as i32);
-
-                        mouse_cursor_layer
-                            .lock()
-                            .set_pos(mouse_status.x_pos as u32, mouse_status.y_pos as u32);
-                        layer_controller.render_partial(old_x, old_y, 13, 19);
-                        layer_controller.render_partial(
-                            mouse_status.x_pos as u32,
-                            mouse_status.y_pos as u32,
-                            13,
-                            19,
-                        );
-                    }
-                }
-            }
+    match process::spawn_path(INIT_PATH, &[INIT_PATH], &[]) {
+        Ok(_) | Err(process::ProcessError::File(vfs::VfsError::NotFound)) => {}
+        Err(err) => {
+            log_warn!("Cannot start {}: {:?}", INIT_PATH, err);
         }
     }
+    executor.run();
+}
+
+/// Task moving the mouse cursor layer as the mouse moves.
+async fn mouse_cursor(mouse_cursor_layer: Arc<Mutex<Layer>>) {
+    let mut events = MouseEventStream::new();
+    let (mut old_x, mut old_y) = mouse::position();
+    while let Some(event) = events.next().await {
+        let MouseEvent::Move { x, y, .. } = event else {
+            continue;
+        };
+
+        mouse_cursor_layer.lock().set_pos(x as u32, y as u32);
+        let layer_controller = LAYER_CONTROLLER.get().unwrap().lock();
+        layer_controller.render_partial(old_x as u32, old_y as u32, 13, 19);
+        layer_controller.render_partial(x as u32, y as u32, 13, 19);
+        (old_x, old_y) = (x, y);
+    }
 }
 
 /// This function is called when Rust panics.
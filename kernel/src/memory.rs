@@ -1,9 +1,32 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use conquer_once::spin::OnceCell;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+use crate::{address_space::KERNEL_PML4_START, sync::IrqSpinLock};
+
+/// Frames below 1 MiB are left alone by the frame allocator; real-mode code
+/// such as the AP startup trampoline has to live there.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Start of the virtual window device registers are mapped into.
+pub const MMIO_START: u64 = 0xFFFF_9000_0000_0000;
+pub const MMIO_SIZE: u64 = 1024 * 1024 * 1024;
+
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+pub static MAPPER: OnceCell<IrqSpinLock<OffsetPageTable<'static>>> = OnceCell::uninit();
+pub static FRAME_ALLOCATOR: OnceCell<IrqSpinLock<BootInfoFrameAllocator>> = OnceCell::uninit();
+static KERNEL_PAGE_TABLE: OnceCell<PhysFrame> = OnceCell::uninit();
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -15,23 +38,165 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// ## Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`,
+/// and this must be called only once.
 pub unsafe fn init_mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Makes the mapper and frame allocator available to the rest of the kernel
+/// once the heap has been set up with them.
+pub fn init(
+    physical_memory_offset: VirtAddr,
+    mut mapper: OffsetPageTable<'static>,
+    mut frame_allocator: BootInfoFrameAllocator,
+) {
+    use x86_64::registers::control::Cr3;
+
+    preallocate_kernel_tables(
+        mapper.level_4_table(),
+        &mut frame_allocator,
+        physical_memory_offset,
+    );
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_PAGE_TABLE.init_once(|| Cr3::read().0);
+    MAPPER.init_once(|| IrqSpinLock::new(mapper));
+    FRAME_ALLOCATOR.init_once(|| IrqSpinLock::new(frame_allocator));
+}
+
+/// Gives every empty level 4 entry of the kernel half a level 3 table.
+///
+/// Address spaces copy the kernel half's level 4 entries when they are
+/// created, so with all of them present, mappings the kernel makes later,
+/// such as MMIO, end up in tables that every address space shares.
+fn preallocate_kernel_tables(
+    level_4_table: &mut PageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) {
+    for entry in level_4_table.iter_mut().skip(KERNEL_PML4_START) {
+        if !entry.is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .expect("no frame for a kernel page table");
+        let table =
+            (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        unsafe { (*table).zero() };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+/// Level 4 page table the kernel was booted with. Its upper half is shared
+/// by every address space.
+pub fn kernel_page_table() -> PhysFrame {
+    *KERNEL_PAGE_TABLE.get().expect("Memory not initialized")
+}
+
+/// Returns the virtual address through which the given physical address can be accessed.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("Physical memory offset not initialized")
+        + addr.as_u64()
+}
+
+/// Bytes of RAM the frame allocator manages, free or not.
+pub fn usable_memory() -> u64 {
+    FRAME_ALLOCATOR
+        .get()
+        .map_or(0, |allocator| allocator.lock().usable_memory())
+}
+
+/// Maps `size` bytes of device memory starting at `phys` as uncached and
+/// returns the virtual address of `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end_frame = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let page_count = end_frame - start_frame + 1;
+
+    let virt_start = MMIO_NEXT.fetch_add(page_count * 4096, Ordering::Relaxed);
+    if virt_start + page_count * 4096 > MMIO_START + MMIO_SIZE {
+        panic!("MMIO window exhausted");
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let mut mapper = MAPPER.get().expect("Mapper not initialized").lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt_start + i as u64 * 4096));
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut *frame_allocator)
+                .expect("failed to map MMIO region")
+                .flush();
+        }
+    }
+
+    VirtAddr::new(virt_start) + (phys.as_u64() - start_frame.start_address().as_u64())
+}
+
+/// A zeroed frame shared with a device, which reads or writes it by its
+/// physical address. The frame goes back to the allocator when dropped.
+pub struct DmaFrame(PhysFrame);
+
+impl DmaFrame {
+    pub fn new() -> Option<Self> {
+        let frame = FRAME_ALLOCATOR.get()?.lock().allocate_frame()?;
+        let dma = DmaFrame(frame);
+        unsafe { core::ptr::write_bytes(dma.virt().as_mut_ptr::<u8>(), 0, 4096) };
+        Some(dma)
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.0.start_address()
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        phys_to_virt(self.phys())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt().as_ptr(), 4096) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt().as_mut_ptr(), 4096) }
+    }
+}
+
+impl Drop for DmaFrame {
+    fn drop(&mut self) {
+        if let Some(allocator) = FRAME_ALLOCATOR.get() {
+            unsafe { allocator.lock().deallocate_frame(self.0) };
+        }
+    }
+}
+
 pub struct BootInfoFrameAllocator {
     memory_regions: &'static MemoryRegions,
     next: usize,
     last_access: usize,
+    /// Frames given back by [`FrameDeallocator::deallocate_frame`], handed out first.
+    free: Vec<PhysFrame>,
 }
 
+// the memory map is written once by the bootloader and only ever read after
+// that, so sharing it with other CPUs is fine
+unsafe impl Send for BootInfoFrameAllocator {}
+
 impl BootInfoFrameAllocator {
+    /// ## Safety
+    /// Every region the memory map marks usable must really be unused.
     pub unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
         BootInfoFrameAllocator {
             memory_regions,
             next: 0,
             last_access: 0,
+            free: Vec::new(),
         }
     }
 
@@ -39,20 +204,50 @@ impl BootInfoFrameAllocator {
         self.memory_regions
             .iter()
             .filter(|r| r.kind == MemoryRegionKind::Usable)
-            .map(|r| r.start..r.end)
+            .map(|r| r.start.max(LOW_MEMORY_END)..r.end)
             .flat_map(|r| r.step_by(4096))
             .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Bytes of RAM the allocator hands out frames from.
+    pub fn usable_memory(&self) -> u64 {
+        self.memory_regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| r.end.saturating_sub(r.start.max(LOW_MEMORY_END)))
+            .sum()
+    }
+
+    /// Returns whether the given frame below 1 MiB is usable RAM. Such frames
+    /// are never handed out by the allocator.
+    pub fn is_usable_low_frame(&self, frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        addr + 4096 <= LOW_MEMORY_END
+            && self.memory_regions.iter().any(|r| {
+                r.kind == MemoryRegionKind::Usable && r.start <= addr && addr + 4096 <= r.end
+            })
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// The free list lives on the heap, so this must not be called before the
+    /// heap is initialized.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free.push(frame);
+    }
+}
+
 impl BootInfoFrameAllocator {
     pub fn allocate_frame_with_iter(&mut self, iter: &mut impl Iterator<Item = PhysFrame>) -> Option<PhysFrame> {
         let frame = iter.nth(self.next - self.last_access);
@@ -0,0 +1,566 @@
+//! PS/2 mouse driver. Wakes IntelliMouse-compatible mice up to their wheel
+//! and extra buttons, decodes their packets and publishes what happened as
+//! [`MouseEvent`]s.
+
+use alloc::collections::VecDeque;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Mutex;
+use x86_64::instructions;
+
+use crate::{colors, graphics, keyboard, log_warn, time};
+
+const MOUSECMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSECMD_GET_ID: u8 = 0xf2;
+const MOUSECMD_ENABLE: u8 = 0xf4;
+const MOUSECMD_DISABLE: u8 = 0xf5;
+const MOUSECMD_SET_DEFAULTS: u8 = 0xf6;
+
+const MOUSE_ACK: u8 = 0xfa;
+const MOUSE_RESEND: u8 = 0xfe;
+const MOUSE_ERROR: u8 = 0xfc;
+
+/// Sample rates which, set in this order, make an IntelliMouse report the
+/// wheel.
+const KNOCK_WHEEL: [u8; 3] = [200, 100, 80];
+/// Sample rates which, set in this order after [`KNOCK_WHEEL`], make it
+/// report the fourth and fifth buttons too.
+const KNOCK_FIVE_BUTTONS: [u8; 3] = [200, 200, 80];
+const SAMPLE_RATE: u8 = 100;
+
+const ID_WHEEL: u8 = 3;
+const ID_FIVE_BUTTONS: u8 = 4;
+
+/// How long the mouse may take to answer a command.
+const COMMAND_TIMEOUT_MS: u64 = 200;
+/// Longest pause between the bytes of one packet. A byte arriving later
+/// starts a new packet: the rest of the previous one got lost.
+const PACKET_GAP_MS: u64 = 50;
+
+// bits of the first byte of a packet
+const FLAG_BUTTONS: u8 = 0b111;
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_SIGN: u8 = 1 << 4;
+const FLAG_Y_SIGN: u8 = 1 << 5;
+const FLAG_X_OVERFLOW: u8 = 1 << 6;
+const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+// bits of the fourth byte of a five-button packet
+const EXTRA_WHEEL: u8 = 0b1111;
+const EXTRA_BACK: u8 = 1 << 4;
+const EXTRA_FORWARD: u8 = 1 << 5;
+const EXTRA_ALWAYS_ZERO: u8 = 0b1100_0000;
+
+pub static MOUSE_BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+pub static MOUSE_STATUS: OnceCell<Mutex<MouseStatus>> = OnceCell::uninit();
+
+static MOUSE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// What the mouse turned out to be when it was initialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// Three buttons, no wheel.
+    Standard,
+    /// Three buttons and a wheel.
+    Wheel,
+    /// Five buttons and a wheel.
+    FiveButtons,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    const ALL: [MouseButton; 5] = [
+        MouseButton::Left,
+        MouseButton::Right,
+        MouseButton::Middle,
+        MouseButton::Back,
+        MouseButton::Forward,
+    ];
+
+    /// The bit of [`MouseStatus::buttons`] telling whether the button is
+    /// held down.
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// The mouse moved by `dx`, `dy`, taking the cursor to `x`, `y`. Like
+    /// the screen's, the y axis grows downwards.
+    Move {
+        dx: i32,
+        dy: i32,
+        x: i32,
+        y: i32,
+    },
+    ButtonDown(MouseButton),
+    ButtonUp(MouseButton),
+    /// The wheel turned by this many notches, positive towards the user.
+    Scroll(i32),
+}
+
+pub struct MouseStatus {
+    pub x_pos: i32,
+    pub y_pos: i32,
+    /// Size of the screen, which the cursor stays on.
+    width: i32,
+    height: i32,
+    kind: MouseKind,
+    /// [`MouseButton::mask`] bits of the buttons held down.
+    buttons: u8,
+    packet: [u8; 4],
+    received: usize,
+    last_byte_at: u64,
+    /// Decoded events not yet taken by a [`MouseEventStream`].
+    events: VecDeque<MouseEvent>,
+}
+
+impl MouseStatus {
+    pub fn kind(&self) -> MouseKind {
+        self.kind
+    }
+
+    pub fn is_pressed(&self, button: MouseButton) -> bool {
+        self.buttons & button.mask() != 0
+    }
+
+    fn packet_len(&self) -> usize {
+        match self.kind {
+            MouseKind::Standard => 3,
+            MouseKind::Wheel | MouseKind::FiveButtons => 4,
+        }
+    }
+
+    /// Feeds one byte from the mouse into the packet decoder, queueing the
+    /// events of the packets it completes.
+    pub fn process_byte(&mut self, byte: u8) {
+        self.receive(byte, time::uptime_ms());
+    }
+
+    /// [`MouseStatus::process_byte`] for a byte received at uptime `now`.
+    fn receive(&mut self, byte: u8, now: u64) {
+        if self.received > 0 && now.saturating_sub(self.last_byte_at) > PACKET_GAP_MS {
+            self.received = 0;
+        }
+        self.last_byte_at = now;
+        if self.received == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+            // out of step with the packets; wait for a byte that can start one
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received == self.packet_len() {
+            self.received = 0;
+            self.decode_packet();
+        }
+    }
+
+    fn decode_packet(&mut self) {
+        let flags = self.packet[0];
+        let mut buttons = flags & FLAG_BUTTONS;
+        let scroll = match self.kind {
+            MouseKind::Standard => 0,
+            MouseKind::Wheel => self.packet[3] as i8 as i32,
+            MouseKind::FiveButtons => {
+                let extra = self.packet[3];
+                if extra & EXTRA_ALWAYS_ZERO != 0 {
+                    // the packet did not start where it seemed to
+                    return;
+                }
+                if extra & EXTRA_BACK != 0 {
+                    buttons |= MouseButton::Back.mask();
+                }
+                if extra & EXTRA_FORWARD != 0 {
+                    buttons |= MouseButton::Forward.mask();
+                }
+                // sign-extend the 4-bit wheel movement
+                (((extra & EXTRA_WHEEL) << 4) as i8 >> 4) as i32
+            }
+        };
+
+        let dx = delta(self.packet[1], flags & FLAG_X_SIGN, flags & FLAG_X_OVERFLOW);
+        let dy = -delta(self.packet[2], flags & FLAG_Y_SIGN, flags & FLAG_Y_OVERFLOW);
+        if dx != 0 || dy != 0 {
+            self.x_pos = (self.x_pos + dx).clamp(0, self.width - 1);
+            self.y_pos = (self.y_pos + dy).clamp(0, self.height - 1);
+            self.events.push_back(MouseEvent::Move {
+                dx,
+                dy,
+                x: self.x_pos,
+                y: self.y_pos,
+            });
+        }
+
+        let changed = buttons ^ self.buttons;
+        self.buttons = buttons;
+        for button in MouseButton::ALL {
+            if changed & button.mask() == 0 {
+                continue;
+            }
+            self.events.push_back(if buttons & button.mask() != 0 {
+                MouseEvent::ButtonDown(button)
+            } else {
+                MouseEvent::ButtonUp(button)
+            });
+        }
+
+        if scroll != 0 {
+            self.events.push_back(MouseEvent::Scroll(scroll));
+        }
+    }
+}
+
+/// The 9-bit movement made of `value` and its `sign` bit. Movement too fast
+/// for 9 bits `overflow`s, which is taken as the fastest movement there is.
+fn delta(value: u8, sign: u8, overflow: u8) -> i32 {
+    match (sign != 0, overflow != 0) {
+        (false, false) => value as i32,
+        (true, false) => value as i32 - 256,
+        (false, true) => 255,
+        (true, true) => -256,
+    }
+}
+
+pub fn add_byte(byte: u8) {
+    if let Ok(queue) = MOUSE_BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            log_warn!("Mouse byte queue full; dropping mouse input {:#X}", byte);
+        } else {
+            MOUSE_WAKER.wake();
+        }
+    } else {
+        log_warn!("Mouse byte queue uninitialized");
+    }
+}
+
+pub fn get_byte() -> Option<u8> {
+    MOUSE_BYTE_QUEUE
+        .try_get()
+        .ok()
+        .and_then(|queue| queue.pop().ok())
+}
+
+pub fn byte_queue_empty() -> bool {
+    MOUSE_BYTE_QUEUE
+        .try_get()
+        .ok()
+        .map(|queue| queue.is_empty())
+        .unwrap_or(true)
+}
+
+/// Waits for the next byte from the mouse for up to `timeout_ms`.
+fn read_byte(timeout_ms: u64) -> Option<u8> {
+    let deadline = time::uptime_ms() + timeout_ms;
+    loop {
+        if let Some(byte) = get_byte() {
+            return Some(byte);
+        }
+        if time::uptime_ms() >= deadline {
+            return None;
+        }
+        // the mouse and the timer interrupt both end the wait
+        instructions::hlt();
+    }
+}
+
+/// Sends `command` to the mouse. Returns whether the mouse acknowledged it.
+fn command(command: u8) -> bool {
+    keyboard::send_to_mouse(command);
+    loop {
+        match read_byte(COMMAND_TIMEOUT_MS) {
+            Some(MOUSE_ACK) => return true,
+            Some(MOUSE_RESEND | MOUSE_ERROR) | None => return false,
+            // movement still on its way from before the command
+            Some(_) => {}
+        }
+    }
+}
+
+fn set_sample_rate(rate: u8) -> bool {
+    command(MOUSECMD_SET_SAMPLE_RATE) && command(rate)
+}
+
+/// Sets the sample rates of `knock` in turn, then asks the mouse for its ID.
+fn knock(knock: [u8; 3]) -> Option<u8> {
+    for rate in knock {
+        if !set_sample_rate(rate) {
+            return None;
+        }
+    }
+    if !command(MOUSECMD_GET_ID) {
+        return None;
+    }
+    read_byte(COMMAND_TIMEOUT_MS)
+}
+
+/// Finds out what the mouse can report and turns it on. Called once the
+/// controller is set up.
+pub fn init() -> MouseKind {
+    MOUSE_BYTE_QUEUE
+        .try_init_once(|| ArrayQueue::new(128))
+        .expect("Mouse byte queue already initialized");
+
+    // quiet the mouse while talking to it, and forget whatever it sent
+    command(MOUSECMD_DISABLE);
+    while get_byte().is_some() {}
+    if !command(MOUSECMD_SET_DEFAULTS) {
+        log_warn!("Mouse did not acknowledge its reset to defaults");
+    }
+
+    let kind = match knock(KNOCK_WHEEL) {
+        Some(ID_WHEEL) => match knock(KNOCK_FIVE_BUTTONS) {
+            Some(ID_FIVE_BUTTONS) => MouseKind::FiveButtons,
+            _ => MouseKind::Wheel,
+        },
+        _ => MouseKind::Standard,
+    };
+    if !set_sample_rate(SAMPLE_RATE) {
+        log_warn!("Mouse did not take its sample rate");
+    }
+
+    MOUSE_STATUS
+        .try_init_once(|| {
+            let width = graphics::get_width() as i32;
+            let height = graphics::get_height() as i32;
+            Mutex::new(MouseStatus {
+                x_pos: width / 2,
+                y_pos: height / 2,
+                width,
+                height,
+                kind,
+                buttons: 0,
+                packet: [0; 4],
+                received: 0,
+                last_byte_at: 0,
+                events: VecDeque::new(),
+            })
+        })
+        .expect("Mouse status already initialized");
+
+    if !command(MOUSECMD_ENABLE) {
+        log_warn!("Mouse did not acknowledge being enabled");
+    }
+    kind
+}
+
+/// Where the cursor is.
+pub fn position() -> (i32, i32) {
+    let status = MOUSE_STATUS.get().unwrap().lock();
+    (status.x_pos, status.y_pos)
+}
+
+/// An endless stream of mouse events, woken by the mouse interrupt.
+pub struct MouseEventStream {
+    _private: (),
+}
+
+impl MouseEventStream {
+    pub fn new() -> Self {
+        MouseEventStream { _private: () }
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let mut status = MOUSE_STATUS.get().unwrap().lock();
+        // register before draining so that a byte pushed in between is not missed
+        MOUSE_WAKER.register(cx.waker());
+        loop {
+            if let Some(event) = status.events.pop_front() {
+                MOUSE_WAKER.take();
+                return Poll::Ready(Some(event));
+            }
+            match get_byte() {
+                Some(byte) => status.process_byte(byte),
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn status(kind: MouseKind) -> MouseStatus {
+        MouseStatus {
+            x_pos: 320,
+            y_pos: 240,
+            width: 640,
+            height: 480,
+            kind,
+            buttons: 0,
+            packet: [0; 4],
+            received: 0,
+            last_byte_at: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// The events made by `bytes`, all received at uptime `now`.
+    fn receive(status: &mut MouseStatus, bytes: &[u8], now: u64) -> Vec<MouseEvent> {
+        for &byte in bytes {
+            status.receive(byte, now);
+        }
+        status.events.drain(..).collect()
+    }
+
+    #[test]
+    fn standard_packets() {
+        let mut status = status(MouseKind::Standard);
+        assert_eq!(
+            receive(&mut status, &[0x09, 10, 5], 0),
+            [
+                MouseEvent::Move {
+                    dx: 10,
+                    dy: -5,
+                    x: 330,
+                    y: 235
+                },
+                MouseEvent::ButtonDown(MouseButton::Left),
+            ]
+        );
+        assert!(status.is_pressed(MouseButton::Left));
+
+        let flags = FLAG_ALWAYS_ONE | FLAG_X_SIGN | FLAG_Y_SIGN | 0b010;
+        assert_eq!(
+            receive(&mut status, &[flags, 0xF6, 0xFB], 0),
+            [
+                MouseEvent::Move {
+                    dx: -10,
+                    dy: 5,
+                    x: 320,
+                    y: 240
+                },
+                MouseEvent::ButtonUp(MouseButton::Left),
+                MouseEvent::ButtonDown(MouseButton::Right),
+            ]
+        );
+        assert_eq!(
+            receive(&mut status, &[0x08, 0, 0], 0),
+            [MouseEvent::ButtonUp(MouseButton::Right)]
+        );
+    }
+
+    #[test]
+    fn overflow_moves_as_far_as_possible() {
+        assert_eq!(delta(17, 0, FLAG_X_OVERFLOW), 255);
+        assert_eq!(delta(17, FLAG_X_SIGN, FLAG_X_OVERFLOW), -256);
+        assert_eq!(delta(0x80, FLAG_X_SIGN, 0), -128);
+
+        // and the cursor stops at the edges of the screen
+        let mut status = status(MouseKind::Standard);
+        let flags = FLAG_ALWAYS_ONE | FLAG_X_OVERFLOW | FLAG_Y_SIGN | FLAG_Y_OVERFLOW;
+        assert_eq!(
+            receive(&mut status, &[flags, 0, 0, flags, 0, 0], 0),
+            [
+                MouseEvent::Move {
+                    dx: 255,
+                    dy: 256,
+                    x: 575,
+                    y: 479
+                },
+                MouseEvent::Move {
+                    dx: 255,
+                    dy: 256,
+                    x: 639,
+                    y: 479
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn wheel_packets() {
+        let mut status = status(MouseKind::Wheel);
+        assert_eq!(
+            receive(&mut status, &[0x08, 0, 0, 0xFF], 0),
+            [MouseEvent::Scroll(-1)]
+        );
+        assert_eq!(
+            receive(&mut status, &[0x08, 0, 0, 2], 0),
+            [MouseEvent::Scroll(2)]
+        );
+        assert!(receive(&mut status, &[0x08, 0, 0, 0], 0).is_empty());
+    }
+
+    #[test]
+    fn five_button_packets() {
+        let mut status = status(MouseKind::FiveButtons);
+        assert_eq!(
+            receive(&mut status, &[0x08, 0, 0, EXTRA_BACK | 0xF], 0),
+            [
+                MouseEvent::ButtonDown(MouseButton::Back),
+                MouseEvent::Scroll(-1),
+            ]
+        );
+        assert_eq!(
+            receive(&mut status, &[0x08, 0, 0, EXTRA_FORWARD | 7], 0),
+            [
+                MouseEvent::ButtonUp(MouseButton::Back),
+                MouseEvent::ButtonDown(MouseButton::Forward),
+                MouseEvent::Scroll(7),
+            ]
+        );
+        // a fourth byte that cannot be one is dropped with its packet
+        assert!(receive(&mut status, &[0x09, 1, 1, 0x80], 0).is_empty());
+        assert!(!status.is_pressed(MouseButton::Left));
+    }
+
+    #[test]
+    fn resync_on_a_byte_that_cannot_start_a_packet() {
+        let mut status = status(MouseKind::Standard);
+        assert_eq!(
+            receive(&mut status, &[0x00, 0x37, 0x08, 1, 0], 0),
+            [MouseEvent::Move {
+                dx: 1,
+                dy: 0,
+                x: 321,
+                y: 240
+            }]
+        );
+    }
+
+    #[test]
+    fn resync_after_a_gap() {
+        let mut status = status(MouseKind::Standard);
+        assert!(receive(&mut status, &[0x09, 4], 0).is_empty());
+        // the rest of the packet never came
+        assert_eq!(
+            receive(&mut status, &[0x08, 3, 0], PACKET_GAP_MS + 1),
+            [MouseEvent::Move {
+                dx: 3,
+                dy: 0,
+                x: 323,
+                y: 240
+            }]
+        );
+        assert!(!status.is_pressed(MouseButton::Left));
+    }
+}
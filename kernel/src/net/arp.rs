@@ -0,0 +1,208 @@
+//! ARP: finding the Ethernet address of a host on the same network.
+//!
+//! Packets for a host whose address is not known yet wait in its cache
+//! entry until it answers, or are dropped if it never does.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use super::{
+    ethernet::{self, TYPE_ARP, TYPE_IPV4},
+    ipv4::{self, Ipv4Address},
+    MacAddress, NetError,
+};
+use crate::{sync::Mutex, time};
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_LEN: usize = 28;
+
+/// How long a resolved address is trusted.
+const ENTRY_TTL_MS: u64 = 5 * 60 * 1000;
+const RETRY_MS: u64 = 1000;
+const MAX_REQUESTS: u32 = 3;
+/// Most packets kept per host while its address is being resolved.
+const MAX_PENDING: usize = 16;
+
+struct Entry {
+    /// `None` while resolving.
+    mac: Option<MacAddress>,
+    interface: String,
+    /// When the address was resolved or last asked for.
+    updated: u64,
+    requests: u32,
+    pending: Vec<Vec<u8>>,
+}
+
+static CACHE: Mutex<BTreeMap<Ipv4Address, Entry>> = Mutex::new(BTreeMap::new());
+
+pub fn lookup(address: Ipv4Address) -> Option<MacAddress> {
+    CACHE.lock().get(&address)?.mac
+}
+
+/// The resolved addresses, with the interface each was found on.
+pub fn entries() -> Vec<(Ipv4Address, MacAddress, String)> {
+    CACHE
+        .lock()
+        .iter()
+        .filter_map(|(address, entry)| Some((*address, entry.mac?, entry.interface.clone())))
+        .collect()
+}
+
+fn encode(
+    op: u16,
+    sender: (MacAddress, Ipv4Address),
+    target: (MacAddress, Ipv4Address),
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_LEN);
+    packet.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&TYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&sender.0 .0);
+    packet.extend_from_slice(&sender.1 .0);
+    packet.extend_from_slice(&target.0 .0);
+    packet.extend_from_slice(&target.1 .0);
+    packet
+}
+
+fn request(interface: &str, target: Ipv4Address) -> Result<(), NetError> {
+    let device = super::get(interface).ok_or(NetError::Unreachable)?;
+    let source =
+        ipv4::interface_config(interface).map_or(Ipv4Address::UNSPECIFIED, |config| config.address);
+    let request = encode(
+        OP_REQUEST,
+        (device.mac(), source),
+        (MacAddress::default(), target),
+    );
+    ethernet::send(&*device, MacAddress::BROADCAST, TYPE_ARP, &request)
+}
+
+/// Sends an IPv4 packet to `next_hop` on `interface`, first asking for its
+/// address if it is not known.
+pub(super) fn send(
+    interface: &str,
+    next_hop: Ipv4Address,
+    packet: Vec<u8>,
+) -> Result<(), NetError> {
+    let device = super::get(interface).ok_or(NetError::Unreachable)?;
+    let mut cache = CACHE.lock();
+    match cache.get_mut(&next_hop) {
+        Some(Entry { mac: Some(mac), .. }) => {
+            let mac = *mac;
+            drop(cache);
+            ethernet::send(&*device, mac, TYPE_IPV4, &packet)
+        }
+        Some(entry) => {
+            if entry.pending.len() < MAX_PENDING {
+                entry.pending.push(packet);
+            }
+            Ok(())
+        }
+        None => {
+            cache.insert(
+                next_hop,
+                Entry {
+                    mac: None,
+                    interface: interface.into(),
+                    updated: time::uptime_ms(),
+                    requests: 1,
+                    pending: alloc::vec![packet],
+                },
+            );
+            drop(cache);
+            request(interface, next_hop)
+        }
+    }
+}
+
+/// Sends an IPv4 packet to every host on `interface`.
+pub(super) fn send_broadcast(interface: &str, packet: &[u8]) -> Result<(), NetError> {
+    let device = super::get(interface).ok_or(NetError::Unreachable)?;
+    ethernet::send(&*device, MacAddress::BROADCAST, TYPE_IPV4, packet)
+}
+
+/// Takes an ARP packet that arrived on `interface`.
+pub(super) fn handle(interface: &str, packet: &[u8]) {
+    if packet.len() < PACKET_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != HARDWARE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != TYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender = Ipv4Address(packet[14..18].try_into().unwrap());
+    let target = Ipv4Address(packet[24..28].try_into().unwrap());
+    let Some(config) = ipv4::interface_config(interface) else {
+        return;
+    };
+    let Some(device) = super::get(interface) else {
+        return;
+    };
+    if sender.is_unspecified() {
+        return;
+    }
+
+    // remember the sender if it asks for us or is already known, and
+    // send what waited for it
+    let for_us = target == config.address;
+    let pending = {
+        let mut cache = CACHE.lock();
+        if for_us || cache.contains_key(&sender) {
+            let entry = cache.entry(sender).or_insert_with(|| Entry {
+                mac: None,
+                interface: interface.into(),
+                updated: 0,
+                requests: 0,
+                pending: Vec::new(),
+            });
+            entry.mac = Some(sender_mac);
+            entry.interface = interface.into();
+            entry.updated = time::uptime_ms();
+            entry.requests = 0;
+            core::mem::take(&mut entry.pending)
+        } else {
+            Vec::new()
+        }
+    };
+    for packet in pending {
+        let _ = ethernet::send(&*device, sender_mac, TYPE_IPV4, &packet);
+    }
+
+    if op == OP_REQUEST && for_us {
+        let reply = encode(
+            OP_REPLY,
+            (device.mac(), config.address),
+            (sender_mac, sender),
+        );
+        let _ = ethernet::send(&*device, sender_mac, TYPE_ARP, &reply);
+    }
+}
+
+/// Forgets stale addresses, asks again for unresolved ones and gives up on
+/// those that never answered. Called by the network thread.
+pub(super) fn tick() {
+    let now = time::uptime_ms();
+    let mut retries = Vec::new();
+    CACHE.lock().retain(|address, entry| {
+        if entry.mac.is_some() {
+            return now - entry.updated < ENTRY_TTL_MS;
+        }
+        if now - entry.updated < RETRY_MS {
+            return true;
+        }
+        if entry.requests >= MAX_REQUESTS {
+            return false;
+        }
+        entry.requests += 1;
+        entry.updated = now;
+        retries.push((entry.interface.clone(), *address));
+        true
+    });
+    for (interface, address) in retries {
+        let _ = request(&interface, address);
+    }
+}
@@ -0,0 +1,569 @@
+//! DHCP client: gets the address, router and DNS servers of an interface
+//! from the network, and renews the lease before it runs out.
+//!
+//! Every interface has a thread of its own going through discovery, then
+//! renewing at half the lease time and rebinding at seven eighths, and
+//! starting over if the lease expires anyway.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{dns, ipv4, udp::UdpSocket, Ipv4Address, MacAddress, NetError};
+use crate::{
+    colors, log_info, log_warn,
+    sync::Mutex,
+    thread::{self, Priority},
+    time,
+};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies, as we cannot take unicast
+/// packets before we have an address.
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Length of the fixed part of a message, up to the magic cookie.
+const FIXED_LEN: usize = 236;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+/// How long to wait for each reply, doubled on every retry.
+const REPLY_TIMEOUT_MS: u64 = 1000;
+const MAX_ATTEMPTS: u32 = 4;
+/// Longest pause between two failed discoveries.
+const MAX_RETRY_MS: u64 = 64_000;
+/// Longest pause between two failed renewals.
+const MAX_RENEW_RETRY_MS: u64 = 60_000;
+
+/// Held while a client has the client port bound, so that the threads of
+/// several interfaces take turns.
+static CLIENT: Mutex<()> = Mutex::new(());
+static NEXT_XID: AtomicU32 = AtomicU32::new(0x4D46_0000);
+
+/// What a server handed out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    pub server: Ipv4Address,
+    /// Uptime when the lease was granted.
+    pub obtained: u64,
+    pub lease_ms: u64,
+    pub renew_ms: u64,
+    pub rebind_ms: u64,
+}
+
+/// A message received from a server.
+struct Reply {
+    kind: u8,
+    your_address: Ipv4Address,
+    server: Option<Ipv4Address>,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+    lease_secs: Option<u32>,
+    renew_secs: Option<u32>,
+    rebind_secs: Option<u32>,
+}
+
+fn message(
+    kind: u8,
+    xid: u32,
+    mac: MacAddress,
+    client: Ipv4Address,
+    options: &[(u8, &[u8])],
+) -> Vec<u8> {
+    let mut message = alloc::vec![0; FIXED_LEN];
+    message[0] = OP_REQUEST;
+    message[1] = HARDWARE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    if client.is_unspecified() {
+        message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    }
+    message[12..16].copy_from_slice(&client.0);
+    message[28..34].copy_from_slice(&mac.0);
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+    for (code, value) in options {
+        message.extend_from_slice(&[*code, value.len() as u8]);
+        message.extend_from_slice(value);
+    }
+    let parameters = [
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_LEASE_TIME,
+        OPTION_RENEWAL_TIME,
+        OPTION_REBINDING_TIME,
+    ];
+    message.extend_from_slice(&[OPTION_PARAMETERS, parameters.len() as u8]);
+    message.extend_from_slice(&parameters);
+    message.push(OPTION_END);
+    message
+}
+
+/// Parses a reply to transaction `xid` of the client with address `mac`.
+fn parse(message: &[u8], xid: u32, mac: MacAddress) -> Option<Reply> {
+    if message.len() < FIXED_LEN + MAGIC_COOKIE.len()
+        || message[0] != OP_REPLY
+        || message[4..8] != xid.to_be_bytes()
+        || message[28..34] != mac.0
+        || message[FIXED_LEN..FIXED_LEN + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut reply = Reply {
+        kind: 0,
+        your_address: Ipv4Address(message[16..20].try_into().unwrap()),
+        server: None,
+        subnet_mask: None,
+        router: None,
+        dns_servers: Vec::new(),
+        lease_secs: None,
+        renew_secs: None,
+        rebind_secs: None,
+    };
+    let address = |value: &[u8]| Some(Ipv4Address(value.get(..4)?.try_into().unwrap()));
+    let seconds = |value: &[u8]| Some(u32::from_be_bytes(value.get(..4)?.try_into().unwrap()));
+
+    let mut options = &message[FIXED_LEN + 4..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let len = *rest.first()? as usize;
+        let value = rest.get(1..1 + len)?;
+        match *code {
+            OPTION_MESSAGE_TYPE => reply.kind = *value.first()?,
+            OPTION_SUBNET_MASK => reply.subnet_mask = address(value),
+            OPTION_ROUTER => reply.router = address(value),
+            OPTION_DNS => {
+                reply.dns_servers = value
+                    .chunks_exact(4)
+                    .map(|chunk| Ipv4Address(chunk.try_into().unwrap()))
+                    .collect()
+            }
+            OPTION_SERVER_ID => reply.server = address(value),
+            OPTION_LEASE_TIME => reply.lease_secs = seconds(value),
+            OPTION_RENEWAL_TIME => reply.renew_secs = seconds(value),
+            OPTION_REBINDING_TIME => reply.rebind_secs = seconds(value),
+            _ => {}
+        }
+        options = &rest[1 + len..];
+    }
+    Some(reply)
+}
+
+impl Lease {
+    fn from_reply(reply: &Reply, server: Ipv4Address) -> Self {
+        // a lease without a time, or with all ones, is infinite
+        let lease_ms = reply
+            .lease_secs
+            .filter(|secs| *secs != u32::MAX)
+            .map_or(u64::MAX / 4, |secs| secs as u64 * 1000);
+        let prefix_len = match reply.subnet_mask {
+            Some(mask) => mask.to_u32().leading_ones() as u8,
+            // the classful default
+            None => match reply.your_address.0[0] {
+                0..=127 => 8,
+                128..=191 => 16,
+                _ => 24,
+            },
+        };
+        Lease {
+            address: reply.your_address,
+            prefix_len,
+            router: reply.router,
+            dns_servers: reply.dns_servers.clone(),
+            server,
+            obtained: time::uptime_ms(),
+            lease_ms,
+            renew_ms: reply
+                .renew_secs
+                .map_or(lease_ms / 2, |secs| secs as u64 * 1000),
+            rebind_ms: reply
+                .rebind_secs
+                .map_or(lease_ms / 8 * 7, |secs| secs as u64 * 1000),
+        }
+    }
+}
+
+/// Sends `request` and waits for a reply of one of `kinds`, sending it
+/// again a few times if none comes. The request goes to `server` if it is
+/// given and is broadcast on `interface` otherwise.
+fn exchange(
+    interface: &str,
+    mac: MacAddress,
+    xid: u32,
+    request: &[u8],
+    server: Option<Ipv4Address>,
+    kinds: &[u8],
+) -> Result<Reply, NetError> {
+    let _client = CLIENT.lock();
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let source =
+        ipv4::interface_config(interface).map_or(Ipv4Address::UNSPECIFIED, |config| config.address);
+    let mut timeout = REPLY_TIMEOUT_MS;
+    for _ in 0..MAX_ATTEMPTS {
+        match server {
+            Some(server) => socket.send_to(request, server, SERVER_PORT)?,
+            None => socket.broadcast(interface, source, request, SERVER_PORT)?,
+        }
+        let deadline = time::uptime_ms() + timeout;
+        loop {
+            let left = deadline.saturating_sub(time::uptime_ms());
+            if left == 0 {
+                break;
+            }
+            let Ok(datagram) = socket.recv(Some(left)) else {
+                break;
+            };
+            if datagram.port != SERVER_PORT {
+                continue;
+            }
+            match parse(&datagram.data, xid, mac) {
+                Some(reply) if kinds.contains(&reply.kind) => return Ok(reply),
+                _ => {}
+            }
+        }
+        timeout *= 2;
+    }
+    Err(NetError::TimedOut)
+}
+
+fn next_xid() -> u32 {
+    NEXT_XID.fetch_add(1, Ordering::Relaxed) ^ time::uptime_ms() as u32
+}
+
+/// Asks for the address of `offer` and returns the lease if the server
+/// grants it.
+fn request(interface: &str, mac: MacAddress, xid: u32, offer: &Reply) -> Result<Lease, NetError> {
+    let server = offer.server.ok_or(NetError::InvalidArgument)?;
+    let request = message(
+        REQUEST,
+        xid,
+        mac,
+        Ipv4Address::UNSPECIFIED,
+        &[
+            (OPTION_REQUESTED_ADDRESS, &offer.your_address.0),
+            (OPTION_SERVER_ID, &server.0),
+        ],
+    );
+    let reply = exchange(interface, mac, xid, &request, None, &[ACK, NAK])?;
+    match reply.kind {
+        ACK => Ok(Lease::from_reply(&reply, server)),
+        _ => Err(NetError::ConnectionRefused),
+    }
+}
+
+/// Goes through discovery on `interface`: broadcasts for an offer and
+/// requests the address offered.
+pub fn discover(interface: &str) -> Result<Lease, NetError> {
+    let mac = super::get(interface).ok_or(NetError::Unreachable)?.mac();
+    let xid = next_xid();
+    let discover = message(DISCOVER, xid, mac, Ipv4Address::UNSPECIFIED, &[]);
+    let offer = exchange(interface, mac, xid, &discover, None, &[OFFER])?;
+    request(interface, mac, xid, &offer)
+}
+
+/// Asks to extend `lease`, from its server while `rebind` is false and
+/// from any server otherwise.
+fn renew(interface: &str, lease: &Lease, rebind: bool) -> Result<Lease, NetError> {
+    let mac = super::get(interface).ok_or(NetError::Unreachable)?.mac();
+    let xid = next_xid();
+    let request = message(REQUEST, xid, mac, lease.address, &[]);
+    let server = (!rebind).then_some(lease.server);
+    let reply = exchange(interface, mac, xid, &request, server, &[ACK, NAK])?;
+    match reply.kind {
+        ACK => Ok(Lease::from_reply(
+            &reply,
+            reply.server.unwrap_or(lease.server),
+        )),
+        _ => Err(NetError::ConnectionRefused),
+    }
+}
+
+/// Configures `interface` as `lease` says.
+fn apply(interface: &str, lease: &Lease) {
+    ipv4::configure(interface, lease.address, lease.prefix_len);
+    if let Some(router) = lease.router {
+        // the first interface with a router keeps the default route
+        let taken = ipv4::routes()
+            .iter()
+            .any(|route| route.prefix_len == 0 && route.interface != interface);
+        if !taken {
+            ipv4::set_default_gateway(router, interface);
+        }
+    }
+    dns::add_servers(&lease.dns_servers);
+}
+
+/// Takes the address of `interface` away when its lease runs out.
+fn expire(interface: &str, lease: &Lease) {
+    ipv4::unconfigure(interface);
+    dns::remove_servers(&lease.dns_servers);
+    log_warn!("{}: lease of {} expired", interface, lease.address);
+}
+
+fn log_lease(interface: &str, lease: &Lease) {
+    let dns: Vec<String> = lease.dns_servers.iter().map(ToString::to_string).collect();
+    let dns = dns.join(", ");
+    match lease.router {
+        Some(router) => {
+            log_info!(
+                "{}: {}/{} via {}, DNS {} (lease {} s)",
+                interface,
+                lease.address,
+                lease.prefix_len,
+                router,
+                dns,
+                lease.lease_ms / 1000
+            );
+        }
+        None => {
+            log_info!(
+                "{}: {}/{}, DNS {} (lease {} s)",
+                interface,
+                lease.address,
+                lease.prefix_len,
+                dns,
+                lease.lease_ms / 1000
+            );
+        }
+    }
+}
+
+/// Keeps `interface` configured for good.
+fn run(interface: &str) -> ! {
+    let mut retry = REPLY_TIMEOUT_MS;
+    loop {
+        let mut lease = match discover(interface) {
+            Ok(lease) => lease,
+            Err(err) => {
+                if retry == REPLY_TIMEOUT_MS {
+                    log_warn!(
+                        "{}: no DHCP server answered ({:?}), retrying",
+                        interface,
+                        err
+                    );
+                }
+                thread::sleep(retry);
+                retry = (retry * 2).min(MAX_RETRY_MS);
+                continue;
+            }
+        };
+        retry = REPLY_TIMEOUT_MS;
+        apply(interface, &lease);
+        log_lease(interface, &lease);
+
+        loop {
+            let elapsed = time::uptime_ms() - lease.obtained;
+            if elapsed < lease.renew_ms {
+                thread::sleep(lease.renew_ms - elapsed);
+                continue;
+            }
+            if elapsed >= lease.lease_ms {
+                expire(interface, &lease);
+                break;
+            }
+            match renew(interface, &lease, elapsed >= lease.rebind_ms) {
+                Ok(renewed) => {
+                    let changed = renewed.address != lease.address
+                        || renewed.prefix_len != lease.prefix_len
+                        || renewed.router != lease.router
+                        || renewed.dns_servers != lease.dns_servers;
+                    if changed {
+                        dns::remove_servers(&lease.dns_servers);
+                        apply(interface, &renewed);
+                        log_lease(interface, &renewed);
+                    }
+                    lease = renewed;
+                }
+                Err(NetError::ConnectionRefused) => {
+                    // the server took the address back
+                    expire(interface, &lease);
+                    break;
+                }
+                Err(_) => {
+                    let left = lease.lease_ms - elapsed;
+                    thread::sleep((left / 2).clamp(REPLY_TIMEOUT_MS, MAX_RENEW_RETRY_MS));
+                }
+            }
+        }
+    }
+}
+
+/// Starts the DHCP client of `interface`.
+pub fn start(interface: String) {
+    thread::spawn_with("dhcp", Priority::Normal, move || run(&interface));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XID: u32 = 0x1234_5678;
+    const MAC: MacAddress = MacAddress([2, 0, 0, 0, 0, 1]);
+    const SERVER: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+
+    /// A reply to `XID` for `MAC` handing out 10.0.2.15, with `options`.
+    fn reply(options: &[u8]) -> Vec<u8> {
+        let mut reply = alloc::vec![0; FIXED_LEN];
+        reply[0] = OP_REPLY;
+        reply[4..8].copy_from_slice(&XID.to_be_bytes());
+        reply[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        reply[28..34].copy_from_slice(&MAC.0);
+        reply.extend_from_slice(&MAGIC_COOKIE);
+        reply.extend_from_slice(options);
+        reply
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = [
+            &[OPTION_PAD, OPTION_MESSAGE_TYPE, 1, ACK][..],
+            &[OPTION_SERVER_ID, 4, 10, 0, 2, 2],
+            &[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0],
+            &[OPTION_ROUTER, 8, 10, 0, 2, 2, 10, 0, 2, 1],
+            &[OPTION_DNS, 8, 10, 0, 2, 3, 1, 1, 1, 1],
+            &[OPTION_LEASE_TIME, 4, 0, 0, 0x0E, 0x10],
+            &[OPTION_PAD, OPTION_PAD, 120, 2, 0, 0],
+            // nothing after the end counts
+            &[OPTION_END, OPTION_ROUTER, 4, 1, 2, 3, 4],
+        ]
+        .concat();
+        let reply = parse(&reply(&options), XID, MAC).unwrap();
+        assert_eq!(reply.kind, ACK);
+        assert_eq!(reply.your_address, Ipv4Address::new(10, 0, 2, 15));
+        assert_eq!(reply.server, Some(SERVER));
+        assert_eq!(reply.subnet_mask, Some(Ipv4Address::new(255, 255, 255, 0)));
+        assert_eq!(reply.router, Some(SERVER));
+        assert_eq!(
+            reply.dns_servers,
+            [Ipv4Address::new(10, 0, 2, 3), Ipv4Address::new(1, 1, 1, 1)]
+        );
+        assert_eq!(reply.lease_secs, Some(3600));
+        assert_eq!(reply.renew_secs, None);
+    }
+
+    #[test]
+    fn parse_ignores_other_messages() {
+        let options = [OPTION_MESSAGE_TYPE, 1, OFFER, OPTION_END];
+        assert!(parse(&reply(&options), XID + 1, MAC).is_none());
+        assert!(parse(&reply(&options), XID, MacAddress([2, 0, 0, 0, 0, 2])).is_none());
+
+        let mut request = reply(&options);
+        request[0] = OP_REQUEST;
+        assert!(parse(&request, XID, MAC).is_none());
+
+        let mut no_cookie = reply(&options);
+        no_cookie[FIXED_LEN] = 0;
+        assert!(parse(&no_cookie, XID, MAC).is_none());
+        assert!(parse(&reply(&[])[..FIXED_LEN], XID, MAC).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_truncated_options() {
+        assert!(parse(&reply(&[OPTION_LEASE_TIME, 4, 0, 0]), XID, MAC).is_none());
+        assert!(parse(&reply(&[OPTION_ROUTER]), XID, MAC).is_none());
+        // short values are dropped, but the message is fine
+        let reply = parse(&reply(&[OPTION_ROUTER, 2, 10, 0]), XID, MAC).unwrap();
+        assert_eq!(reply.router, None);
+    }
+
+    #[test]
+    fn message_asks_for_a_broadcast_reply_without_an_address() {
+        let requested = [10, 0, 2, 15];
+        let request = message(
+            REQUEST,
+            XID,
+            MAC,
+            Ipv4Address::UNSPECIFIED,
+            &[(OPTION_REQUESTED_ADDRESS, &requested)],
+        );
+        assert_eq!(request[0], OP_REQUEST);
+        assert_eq!(request[4..8], XID.to_be_bytes());
+        assert_eq!(request[10..12], FLAG_BROADCAST.to_be_bytes());
+        assert_eq!(request[28..34], MAC.0);
+        assert_eq!(request[FIXED_LEN..FIXED_LEN + 4], MAGIC_COOKIE);
+        assert_eq!(
+            request[FIXED_LEN + 4..FIXED_LEN + 7],
+            [OPTION_MESSAGE_TYPE, 1, REQUEST]
+        );
+        assert_eq!(
+            request[FIXED_LEN + 7..FIXED_LEN + 13],
+            [OPTION_REQUESTED_ADDRESS, 4, 10, 0, 2, 15]
+        );
+        assert_eq!(request.last(), Some(&OPTION_END));
+
+        let renewal = message(REQUEST, XID, MAC, Ipv4Address::new(10, 0, 2, 15), &[]);
+        assert_eq!(renewal[10..12], [0, 0]);
+        assert_eq!(renewal[12..16], [10, 0, 2, 15]);
+    }
+
+    #[test]
+    fn lease_times() {
+        let mut reply = parse(&reply(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0E, 0x10]), XID, MAC).unwrap();
+        let lease = Lease::from_reply(&reply, SERVER);
+        assert_eq!(lease.lease_ms, 3_600_000);
+        assert_eq!(lease.renew_ms, 1_800_000);
+        assert_eq!(lease.rebind_ms, 3_150_000);
+
+        reply.renew_secs = Some(600);
+        reply.rebind_secs = Some(1200);
+        let lease = Lease::from_reply(&reply, SERVER);
+        assert_eq!((lease.renew_ms, lease.rebind_ms), (600_000, 1_200_000));
+
+        // no lease time, or all ones, never runs out
+        reply.lease_secs = Some(u32::MAX);
+        assert_eq!(Lease::from_reply(&reply, SERVER).lease_ms, u64::MAX / 4);
+        reply.lease_secs = None;
+        assert_eq!(Lease::from_reply(&reply, SERVER).lease_ms, u64::MAX / 4);
+    }
+
+    #[test]
+    fn lease_prefix() {
+        let mut reply =
+            parse(&reply(&[OPTION_SUBNET_MASK, 4, 255, 255, 240, 0]), XID, MAC).unwrap();
+        assert_eq!(Lease::from_reply(&reply, SERVER).prefix_len, 20);
+
+        // without a mask the class of the address decides
+        reply.subnet_mask = None;
+        for (first, prefix_len) in [(10, 8), (172, 16), (192, 24)] {
+            reply.your_address = Ipv4Address::new(first, 0, 2, 15);
+            assert_eq!(Lease::from_reply(&reply, SERVER).prefix_len, prefix_len);
+        }
+    }
+}
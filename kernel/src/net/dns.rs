@@ -0,0 +1,402 @@
+//! DNS stub resolver: asks the servers learned through DHCP for the
+//! addresses of host names, and remembers the answers for as long as the
+//! servers say they are valid. Names that do not exist are remembered for a
+//! while too.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{udp::UdpSocket, Ipv4Address, NetError};
+use crate::{sync::Mutex, time};
+
+const PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// How long to wait for each server to answer.
+const QUERY_TIMEOUT_MS: u64 = 2000;
+/// Rounds through all servers before giving up.
+const ATTEMPTS: u32 = 2;
+/// How long a name that does not exist is remembered.
+const NEGATIVE_TTL_MS: u64 = 60_000;
+/// Longest any answer is remembered, whatever the server says.
+const MAX_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+const MAX_ENTRIES: usize = 256;
+
+static SERVERS: spin::Mutex<Vec<Ipv4Address>> = spin::Mutex::new(Vec::new());
+static CACHE: Mutex<Cache> = Mutex::new(Cache::new());
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+struct Entry {
+    /// Empty if the name does not exist.
+    addresses: Vec<Ipv4Address>,
+    /// Uptime at which the entry is forgotten.
+    expires: u64,
+}
+
+/// Answers by name. The uptime is handed in rather than read here.
+struct Cache {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Cache {
+    const fn new() -> Self {
+        Cache {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, name: &str, now: u64) -> Option<&Entry> {
+        self.entries.get(name).filter(|entry| entry.expires > now)
+    }
+
+    fn insert(&mut self, name: String, addresses: Vec<Ipv4Address>, ttl_ms: u64, now: u64) {
+        self.entries.retain(|_, entry| entry.expires > now);
+        if self.entries.len() >= MAX_ENTRIES {
+            // make room by forgetting what would expire first
+            let soonest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(name, _)| name.clone());
+            if let Some(soonest) = soonest {
+                self.entries.remove(&soonest);
+            }
+        }
+        let expires = now + ttl_ms.min(MAX_TTL_MS);
+        self.entries.insert(name, Entry { addresses, expires });
+    }
+
+    /// The entries still valid, with how many milliseconds they have left.
+    fn valid(&self, now: u64) -> impl Iterator<Item = (&String, &Entry, u64)> {
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| entry.expires > now)
+            .map(move |(name, entry)| (name, entry, entry.expires - now))
+    }
+}
+
+/// Adds servers to ask, after those already known.
+pub fn add_servers(servers: &[Ipv4Address]) {
+    let mut known = SERVERS.lock();
+    for server in servers {
+        if !known.contains(server) {
+            known.push(*server);
+        }
+    }
+}
+
+pub fn remove_servers(servers: &[Ipv4Address]) {
+    SERVERS.lock().retain(|server| !servers.contains(server));
+}
+
+/// The servers asked, in the order they are tried.
+pub fn servers() -> Vec<Ipv4Address> {
+    SERVERS.lock().clone()
+}
+
+/// Forgets every answer.
+pub fn flush() {
+    CACHE.lock().entries.clear();
+}
+
+/// The first address of `name`. See [`lookup`].
+pub fn resolve(name: &str) -> Result<Ipv4Address, NetError> {
+    Ok(lookup(name)?[0])
+}
+
+/// All addresses of `name`, which may also be an address in dotted
+/// notation. Fails with [`NetError::NotFound`] if the name does not exist
+/// or has no address.
+pub fn lookup(name: &str) -> Result<Vec<Ipv4Address>, NetError> {
+    if let Ok(address) = name.parse() {
+        return Ok(alloc::vec![address]);
+    }
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .split('.')
+            .all(|label| (1..=MAX_LABEL_LEN).contains(&label.len()));
+    if !valid {
+        return Err(NetError::InvalidArgument);
+    }
+
+    if let Some(entry) = CACHE.lock().get(&name, time::uptime_ms()) {
+        return if entry.addresses.is_empty() {
+            Err(NetError::NotFound)
+        } else {
+            Ok(entry.addresses.clone())
+        };
+    }
+
+    let (addresses, ttl_ms) = match query(&name) {
+        Ok(answer) => answer,
+        Err(NetError::NotFound) => (Vec::new(), NEGATIVE_TTL_MS),
+        Err(err) => return Err(err),
+    };
+    CACHE
+        .lock()
+        .insert(name, addresses.clone(), ttl_ms, time::uptime_ms());
+    if addresses.is_empty() {
+        Err(NetError::NotFound)
+    } else {
+        Ok(addresses)
+    }
+}
+
+/// Asks the servers for the addresses of `name` in turn until one answers.
+/// Returns the addresses and how long they are valid.
+fn query(name: &str) -> Result<(Vec<Ipv4Address>, u64), NetError> {
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(NetError::Unreachable);
+    }
+    let socket = UdpSocket::bind(0)?;
+    let mut result = Err(NetError::TimedOut);
+    for _ in 0..ATTEMPTS {
+        for server in &servers {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) ^ time::uptime_ms() as u16;
+            if let Err(err) = socket.send_to(&encode(id, name), *server, PORT) {
+                result = Err(err);
+                continue;
+            }
+            let deadline = time::uptime_ms() + QUERY_TIMEOUT_MS;
+            loop {
+                let left = deadline.saturating_sub(time::uptime_ms());
+                if left == 0 {
+                    break;
+                }
+                let Ok(datagram) = socket.recv(Some(left)) else {
+                    break;
+                };
+                if datagram.source != *server || datagram.port != PORT {
+                    continue;
+                }
+                match parse(&datagram.data, id) {
+                    None => continue,
+                    Some(Err(NetError::Io)) => {
+                        // the server failed; another may do better
+                        result = Err(NetError::Io);
+                        break;
+                    }
+                    Some(answer) => return answer,
+                }
+            }
+        }
+    }
+    result
+}
+
+fn encode(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answers or other records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+/// The position after the name starting at `pos`, which may end in a
+/// pointer to another name.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+/// Parses the response to query `id`: its addresses and how long they are
+/// valid, [`NetError::NotFound`] if the name does not exist or [`NetError::Io`]
+/// if the server failed. Returns `None` for anything else.
+fn parse(message: &[u8], id: u16) -> Option<Result<(Vec<Ipv4Address>, u64), NetError>> {
+    let word = |pos: usize| {
+        Some(u16::from_be_bytes([
+            *message.get(pos)?,
+            *message.get(pos + 1)?,
+        ]))
+    };
+    if message.len() < HEADER_LEN || word(0)? != id {
+        return None;
+    }
+    let flags = word(2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err(NetError::NotFound)),
+        _ => return Some(Err(NetError::Io)),
+    }
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..word(4)? {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl_secs = u32::MAX;
+    // the answers may go through aliases first; all addresses found are
+    // those of the name asked for
+    for _ in 0..word(6)? {
+        pos = skip_name(message, pos)?;
+        let kind = word(pos)?;
+        let class = word(pos + 2)?;
+        let ttl = u32::from_be_bytes(message.get(pos + 4..pos + 8)?.try_into().unwrap());
+        let len = word(pos + 8)? as usize;
+        let data = message.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        ttl_secs = ttl_secs.min(ttl);
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            addresses.push(Ipv4Address(data.try_into().unwrap()));
+        }
+    }
+    if addresses.is_empty() {
+        return Some(Err(NetError::NotFound));
+    }
+    Some(Ok((addresses, ttl_secs as u64 * 1000)))
+}
+
+/// The names remembered with their addresses, if they exist, and how many
+/// milliseconds they are still valid.
+pub fn cached() -> Vec<(String, Vec<Ipv4Address>, u64)> {
+    CACHE
+        .lock()
+        .valid(time::uptime_ms())
+        .map(|(name, entry, left)| (name.clone(), entry.addresses.clone(), left))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::ToString};
+
+    use super::*;
+
+    const ADDRESS: Ipv4Address = Ipv4Address::new(93, 184, 216, 34);
+
+    #[test]
+    fn cache_entries_expire() {
+        let mut cache = Cache::new();
+        cache.insert(
+            "example.com".to_string(),
+            alloc::vec![ADDRESS],
+            300_000,
+            1000,
+        );
+        cache.insert("nope.test".to_string(), Vec::new(), NEGATIVE_TTL_MS, 1000);
+
+        let entry = cache.get("example.com", 1000).unwrap();
+        assert_eq!(entry.addresses, [ADDRESS]);
+        assert!(cache.get("example.com", 300_999).is_some());
+        assert!(cache.get("example.com", 301_000).is_none());
+        assert!(cache.get("nope.test", 60_999).unwrap().addresses.is_empty());
+        assert!(cache.get("nope.test", 61_000).is_none());
+        assert!(cache.get("other.test", 1000).is_none());
+
+        let valid: Vec<_> = cache
+            .valid(100_000)
+            .map(|(name, _, left)| (name.as_str(), left))
+            .collect();
+        assert_eq!(valid, [("example.com", 201_000)]);
+    }
+
+    #[test]
+    fn cache_caps_the_ttl() {
+        let mut cache = Cache::new();
+        cache.insert("example.com".to_string(), alloc::vec![ADDRESS], u64::MAX, 0);
+        assert!(cache.get("example.com", MAX_TTL_MS - 1).is_some());
+        assert!(cache.get("example.com", MAX_TTL_MS).is_none());
+    }
+
+    #[test]
+    fn full_cache_forgets_what_expires_first() {
+        let mut cache = Cache::new();
+        for i in 0..MAX_ENTRIES as u64 {
+            // the fifth name runs out first
+            let ttl_ms = if i == 5 { 1000 } else { 10_000 + i };
+            cache.insert(format!("host{i}.test"), alloc::vec![ADDRESS], ttl_ms, 0);
+        }
+        cache.insert("new.test".to_string(), alloc::vec![ADDRESS], 1000, 0);
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache.get("host5.test", 0).is_none());
+        assert!(cache.get("new.test", 0).is_some());
+
+        // expired entries go before anything else does
+        cache.insert("later.test".to_string(), alloc::vec![ADDRESS], 1000, 10_005);
+        assert_eq!(cache.entries.len(), MAX_ENTRIES - 5);
+    }
+
+    #[test]
+    fn encode_query() {
+        let query = encode(0xBEEF, "example.com");
+        assert_eq!(query[..4], [0xBE, 0xEF, 0x01, 0x00]);
+        assert_eq!(query[4..HEADER_LEN], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            &query[HEADER_LEN..],
+            b"\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+    }
+
+    /// The response to `encode(id, "alias.test")`: an alias for example.com,
+    /// then its address.
+    fn response(id: u16) -> Vec<u8> {
+        let mut response = encode(id, "alias.test");
+        response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        response[7] = 2;
+        response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 100, 0, 13]);
+        response.extend_from_slice(b"\x07example\x03com\x00");
+        response.extend_from_slice(&[0xC0, 40, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4]);
+        response.extend_from_slice(&ADDRESS.0);
+        response
+    }
+
+    #[test]
+    fn parse_answers_through_an_alias() {
+        // the shortest TTL of the chain holds
+        assert_eq!(
+            parse(&response(7), 7),
+            Some(Ok((alloc::vec![ADDRESS], 100_000)))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse(&response(7), 8), None);
+
+        let mut query = response(7);
+        query[2] &= !0x80;
+        assert_eq!(parse(&query, 7), None);
+
+        let mut missing = response(7);
+        missing[3] = 0x83;
+        assert_eq!(parse(&missing, 7), Some(Err(NetError::NotFound)));
+
+        let mut failed = response(7);
+        failed[3] = 0x82;
+        assert_eq!(parse(&failed, 7), Some(Err(NetError::Io)));
+
+        let truncated = response(7);
+        assert_eq!(parse(&truncated[..truncated.len() - 2], 7), None);
+    }
+}
@@ -0,0 +1,386 @@
+//! Intel 8254x gigabit NICs, the e1000 QEMU emulates by default.
+//!
+//! Frames are received into and sent from rings of descriptors in DMA
+//! memory, each descriptor with a frame of its own. The card interrupts
+//! when frames arrive or the link changes; QEMU's models cannot signal
+//! message interrupts, so the shared PCI line is used unless MSI works.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, AtomicBool, Ordering},
+};
+
+use x86_64::VirtAddr;
+
+use super::{MacAddress, NetDevice, NetError};
+use crate::{
+    colors, interrupts, log_info,
+    memory::DmaFrame,
+    pci::{self, DeviceMatch, PciDevice, PciDriver},
+    sync::Mutex,
+    time,
+};
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEVICE_82540EM: u16 = 0x100E;
+const DEVICE_82544GC: u16 = 0x100C;
+const DEVICE_82545EM: u16 = 0x100F;
+
+// registers
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMS: u64 = 0x00D0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+const REG_MTA: u64 = 0x5200;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+const MTA_ENTRIES: u64 = 128;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+
+/// The receive address is valid.
+const RAH_AV: u32 = 1 << 31;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+// interrupt causes
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RECEIVE: u32 = INT_RXDMT0 | INT_RXO | INT_RXT0;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the checksum from received frames.
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Inter-packet gap the manual asks for on copper.
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const DESC_DD: u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS: u8 = 1 << 3;
+
+const RECEIVE_DESCRIPTORS: usize = 64;
+const TRANSMIT_DESCRIPTORS: usize = 64;
+const DESCRIPTOR_SIZE: usize = 16;
+/// Size of the receive buffers, the default the control register selects.
+const BUFFER_SIZE: usize = 2048;
+
+const TIMEOUT_MS: u64 = 100;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches: &[
+        DeviceMatch::Id {
+            vendor: VENDOR_INTEL,
+            device: DEVICE_82540EM,
+        },
+        DeviceMatch::Id {
+            vendor: VENDOR_INTEL,
+            device: DEVICE_82544GC,
+        },
+        DeviceMatch::Id {
+            vendor: VENDOR_INTEL,
+            device: DEVICE_82545EM,
+        },
+    ],
+    probe,
+};
+
+/// A ring of descriptors, the frames they point to and our position in it.
+struct Ring {
+    descriptors: DmaFrame,
+    frames: Vec<DmaFrame>,
+    /// Next descriptor to look at.
+    next: usize,
+}
+
+impl Ring {
+    fn new(count: usize) -> Option<Self> {
+        Some(Ring {
+            descriptors: DmaFrame::new()?,
+            frames: (0..count).map(|_| DmaFrame::new()).collect::<Option<_>>()?,
+            next: 0,
+        })
+    }
+
+    fn descriptor(&mut self, index: usize) -> &mut [u8] {
+        &mut self.descriptors.as_mut_slice()[index * DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE]
+    }
+
+    fn status(&self, index: usize) -> u8 {
+        let status = self.descriptors.virt() + (index * DESCRIPTOR_SIZE + 12) as u64;
+        unsafe { ptr::read_volatile(status.as_ptr()) }
+    }
+}
+
+pub struct E1000 {
+    registers: VirtAddr,
+    mac: MacAddress,
+    link_up: AtomicBool,
+    receive_ring: Mutex<Ring>,
+    transmit_ring: Mutex<Ring>,
+}
+
+impl E1000 {
+    fn read(&self, register: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + register).as_ptr()) }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + register).as_mut_ptr(), value) }
+    }
+
+    fn wait(&self, mut done: impl FnMut() -> bool) -> bool {
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        while !done() {
+            if time::uptime_ms() >= deadline {
+                return false;
+            }
+            spin_loop();
+        }
+        true
+    }
+
+    fn reset(&self) -> Result<(), &'static str> {
+        self.write(REG_IMC, !0);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_RST);
+        if !self.wait(|| self.read(REG_CTRL) & CTRL_RST == 0) {
+            return Err("card does not come out of reset");
+        }
+        self.write(REG_IMC, !0);
+        self.read(REG_ICR);
+        Ok(())
+    }
+
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(REG_EERD, EERD_START | (word as u32) << 8);
+        let mut value = 0;
+        self.wait(|| {
+            value = self.read(REG_EERD);
+            value & EERD_DONE != 0
+        })
+        .then_some((value >> 16) as u16)
+    }
+
+    /// The address stored in the EEPROM, or the one the firmware left in
+    /// the first receive address register if there is no EEPROM.
+    fn read_mac(&self) -> MacAddress {
+        let mut mac = [0; 6];
+        let words = (0..3).map(|word| self.read_eeprom(word));
+        if let Some(words) = words.collect::<Option<Vec<_>>>() {
+            for (bytes, word) in mac.chunks_exact_mut(2).zip(words) {
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        } else {
+            mac[..4].copy_from_slice(&self.read(REG_RAL).to_le_bytes());
+            mac[4..].copy_from_slice(&self.read(REG_RAH).to_le_bytes()[..2]);
+        }
+        MacAddress(mac)
+    }
+
+    fn set_up_receive(&self) {
+        let mut ring = self.receive_ring.lock();
+        for index in 0..RECEIVE_DESCRIPTORS {
+            let addr = ring.frames[index].phys().as_u64();
+            let descriptor = ring.descriptor(index);
+            descriptor.fill(0);
+            descriptor[0..8].copy_from_slice(&addr.to_le_bytes());
+        }
+        let base = ring.descriptors.phys().as_u64();
+        self.write(REG_RDBAL, base as u32);
+        self.write(REG_RDBAH, (base >> 32) as u32);
+        self.write(REG_RDLEN, (RECEIVE_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_RDH, 0);
+        // the card owns everything between head and tail, which must not
+        // meet
+        self.write(REG_RDT, RECEIVE_DESCRIPTORS as u32 - 1);
+        self.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+    }
+
+    fn set_up_transmit(&self) {
+        let mut ring = self.transmit_ring.lock();
+        for index in 0..TRANSMIT_DESCRIPTORS {
+            let addr = ring.frames[index].phys().as_u64();
+            let descriptor = ring.descriptor(index);
+            descriptor.fill(0);
+            descriptor[0..8].copy_from_slice(&addr.to_le_bytes());
+            // every descriptor starts out free
+            descriptor[12] = DESC_DD;
+        }
+        let base = ring.descriptors.phys().as_u64();
+        self.write(REG_TDBAL, base as u32);
+        self.write(REG_TDBAH, (base >> 32) as u32);
+        self.write(REG_TDLEN, (TRANSMIT_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_TDH, 0);
+        self.write(REG_TDT, 0);
+        self.write(REG_TIPG, TIPG_COPPER);
+        self.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    }
+
+    fn handle_interrupt(&self) {
+        // reading the cause acknowledges it; nothing means another device
+        // on the line interrupted
+        let cause = self.read(REG_ICR);
+        if cause & INT_LSC != 0 {
+            self.link_up
+                .store(self.read(REG_STATUS) & STATUS_LU != 0, Ordering::Relaxed);
+        }
+        if cause & INT_RECEIVE != 0 {
+            super::frames_arrived();
+        }
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.check_frame(frame)?;
+        let mut ring = self.transmit_ring.lock();
+        let index = ring.next;
+        if ring.status(index) & DESC_DD == 0 {
+            return Err(NetError::Busy);
+        }
+        ring.frames[index].as_mut_slice()[..frame.len()].copy_from_slice(frame);
+        let descriptor = ring.descriptor(index);
+        descriptor[8..10].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+        descriptor[11] = CMD_EOP | CMD_IFCS | CMD_RS;
+        descriptor[12] = 0;
+        ring.next = (index + 1) % TRANSMIT_DESCRIPTORS;
+        // the descriptor has to be complete before the card may fetch it
+        fence(Ordering::SeqCst);
+        self.write(REG_TDT, ring.next as u32);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut ring = self.receive_ring.lock();
+        loop {
+            let index = ring.next;
+            let status = ring.status(index);
+            if status & DESC_DD == 0 {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            let descriptor = ring.descriptor(index);
+            let len = u16::from_le_bytes([descriptor[8], descriptor[9]]) as usize;
+            let errors = descriptor[13];
+            descriptor[12] = 0;
+            // frames larger than a buffer are not enabled, so a frame not
+            // ending in its first descriptor is broken
+            let frame = (status & DESC_EOP != 0 && errors == 0)
+                .then(|| ring.frames[index].as_slice()[..len.min(BUFFER_SIZE)].to_vec());
+            ring.next = (index + 1) % RECEIVE_DESCRIPTORS;
+            fence(Ordering::SeqCst);
+            self.write(REG_RDT, index as u32);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let registers = device.map_bar(0).ok_or("e1000 BAR 0 is not memory")?;
+    device.enable_bus_master();
+    let mut nic = E1000 {
+        registers,
+        mac: MacAddress::default(),
+        link_up: AtomicBool::new(false),
+        receive_ring: Mutex::new(
+            Ring::new(RECEIVE_DESCRIPTORS).ok_or("out of memory for receive buffers")?,
+        ),
+        transmit_ring: Mutex::new(
+            Ring::new(TRANSMIT_DESCRIPTORS).ok_or("out of memory for transmit buffers")?,
+        ),
+    };
+    nic.reset()?;
+    nic.mac = nic.read_mac();
+
+    // take frames for our address and broadcasts, but no multicasts
+    let mac = nic.mac.0;
+    nic.write(
+        REG_RAL,
+        u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+    );
+    nic.write(
+        REG_RAH,
+        u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV,
+    );
+    for index in 0..MTA_ENTRIES {
+        nic.write(REG_MTA + index * 4, 0);
+    }
+    nic.set_up_receive();
+    nic.set_up_transmit();
+    nic.write(REG_CTRL, nic.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+    nic.link_up
+        .store(nic.read(REG_STATUS) & STATUS_LU != 0, Ordering::Relaxed);
+
+    let nic = Arc::new(nic);
+    let weak: Weak<E1000> = Arc::downgrade(&nic);
+    let handler: Arc<dyn Fn() + Send + Sync> = Arc::new(move || {
+        if let Some(nic) = weak.upgrade() {
+            nic.handle_interrupt();
+        }
+    });
+    let vector = interrupts::allocate_vector(handler.clone());
+    if !vector.is_some_and(|vector| device.enable_msi(vector).is_ok()) {
+        if let Some(vector) = vector {
+            interrupts::free_vector(vector);
+        }
+        interrupts::add_line_handler(device.interrupt_line, handler)?;
+    }
+    nic.write(REG_IMS, INT_LSC | INT_RECEIVE);
+
+    let link = if nic.link_up() { "up" } else { "down" };
+    let mac = nic.mac;
+    let name = super::register(nic);
+    log_info!("{}: e1000, MAC {}, link {}", name, mac, link);
+    Ok(())
+}
+
+/// Registers the driver, which takes every supported Intel NIC on the PCI
+/// bus. Returns how many there were.
+pub fn init() -> usize {
+    pci::register_driver(&DRIVER)
+}
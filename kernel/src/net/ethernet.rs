@@ -0,0 +1,45 @@
+//! Ethernet framing.
+
+use alloc::vec::Vec;
+
+use super::{arp, ipv4, MacAddress, NetDevice, NetError, ETHERNET_HEADER_LEN};
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+/// Shortest frame allowed on the wire, without the checksum. Shorter ones
+/// are padded with zeros.
+const MIN_FRAME_LEN: usize = 60;
+
+/// Sends `payload` to `destination` in a frame of type `ether_type`.
+pub fn send(
+    device: &dyn NetDevice,
+    destination: MacAddress,
+    ether_type: u16,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let mut frame = Vec::with_capacity((ETHERNET_HEADER_LEN + payload.len()).max(MIN_FRAME_LEN));
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&device.mac().0);
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_FRAME_LEN), 0);
+    device.transmit(&frame)
+}
+
+/// Takes a frame that arrived on `interface`, a device with address `mac`.
+pub(super) fn handle(interface: &str, mac: MacAddress, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+    let destination = MacAddress(frame[0..6].try_into().unwrap());
+    if destination != mac && !destination.is_broadcast() {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        TYPE_ARP => arp::handle(interface, payload),
+        TYPE_IPV4 => ipv4::handle(interface, payload),
+        _ => {}
+    }
+}
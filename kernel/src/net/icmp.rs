@@ -0,0 +1,96 @@
+//! ICMP: answering echo requests and sending our own.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{
+    ipv4::{self, Header, Ipv4Address, PROTOCOL_ICMP},
+    NetError,
+};
+use crate::{sync::WaitQueue, time};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+const HEADER_LEN: usize = 8;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+/// Echo requests waiting for their reply, by identifier and sequence
+/// number, with the time the reply arrived once it did.
+static ECHOES: spin::Mutex<BTreeMap<(u16, u16), Option<u64>>> = spin::Mutex::new(BTreeMap::new());
+static REPLIED: WaitQueue = WaitQueue::new();
+
+fn encode(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(data);
+    let checksum = ipv4::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// Takes an ICMP message that arrived in a packet described by `header`.
+pub(super) fn handle(header: &Header, message: &[u8]) {
+    if message.len() < HEADER_LEN || ipv4::checksum(message) != 0 {
+        return;
+    }
+    let rest: [u8; 4] = message[4..8].try_into().unwrap();
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // broadcast pings go unanswered
+            if !ipv4::is_local(header.destination) {
+                return;
+            }
+            let reply = encode(TYPE_ECHO_REPLY, 0, rest, &message[HEADER_LEN..]);
+            let _ = ipv4::send(header.source, PROTOCOL_ICMP, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let id = u16::from_be_bytes([rest[0], rest[1]]);
+            let sequence = u16::from_be_bytes([rest[2], rest[3]]);
+            let expected = ECHOES
+                .lock()
+                .get_mut(&(id, sequence))
+                .map(|arrival| *arrival = Some(time::uptime_ms()))
+                .is_some();
+            // waiters check the echoes with the queue locked, so it must not
+            // be notified with the echoes locked
+            if expected {
+                REPLIED.notify_all();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends an echo request with `len` bytes of data to `destination` and
+/// waits at most `timeout_ms` for the reply. Returns the round trip time in
+/// milliseconds.
+pub fn ping(
+    destination: Ipv4Address,
+    sequence: u16,
+    len: usize,
+    timeout_ms: u64,
+) -> Result<u64, NetError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    let ([a, b], [c, d]) = (id.to_be_bytes(), sequence.to_be_bytes());
+    let request = encode(TYPE_ECHO_REQUEST, 0, [a, b, c, d], &data);
+    ECHOES.lock().insert((id, sequence), None);
+    let sent = time::uptime_ms();
+    let result = ipv4::send(destination, PROTOCOL_ICMP, &request).and_then(|()| {
+        let replied = REPLIED.wait_while_timeout(timeout_ms, || {
+            ECHOES
+                .lock()
+                .get(&(id, sequence))
+                .is_some_and(Option::is_none)
+        })?;
+        if replied {
+            Ok(())
+        } else {
+            Err(NetError::TimedOut)
+        }
+    });
+    let arrival = ECHOES.lock().remove(&(id, sequence)).flatten();
+    result?;
+    Ok(arrival.unwrap_or(sent) - sent)
+}
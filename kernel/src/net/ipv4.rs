@@ -0,0 +1,346 @@
+//! IPv4: addresses of the interfaces, the routing table, and sending and
+//! receiving packets.
+//!
+//! Fragmented packets are dropped and packets that do not fit an Ethernet
+//! frame are not sent; nothing the stack sends needs fragmentation.
+
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use super::{arp, icmp, tcp, udp, NetError, MTU};
+use crate::sync::Mutex;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_LEN: usize = 20;
+/// Largest payload of a packet that fits an Ethernet frame.
+pub const MAX_PAYLOAD: usize = MTU - HEADER_LEN;
+
+const VERSION_IHL: u8 = 0x45;
+const DEFAULT_TTL: u8 = 64;
+/// Don't fragment.
+const FLAG_DF: u16 = 0x4000;
+const FLAG_MF: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+
+/// Address and prefix of every configured interface, by name.
+static INTERFACES: Mutex<BTreeMap<String, InterfaceConfig>> = Mutex::new(BTreeMap::new());
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xFF; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Ipv4Address([a, b, c, d])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        Ipv4Address(value.to_be_bytes())
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xF0 == 0xE0
+    }
+
+    /// Whether the first `prefix_len` bits match those of `network`.
+    pub fn in_network(&self, network: Ipv4Address, prefix_len: u8) -> bool {
+        let mask = netmask(prefix_len);
+        self.to_u32() & mask == network.to_u32() & mask
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    /// Parses dotted decimal notation, e.g. `10.0.2.2`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in &mut octets {
+            *octet = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(Ipv4Address(octets)),
+        }
+    }
+}
+
+fn netmask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len => !0 << (32 - len.min(32) as u32),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+}
+
+impl InterfaceConfig {
+    /// The address of every host on the interface's network.
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !netmask(self.prefix_len))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Address,
+    pub prefix_len: u8,
+    /// Router to send the packets to, or `None` if the destination is on the
+    /// interface's network.
+    pub gateway: Option<Ipv4Address>,
+    pub interface: String,
+}
+
+/// Gives `interface` an address, with a route to its network. Replaces the
+/// interface's previous address and routes.
+pub fn configure(interface: &str, address: Ipv4Address, prefix_len: u8) {
+    unconfigure(interface);
+    INTERFACES.lock().insert(
+        interface.into(),
+        InterfaceConfig {
+            address,
+            prefix_len,
+        },
+    );
+    add_route(Route {
+        destination: address,
+        prefix_len,
+        gateway: None,
+        interface: interface.into(),
+    });
+}
+
+/// Takes the address of `interface` away, and all routes through it.
+pub fn unconfigure(interface: &str) {
+    INTERFACES.lock().remove(interface);
+    ROUTES.lock().retain(|route| route.interface != interface);
+}
+
+pub fn interface_config(interface: &str) -> Option<InterfaceConfig> {
+    INTERFACES.lock().get(interface).copied()
+}
+
+/// All configured interfaces, ordered by name.
+pub fn interfaces() -> Vec<(String, InterfaceConfig)> {
+    INTERFACES
+        .lock()
+        .iter()
+        .map(|(name, config)| (name.clone(), *config))
+        .collect()
+}
+
+pub fn add_route(route: Route) {
+    ROUTES.lock().push(route);
+}
+
+/// Adds a default route through `gateway`, replacing the previous one.
+pub fn set_default_gateway(gateway: Ipv4Address, interface: &str) {
+    let mut routes = ROUTES.lock();
+    routes.retain(|route| route.prefix_len != 0);
+    routes.push(Route {
+        destination: Ipv4Address::UNSPECIFIED,
+        prefix_len: 0,
+        gateway: Some(gateway),
+        interface: interface.into(),
+    });
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+/// Finds the most specific route to `destination`. Returns the interface,
+/// the host to hand the packet to and our address on that interface.
+pub fn route(destination: Ipv4Address) -> Option<(String, Ipv4Address, Ipv4Address)> {
+    let route = ROUTES
+        .lock()
+        .iter()
+        .filter(|route| destination.in_network(route.destination, route.prefix_len))
+        .max_by_key(|route| route.prefix_len)
+        .cloned()?;
+    let source = interface_config(&route.interface)?.address;
+    Some((
+        route.interface,
+        route.gateway.unwrap_or(destination),
+        source,
+    ))
+}
+
+/// Whether `address` is one of ours.
+pub fn is_local(address: Ipv4Address) -> bool {
+    INTERFACES
+        .lock()
+        .values()
+        .any(|config| config.address == address)
+}
+
+/// Adds `data` to a running one's complement sum.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds a running sum into the final checksum.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// The sum over the pseudo header TCP and UDP include in their checksums.
+pub fn pseudo_header_sum(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    len: usize,
+) -> u32 {
+    let sum = checksum_add(0, &source.0);
+    let sum = checksum_add(sum, &destination.0);
+    sum + protocol as u32 + len as u32
+}
+
+/// Sends `payload` to `destination`, routed by the routing table.
+pub fn send(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let (interface, next_hop, source) = route(destination).ok_or(NetError::Unreachable)?;
+    send_on(&interface, source, destination, next_hop, protocol, payload)
+}
+
+/// Sends `payload` from `source` to `destination` through `interface`,
+/// handing it to `next_hop`. Broadcasts need no route.
+pub fn send_on(
+    interface: &str,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    next_hop: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::InvalidArgument);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let total_len = (HEADER_LEN + payload.len()) as u16;
+    let mut packet = vec![0; HEADER_LEN];
+    packet[0] = VERSION_IHL;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    packet[6..8].copy_from_slice(&FLAG_DF.to_be_bytes());
+    packet[8] = DEFAULT_TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source.0);
+    packet[16..20].copy_from_slice(&destination.0);
+    let header_checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let broadcast = destination.is_broadcast()
+        || interface_config(interface).is_some_and(|config| config.broadcast() == destination);
+    if broadcast {
+        arp::send_broadcast(interface, &packet)
+    } else {
+        arp::send(interface, next_hop, packet)
+    }
+}
+
+/// Takes a packet that arrived on `interface`.
+pub(super) fn handle(interface: &str, packet: &[u8]) {
+    if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0x0F) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+        return;
+    }
+    if checksum(&packet[..header_len]) != 0 {
+        return;
+    }
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    if flags & FLAG_MF != 0 || flags & FRAGMENT_OFFSET != 0 {
+        return;
+    }
+
+    let source = Ipv4Address(packet[12..16].try_into().unwrap());
+    let destination = Ipv4Address(packet[16..20].try_into().unwrap());
+    let config = interface_config(interface);
+    let for_us = match config {
+        Some(config) => {
+            destination == config.address
+                || destination == config.broadcast()
+                || destination.is_broadcast()
+        }
+        // an interface without an address takes everything, so that it can
+        // be configured
+        None => true,
+    };
+    if !for_us {
+        return;
+    }
+
+    let header = Header {
+        source,
+        destination,
+        interface,
+    };
+    let payload = &packet[header_len..total_len];
+    match packet[9] {
+        PROTOCOL_ICMP => icmp::handle(&header, payload),
+        PROTOCOL_UDP => udp::handle(&header, payload),
+        PROTOCOL_TCP => tcp::handle(&header, payload),
+        _ => {}
+    }
+}
+
+/// What the protocols above need to know about a received packet.
+pub struct Header<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub interface: &'a str,
+}
@@ -0,0 +1,226 @@
+//! Network interfaces: devices that send and receive Ethernet frames, and
+//! the protocols running on them.
+//!
+//! Drivers register the devices they find, which are named `eth0`, `eth1`
+//! and so on in that order. Received frames wait in the device until they
+//! are asked for; drivers only announce them with [`frames_arrived`]. The
+//! network thread started by [`init`] then hands them up the stack and runs
+//! the protocol timers.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU16, AtomicU64, Ordering},
+};
+
+use crate::{
+    sync::{Killed, WaitQueue},
+    thread::{self, Priority},
+    time,
+};
+
+pub mod arp;
+pub mod dhcp;
+pub mod dns;
+pub mod e1000;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+pub mod virtio_net;
+
+pub use ipv4::Ipv4Address;
+
+/// Largest payload of an Ethernet frame.
+pub const MTU: usize = 1500;
+/// Length of the Ethernet header: two addresses and the type.
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// Largest frame a device has to send or receive, without the checksum.
+pub const MAX_FRAME_LEN: usize = ETHERNET_HEADER_LEN + MTU;
+
+static DEVICES: spin::Mutex<Vec<(String, Arc<dyn NetDevice>)>> = spin::Mutex::new(Vec::new());
+
+/// Bumped whenever a device has received frames.
+static ARRIVALS: AtomicU64 = AtomicU64::new(0);
+static ARRIVED: WaitQueue = WaitQueue::new();
+
+/// Ports handed to sockets that do not ask for one.
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(*EPHEMERAL_PORTS.start());
+
+/// How often the protocol timers run.
+const TICK_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame is longer than [`MAX_FRAME_LEN`] or shorter than a header.
+    InvalidFrame,
+    /// The transmit queue is full; the frame may be sent again later.
+    Busy,
+    LinkDown,
+    /// The device reported an error or is out of memory.
+    Io,
+    /// No route or device leads to the destination.
+    Unreachable,
+    AddressInUse,
+    /// The peer answered the connection request with a reset.
+    ConnectionRefused,
+    /// The peer reset an established connection.
+    ConnectionReset,
+    /// The peer stopped answering, or nothing arrived in time.
+    TimedOut,
+    NotConnected,
+    InvalidArgument,
+    /// The host name does not exist or has no address.
+    NotFound,
+    /// The waiting thread was killed.
+    Interrupted,
+}
+
+impl From<Killed> for NetError {
+    fn from(_: Killed) -> Self {
+        NetError::Interrupted
+    }
+}
+
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddress;
+
+    /// Whether the device is connected to a network.
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    /// Sends an Ethernet frame, header included and checksum excluded.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Takes the oldest frame the device has received, if there is one.
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    /// Checks that `frame` is something an Ethernet device can send.
+    fn check_frame(&self, frame: &[u8]) -> Result<(), NetError> {
+        if !(ETHERNET_HEADER_LEN..=MAX_FRAME_LEN).contains(&frame.len()) {
+            return Err(NetError::InvalidFrame);
+        }
+        if !self.link_up() {
+            return Err(NetError::LinkDown);
+        }
+        Ok(())
+    }
+}
+
+/// Makes `device` available under the next free `eth` name, which is
+/// returned.
+pub fn register(device: Arc<dyn NetDevice>) -> String {
+    let mut devices = DEVICES.lock();
+    let name = format!("eth{}", devices.len());
+    devices.push((name.clone(), device));
+    name
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(device_name, _)| device_name == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// All registered devices, in the order they were found.
+pub fn devices() -> Vec<(String, Arc<dyn NetDevice>)> {
+    DEVICES.lock().clone()
+}
+
+/// Wakes whoever waits for frames. Called by drivers, usually from their
+/// interrupt handler.
+pub fn frames_arrived() {
+    ARRIVALS.fetch_add(1, Ordering::Release);
+    ARRIVED.notify_all();
+}
+
+/// How many times frames have arrived so far, to be passed to
+/// [`wait_for_frames`].
+pub fn arrivals() -> u64 {
+    ARRIVALS.load(Ordering::Acquire)
+}
+
+/// Waits at most `ms` milliseconds for frames to arrive after [`arrivals`]
+/// returned `seen`. Returns `false` on timeout or if the thread is killed.
+pub fn wait_for_frames(seen: u64, ms: u64) -> bool {
+    ARRIVED
+        .wait_while_timeout(ms, || arrivals() == seen)
+        .unwrap_or(false)
+}
+
+/// A free ephemeral port, tried in turn; `in_use` tells which are taken.
+fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    let count = EPHEMERAL_PORTS.len();
+    (0..count).find_map(|_| {
+        let port = NEXT_EPHEMERAL
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                Some(if port == *EPHEMERAL_PORTS.end() {
+                    *EPHEMERAL_PORTS.start()
+                } else {
+                    port + 1
+                })
+            })
+            .unwrap();
+        (!in_use(port)).then_some(port)
+    })
+}
+
+/// Starts the network thread and the DHCP client of every interface.
+/// Called once the drivers are initialized.
+pub fn init() {
+    thread::spawn_with("net", Priority::High, || {
+        let mut last_tick = time::uptime_ms();
+        loop {
+            let seen = arrivals();
+            for (name, device) in devices() {
+                while let Some(frame) = device.receive() {
+                    ethernet::handle(&name, device.mac(), &frame);
+                }
+            }
+            let now = time::uptime_ms();
+            if now - last_tick >= TICK_MS {
+                last_tick = now;
+                arp::tick();
+                tcp::tick();
+            }
+            wait_for_frames(seen, TICK_MS);
+        }
+    });
+
+    for (name, _) in devices() {
+        dhcp::start(name);
+    }
+}
@@ -0,0 +1,273 @@
+//! Sockets: TCP and UDP as handles of a process.
+//!
+//! A socket starts out unconnected and becomes a stream, a listener or a
+//! bound UDP port depending on what is done with it. Reading and writing
+//! it like a file receives and sends; everything else has its own call.
+
+use alloc::sync::Arc;
+
+use super::{
+    tcp::{self, TcpListener, TcpStream},
+    udp::UdpSocket,
+    Ipv4Address, NetError,
+};
+use crate::vfs::{File, Metadata, VfsError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+enum SocketState {
+    /// A TCP socket that is neither connected nor listening, with the port
+    /// it is bound to, or 0.
+    Tcp {
+        port: u16,
+    },
+    Stream(Arc<TcpStream>),
+    Listener(Arc<TcpListener>),
+    Udp {
+        /// Bound on [`Socket::bind`] or the first datagram sent.
+        socket: Option<Arc<UdpSocket>>,
+        /// Where writes go, set by [`Socket::connect`].
+        peer: Option<(Ipv4Address, u16)>,
+    },
+}
+
+/// What data goes through, taken out of the state so that it is not kept
+/// locked while waiting.
+enum Transport {
+    Stream(Arc<TcpStream>),
+    Udp(Arc<UdpSocket>),
+}
+
+pub struct Socket {
+    state: spin::Mutex<SocketState>,
+}
+
+impl Socket {
+    pub fn new(kind: SocketKind) -> Self {
+        let state = match kind {
+            SocketKind::Tcp => SocketState::Tcp { port: 0 },
+            SocketKind::Udp => SocketState::Udp {
+                socket: None,
+                peer: None,
+            },
+        };
+        Socket {
+            state: spin::Mutex::new(state),
+        }
+    }
+
+    pub fn kind(&self) -> SocketKind {
+        match *self.state.lock() {
+            SocketState::Udp { .. } => SocketKind::Udp,
+            _ => SocketKind::Tcp,
+        }
+    }
+
+    /// Picks the local port, a free one if `port` is 0. A TCP port is only
+    /// taken once the socket listens or connects.
+    pub fn bind(&self, port: u16) -> Result<(), NetError> {
+        match &mut *self.state.lock() {
+            SocketState::Tcp { port: 0 } => {}
+            SocketState::Udp { socket, .. } if socket.is_none() => {
+                *socket = Some(UdpSocket::bind(port)?);
+                return Ok(());
+            }
+            _ => return Err(NetError::InvalidArgument),
+        }
+        // the connection table is not locked with the state held
+        let port = tcp::choose_port(port)?;
+        match &mut *self.state.lock() {
+            SocketState::Tcp { port: bound } if *bound == 0 => {
+                *bound = port;
+                Ok(())
+            }
+            _ => Err(NetError::InvalidArgument),
+        }
+    }
+
+    /// Connects a TCP socket to `port` of `address`, from the port it is
+    /// bound to or an ephemeral one. A UDP socket just remembers where
+    /// writes go.
+    pub fn connect(&self, address: Ipv4Address, port: u16) -> Result<(), NetError> {
+        let local_port = match &mut *self.state.lock() {
+            SocketState::Tcp { port } => *port,
+            SocketState::Udp { peer, .. } => {
+                *peer = Some((address, port));
+                return Ok(());
+            }
+            _ => return Err(NetError::InvalidArgument),
+        };
+        // the state is not kept locked while connecting, so a second
+        // connect may race this one; the loser's stream is dropped
+        let stream = TcpStream::connect(address, port, local_port, None)?;
+        let mut state = self.state.lock();
+        match *state {
+            SocketState::Tcp { .. } => {
+                *state = SocketState::Stream(stream);
+                Ok(())
+            }
+            _ => Err(NetError::InvalidArgument),
+        }
+    }
+
+    /// Makes a bound TCP socket accept connections.
+    pub fn listen(&self) -> Result<(), NetError> {
+        let port = match *self.state.lock() {
+            SocketState::Tcp { port } if port != 0 => port,
+            _ => return Err(NetError::InvalidArgument),
+        };
+        let listener = TcpListener::bind(port)?;
+        let mut state = self.state.lock();
+        match *state {
+            SocketState::Tcp { .. } => {
+                *state = SocketState::Listener(listener);
+                Ok(())
+            }
+            _ => Err(NetError::InvalidArgument),
+        }
+    }
+
+    /// Waits for a connection to a listening socket and returns it as a new
+    /// socket, with the address of the peer.
+    pub fn accept(&self) -> Result<(Socket, (Ipv4Address, u16)), NetError> {
+        let listener = match &*self.state.lock() {
+            SocketState::Listener(listener) => listener.clone(),
+            _ => return Err(NetError::InvalidArgument),
+        };
+        let stream = listener.accept(None)?;
+        let peer = stream.peer_addr();
+        let socket = Socket {
+            state: spin::Mutex::new(SocketState::Stream(stream)),
+        };
+        Ok((socket, peer))
+    }
+
+    /// Sends `data` to `port` of `address` in a UDP datagram, binding an
+    /// ephemeral port first if needed. A connected TCP socket ignores the
+    /// address and sends on its stream.
+    pub fn send_to(&self, data: &[u8], address: Ipv4Address, port: u16) -> Result<usize, NetError> {
+        let transport = match &mut *self.state.lock() {
+            SocketState::Stream(stream) => Transport::Stream(stream.clone()),
+            SocketState::Udp { socket, .. } => match socket {
+                Some(socket) => Transport::Udp(socket.clone()),
+                None => Transport::Udp(socket.insert(UdpSocket::bind(0)?).clone()),
+            },
+            _ => return Err(NetError::NotConnected),
+        };
+        match transport {
+            Transport::Stream(stream) => stream.send(data),
+            Transport::Udp(socket) => {
+                socket.send_to(data, address, port)?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// Receives into `buf`, waiting until something arrives. Returns the
+    /// length received and the sender.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Ipv4Address, u16), NetError> {
+        let transport = match &*self.state.lock() {
+            SocketState::Stream(stream) => Transport::Stream(stream.clone()),
+            SocketState::Udp {
+                socket: Some(socket),
+                ..
+            } => Transport::Udp(socket.clone()),
+            _ => return Err(NetError::NotConnected),
+        };
+        match transport {
+            Transport::Stream(stream) => {
+                let len = stream.recv(buf, None)?;
+                let (address, port) = stream.peer_addr();
+                Ok((len, address, port))
+            }
+            Transport::Udp(socket) => socket.recv_from(buf, None),
+        }
+    }
+
+    /// The peer writes go to: that of the TCP stream or the one a UDP
+    /// socket was connected to.
+    fn peer(&self) -> Result<(Ipv4Address, u16), NetError> {
+        match &*self.state.lock() {
+            SocketState::Stream(stream) => Ok(stream.peer_addr()),
+            SocketState::Udp {
+                peer: Some(peer), ..
+            } => Ok(*peer),
+            _ => Err(NetError::NotConnected),
+        }
+    }
+}
+
+impl From<NetError> for VfsError {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::InvalidArgument => VfsError::NotSupported,
+            _ => VfsError::Io,
+        }
+    }
+}
+
+impl File for Socket {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(self.recv_from(buf)?.0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        let (address, port) = self.peer()?;
+        Ok(self.send_to(buf, address, port)?)
+    }
+
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listening_port(socket: &Socket) -> Option<u16> {
+        match &*socket.state.lock() {
+            SocketState::Listener(listener) => Some(listener.port()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bind_to_any_port_then_listen() {
+        let socket = Socket::new(SocketKind::Tcp);
+        socket.bind(0).unwrap();
+        assert_eq!(socket.bind(0), Err(NetError::InvalidArgument));
+        socket.listen().unwrap();
+        let port = listening_port(&socket).unwrap();
+        assert_ne!(port, 0);
+
+        let other = Socket::new(SocketKind::Tcp);
+        assert_eq!(other.bind(port), Err(NetError::AddressInUse));
+        other.bind(0).unwrap();
+        other.listen().unwrap();
+        assert_ne!(listening_port(&other), Some(port));
+    }
+
+    #[test]
+    fn connect_uses_the_bound_port() {
+        const PORT: u16 = 4242;
+        let socket = Socket::new(SocketKind::Tcp);
+        socket.bind(PORT).unwrap();
+        // somebody else takes the port before the socket connects
+        let listener = TcpListener::bind(PORT).unwrap();
+        assert_eq!(
+            socket.connect(Ipv4Address::new(10, 0, 2, 2), 80),
+            Err(NetError::AddressInUse)
+        );
+        drop(listener);
+        // there is no route on the host, but the port was fine
+        assert_eq!(
+            socket.connect(Ipv4Address::new(10, 0, 2, 2), 80),
+            Err(NetError::Unreachable)
+        );
+    }
+}
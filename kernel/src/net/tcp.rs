@@ -0,0 +1,1434 @@
+//! TCP: reliable byte streams.
+//!
+//! Every connection keeps what it sent until the peer acknowledges it, and
+//! sends it again from the first unacknowledged byte when the
+//! retransmission timer, run by the network thread, expires, or when the
+//! peer acknowledges the same byte three times over. Segments that arrive
+//! out of order are dropped and answered with the sequence number we
+//! expect, so the peer sends them again.
+//!
+//! Locks are taken in the order connection table, connection, ARP cache,
+//! device.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::{
+    ipv4::{self, Header, Ipv4Address, PROTOCOL_TCP},
+    NetError,
+};
+use crate::{
+    sync::{Mutex, WaitQueue},
+    time,
+};
+
+const HEADER_LEN: usize = 20;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Segment size assumed when the peer does not announce one.
+const DEFAULT_MSS: usize = 536;
+/// Segment size we announce: whatever fits an Ethernet frame.
+const MSS: usize = ipv4::MAX_PAYLOAD - HEADER_LEN;
+/// Size of the send and receive buffer of every connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Most connections waiting to be accepted per listener.
+const BACKLOG: usize = 16;
+
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 60_000;
+/// Retransmissions of the same segment before the connection is given up.
+const MAX_RETRANSMISSIONS: u32 = 6;
+/// Duplicate acknowledgements that make us retransmit without waiting for
+/// the timer.
+const DUPLICATE_ACKS: u32 = 3;
+/// How long a closed connection lingers to take retransmitted FINs.
+const TIME_WAIT_MS: u64 = 30_000;
+
+static CONNECTIONS: Mutex<BTreeMap<Endpoints, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Weak<TcpListener>>> = Mutex::new(BTreeMap::new());
+static NEXT_ISN: AtomicU32 = AtomicU32::new(0);
+
+/// `a < b` for sequence numbers, which wrap around.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+/// An initial sequence number, driven by the clock like RFC 793 asks so
+/// that old segments do not fall into a new connection.
+fn initial_sequence() -> u32 {
+    let clock = (time::uptime_ms() as u32).wrapping_mul(250);
+    clock.wrapping_add(NEXT_ISN.fetch_add(64_000, Ordering::Relaxed))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Endpoints {
+    local: Ipv4Address,
+    local_port: u16,
+    remote: Ipv4Address,
+    remote_port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+struct Segment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Sequence numbers the segment takes up.
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+fn parse(segment: &[u8]) -> Option<(u16, u16, Segment<'_>)> {
+    if segment.len() < HEADER_LEN {
+        return None;
+    }
+    let data_offset = (segment[12] >> 4) as usize * 4;
+    if data_offset < HEADER_LEN || data_offset > segment.len() {
+        return None;
+    }
+    let mut mss = None;
+    let mut options = &segment[HEADER_LEN..data_offset];
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    let source_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let destination_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let segment = Segment {
+        seq: u32::from_be_bytes(segment[4..8].try_into().unwrap()),
+        ack: u32::from_be_bytes(segment[8..12].try_into().unwrap()),
+        flags: segment[13],
+        window: u16::from_be_bytes([segment[14], segment[15]]),
+        mss,
+        data: &segment[data_offset..],
+    };
+    Some((source_port, destination_port, segment))
+}
+
+/// Sends a segment from `ends.local` to `ends.remote`.
+fn transmit(ends: &Endpoints, segment: &Segment) -> Result<(), NetError> {
+    let header_len = HEADER_LEN + if segment.mss.is_some() { 4 } else { 0 };
+    let mut packet = Vec::with_capacity(header_len + segment.data.len());
+    packet.extend_from_slice(&ends.local_port.to_be_bytes());
+    packet.extend_from_slice(&ends.remote_port.to_be_bytes());
+    packet.extend_from_slice(&segment.seq.to_be_bytes());
+    packet.extend_from_slice(&segment.ack.to_be_bytes());
+    packet.extend_from_slice(&[((header_len / 4) as u8) << 4, segment.flags]);
+    packet.extend_from_slice(&segment.window.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = segment.mss {
+        packet.extend_from_slice(&[OPTION_MSS, 4]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+    }
+    packet.extend_from_slice(segment.data);
+    let sum = ipv4::pseudo_header_sum(ends.local, ends.remote, PROTOCOL_TCP, packet.len());
+    let checksum = ipv4::checksum_finish(ipv4::checksum_add(sum, &packet));
+    packet[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    let (interface, next_hop, _) = ipv4::route(ends.remote).ok_or(NetError::Unreachable)?;
+    ipv4::send_on(
+        &interface,
+        ends.local,
+        ends.remote,
+        next_hop,
+        PROTOCOL_TCP,
+        &packet,
+    )
+}
+
+/// How segments of a connection leave; [`transmit`] unless the segments
+/// are meant to be looked at instead.
+type Transmit = fn(&Endpoints, &Segment) -> Result<(), NetError>;
+
+/// Both ends of a connection and the way from one to the other.
+#[derive(Clone, Copy)]
+struct Link {
+    ends: Endpoints,
+    transmit: Transmit,
+}
+
+impl Link {
+    fn new(ends: Endpoints) -> Self {
+        Link { ends, transmit }
+    }
+
+    /// Sends `segment`. Lost segments are sent again by the timers, so
+    /// failures are not reported.
+    fn send(&self, segment: &Segment) {
+        let _ = (self.transmit)(&self.ends, segment);
+    }
+}
+
+/// Answers a segment that belongs to no connection with a reset.
+fn reset(link: &Link, segment: &Segment) {
+    let reply = if segment.flags & ACK != 0 {
+        Segment {
+            seq: segment.ack,
+            ack: 0,
+            flags: RST,
+            window: 0,
+            mss: None,
+            data: &[],
+        }
+    } else {
+        Segment {
+            seq: 0,
+            ack: segment.seq.wrapping_add(segment.len()),
+            flags: RST | ACK,
+            window: 0,
+            mss: None,
+            data: &[],
+        }
+    };
+    link.send(&reply);
+}
+
+/// Transmission control block: everything about a connection that changes.
+struct Tcb {
+    state: State,
+    /// Why the connection was closed, if it was not closed normally.
+    error: Option<NetError>,
+
+    iss: u32,
+    /// Oldest sequence number not acknowledged.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// Highest sequence number sent; `snd_nxt` goes back to `snd_una` when
+    /// retransmitting.
+    snd_max: u32,
+    /// Window the peer announced.
+    snd_wnd: u32,
+    /// Bytes from `snd_una` on, sent or not.
+    send_buffer: VecDeque<u8>,
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+
+    /// Next sequence number expected.
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    fin_received: bool,
+
+    rto: u64,
+    srtt: Option<u64>,
+    rttvar: u64,
+    retransmit_at: Option<u64>,
+    retransmissions: u32,
+    duplicate_acks: u32,
+    /// `snd_max` when we last went back to `snd_una`; duplicate
+    /// acknowledgements of what was sent before are expected and ignored.
+    recover: u32,
+    /// Segment being timed: the sequence number that acknowledges it and
+    /// when it was sent. Retransmitted segments are not timed.
+    timing: Option<(u32, u64)>,
+    time_wait_until: u64,
+}
+
+impl Tcb {
+    fn new(state: State, iss: u32) -> Self {
+        Tcb {
+            state,
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            send_buffer: VecDeque::new(),
+            mss: DEFAULT_MSS,
+            cwnd: DEFAULT_MSS,
+            ssthresh: BUFFER_SIZE,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            fin_received: false,
+            rto: INITIAL_RTO_MS,
+            srtt: None,
+            rttvar: 0,
+            retransmit_at: None,
+            retransmissions: 0,
+            duplicate_acks: 0,
+            recover: iss,
+            timing: None,
+            time_wait_until: 0,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// Whether we have to send a FIN after the data in the send buffer.
+    fn closing(&self) -> bool {
+        matches!(
+            self.state,
+            State::FinWait1 | State::Closing | State::LastAck
+        )
+    }
+
+    fn segment<'a>(&self, seq: u32, flags: u8, data: &'a [u8]) -> Segment<'a> {
+        Segment {
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.window(),
+            mss: (flags & SYN != 0).then_some(MSS as u16),
+            data,
+        }
+    }
+
+    fn send_ack(&self, link: &Link) {
+        link.send(&self.segment(self.snd_nxt, ACK, &[]));
+    }
+
+    fn send_syn(&self, link: &Link) {
+        let flags = match self.state {
+            State::SynSent => SYN,
+            _ => SYN | ACK,
+        };
+        link.send(&self.segment(self.iss, flags, &[]));
+    }
+
+    /// Sends as much of the send buffer as the windows allow, and the FIN
+    /// once everything is sent if the connection is closing.
+    fn output(&mut self, link: &Link) {
+        if !matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        ) {
+            return;
+        }
+        let now = time::uptime_ms();
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if in_flight > self.send_buffer.len() {
+                // the FIN is out
+                break;
+            }
+            let window = (self.snd_wnd as usize).min(self.cwnd);
+            // with nothing in flight, a zero window is probed one byte at a
+            // time until it opens
+            let window = if in_flight == 0 {
+                window.max(1)
+            } else {
+                window
+            };
+            let len = (self.send_buffer.len() - in_flight)
+                .min(self.mss)
+                .min(window.saturating_sub(in_flight));
+            let fin = self.closing() && in_flight + len == self.send_buffer.len();
+            if len == 0 && !fin {
+                break;
+            }
+
+            let data: Vec<u8> = self
+                .send_buffer
+                .range(in_flight..in_flight + len)
+                .copied()
+                .collect();
+            let mut flags = ACK;
+            if len > 0 && in_flight + len == self.send_buffer.len() {
+                flags |= PSH;
+            }
+            if fin {
+                flags |= FIN;
+            }
+            link.send(&self.segment(self.snd_nxt, flags, &data));
+
+            let end = self.snd_nxt.wrapping_add(len as u32 + fin as u32);
+            if self.timing.is_none() && len > 0 && !seq_lt(self.snd_nxt, self.snd_max) {
+                self.timing = Some((end, now));
+            }
+            self.snd_nxt = end;
+            if seq_lt(self.snd_max, end) {
+                self.snd_max = end;
+            }
+            self.retransmit_at.get_or_insert(now + self.rto);
+            if fin {
+                break;
+            }
+        }
+    }
+
+    fn measure_rtt(&mut self, rtt: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+    }
+
+    /// The retransmission timeout before any backoff.
+    fn base_rto(&self) -> u64 {
+        match self.srtt {
+            Some(srtt) => (srtt + (4 * self.rttvar).max(100)).clamp(MIN_RTO_MS, MAX_RTO_MS),
+            None => INITIAL_RTO_MS,
+        }
+    }
+
+    /// Takes an acknowledgement of everything before `ack`. Returns whether
+    /// it acknowledged our FIN.
+    fn acknowledge(&mut self, ack: u32, now: u64) -> bool {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        let data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.snd_una = ack;
+        if seq_lt(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+        if let Some((end, sent)) = self.timing {
+            if seq_le(end, ack) {
+                self.measure_rtt(now - sent);
+                self.timing = None;
+            }
+        }
+        // slow start, then one segment per round trip
+        self.cwnd += if self.cwnd < self.ssthresh {
+            self.mss
+        } else {
+            (self.mss * self.mss / self.cwnd).max(1)
+        };
+        self.cwnd = self.cwnd.min(BUFFER_SIZE);
+        // new data got through, so the backoff ends even if the segment
+        // could not be timed
+        self.rto = self.base_rto();
+        self.retransmissions = 0;
+        self.duplicate_acks = 0;
+        self.retransmit_at = (self.snd_una != self.snd_max).then_some(now + self.rto);
+        acked > data
+    }
+}
+
+struct Connection {
+    link: Link,
+    tcb: Mutex<Tcb>,
+    /// Listener that accepts the connection once it is established.
+    listener: Option<Weak<TcpListener>>,
+    /// Bumped whenever the state or one of the buffers changes.
+    events: AtomicU64,
+    changed: WaitQueue,
+}
+
+impl Connection {
+    fn new(link: Link, tcb: Tcb, listener: Option<Weak<TcpListener>>) -> Arc<Self> {
+        Arc::new(Connection {
+            link,
+            tcb: Mutex::new(tcb),
+            listener,
+            events: AtomicU64::new(0),
+            changed: WaitQueue::new(),
+        })
+    }
+
+    fn notify(&self) {
+        self.events.fetch_add(1, Ordering::Release);
+        self.changed.notify_all();
+    }
+
+    fn events(&self) -> u64 {
+        self.events.load(Ordering::Acquire)
+    }
+
+    /// Waits until something changed after [`Connection::events`] returned
+    /// `seen`, or until the uptime reaches `deadline`.
+    fn wait(&self, seen: u64, deadline: Option<u64>) -> Result<(), NetError> {
+        let unchanged = || self.events() == seen;
+        match deadline {
+            None => self.changed.wait_while(unchanged)?,
+            Some(deadline) => {
+                let left = deadline.saturating_sub(time::uptime_ms());
+                if left == 0 || !self.changed.wait_while_timeout(left, unchanged)? {
+                    return Err(NetError::TimedOut);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the connection at once, telling the peer with a reset.
+    fn abort(&self, error: NetError) {
+        let mut tcb = self.tcb.lock();
+        if !matches!(tcb.state, State::Closed | State::TimeWait) {
+            self.link.send(&tcb.segment(tcb.snd_nxt, RST | ACK, &[]));
+            tcb.state = State::Closed;
+            tcb.error.get_or_insert(error);
+        }
+        drop(tcb);
+        self.notify();
+    }
+
+    /// Takes a segment that arrived for the connection.
+    fn receive(self: &Arc<Self>, segment: &Segment) {
+        let mut tcb = self.tcb.lock();
+        self.process(&mut tcb, segment);
+        drop(tcb);
+        self.notify();
+    }
+
+    fn process(self: &Arc<Self>, tcb: &mut Tcb, segment: &Segment) {
+        let link = &self.link;
+        let now = time::uptime_ms();
+        let acceptable_ack =
+            |tcb: &Tcb| seq_lt(tcb.snd_una, segment.ack) && seq_le(segment.ack, tcb.snd_max);
+
+        match tcb.state {
+            State::Closed => return,
+            State::SynSent => {
+                if segment.flags & ACK != 0 && !acceptable_ack(tcb) {
+                    if segment.flags & RST == 0 {
+                        reset(link, segment);
+                    }
+                    return;
+                }
+                if segment.flags & RST != 0 {
+                    if segment.flags & ACK != 0 {
+                        tcb.state = State::Closed;
+                        tcb.error = Some(NetError::ConnectionRefused);
+                    }
+                    return;
+                }
+                if segment.flags & SYN == 0 {
+                    return;
+                }
+                tcb.rcv_nxt = segment.seq.wrapping_add(1);
+                tcb.snd_wnd = segment.window as u32;
+                tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+                tcb.cwnd = tcb.mss;
+                if segment.flags & ACK != 0 {
+                    tcb.acknowledge(segment.ack, now);
+                    tcb.state = State::Established;
+                    tcb.send_ack(link);
+                } else {
+                    // both sides opened at once
+                    tcb.state = State::SynReceived;
+                    tcb.send_syn(link);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        // only segments that start where we expect them are taken; what was
+        // received before is cut off
+        let mut seq = segment.seq;
+        let mut data = segment.data;
+        let mut syn = segment.flags & SYN != 0;
+        let consumes = segment.len() > 0;
+        if syn && seq_lt(seq, tcb.rcv_nxt) {
+            syn = false;
+            seq = seq.wrapping_add(1);
+        }
+        if seq_lt(seq, tcb.rcv_nxt) {
+            let old = (tcb.rcv_nxt.wrapping_sub(seq) as usize).min(data.len());
+            data = &data[old..];
+            seq = seq.wrapping_add(old as u32);
+        }
+        if seq_lt(tcb.rcv_nxt, seq) {
+            if segment.flags & RST == 0 {
+                tcb.send_ack(link);
+            }
+            return;
+        }
+
+        if segment.flags & RST != 0 {
+            // a reset is only believed if it is exactly where we expect it
+            if segment.seq == tcb.rcv_nxt {
+                if tcb.state != State::TimeWait {
+                    tcb.error.get_or_insert(NetError::ConnectionReset);
+                }
+                tcb.state = State::Closed;
+            }
+            return;
+        }
+        if syn {
+            tcb.send_ack(link);
+            return;
+        }
+        if segment.flags & ACK == 0 {
+            return;
+        }
+
+        if tcb.state == State::SynReceived {
+            if !acceptable_ack(tcb) {
+                reset(link, segment);
+                return;
+            }
+            tcb.acknowledge(segment.ack, now);
+            tcb.snd_wnd = segment.window as u32;
+            tcb.state = State::Established;
+            if let Some(listener) = &self.listener {
+                let Some(listener) = listener.upgrade() else {
+                    link.send(&tcb.segment(tcb.snd_nxt, RST | ACK, &[]));
+                    tcb.state = State::Closed;
+                    return;
+                };
+                listener.established(self.clone());
+            }
+        } else if seq_lt(tcb.snd_max, segment.ack) {
+            // acknowledges what we never sent
+            tcb.send_ack(link);
+            return;
+        } else if acceptable_ack(tcb) {
+            let fin_acked = tcb.acknowledge(segment.ack, now);
+            if fin_acked {
+                match tcb.state {
+                    State::FinWait1 => tcb.state = State::FinWait2,
+                    State::Closing => {
+                        tcb.state = State::TimeWait;
+                        tcb.time_wait_until = now + TIME_WAIT_MS;
+                    }
+                    State::LastAck => tcb.state = State::Closed,
+                    _ => {}
+                }
+            }
+        } else if segment.ack == tcb.snd_una
+            && segment.data.is_empty()
+            && tcb.snd_una != tcb.snd_max
+            && seq_le(tcb.recover, tcb.snd_una)
+        {
+            // the peer got something after a lost segment; send everything
+            // from the lost one again with half the congestion window
+            tcb.duplicate_acks += 1;
+            if tcb.duplicate_acks == DUPLICATE_ACKS {
+                let in_flight = tcb.snd_max.wrapping_sub(tcb.snd_una) as usize;
+                tcb.ssthresh = (in_flight / 2).max(2 * tcb.mss);
+                tcb.cwnd = tcb.ssthresh;
+                tcb.timing = None;
+                tcb.recover = tcb.snd_max;
+                tcb.snd_nxt = tcb.snd_una;
+                tcb.retransmit_at = Some(now + tcb.rto);
+            }
+        }
+        if seq_le(tcb.snd_una, segment.ack) {
+            tcb.snd_wnd = segment.window as u32;
+        }
+
+        if !data.is_empty()
+            && matches!(
+                tcb.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            // what does not fit is dropped; the peer sends it again
+            let len = data.len().min(BUFFER_SIZE - tcb.recv_buffer.len());
+            tcb.recv_buffer.extend(&data[..len]);
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(len as u32);
+            seq = seq.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+
+        if segment.flags & FIN != 0 && data.is_empty() && seq == tcb.rcv_nxt && !tcb.fin_received {
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+            tcb.fin_received = true;
+            match tcb.state {
+                State::Established => tcb.state = State::CloseWait,
+                State::FinWait1 => tcb.state = State::Closing,
+                State::FinWait2 => {
+                    tcb.state = State::TimeWait;
+                    tcb.time_wait_until = now + TIME_WAIT_MS;
+                }
+                _ => {}
+            }
+        }
+        if tcb.state == State::TimeWait {
+            tcb.time_wait_until = now + TIME_WAIT_MS;
+        }
+
+        if consumes {
+            tcb.send_ack(link);
+        }
+        tcb.output(link);
+    }
+
+    /// Runs the timers of the connection. Returns whether the connection is
+    /// gone.
+    fn tick(&self, now: u64) -> bool {
+        let mut tcb = self.tcb.lock();
+        match tcb.state {
+            State::Closed => return true,
+            State::TimeWait => {
+                if now >= tcb.time_wait_until {
+                    tcb.state = State::Closed;
+                    drop(tcb);
+                    self.notify();
+                    return true;
+                }
+                return false;
+            }
+            _ => {}
+        }
+        match tcb.retransmit_at {
+            Some(at) if now >= at => {}
+            _ => return false,
+        }
+
+        // zero window probes do not count as retransmissions
+        if tcb.snd_wnd != 0 || matches!(tcb.state, State::SynSent | State::SynReceived) {
+            tcb.retransmissions += 1;
+        }
+        if tcb.retransmissions > MAX_RETRANSMISSIONS {
+            self.link.send(&tcb.segment(tcb.snd_nxt, RST | ACK, &[]));
+            tcb.state = State::Closed;
+            tcb.error = Some(NetError::TimedOut);
+            drop(tcb);
+            self.notify();
+            return true;
+        }
+        tcb.rto = (tcb.rto * 2).min(MAX_RTO_MS);
+        tcb.timing = None;
+        let in_flight = tcb.snd_max.wrapping_sub(tcb.snd_una) as usize;
+        tcb.ssthresh = (in_flight / 2).max(2 * tcb.mss);
+        tcb.cwnd = tcb.mss;
+        tcb.retransmit_at = Some(now + tcb.rto);
+        match tcb.state {
+            State::SynSent | State::SynReceived => tcb.send_syn(&self.link),
+            _ => {
+                tcb.recover = tcb.snd_max;
+                tcb.snd_nxt = tcb.snd_una;
+                tcb.output(&self.link);
+            }
+        }
+        false
+    }
+}
+
+/// A connection to a port of another host. Dropping the stream closes it.
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Connects to `port` of `address` from `local_port`, or from an
+    /// ephemeral port if that is 0, giving up after `timeout_ms`
+    /// milliseconds if that is not `None`.
+    pub fn connect(
+        address: Ipv4Address,
+        port: u16,
+        local_port: u16,
+        timeout_ms: Option<u64>,
+    ) -> Result<Arc<Self>, NetError> {
+        let connection = {
+            let mut connections = CONNECTIONS.lock();
+            let local_port = choose_port_locked(&connections, local_port)?;
+            let (_, _, local) = ipv4::route(address).ok_or(NetError::Unreachable)?;
+            let ends = Endpoints {
+                local,
+                local_port,
+                remote: address,
+                remote_port: port,
+            };
+            let connection = Connection::new(
+                Link::new(ends),
+                Tcb::new(State::SynSent, initial_sequence()),
+                None,
+            );
+            connections.insert(ends, connection.clone());
+            connection
+        };
+
+        let deadline = timeout_ms.map(|ms| time::uptime_ms() + ms);
+        let stream = Arc::new(TcpStream { connection });
+        let connection = stream.connection.clone();
+        {
+            let mut tcb = connection.tcb.lock();
+            tcb.send_syn(&connection.link);
+            tcb.retransmit_at = Some(time::uptime_ms() + tcb.rto);
+        }
+        loop {
+            let seen = connection.events();
+            {
+                let tcb = connection.tcb.lock();
+                match tcb.state {
+                    State::SynSent | State::SynReceived => {}
+                    State::Closed => return Err(tcb.error.unwrap_or(NetError::ConnectionRefused)),
+                    _ => return Ok(stream),
+                }
+            }
+            if let Err(error) = connection.wait(seen, deadline) {
+                connection.abort(error);
+                return Err(error);
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> (Ipv4Address, u16) {
+        let ends = &self.connection.link.ends;
+        (ends.local, ends.local_port)
+    }
+
+    pub fn peer_addr(&self) -> (Ipv4Address, u16) {
+        let ends = &self.connection.link.ends;
+        (ends.remote, ends.remote_port)
+    }
+
+    pub fn state(&self) -> State {
+        self.connection.tcb.lock().state
+    }
+
+    /// Queues all of `data` for sending, waiting for room in the send
+    /// buffer as needed.
+    pub fn send(&self, data: &[u8]) -> Result<usize, NetError> {
+        let connection = &self.connection;
+        let mut sent = 0;
+        while sent < data.len() {
+            let seen = connection.events();
+            {
+                let mut tcb = connection.tcb.lock();
+                if !matches!(tcb.state, State::Established | State::CloseWait) {
+                    return Err(tcb.error.unwrap_or(NetError::NotConnected));
+                }
+                let len = (data.len() - sent).min(BUFFER_SIZE - tcb.send_buffer.len());
+                tcb.send_buffer.extend(&data[sent..sent + len]);
+                sent += len;
+                tcb.output(&connection.link);
+            }
+            if sent < data.len() {
+                connection.wait(seen, None)?;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Reads what has arrived into `buf`, waiting at most `timeout_ms`
+    /// milliseconds for something to arrive, or forever if that is `None`.
+    /// Returns 0 once the peer has closed its side and everything was read.
+    pub fn recv(&self, buf: &mut [u8], timeout_ms: Option<u64>) -> Result<usize, NetError> {
+        let connection = &self.connection;
+        let deadline = timeout_ms.map(|ms| time::uptime_ms() + ms);
+        loop {
+            let seen = connection.events();
+            {
+                let mut tcb = connection.tcb.lock();
+                if !tcb.recv_buffer.is_empty() {
+                    let was_small = (tcb.window() as usize) < tcb.mss;
+                    let len = buf.len().min(tcb.recv_buffer.len());
+                    for (byte, received) in buf.iter_mut().zip(tcb.recv_buffer.drain(..len)) {
+                        *byte = received;
+                    }
+                    // tell the peer that the window opened again
+                    if was_small && tcb.state != State::Closed {
+                        tcb.send_ack(&connection.link);
+                    }
+                    return Ok(len);
+                }
+                if tcb.fin_received {
+                    return Ok(0);
+                }
+                if let Some(error) = tcb.error {
+                    return Err(error);
+                }
+                if tcb.state == State::Closed {
+                    return Err(NetError::NotConnected);
+                }
+            }
+            connection.wait(seen, deadline)?;
+        }
+    }
+
+    /// Sends a FIN after the queued data. What the peer still sends can be
+    /// received.
+    pub fn close(&self) {
+        let mut tcb = self.connection.tcb.lock();
+        match tcb.state {
+            State::SynSent => tcb.state = State::Closed,
+            State::SynReceived | State::Established => tcb.state = State::FinWait1,
+            State::CloseWait => tcb.state = State::LastAck,
+            _ => return,
+        }
+        tcb.output(&self.connection.link);
+        drop(tcb);
+        self.connection.notify();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A port that accepts connections. The port is free again once the
+/// listener is dropped, and connections that were not accepted are reset.
+pub struct TcpListener {
+    port: u16,
+    backlog: spin::Mutex<VecDeque<Arc<Connection>>>,
+    /// Bumped whenever a connection joins the backlog.
+    arrivals: AtomicU64,
+    arrived: WaitQueue,
+}
+
+impl TcpListener {
+    /// Listens on `port`, or on a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<Arc<Self>, NetError> {
+        let mut listeners = LISTENERS.lock();
+        listeners.retain(|_, listener| listener.strong_count() > 0);
+        let port = match port {
+            0 => super::ephemeral_port(|port| listeners.contains_key(&port))
+                .ok_or(NetError::AddressInUse)?,
+            port if listeners.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let listener = Arc::new(TcpListener {
+            port,
+            backlog: spin::Mutex::new(VecDeque::new()),
+            arrivals: AtomicU64::new(0),
+            arrived: WaitQueue::new(),
+        });
+        listeners.insert(port, Arc::downgrade(&listener));
+        Ok(listener)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Takes the oldest established connection, waiting at most
+    /// `timeout_ms` milliseconds for one, or forever if that is `None`.
+    pub fn accept(&self, timeout_ms: Option<u64>) -> Result<Arc<TcpStream>, NetError> {
+        let deadline = timeout_ms.map(|ms| time::uptime_ms() + ms);
+        loop {
+            let seen = self.arrivals.load(Ordering::Acquire);
+            if let Some(connection) = self.backlog.lock().pop_front() {
+                return Ok(Arc::new(TcpStream { connection }));
+            }
+            let unchanged = || self.arrivals.load(Ordering::Acquire) == seen;
+            match deadline {
+                None => self.arrived.wait_while(unchanged)?,
+                Some(deadline) => {
+                    let left = deadline.saturating_sub(time::uptime_ms());
+                    if left == 0 || !self.arrived.wait_while_timeout(left, unchanged)? {
+                        return Err(NetError::TimedOut);
+                    }
+                }
+            }
+        }
+    }
+
+    fn established(&self, connection: Arc<Connection>) {
+        self.backlog.lock().push_back(connection);
+        self.arrivals.fetch_add(1, Ordering::Release);
+        self.arrived.notify_all();
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.port);
+        let backlog = core::mem::take(&mut *self.backlog.lock());
+        for connection in backlog {
+            connection.abort(NetError::ConnectionReset);
+        }
+    }
+}
+
+/// Checks that no listener or connection uses `port`, or picks a free
+/// ephemeral port if it is 0. The port is not reserved; it is taken by the
+/// listener or connection that uses it first.
+pub fn choose_port(port: u16) -> Result<u16, NetError> {
+    choose_port_locked(&CONNECTIONS.lock(), port)
+}
+
+fn choose_port_locked(
+    connections: &BTreeMap<Endpoints, Arc<Connection>>,
+    port: u16,
+) -> Result<u16, NetError> {
+    let listeners = LISTENERS.lock();
+    let in_use = |port| {
+        listeners.contains_key(&port) || connections.keys().any(|ends| ends.local_port == port)
+    };
+    match port {
+        0 => super::ephemeral_port(in_use).ok_or(NetError::AddressInUse),
+        port if in_use(port) => Err(NetError::AddressInUse),
+        port => Ok(port),
+    }
+}
+
+/// Takes a segment that arrived in a packet described by `header`.
+pub(super) fn handle(header: &Header, packet: &[u8]) {
+    let sum = ipv4::pseudo_header_sum(
+        header.source,
+        header.destination,
+        PROTOCOL_TCP,
+        packet.len(),
+    );
+    if ipv4::checksum_finish(ipv4::checksum_add(sum, packet)) != 0 {
+        return;
+    }
+    let Some((source_port, destination_port, segment)) = parse(packet) else {
+        return;
+    };
+    if !ipv4::is_local(header.destination) {
+        return;
+    }
+    let ends = Endpoints {
+        local: header.destination,
+        local_port: destination_port,
+        remote: header.source,
+        remote_port: source_port,
+    };
+
+    let mut connections = CONNECTIONS.lock();
+    if let Some(connection) = connections.get(&ends).cloned() {
+        drop(connections);
+        connection.receive(&segment);
+        return;
+    }
+    if segment.flags & RST != 0 {
+        return;
+    }
+    if segment.flags & (SYN | ACK) != SYN {
+        drop(connections);
+        reset(&Link::new(ends), &segment);
+        return;
+    }
+    let Some(listener) = LISTENERS
+        .lock()
+        .get(&destination_port)
+        .and_then(Weak::upgrade)
+    else {
+        drop(connections);
+        reset(&Link::new(ends), &segment);
+        return;
+    };
+
+    // half open connections count against the backlog too
+    let half_open = connections
+        .values()
+        .filter(|connection| {
+            connection.link.ends.local_port == destination_port
+                && connection.tcb.lock().state == State::SynReceived
+        })
+        .count();
+    if half_open + listener.backlog.lock().len() >= BACKLOG {
+        return;
+    }
+    let mut tcb = Tcb::new(State::SynReceived, initial_sequence());
+    tcb.rcv_nxt = segment.seq.wrapping_add(1);
+    tcb.snd_wnd = segment.window as u32;
+    tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+    tcb.cwnd = tcb.mss;
+    tcb.retransmit_at = Some(time::uptime_ms() + tcb.rto);
+    let link = Link::new(ends);
+    tcb.send_syn(&link);
+    let connection = Connection::new(link, tcb, Some(Arc::downgrade(&listener)));
+    connections.insert(ends, connection);
+}
+
+/// Runs the timers of every connection and forgets the closed ones. Called
+/// by the network thread.
+pub(super) fn tick() {
+    let now = time::uptime_ms();
+    let connections: Vec<(Endpoints, Arc<Connection>)> = CONNECTIONS
+        .lock()
+        .iter()
+        .map(|(ends, connection)| (*ends, connection.clone()))
+        .collect();
+    let closed: Vec<Endpoints> = connections
+        .into_iter()
+        .filter(|(_, connection)| connection.tick(now))
+        .map(|(ends, _)| ends)
+        .collect();
+    if !closed.is_empty() {
+        let mut connections = CONNECTIONS.lock();
+        for ends in closed {
+            connections.remove(&ends);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+
+    /// Sequence number, acknowledgement number, flags and data of a segment.
+    type Sent = (u32, u32, u8, Vec<u8>);
+
+    std::thread_local! {
+        /// Segments sent on this thread.
+        static SENT: RefCell<Vec<Sent>> = const { RefCell::new(Vec::new()) };
+    }
+
+    const ENDS: Endpoints = Endpoints {
+        local: Ipv4Address::new(10, 0, 2, 15),
+        local_port: 49152,
+        remote: Ipv4Address::new(10, 0, 2, 2),
+        remote_port: 80,
+    };
+    const ISS: u32 = 1000;
+    const PEER_ISS: u32 = 5000;
+
+    fn capture(_: &Endpoints, segment: &Segment) -> Result<(), NetError> {
+        let sent = (
+            segment.seq,
+            segment.ack,
+            segment.flags,
+            segment.data.to_vec(),
+        );
+        SENT.with(|segments| segments.borrow_mut().push(sent));
+        Ok(())
+    }
+
+    /// The segments sent since the last call.
+    fn sent() -> Vec<Sent> {
+        SENT.with(|segments| segments.take())
+    }
+
+    fn link() -> Link {
+        Link {
+            ends: ENDS,
+            transmit: capture,
+        }
+    }
+
+    fn segment(seq: u32, ack: u32, flags: u8, data: &[u8]) -> Segment<'_> {
+        Segment {
+            seq,
+            ack,
+            flags,
+            window: 8192,
+            mss: None,
+            data,
+        }
+    }
+
+    fn state(connection: &Connection) -> State {
+        connection.tcb.lock().state
+    }
+
+    /// A connection that sent its SYN and got the peer's SYN and
+    /// acknowledgement in return.
+    fn established() -> Arc<Connection> {
+        let connection = Connection::new(link(), Tcb::new(State::SynSent, ISS), None);
+        connection.receive(&segment(PEER_ISS, ISS + 1, SYN | ACK, &[]));
+        assert_eq!(state(&connection), State::Established);
+        sent();
+        connection
+    }
+
+    /// A stream on `connection`, which closes it when dropped.
+    fn stream(connection: &Arc<Connection>) -> TcpStream {
+        TcpStream {
+            connection: connection.clone(),
+        }
+    }
+
+    #[test]
+    fn sequence_order() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 1));
+        assert!(!seq_lt(5, 5));
+        assert!(seq_le(5, 5));
+        assert!(seq_le(1, 2));
+        assert!(!seq_le(2, 1));
+    }
+
+    #[test]
+    fn sequence_order_wraps_around() {
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_lt(u32::MAX - 10, 10));
+        assert!(!seq_lt(10, u32::MAX - 10));
+        assert!(seq_le(u32::MAX, 0));
+        assert!(!seq_le(0, u32::MAX));
+        // up to just under half the space ahead counts as later
+        assert!(seq_lt(0, 0x7FFF_FFFF));
+        assert!(seq_lt(0xC000_0000, 0x3FFF_FFFF));
+        assert!(!seq_lt(0x3FFF_FFFF, 0xC000_0000));
+    }
+
+    #[test]
+    fn active_open() {
+        let connection = Connection::new(link(), Tcb::new(State::SynSent, ISS), None);
+        // an acknowledgement of something else is answered with a reset
+        connection.receive(&segment(PEER_ISS, ISS + 5, SYN | ACK, &[]));
+        assert_eq!(state(&connection), State::SynSent);
+        assert_eq!(sent(), [(ISS + 5, 0, RST, vec![])]);
+
+        let mut syn_ack = segment(PEER_ISS, ISS + 1, SYN | ACK, &[]);
+        syn_ack.mss = Some(1200);
+        connection.receive(&syn_ack);
+        assert_eq!(state(&connection), State::Established);
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 1, ACK, vec![])]);
+        let tcb = connection.tcb.lock();
+        assert_eq!((tcb.snd_una, tcb.rcv_nxt), (ISS + 1, PEER_ISS + 1));
+        assert_eq!((tcb.mss, tcb.snd_wnd), (1200, 8192));
+    }
+
+    #[test]
+    fn refused_open() {
+        let connection = Connection::new(link(), Tcb::new(State::SynSent, ISS), None);
+        // a reset without an acknowledgement of our SYN might be forged
+        connection.receive(&segment(0, 0, RST, &[]));
+        assert_eq!(state(&connection), State::SynSent);
+
+        connection.receive(&segment(0, ISS + 1, RST | ACK, &[]));
+        let tcb = connection.tcb.lock();
+        assert_eq!(tcb.state, State::Closed);
+        assert_eq!(tcb.error, Some(NetError::ConnectionRefused));
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let connection = Connection::new(link(), Tcb::new(State::SynSent, ISS), None);
+        connection.receive(&segment(PEER_ISS, 0, SYN, &[]));
+        assert_eq!(state(&connection), State::SynReceived);
+        let [(seq, ack, flags, _)] = sent()[..] else {
+            panic!("expected one segment");
+        };
+        assert_eq!((seq, ack, flags), (ISS, PEER_ISS + 1, SYN | ACK));
+
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK, &[]));
+        assert_eq!(state(&connection), State::Established);
+    }
+
+    #[test]
+    fn passive_open() {
+        let listener = TcpListener::bind(0).unwrap();
+        let mut tcb = Tcb::new(State::SynReceived, ISS);
+        tcb.rcv_nxt = PEER_ISS + 1;
+        let connection = Connection::new(link(), tcb, Some(Arc::downgrade(&listener)));
+
+        connection.receive(&segment(PEER_ISS + 1, ISS + 7, ACK, &[]));
+        assert_eq!(state(&connection), State::SynReceived);
+        assert_eq!(sent(), [(ISS + 7, 0, RST, vec![])]);
+
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK, &[]));
+        assert_eq!(state(&connection), State::Established);
+        let accepted = listener.accept(Some(0)).unwrap();
+        assert!(Arc::ptr_eq(&accepted.connection, &connection));
+    }
+
+    #[test]
+    fn data_is_acknowledged_and_buffered() {
+        let connection = established();
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK | PSH, b"hello"));
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 6, ACK, vec![])]);
+        let mut buf = [0; 8];
+        assert_eq!(stream(&connection).recv(&mut buf, Some(0)), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn passive_close() {
+        let connection = established();
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK | FIN, b"bye"));
+        assert_eq!(state(&connection), State::CloseWait);
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 5, ACK, vec![])]);
+
+        let stream = stream(&connection);
+        let mut buf = [0; 8];
+        assert_eq!(stream.recv(&mut buf, Some(0)), Ok(3));
+        assert_eq!(stream.recv(&mut buf, Some(0)), Ok(0));
+        stream.close();
+        assert_eq!(state(&connection), State::LastAck);
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 5, ACK | FIN, vec![])]);
+
+        connection.receive(&segment(PEER_ISS + 5, ISS + 2, ACK, &[]));
+        assert_eq!(state(&connection), State::Closed);
+        assert_eq!(connection.tcb.lock().error, None);
+    }
+
+    #[test]
+    fn active_close_waits_in_time_wait() {
+        let connection = established();
+        stream(&connection).close();
+        assert_eq!(state(&connection), State::FinWait1);
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 1, ACK | FIN, vec![])]);
+
+        connection.receive(&segment(PEER_ISS + 1, ISS + 2, ACK, &[]));
+        assert_eq!(state(&connection), State::FinWait2);
+        connection.receive(&segment(PEER_ISS + 1, ISS + 2, ACK | FIN, &[]));
+        assert_eq!(state(&connection), State::TimeWait);
+        assert_eq!(sent(), [(ISS + 2, PEER_ISS + 2, ACK, vec![])]);
+
+        // a retransmitted FIN is acknowledged again
+        connection.receive(&segment(PEER_ISS + 1, ISS + 2, ACK | FIN, &[]));
+        assert_eq!(sent(), [(ISS + 2, PEER_ISS + 2, ACK, vec![])]);
+
+        let until = connection.tcb.lock().time_wait_until;
+        assert!(!connection.tick(until - 1));
+        assert!(connection.tick(until));
+        assert_eq!(state(&connection), State::Closed);
+        assert_eq!(connection.tcb.lock().error, None);
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let connection = established();
+        stream(&connection).close();
+        sent();
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK | FIN, &[]));
+        assert_eq!(state(&connection), State::Closing);
+        connection.receive(&segment(PEER_ISS + 2, ISS + 2, ACK, &[]));
+        assert_eq!(state(&connection), State::TimeWait);
+    }
+
+    #[test]
+    fn reset_only_counts_at_the_expected_sequence_number() {
+        let connection = established();
+        connection.receive(&segment(PEER_ISS + 100, 0, RST, &[]));
+        connection.receive(&segment(PEER_ISS, 0, RST, &[]));
+        assert_eq!(state(&connection), State::Established);
+        assert!(sent().is_empty());
+
+        connection.receive(&segment(PEER_ISS + 1, 0, RST, &[]));
+        let tcb = connection.tcb.lock();
+        assert_eq!(tcb.state, State::Closed);
+        assert_eq!(tcb.error, Some(NetError::ConnectionReset));
+    }
+
+    #[test]
+    fn out_of_window_segments_are_not_taken() {
+        let connection = established();
+        // ahead of what we expect: dropped and answered with what we expect
+        connection.receive(&segment(PEER_ISS + 11, ISS + 1, ACK, b"later"));
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 1, ACK, vec![])]);
+        assert!(connection.tcb.lock().recv_buffer.is_empty());
+
+        // partly old: only the new part is taken
+        connection.receive(&segment(PEER_ISS + 1, ISS + 1, ACK, b"abc"));
+        connection.receive(&segment(PEER_ISS + 2, ISS + 1, ACK, b"bcdef"));
+        assert_eq!(connection.tcb.lock().recv_buffer, b"abcdef");
+        assert_eq!(connection.tcb.lock().rcv_nxt, PEER_ISS + 7);
+        sent();
+
+        // acknowledges something we never sent
+        connection.receive(&segment(PEER_ISS + 7, ISS + 50, ACK, &[]));
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 7, ACK, vec![])]);
+        assert_eq!(connection.tcb.lock().snd_una, ISS + 1);
+    }
+
+    #[test]
+    fn retransmission_timeout_backs_off() {
+        let connection = established();
+        let stream = stream(&connection);
+        assert_eq!(stream.send(b"data"), Ok(4));
+        assert_eq!(
+            sent(),
+            [(ISS + 1, PEER_ISS + 1, ACK | PSH, b"data".to_vec())]
+        );
+
+        let mut rto = INITIAL_RTO_MS;
+        let mut at = connection.tcb.lock().retransmit_at.unwrap();
+        assert!(!connection.tick(at - 1));
+        assert!(sent().is_empty());
+        for _ in 0..MAX_RETRANSMISSIONS {
+            assert!(!connection.tick(at));
+            assert_eq!(
+                sent(),
+                [(ISS + 1, PEER_ISS + 1, ACK | PSH, b"data".to_vec())]
+            );
+            rto = (rto * 2).min(MAX_RTO_MS);
+            let tcb = connection.tcb.lock();
+            assert_eq!(tcb.rto, rto);
+            assert_eq!(tcb.retransmit_at, Some(at + rto));
+            at += rto;
+        }
+
+        assert!(connection.tick(at));
+        assert_eq!(sent(), [(ISS + 5, PEER_ISS + 1, RST | ACK, vec![])]);
+        let tcb = connection.tcb.lock();
+        assert_eq!(tcb.state, State::Closed);
+        assert_eq!(tcb.error, Some(NetError::TimedOut));
+    }
+
+    #[test]
+    fn acknowledgement_ends_the_backoff() {
+        let connection = established();
+        let stream = stream(&connection);
+        stream.send(b"data").unwrap();
+        let at = connection.tcb.lock().retransmit_at.unwrap();
+        connection.tick(at);
+        assert_eq!(connection.tcb.lock().rto, 2 * INITIAL_RTO_MS);
+
+        connection.receive(&segment(PEER_ISS + 1, ISS + 5, ACK, &[]));
+        let tcb = connection.tcb.lock();
+        assert_eq!(tcb.rto, INITIAL_RTO_MS);
+        assert_eq!((tcb.retransmissions, tcb.retransmit_at), (0, None));
+        assert!(tcb.send_buffer.is_empty());
+    }
+
+    #[test]
+    fn zero_window_is_probed_without_giving_up() {
+        let connection = established();
+        let mut closed = segment(PEER_ISS + 1, ISS + 1, ACK, &[]);
+        closed.window = 0;
+        connection.receive(&closed);
+        let stream = stream(&connection);
+        stream.send(b"abc").unwrap();
+        // one byte at a time
+        assert_eq!(sent(), [(ISS + 1, PEER_ISS + 1, ACK, b"a".to_vec())]);
+
+        for _ in 0..2 * MAX_RETRANSMISSIONS {
+            let at = connection.tcb.lock().retransmit_at.unwrap();
+            assert!(!connection.tick(at));
+            assert_eq!(sent(), [(ISS + 1, PEER_ISS + 1, ACK, b"a".to_vec())]);
+        }
+        assert_eq!(state(&connection), State::Established);
+        assert_eq!(connection.tcb.lock().retransmissions, 0);
+
+        // the window opens with the acknowledgement of the probe
+        connection.receive(&segment(PEER_ISS + 1, ISS + 2, ACK, &[]));
+        assert_eq!(sent(), [(ISS + 2, PEER_ISS + 1, ACK | PSH, b"bc".to_vec())]);
+    }
+}
@@ -0,0 +1,209 @@
+//! UDP: datagrams between ports.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{
+    ipv4::{self, Header, Ipv4Address, PROTOCOL_UDP},
+    NetError,
+};
+use crate::sync::WaitQueue;
+
+const HEADER_LEN: usize = 8;
+/// Largest datagram that is sent without fragmentation.
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_LEN;
+/// Most datagrams waiting in a socket; more are dropped.
+const MAX_QUEUED: usize = 64;
+
+static SOCKETS: spin::Mutex<BTreeMap<u16, Weak<UdpSocket>>> = spin::Mutex::new(BTreeMap::new());
+
+pub struct Datagram {
+    pub source: Ipv4Address,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+/// A bound port. The port is free again once the socket is dropped.
+pub struct UdpSocket {
+    port: u16,
+    queue: spin::Mutex<VecDeque<Datagram>>,
+    /// Bumped whenever a datagram is queued.
+    arrivals: AtomicU64,
+    arrived: WaitQueue,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<Arc<Self>, NetError> {
+        let mut sockets = SOCKETS.lock();
+        sockets.retain(|_, socket| socket.strong_count() > 0);
+        let port = match port {
+            0 => super::ephemeral_port(|port| sockets.contains_key(&port))
+                .ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let socket = Arc::new(UdpSocket {
+            port,
+            queue: spin::Mutex::new(VecDeque::new()),
+            arrivals: AtomicU64::new(0),
+            arrived: WaitQueue::new(),
+        });
+        sockets.insert(port, Arc::downgrade(&socket));
+        Ok(socket)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` to `port` of `destination` in one datagram.
+    pub fn send_to(
+        &self,
+        data: &[u8],
+        destination: Ipv4Address,
+        port: u16,
+    ) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::InvalidArgument);
+        }
+        let (interface, next_hop, source) =
+            ipv4::route(destination).ok_or(NetError::Unreachable)?;
+        let datagram = self.datagram(source, destination, port, data);
+        ipv4::send_on(
+            &interface,
+            source,
+            destination,
+            next_hop,
+            PROTOCOL_UDP,
+            &datagram,
+        )
+    }
+
+    /// Sends `data` to `port` of every host on `interface`, from `source`,
+    /// which may be unspecified while the interface has no address.
+    pub fn broadcast(
+        &self,
+        interface: &str,
+        source: Ipv4Address,
+        data: &[u8],
+        port: u16,
+    ) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::InvalidArgument);
+        }
+        let destination = Ipv4Address::BROADCAST;
+        let datagram = self.datagram(source, destination, port, data);
+        ipv4::send_on(
+            interface,
+            source,
+            destination,
+            destination,
+            PROTOCOL_UDP,
+            &datagram,
+        )
+    }
+
+    fn datagram(
+        &self,
+        source: Ipv4Address,
+        destination: Ipv4Address,
+        port: u16,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let len = HEADER_LEN + data.len();
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.port.to_be_bytes());
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_UDP, len);
+        // a checksum of zero means there is none
+        let checksum = match ipv4::checksum_finish(ipv4::checksum_add(sum, &datagram)) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        datagram
+    }
+
+    /// Takes the oldest datagram that arrived, waiting for one at most
+    /// `timeout_ms` milliseconds, or forever if that is `None`.
+    pub fn recv(&self, timeout_ms: Option<u64>) -> Result<Datagram, NetError> {
+        loop {
+            let seen = self.arrivals.load(Ordering::Acquire);
+            if let Some(datagram) = self.queue.lock().pop_front() {
+                return Ok(datagram);
+            }
+            let unchanged = || self.arrivals.load(Ordering::Acquire) == seen;
+            match timeout_ms {
+                Some(ms) => {
+                    if !self.arrived.wait_while_timeout(ms, unchanged)? {
+                        return Err(NetError::TimedOut);
+                    }
+                }
+                None => self.arrived.wait_while(unchanged)?,
+            }
+        }
+    }
+
+    /// Like [`UdpSocket::recv`], but copies the data into `buf`, cutting off
+    /// what does not fit. Returns the length copied and the sender.
+    pub fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout_ms: Option<u64>,
+    ) -> Result<(usize, Ipv4Address, u16), NetError> {
+        let datagram = self.recv(timeout_ms)?;
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Ok((len, datagram.source, datagram.port))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Takes a datagram that arrived in a packet described by `header`.
+pub(super) fn handle(header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_LEN {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_LEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if datagram[6..8] != [0, 0] {
+        let sum = ipv4::pseudo_header_sum(header.source, header.destination, PROTOCOL_UDP, len);
+        if ipv4::checksum_finish(ipv4::checksum_add(sum, datagram)) != 0 {
+            return;
+        }
+    }
+
+    let port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let Some(socket) = SOCKETS.lock().get(&port).and_then(Weak::upgrade) else {
+        return;
+    };
+    {
+        let mut queue = socket.queue.lock();
+        if queue.len() >= MAX_QUEUED {
+            return;
+        }
+        queue.push_back(Datagram {
+            source: header.source,
+            port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            data: datagram[HEADER_LEN..].to_vec(),
+        });
+    }
+    socket.arrivals.fetch_add(1, Ordering::Release);
+    socket.arrived.notify_all();
+}
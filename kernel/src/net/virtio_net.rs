@@ -0,0 +1,205 @@
+//! Network cards on virtio-net, the paravirtualized NIC of QEMU and friends.
+//!
+//! The receive queue is kept full of frames for the device to write into.
+//! Its interrupt only announces that frames arrived; they are copied out
+//! and their buffers offered again when someone asks for them. Sent frames
+//! are reclaimed on the next send, so the transmit queue needs no interrupt.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{MacAddress, NetDevice, NetError};
+use crate::{
+    colors, interrupts, log_info,
+    memory::DmaFrame,
+    pci::{self, DeviceMatch, PciDevice, PciDriver},
+    sync::Mutex,
+    virtio::{self, Buffer, Transport, Virtqueue, NO_VECTOR},
+};
+
+const DEVICE_ID_TRANSITIONAL: u16 = 0x1000;
+const DEVICE_ID_MODERN: u16 = 0x1041;
+
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+// device configuration
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+
+const STATUS_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+/// Every frame is preceded by a header describing offloads, which are not
+/// used, so it stays zero.
+const HEADER_LEN: usize = 12;
+const FRAME_SIZE: usize = 4096;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    matches: &[
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: DEVICE_ID_TRANSITIONAL,
+        },
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: DEVICE_ID_MODERN,
+        },
+    ],
+    probe,
+};
+
+/// A virtqueue and the frames the device holds, by the head of their chain.
+struct Queue {
+    virtqueue: Virtqueue,
+    frames: Vec<Option<DmaFrame>>,
+}
+
+impl Queue {
+    fn new(virtqueue: Virtqueue) -> Self {
+        Queue {
+            frames: (0..virtqueue.size()).map(|_| None).collect(),
+            virtqueue,
+        }
+    }
+
+    /// Offers `frame` to the device, `len` bytes of it. Gives the frame
+    /// back if the queue is full.
+    fn add(&mut self, frame: DmaFrame, len: usize, writable: bool) -> Result<(), DmaFrame> {
+        let buffer = Buffer {
+            addr: frame.phys(),
+            len: len as u32,
+            writable,
+        };
+        match self.virtqueue.add(&[buffer]) {
+            Some(head) => {
+                self.frames[head as usize] = Some(frame);
+                Ok(())
+            }
+            None => Err(frame),
+        }
+    }
+
+    /// Takes back the next frame the device is done with, and how many bytes
+    /// it wrote into it.
+    fn pop(&mut self) -> Option<(DmaFrame, usize)> {
+        let (head, len) = self.virtqueue.pop_used()?;
+        let frame = self.frames[head as usize].take()?;
+        Some((frame, len as usize))
+    }
+}
+
+pub struct VirtioNet {
+    transport: Transport,
+    mac: MacAddress,
+    has_status: bool,
+    receive_queue: Mutex<Queue>,
+    transmit_queue: Mutex<Queue>,
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.has_status || self.transport.read_config::<u16>(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.check_frame(frame)?;
+        let mut queue = self.transmit_queue.lock();
+        // frames already sent are reused rather than given back
+        let mut sent = None;
+        while let Some((buffer, _)) = queue.pop() {
+            sent = Some(buffer);
+        }
+
+        let mut buffer = match sent {
+            Some(buffer) => buffer,
+            None => DmaFrame::new().ok_or(NetError::Io)?,
+        };
+        buffer.as_mut_slice()[HEADER_LEN..HEADER_LEN + frame.len()].copy_from_slice(frame);
+        queue
+            .add(buffer, HEADER_LEN + frame.len(), false)
+            .map_err(|_| NetError::Busy)?;
+        queue.virtqueue.notify();
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut queue = self.receive_queue.lock();
+        loop {
+            let (buffer, len) = queue.pop()?;
+            let frame = (len > HEADER_LEN).then(|| buffer.as_slice()[HEADER_LEN..len].to_vec());
+            // the buffer came out of the queue, so there is room for it
+            let _ = queue.add(buffer, FRAME_SIZE, true);
+            queue.virtqueue.notify();
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(F_MAC | F_STATUS)?;
+    let (receive, transmit) = transport
+        .setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)
+        .and_then(|receive| Ok((receive, transport.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?)))
+        .inspect_err(|_| transport.fail())?;
+
+    let mut receive_queue = Queue::new(receive);
+    for _ in 0..receive_queue.virtqueue.size() {
+        let frame = DmaFrame::new()
+            .ok_or("out of memory for receive buffers")
+            .inspect_err(|_| transport.fail())?;
+        let _ = receive_queue.add(frame, FRAME_SIZE, true);
+    }
+
+    // without a MAC from the device, make up a locally administered one
+    let mac = if features & F_MAC != 0 {
+        MacAddress(core::array::from_fn(|i| {
+            transport.read_config(CONFIG_MAC + i as u64)
+        }))
+    } else {
+        MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, super::devices().len() as u8])
+    };
+
+    let vector = interrupts::allocate_vector(Arc::new(super::frames_arrived))
+        .ok_or("out of interrupt vectors")
+        .inspect_err(|_| transport.fail())?;
+    let started = device.enable_msix(&[vector]).and_then(|_| {
+        transport.enable_queue(RECEIVE_QUEUE, 0)?;
+        transport.enable_queue(TRANSMIT_QUEUE, NO_VECTOR)
+    });
+    if let Err(err) = started {
+        interrupts::free_vector(vector);
+        transport.fail();
+        return Err(err);
+    }
+    transport.driver_ok();
+    receive_queue.virtqueue.notify();
+
+    let nic = Arc::new(VirtioNet {
+        transport,
+        mac,
+        has_status: features & F_STATUS != 0,
+        receive_queue: Mutex::new(receive_queue),
+        transmit_queue: Mutex::new(Queue::new(transmit)),
+    });
+    let link = if nic.link_up() { "up" } else { "down" };
+    let name = super::register(nic);
+    log_info!("{}: virtio-net, MAC {}, link {}", name, mac, link);
+    Ok(())
+}
+
+/// Registers the driver, which takes every virtio-net device on the PCI
+/// bus. Returns how many there were.
+pub fn init() -> usize {
+    pci::register_driver(&DRIVER)
+}
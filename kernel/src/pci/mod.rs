@@ -0,0 +1,593 @@
+//! PCI bus enumeration.
+//!
+//! Configuration space is reached through the memory-mapped ECAM windows the
+//! ACPI MCFG table describes, or through the legacy 0xCF8/0xCFC ports when
+//! there is no such table. Every function found at boot is recorded with its
+//! BARs and capabilities; drivers then claim devices by ID or class code.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{fmt, ptr};
+
+use conquer_once::spin::OnceCell;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{acpi::ACPI, colors, log_info, log_warn, memory, sync::IrqSpinLock};
+
+mod msi;
+
+pub use self::msi::MsiX;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// configuration space registers
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0E;
+const REG_BAR0: u16 = 0x10;
+const REG_SECONDARY_BUS: u16 = 0x19;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3C;
+const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Size of the configuration space of one bus in an ECAM window.
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+static CONFIG: OnceCell<ConfigSpace> = OnceCell::uninit();
+static DEVICES: OnceCell<Vec<Arc<PciDevice>>> = OnceCell::uninit();
+static DRIVERS: spin::Mutex<Vec<&'static PciDriver>> = spin::Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// One segment's window of memory-mapped configuration space.
+struct EcamWindow {
+    base: PhysAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /// Buses are mapped when first touched, 1 MiB each.
+    mapped: IrqSpinLock<BTreeMap<u8, VirtAddr>>,
+}
+
+enum ConfigSpace {
+    Legacy(IrqSpinLock<()>),
+    Ecam(Vec<EcamWindow>),
+}
+
+impl ConfigSpace {
+    fn from_mcfg() -> Option<ConfigSpace> {
+        let mcfg = ACPI.get()?.find_table(b"MCFG")?;
+        let bytes = unsafe { crate::acpi::table_bytes(mcfg) };
+        // the header is followed by 8 reserved bytes, then 16 bytes per window
+        let windows: Vec<EcamWindow> = bytes
+            .get(44..)?
+            .chunks_exact(16)
+            .map(|entry| EcamWindow {
+                base: PhysAddr::new(u64::from_le_bytes(entry[0..8].try_into().unwrap())),
+                segment: u16::from_le_bytes([entry[8], entry[9]]),
+                start_bus: entry[10],
+                end_bus: entry[11],
+                mapped: IrqSpinLock::new(BTreeMap::new()),
+            })
+            .collect();
+        (!windows.is_empty()).then_some(ConfigSpace::Ecam(windows))
+    }
+
+    /// Virtual address of the 32-bit register at `offset` of `address`.
+    fn ecam_register(windows: &[EcamWindow], address: PciAddress, offset: u16) -> Option<*mut u32> {
+        let window = windows.iter().find(|window| {
+            window.segment == address.segment
+                && (window.start_bus..=window.end_bus).contains(&address.bus)
+        })?;
+        let bus = *window.mapped.lock().entry(address.bus).or_insert_with(|| {
+            let bus_base = window.base + (address.bus - window.start_bus) as u64 * ECAM_BUS_SIZE;
+            memory::map_mmio(bus_base, ECAM_BUS_SIZE)
+        });
+        let function = (address.device as u64) << 15 | (address.function as u64) << 12;
+        Some((bus + function + (offset & 0xFFC) as u64).as_mut_ptr())
+    }
+
+    fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        match self {
+            ConfigSpace::Legacy(lock) => {
+                if address.segment != 0 || offset >= 0x100 {
+                    return u32::MAX;
+                }
+                let _lock = lock.lock();
+                unsafe {
+                    Port::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+                    Port::<u32>::new(CONFIG_DATA).read()
+                }
+            }
+            ConfigSpace::Ecam(windows) => match Self::ecam_register(windows, address, offset) {
+                Some(register) => unsafe { ptr::read_volatile(register) },
+                None => u32::MAX,
+            },
+        }
+    }
+
+    fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        match self {
+            ConfigSpace::Legacy(lock) => {
+                if address.segment != 0 || offset >= 0x100 {
+                    return;
+                }
+                let _lock = lock.lock();
+                unsafe {
+                    Port::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+                    Port::new(CONFIG_DATA).write(value);
+                }
+            }
+            ConfigSpace::Ecam(windows) => {
+                if let Some(register) = Self::ecam_register(windows, address, offset) {
+                    unsafe { ptr::write_volatile(register, value) };
+                }
+            }
+        }
+    }
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xFC) as u32
+}
+
+fn config() -> &'static ConfigSpace {
+    CONFIG.get().expect("PCI not initialized")
+}
+
+/// Reads the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    config().read(address, offset)
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    config().write(address, offset, value)
+}
+
+/// Writes 16 bits by rewriting the register they are part of.
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let register = read_u32(address, offset) & !(0xFFFF << shift);
+    write_u32(address, offset, register | (value as u32) << shift);
+}
+
+/// Writes the command register. The status register next to it has bits
+/// that are cleared by writing ones, so it gets zeros.
+fn write_command(address: PciAddress, command: u16) {
+    write_u32(address, REG_COMMAND, command as u32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in configuration space.
+    pub offset: u16,
+}
+
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Legacy interrupt line and pin (1 for INTA# through 4 for INTD#, 0 for none).
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// Name of the driver that claimed the device.
+    driver: spin::Mutex<Option<&'static str>>,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = read_u16(address, REG_VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class = read_u32(address, REG_CLASS);
+        let header_type = read_u8(address, REG_HEADER_TYPE) & HEADER_TYPE_MASK;
+        // bridges only have two BARs, card bus bridges none we care about
+        let bar_count = match header_type {
+            0 => 6,
+            HEADER_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [None; 6];
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = size_bar(address, index);
+            bars[index] = bar;
+            index += slots;
+        }
+
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: read_u16(address, REG_DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            interrupt_line: read_u8(address, REG_INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, REG_INTERRUPT_PIN),
+            bars,
+            capabilities: read_capabilities(address),
+            driver: spin::Mutex::new(None),
+        })
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        read_u32(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        read_u16(self.address, offset)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        read_u8(self.address, offset)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        write_u32(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        write_u16(self.address, offset, value)
+    }
+
+    /// Sets `bits` in the command register.
+    pub fn enable(&self, bits: u16) {
+        let command = self.read_u16(REG_COMMAND);
+        write_command(self.address, command | bits);
+    }
+
+    /// Lets the device access memory by itself, which DMA needs.
+    pub fn enable_bus_master(&self) {
+        self.enable(COMMAND_BUS_MASTER);
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|cap| cap.id == id)
+    }
+
+    /// All capabilities with the given ID, e.g. the vendor-specific ones.
+    pub fn capabilities_with_id(&self, id: u8) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(move |cap| cap.id == id)
+    }
+
+    /// Maps the memory BAR `index` and turns on memory decoding.
+    pub fn map_bar(&self, index: usize) -> Option<VirtAddr> {
+        match self.bars.get(index).copied().flatten()? {
+            Bar::Memory { address, size, .. } => {
+                self.enable(COMMAND_MEMORY_SPACE);
+                Some(memory::map_mmio(address, size))
+            }
+            Bar::Io { .. } => None,
+        }
+    }
+
+    /// First port of the I/O BAR `index`; turns on I/O decoding.
+    pub fn io_bar(&self, index: usize) -> Option<u16> {
+        match self.bars.get(index).copied().flatten()? {
+            Bar::Io { port, .. } => {
+                self.enable(COMMAND_IO_SPACE);
+                Some(port)
+            }
+            Bar::Memory { .. } => None,
+        }
+    }
+
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+
+    /// A short description of the class code, for listings.
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {} ({:02x}:{:02x}:{:02x})",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.prog_if
+        )
+    }
+}
+
+/// Finds out what BAR `index` decodes by writing all ones to it and reading
+/// back which bits stuck. Returns the BAR and how many slots it takes.
+fn size_bar(address: PciAddress, index: usize) -> (Option<Bar>, usize) {
+    let offset = REG_BAR0 + index as u16 * 4;
+    let original = read_u32(address, offset);
+
+    // no decoding while the BAR holds the probe value
+    let command = read_u16(address, REG_COMMAND);
+    write_command(
+        address,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+
+    let result = if original & 1 == 1 {
+        write_u32(address, offset, u32::MAX);
+        // I/O space is only 64 KiB, the upper half may read as zeros
+        let mask = read_u32(address, offset) & !0x3 | 0xFFFF_0000;
+        write_u32(address, offset, original);
+        let size = (!mask).wrapping_add(1);
+        let bar = (mask != 0xFFFF_0000).then_some(Bar::Io {
+            port: (original & !0x3) as u16,
+            size,
+        });
+        (bar, 1)
+    } else {
+        let is_64 = (original >> 1) & 0x3 == 0x2;
+        let original_high = if is_64 {
+            read_u32(address, offset + 4)
+        } else {
+            0
+        };
+        write_u32(address, offset, u32::MAX);
+        let mut mask = (read_u32(address, offset) & !0xF) as u64;
+        write_u32(address, offset, original);
+        if is_64 {
+            write_u32(address, offset + 4, u32::MAX);
+            mask |= (read_u32(address, offset + 4) as u64) << 32;
+            write_u32(address, offset + 4, original_high);
+        } else {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        let bar = (mask & 0xFFFF_FFF0 != 0).then(|| Bar::Memory {
+            address: PhysAddr::new((original & !0xF) as u64 | (original_high as u64) << 32),
+            size: (!mask).wrapping_add(1),
+            prefetchable: original & 0x8 != 0,
+        });
+        (bar, if is_64 { 2 } else { 1 })
+    };
+
+    write_command(address, command);
+    result
+}
+
+fn read_capabilities(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if read_u16(address, REG_STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = (read_u8(address, REG_CAPABILITIES) & 0xFC) as u16;
+    // a broken list could loop; there is room for at most 48 entries
+    while offset != 0 && capabilities.len() < 48 {
+        capabilities.push(Capability {
+            id: read_u8(address, offset),
+            offset,
+        });
+        offset = (read_u8(address, offset + 1) & 0xFC) as u16;
+    }
+    capabilities
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        _ => "device",
+    }
+}
+
+/// Scans a bus and, through the bridges on it, the buses behind them.
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        for function in 0..8 {
+            let address = PciAddress {
+                segment,
+                bus,
+                device,
+                function,
+            };
+            let Some(found) = PciDevice::probe(address) else {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            };
+            devices.push(found);
+            let header_type = read_u8(address, REG_HEADER_TYPE);
+            if header_type & HEADER_TYPE_MASK == HEADER_PCI_BRIDGE {
+                let secondary = read_u8(address, REG_SECONDARY_BUS);
+                // a bridge pointing back at its own bus is not configured
+                if secondary > bus {
+                    scan_bus(segment, secondary, devices);
+                }
+            }
+            if function == 0 && header_type & HEADER_MULTI_FUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Finds the configuration space mechanism, scans every bus and lists the
+/// devices. Returns how many there are.
+pub fn init() -> usize {
+    let config = ConfigSpace::from_mcfg().unwrap_or(ConfigSpace::Legacy(IrqSpinLock::new(())));
+    let roots: Vec<(u16, u8)> = match &config {
+        ConfigSpace::Legacy(_) => vec![(0, 0)],
+        ConfigSpace::Ecam(windows) => windows
+            .iter()
+            .map(|window| (window.segment, window.start_bus))
+            .collect(),
+    };
+    log_info!(
+        "PCI configuration through {}",
+        match config {
+            ConfigSpace::Legacy(_) => "I/O ports",
+            ConfigSpace::Ecam(_) => "ECAM",
+        }
+    );
+    CONFIG.init_once(|| config);
+
+    let mut devices = Vec::new();
+    for (segment, bus) in roots {
+        scan_bus(segment, bus, &mut devices);
+    }
+    devices.sort_by_key(|device| device.address);
+    devices.dedup_by_key(|device| device.address);
+    for device in &devices {
+        log_info!("PCI {}", device);
+    }
+    let count = devices.len();
+    DEVICES.init_once(|| devices.into_iter().map(Arc::new).collect());
+    count
+}
+
+pub fn devices() -> &'static [Arc<PciDevice>] {
+    DEVICES.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl DeviceMatch {
+    fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            DeviceMatch::Id { vendor, device: id } => {
+                device.vendor_id == vendor && device.device_id == id
+            }
+            DeviceMatch::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Sets up a matching device. A device stays unclaimed if this fails.
+    pub probe: fn(&Arc<PciDevice>) -> Result<(), &'static str>,
+}
+
+/// Registers `driver` and probes it on every unclaimed device it matches.
+/// Returns how many devices it took.
+pub fn register_driver(driver: &'static PciDriver) -> usize {
+    DRIVERS.lock().push(driver);
+    let mut claimed = 0;
+    for device in devices() {
+        if device.driver().is_some() || !driver.matches.iter().any(|m| m.matches(device)) {
+            continue;
+        }
+        match (driver.probe)(device) {
+            Ok(()) => {
+                *device.driver.lock() = Some(driver.name);
+                claimed += 1;
+            }
+            Err(err) => {
+                log_warn!(
+                    "{}: cannot use PCI {}: {}",
+                    driver.name,
+                    device.address,
+                    err
+                );
+            }
+        }
+    }
+    claimed
+}
+
+/// Names of the registered drivers.
+pub fn drivers() -> Vec<&'static str> {
+    DRIVERS.lock().iter().map(|driver| driver.name).collect()
+}
@@ -0,0 +1,123 @@
+//! Message signalled interrupts: MSI and its table-based successor MSI-X.
+//!
+//! Messages are sent straight to the local APIC of the CPU that enabled
+//! them, as fixed edge-triggered interrupts.
+
+use core::ptr;
+
+use x86_64::VirtAddr;
+
+use super::{PciDevice, CAP_MSI, CAP_MSIX, COMMAND_INTX_DISABLE};
+use crate::apic;
+
+const MSI_ENABLE: u16 = 1 << 0;
+/// Bits 4 to 6 of the message control: how many vectors are enabled, as a power of two.
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// Address of the local APIC message window.
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+
+fn message_address() -> u32 {
+    MESSAGE_ADDRESS | apic::id() << 12
+}
+
+impl PciDevice {
+    /// Makes the device signal its interrupt as `vector` and turns off its
+    /// legacy interrupt line.
+    pub fn enable_msi(&self, vector: u8) -> Result<(), &'static str> {
+        let cap = self.find_capability(CAP_MSI).ok_or("no MSI capability")?;
+        let control = self.read_u16(cap.offset + 2);
+        self.write_u32(cap.offset + 4, message_address());
+        let data_offset = if control & MSI_64_BIT != 0 {
+            self.write_u32(cap.offset + 8, 0);
+            cap.offset + 12
+        } else {
+            cap.offset + 8
+        };
+        self.write_u16(data_offset, vector as u16);
+        // a single vector
+        self.write_u16(cap.offset + 2, control & !MSI_MULTIPLE_ENABLE | MSI_ENABLE);
+        self.enable(COMMAND_INTX_DISABLE);
+        Ok(())
+    }
+
+    /// Sets up MSI-X with table entry `i` raising `vectors[i]` and turns off
+    /// the legacy interrupt line. Entries past `vectors` stay masked.
+    pub fn enable_msix(&self, vectors: &[u8]) -> Result<MsiX, &'static str> {
+        let cap = self
+            .find_capability(CAP_MSIX)
+            .ok_or("no MSI-X capability")?;
+        let control = self.read_u16(cap.offset + 2);
+        let size = (control & MSIX_TABLE_SIZE) + 1;
+        if vectors.len() > size as usize {
+            return Err("more vectors than MSI-X table entries");
+        }
+        let table = self.read_u32(cap.offset + 4);
+        let bar = self
+            .map_bar((table & 0x7) as usize)
+            .ok_or("MSI-X table is not in a memory BAR")?;
+
+        // entries are programmed with the function masked
+        self.write_u16(cap.offset + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        let msix = MsiX {
+            table: bar + (table & !0x7) as u64,
+            size,
+        };
+        for entry in 0..size {
+            match vectors.get(entry as usize) {
+                Some(&vector) => msix.set_vector(entry, vector),
+                None => msix.set_masked(entry, true),
+            }
+        }
+        self.write_u16(
+            cap.offset + 2,
+            (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+        );
+        self.enable(COMMAND_INTX_DISABLE);
+        Ok(msix)
+    }
+}
+
+/// The mapped MSI-X table of a device.
+pub struct MsiX {
+    table: VirtAddr,
+    size: u16,
+}
+
+impl MsiX {
+    /// Number of entries in the table.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn entry(&self, entry: u16, word: u64) -> *mut u32 {
+        assert!(entry < self.size, "MSI-X entry out of range");
+        (self.table + entry as u64 * MSIX_ENTRY_SIZE + word * 4).as_mut_ptr()
+    }
+
+    /// Points `entry` at `vector` on the calling CPU and unmasks it.
+    pub fn set_vector(&self, entry: u16, vector: u8) {
+        unsafe {
+            ptr::write_volatile(self.entry(entry, 0), message_address());
+            ptr::write_volatile(self.entry(entry, 1), 0);
+            ptr::write_volatile(self.entry(entry, 2), vector as u32);
+        }
+        self.set_masked(entry, false);
+    }
+
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        let control = self.entry(entry, 3);
+        unsafe {
+            let value = ptr::read_volatile(control) & !MSIX_VECTOR_MASKED;
+            let mask = if masked { MSIX_VECTOR_MASKED } else { 0 };
+            ptr::write_volatile(control, value | mask);
+        }
+    }
+}
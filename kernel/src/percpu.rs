@@ -0,0 +1,175 @@
+//! Per-CPU data, reached through the GS base of each CPU.
+
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    registers::model_specific::GsBase,
+    structures::{idt::InterruptStackFrame, tss::TaskStateSegment},
+    VirtAddr,
+};
+
+use crate::{apic, gdt};
+
+pub const MAX_CPUS: usize = 64;
+
+/// Offset of [`PerCpu`]'s kernel stack top, for the syscall entry.
+pub const KERNEL_STACK_OFFSET: usize = 8;
+/// Offset of [`PerCpu`]'s scratch slot for the user stack pointer, for the syscall entry.
+pub const USER_RSP_OFFSET: usize = 16;
+
+/// Data owned by a single CPU. `gs:[0]` always points to the structure itself.
+///
+/// The syscall entry addresses the first fields through GS directly, so their
+/// offsets must match the constants above.
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: *const PerCpu,
+    kernel_stack: AtomicU64,
+    #[allow(dead_code)] // only accessed by the syscall entry
+    user_rsp: AtomicU64,
+    pub cpu_id: usize,
+    pub apic_id: u32,
+    tss: *mut TaskStateSegment,
+    current_thread: AtomicU64,
+    /// Number of hardware interrupt handlers running on this CPU.
+    irq_depth: AtomicUsize,
+}
+
+// `tss` is only ever touched by the CPU owning it
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl PerCpu {
+    pub fn current_thread(&self) -> u64 {
+        self.current_thread.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_thread(&self, id: u64) {
+        self.current_thread.store(id, Ordering::Relaxed);
+    }
+
+    /// Sets the stack this CPU switches to when an interrupt or system call
+    /// arrives in user mode. Must be called with the kernel stack of every
+    /// thread switched to.
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        unsafe { (*self.tss).privilege_stack_table[0] = stack_top };
+        self.kernel_stack.store(stack_top.as_u64(), Ordering::Relaxed);
+    }
+}
+
+/// Switches to the kernel GS base for the duration of an interrupt handler.
+///
+/// While user code runs, the GS base belongs to it and the per-CPU pointer is
+/// parked in `KERNEL_GS_BASE`. Handlers that may interrupt user mode create
+/// one of these first thing, before anything calls [`current`].
+pub struct KernelGsGuard {
+    from_user: bool,
+}
+
+impl KernelGsGuard {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment & 3 == 3;
+        if from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGsGuard { from_user }
+    }
+}
+
+impl Drop for KernelGsGuard {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+/// Marks the calling CPU as handling a hardware interrupt for as long as it
+/// lives; see [`in_interrupt`]. Created after the [`KernelGsGuard`].
+pub struct IrqGuard {
+    counted: bool,
+}
+
+impl IrqGuard {
+    pub fn enter() -> Self {
+        // the clock ticks before the per-CPU data exists
+        let counted = is_initialized();
+        if counted {
+            current().irq_depth.fetch_add(1, Ordering::Relaxed);
+        }
+        IrqGuard { counted }
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        if self.counted {
+            current().irq_depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether the calling CPU is handling a hardware interrupt rather than
+/// running a thread.
+pub fn in_interrupt() -> bool {
+    is_initialized() && current().irq_depth.load(Ordering::Relaxed) > 0
+}
+
+fn register(cpu_id: usize, apic_id: u32, tss: *mut TaskStateSegment) -> &'static PerCpu {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: ptr::null(),
+        kernel_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+        cpu_id,
+        apic_id,
+        tss,
+        current_thread: AtomicU64::new(0),
+        irq_depth: AtomicUsize::new(0),
+    }));
+    per_cpu.self_ptr = per_cpu;
+    GsBase::write(VirtAddr::from_ptr(per_cpu as *const PerCpu));
+    CPUS[cpu_id].store(per_cpu, Ordering::Release);
+    per_cpu
+}
+
+/// Sets up the per-CPU data of the bootstrap processor. Requires the heap and the local APIC.
+pub fn init_bsp() -> &'static PerCpu {
+    CPU_COUNT.store(1, Ordering::Release);
+    register(0, apic::id(), gdt::bsp_tss())
+}
+
+/// Sets up the per-CPU data of an application processor and counts it as online.
+pub fn init_ap(cpu_id: usize, tss: *mut TaskStateSegment) -> &'static PerCpu {
+    let per_cpu = register(cpu_id, apic::id(), tss);
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+    per_cpu
+}
+
+/// Per-CPU data of the calling CPU.
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags)) };
+    unsafe { &*ptr }
+}
+
+pub fn is_initialized() -> bool {
+    CPU_COUNT.load(Ordering::Acquire) > 0
+}
+
+pub fn get(cpu_id: usize) -> Option<&'static PerCpu> {
+    let ptr = CPUS.get(cpu_id)?.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Number of CPUs that are online.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
@@ -0,0 +1,370 @@
+//! Processes: an address space plus the threads, handles and windows running in it.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    address_space::AddressSpace,
+    colors, elf,
+    layer::{Layer, LAYER_CONTROLLER},
+    log_info,
+    net::socket::Socket,
+    sync::{Condvar, Mutex},
+    thread::{self, ThreadId},
+    vfs::{self, File},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Exit status of a process that was killed rather than exiting on its own.
+pub const KILLED_STATUS: i64 = -9;
+/// Exit status of a process terminated because of a CPU exception.
+pub const FAULT_STATUS: i64 = -11;
+
+/// A kernel object a process refers to by a small number.
+/// Open files share the table with the other handles, so it doubles as the
+/// file descriptor table of the process.
+pub enum Handle {
+    Window(Arc<spin::Mutex<Layer>>),
+    File(Arc<dyn File>),
+    Socket(Arc<Socket>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// All threads are gone; the parent has not collected the status yet.
+    Zombie(i64),
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    /// `None` once the process is a zombie.
+    address_space: Option<Arc<AddressSpace>>,
+    threads: Vec<ThreadId>,
+    handles: BTreeMap<u64, Handle>,
+    next_handle: u64,
+    /// Layers created by the process, removed from the screen when it exits.
+    layers: Vec<Arc<spin::Mutex<Layer>>>,
+    state: ProcessState,
+    /// Status passed to [`exit`], reported once the last thread is gone.
+    exit_status: Option<i64>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    /// The user address space, `None` once the process is a zombie.
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>> {
+        self.address_space.as_ref()
+    }
+
+    pub fn add_handle(&mut self, handle: Handle) -> u64 {
+        let id = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(id, handle);
+        id
+    }
+
+    pub fn handle(&self, id: u64) -> Option<&Handle> {
+        self.handles.get(&id)
+    }
+
+    /// The open file behind handle `id`. Sockets can be read and written
+    /// like files too.
+    pub fn file(&self, id: u64) -> Option<Arc<dyn File>> {
+        match self.handles.get(&id)? {
+            Handle::File(file) => Some(file.clone()),
+            Handle::Socket(socket) => Some(socket.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn socket(&self, id: u64) -> Option<Arc<Socket>> {
+        match self.handles.get(&id)? {
+            Handle::Socket(socket) => Some(socket.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn remove_handle(&mut self, id: u64) -> Option<Handle> {
+        self.handles.remove(&id)
+    }
+
+    /// Hands a layer to the process, to be removed from the screen when it exits.
+    pub fn own_layer(&mut self, layer: Arc<spin::Mutex<Layer>>) {
+        self.layers.push(layer);
+    }
+
+    /// Takes a layer back from the process, e.g. when its window is closed.
+    pub fn disown_layer(&mut self, layer: &Arc<spin::Mutex<Layer>>) {
+        self.layers.retain(|owned| !Arc::ptr_eq(owned, layer));
+    }
+
+    /// Frees everything but the exit status. The address space itself goes
+    /// away with the last thread that still runs in it.
+    fn release_resources(&mut self) {
+        self.address_space = None;
+        self.handles.clear();
+        if let Some(layer_controller) = LAYER_CONTROLLER.get() {
+            let mut layer_controller = layer_controller.lock();
+            for layer in self.layers.drain(..) {
+                let (x, y, width, height) = {
+                    let layer = layer.lock();
+                    let (x, y) = layer.get_pos_usize();
+                    (x as u32, y as u32, layer.get_width(), layer.get_height())
+                };
+                layer_controller.remove(&layer);
+                layer_controller.render_partial(x, y, width, height);
+            }
+        }
+    }
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+/// Notified whenever a process turns into a zombie.
+static PROCESS_EXITED: Condvar = Condvar::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    NotAChild,
+    NoChildren,
+    Load(elf::ElfError),
+    /// The executable could not be read.
+    File(vfs::VfsError),
+    /// The calling thread was killed while waiting.
+    Interrupted,
+}
+
+/// Loads the executable in `image` and starts it as a child of the calling
+/// process, or without a parent when called from a kernel thread.
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let program = elf::load(image, argv, envp).map_err(ProcessError::Load)?;
+    let pid = Pid::new();
+    let parent = thread::current_process();
+
+    let mut processes = PROCESSES.lock();
+    if let Some(parent) = parent.and_then(|parent| processes.get_mut(&parent)) {
+        parent.children.push(pid);
+    }
+    let main_thread = thread::spawn_user(
+        name,
+        pid,
+        program.address_space.clone(),
+        program.entry,
+        program.stack_pointer,
+    );
+    processes.insert(
+        pid,
+        Process {
+            pid,
+            name: name.to_string(),
+            parent,
+            children: Vec::new(),
+            address_space: Some(program.address_space),
+            threads: alloc::vec![main_thread],
+            handles: BTreeMap::new(),
+            next_handle: 1,
+            layers: Vec::new(),
+            state: ProcessState::Running,
+            exit_status: None,
+        },
+    );
+    log_info!("Started process {} ({})", pid.as_u64(), name);
+    Ok(pid)
+}
+
+/// Starts the executable at `path` in the VFS, like [`spawn`].
+pub fn spawn_path(path: &str, argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let image = vfs::read_to_end(path).map_err(ProcessError::File)?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    spawn(name, &image, argv, envp)
+}
+
+/// Runs `f` on the process of the calling thread.
+pub fn with_current<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    let pid = thread::current_process()?;
+    PROCESSES.lock().get_mut(&pid).map(f)
+}
+
+/// Terminates the calling process with `status`: all its other threads are
+/// killed, then the calling thread exits.
+pub fn exit(status: i64) -> ! {
+    if let Some(pid) = thread::current_process() {
+        let current = thread::current_id();
+        let mut processes = PROCESSES.lock();
+        if let Some(process) = processes.get_mut(&pid) {
+            process.exit_status.get_or_insert(status);
+            for &thread in process.threads.iter().filter(|&&thread| thread != current) {
+                thread::kill(thread);
+            }
+        }
+    }
+    exit_current_thread(Some(status));
+}
+
+/// Kills every thread of process `pid`. The process turns into a zombie
+/// once they are all gone.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    if process.state != ProcessState::Running {
+        return Ok(());
+    }
+    process.exit_status.get_or_insert(KILLED_STATUS);
+    for &thread in &process.threads {
+        thread::kill(thread);
+    }
+    // threads blocked in `wait` only notice when woken up
+    PROCESS_EXITED.notify_all();
+    Ok(())
+}
+
+/// Removes the calling thread from its process and exits it. The last thread
+/// to leave turns the process into a zombie and frees its resources.
+pub fn exit_current_thread(status: Option<i64>) -> ! {
+    if let Some(pid) = thread::current_process() {
+        let current = thread::current_id();
+        let mut processes = PROCESSES.lock();
+        let last = match processes.get_mut(&pid) {
+            Some(process) => {
+                process.threads.retain(|&thread| thread != current);
+                if let Some(status) = status {
+                    process.exit_status.get_or_insert(status);
+                }
+                process.threads.is_empty()
+            }
+            None => false,
+        };
+        if last {
+            finish(&mut processes, pid);
+        }
+    }
+    thread::exit();
+}
+
+fn finish(processes: &mut BTreeMap<Pid, Process>, pid: Pid) {
+    let process = processes.get_mut(&pid).unwrap();
+    let status = process.exit_status.unwrap_or(KILLED_STATUS);
+    process.state = ProcessState::Zombie(status);
+    process.release_resources();
+    let parent = process.parent;
+    let children = core::mem::take(&mut process.children);
+    log_info!("Process {} ({}) exited with status {}", pid.as_u64(), process.name, status);
+
+    // orphans are not waited for by anyone
+    for child in children {
+        match processes.get(&child).map(|child| child.state) {
+            Some(ProcessState::Zombie(_)) => {
+                processes.remove(&child);
+            }
+            Some(ProcessState::Running) => processes.get_mut(&child).unwrap().parent = None,
+            None => {}
+        }
+    }
+    let parent_alive = parent.is_some_and(|parent| processes.contains_key(&parent));
+    if !parent_alive {
+        processes.remove(&pid);
+    }
+    PROCESS_EXITED.notify_all();
+}
+
+/// Waits for a child of the calling process to exit and returns its PID and
+/// exit status. With `pid` set, waits for that particular child.
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, i64), ProcessError> {
+    let parent = thread::current_process();
+    let is_child = |process: &Process| process.parent.is_some() && process.parent == parent;
+
+    let mut processes = PROCESSES.lock();
+    match pid {
+        Some(pid) => {
+            let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+            if !is_child(process) {
+                return Err(ProcessError::NotAChild);
+            }
+        }
+        None => {
+            if !processes.values().any(is_child) {
+                return Err(ProcessError::NoChildren);
+            }
+        }
+    }
+
+    let awaited = |process: &Process| pid.is_none_or(|pid| process.pid == pid) && is_child(process);
+    let find_zombie = |processes: &BTreeMap<Pid, Process>| {
+        processes
+            .values()
+            .filter(|process| awaited(process))
+            .find_map(|process| match process.state {
+                ProcessState::Zombie(status) => Some((process.pid, status)),
+                ProcessState::Running => None,
+            })
+    };
+    // another thread of the process may reap the children first, which
+    // leaves nothing to wait for
+    processes = PROCESS_EXITED
+        .wait_while(processes, |processes| {
+            processes.values().any(awaited) && find_zombie(processes).is_none()
+        })
+        .map_err(|_| ProcessError::Interrupted)?;
+    let (child, status) = find_zombie(&processes).ok_or(match pid {
+        Some(_) => ProcessError::NoSuchProcess,
+        None => ProcessError::NoChildren,
+    })?;
+    processes.remove(&child);
+    if let Some(parent) = parent.and_then(|parent| processes.get_mut(&parent)) {
+        parent.children.retain(|&pid| pid != child);
+    }
+    Ok((child, status))
+}
+
+/// PIDs and names of all processes.
+pub fn list() -> Vec<(Pid, String, ProcessState)> {
+    PROCESSES
+        .lock()
+        .values()
+        .map(|process| (process.pid, process.name.clone(), process.state))
+        .collect()
+}
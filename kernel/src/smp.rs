@@ -0,0 +1,338 @@
+//! Application processor startup and cross-CPU TLB shootdown.
+
+use alloc::vec;
+use core::{
+    arch::global_asm,
+    hint::spin_loop,
+    ptr::{self, addr_of},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::{hlt, tlb},
+    registers::control::Cr3,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::ACPI,
+    apic, colors, gdt, interrupts, log_info, log_warn,
+    memory::{self, FRAME_ALLOCATOR, MAPPER},
+    percpu::{self, MAX_CPUS},
+    syscall, thread, time,
+};
+
+/// Physical (and, while APs start, virtual) address the trampoline is copied to.
+/// APs begin executing in real mode, so it has to be below 1 MiB.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+const AP_STACK_SIZE: usize = 4096 * 16;
+/// How long to wait for an AP to report in after its startup IPIs.
+const AP_STARTUP_TIMEOUT_TICKS: u64 = 10;
+
+// Real mode -> protected mode -> long mode, then jump into `ap_main` on the
+// stack the BSP prepared. Everything is addressed relative to `AP_TRAMPOLINE`
+// since the code runs from its copy rather than from the kernel image.
+global_asm!(
+    r#"
+.set AP_TRAMPOLINE, 0x8000
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    lgdtl (AP_TRAMPOLINE + ap_trampoline_gdtr - ap_trampoline_start)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(AP_TRAMPOLINE + ap_trampoline_32 - ap_trampoline_start)
+
+.code32
+ap_trampoline_32:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (AP_TRAMPOLINE + ap_trampoline_cr3 - ap_trampoline_start), %eax
+    movl %eax, %cr3
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    movl %cr0, %eax
+    orl $((1 << 31) | (1 << 16)), %eax
+    movl %eax, %cr0
+    ljmp $0x18, $(AP_TRAMPOLINE + ap_trampoline_64 - ap_trampoline_start)
+
+.code64
+ap_trampoline_64:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movq ap_trampoline_stack(%rip), %rsp
+    movq ap_trampoline_cpu(%rip), %rdi
+    movq ap_trampoline_entry(%rip), %rax
+    callq *%rax
+1:
+    hlt
+    jmp 1b
+
+.align 16
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+    .long AP_TRAMPOLINE + ap_trampoline_gdt - ap_trampoline_start
+
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu:
+    .quad 0
+ap_trampoline_end:
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Writes `value` into the copy of the trampoline at the position of `field`.
+unsafe fn write_trampoline_field(field: *const u8, value: u64) {
+    let offset = field as usize - addr_of!(ap_trampoline_start) as usize;
+    let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)) + offset;
+    ptr::write_volatile(dest.as_mut_ptr::<u64>(), value);
+}
+
+/// Waits for at least `ticks` full timer periods. The period that is already
+/// running when this is called does not count, as it may be about to end.
+fn wait_ticks(ticks: u64) {
+    let end = time::ticks() + ticks + 1;
+    while time::ticks() < end {
+        spin_loop();
+    }
+}
+
+/// Starts every enabled application processor listed in the MADT.
+///
+/// Requires interrupts to be enabled, since the startup delays are timed with the PIT.
+pub fn init() {
+    let Some(madt) = ACPI.get().and_then(|acpi| acpi.madt.as_ref()) else {
+        log_warn!("No MADT found; running on the BSP only");
+        return;
+    };
+
+    let trampoline_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    if !FRAME_ALLOCATOR
+        .get()
+        .unwrap()
+        .lock()
+        .is_usable_low_frame(trampoline_frame)
+    {
+        log_warn!("AP trampoline frame {:#X} is not usable; running on the BSP only", TRAMPOLINE_ADDR);
+        return;
+    }
+    let (level_4_table_frame, _) = Cr3::read();
+    let cr3 = level_4_table_frame.start_address().as_u64();
+    if cr3 >= 1 << 32 {
+        log_warn!("Kernel page table above 4 GiB; running on the BSP only");
+        return;
+    }
+
+    // the AP enables paging while executing the trampoline, so it must be identity mapped
+    let trampoline_page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let mapped = unsafe {
+        MAPPER.get().unwrap().lock().map_to(
+            trampoline_page,
+            trampoline_frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            &mut *FRAME_ALLOCATOR.get().unwrap().lock(),
+        )
+    };
+    match mapped {
+        Ok(flush) => flush.flush(),
+        Err(err) => {
+            log_warn!("Cannot identity map the AP trampoline: {:?}", err);
+            return;
+        }
+    }
+
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+        let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR));
+        ptr::copy_nonoverlapping(start, dest.as_mut_ptr::<u8>(), len);
+        write_trampoline_field(addr_of!(ap_trampoline_cr3), cr3);
+        write_trampoline_field(addr_of!(ap_trampoline_entry), ap_main as *const () as u64);
+    }
+
+    let bsp_apic_id = apic::id();
+    let mut next_cpu_id = 1;
+    for processor in madt
+        .processors
+        .iter()
+        .filter(|processor| processor.enabled && processor.apic_id != bsp_apic_id)
+    {
+        if next_cpu_id >= MAX_CPUS {
+            log_warn!("More than {} CPUs present; ignoring the rest", MAX_CPUS);
+            break;
+        }
+        if processor.apic_id > 0xFF {
+            log_warn!("Cannot start CPU with x2APIC ID {}", processor.apic_id);
+            continue;
+        }
+
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+        unsafe {
+            write_trampoline_field(addr_of!(ap_trampoline_stack), stack_top);
+            write_trampoline_field(addr_of!(ap_trampoline_cpu), next_cpu_id as u64);
+        }
+        AP_STARTED.store(false, Ordering::Release);
+
+        // the INIT de-assert needs 10 ms
+        apic::send_init(processor.apic_id);
+        wait_ticks((10 * time::TICKS_PER_SECOND).div_ceil(1000));
+        apic::send_startup(processor.apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        wait_ticks(1);
+        if !AP_STARTED.load(Ordering::Acquire) {
+            apic::send_startup(processor.apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        }
+
+        let deadline = time::ticks() + AP_STARTUP_TIMEOUT_TICKS;
+        while !AP_STARTED.load(Ordering::Acquire) && time::ticks() < deadline {
+            spin_loop();
+        }
+        if AP_STARTED.load(Ordering::Acquire) {
+            log_info!("CPU {} (APIC ID {}) online", next_cpu_id, processor.apic_id);
+            next_cpu_id += 1;
+        } else {
+            log_warn!("CPU with APIC ID {} did not start", processor.apic_id);
+        }
+    }
+
+    let unmapped = MAPPER.get().unwrap().lock().unmap(trampoline_page);
+    if let Ok((_, flush)) = unmapped {
+        flush.ignore();
+        tlb_shootdown(trampoline_page.start_address(), 1);
+    }
+}
+
+/// Rust entry point of an application processor, called by the trampoline.
+extern "C" fn ap_main(cpu_id: u64) -> ! {
+    let tss = gdt::init_ap();
+    interrupts::idt_init();
+    apic::init_local();
+    percpu::init_ap(cpu_id as usize, tss);
+    syscall::init();
+    thread::init_ap();
+    AP_STARTED.store(true, Ordering::Release);
+
+    apic::start_timer(interrupts::LAPIC_TIMER_VECTOR);
+    x86_64::instructions::interrupts::enable();
+    // from here on this is the idle thread of the CPU
+    loop {
+        hlt();
+    }
+}
+
+/// Pages to flush in the pending shootdown; `u64::MAX` flushes the whole TLB.
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+/// Bit mask of the CPUs that still have to flush.
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+
+const FULL_FLUSH_THRESHOLD: u64 = 64;
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FULL_FLUSH_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            tlb::flush(start + page * 4096);
+        }
+    }
+}
+
+/// Performs the pending shootdown request on this CPU, if there is one for it.
+fn service_shootdown() {
+    let mask = 1 << percpu::current().cpu_id;
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & mask != 0 {
+        flush_local(
+            VirtAddr::new(SHOOTDOWN_START.load(Ordering::Relaxed)),
+            SHOOTDOWN_PAGES.load(Ordering::Relaxed),
+        );
+        SHOOTDOWN_PENDING.fetch_and(!mask, Ordering::AcqRel);
+    }
+}
+
+/// Invalidates `pages` pages starting at `start` in the TLBs of all CPUs.
+///
+/// Must be called after changing or removing mappings of the shared kernel
+/// address space. Waiting CPUs keep servicing requests from others, so this
+/// is safe to call with interrupts disabled.
+pub fn tlb_shootdown(start: VirtAddr, pages: u64) {
+    flush_local(start, pages);
+
+    let cpu_count = percpu::cpu_count();
+    if cpu_count <= 1 {
+        return;
+    }
+    let all: u64 = if cpu_count >= 64 {
+        u64::MAX
+    } else {
+        (1 << cpu_count) - 1
+    };
+    let others = all & !(1 << percpu::current().cpu_id);
+
+    while SHOOTDOWN_LOCK
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        service_shootdown();
+        spin_loop();
+    }
+
+    SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+    SHOOTDOWN_PAGES.store(pages, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.store(others, Ordering::Release);
+    apic::send_ipi_all_excluding_self(interrupts::TLB_SHOOTDOWN_VECTOR);
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) & others != 0 {
+        spin_loop();
+    }
+
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+/// Called from the TLB shootdown IPI.
+pub fn handle_shootdown_ipi() {
+    service_shootdown();
+}
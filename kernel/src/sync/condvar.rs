@@ -0,0 +1,63 @@
+use super::{Killed, MutexGuard, WaitQueue};
+
+/// A condition variable to be used together with a [`super::Mutex`].
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex, parks the current thread until notified and
+    /// re-acquires the mutex before returning.
+    ///
+    /// Returns at once if the thread has been killed, like a spurious wake-up.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_killable(guard).0
+    }
+
+    fn wait_killable<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+    ) -> (MutexGuard<'a, T>, Result<(), Killed>) {
+        let mutex = guard.mutex();
+        let result = self.waiters.wait_with(|| drop(guard));
+        (mutex.lock(), result)
+    }
+
+    /// Waits for as long as `condition` returns `true` for the protected value.
+    /// Gives up, releasing the mutex, if the thread is killed.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> Result<MutexGuard<'a, T>, Killed>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            let (next, result) = self.wait_killable(guard);
+            guard = next;
+            result?;
+        }
+        Ok(guard)
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
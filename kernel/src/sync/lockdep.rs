@@ -0,0 +1,171 @@
+//! Lock dependency tracking for debug builds.
+//!
+//! Every time a thread acquires a lock while already holding others, an edge
+//! "held -> acquired" is added to a global graph. Acquiring a lock that can
+//! already reach one of the held locks means two threads could take the same
+//! pair of locks in opposite order, i.e. a potential deadlock, which is
+//! reported the first time it is seen. Re-acquiring a held lock is a certain
+//! deadlock and panics.
+//!
+//! Locks taken by interrupt handlers are not tracked: they would be recorded
+//! as held by whichever thread the interrupt happened to stop.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    colors, log, log_warn, percpu, serial_println,
+    thread::{self, ThreadId},
+};
+
+struct HeldLock {
+    addr: usize,
+    shared: bool,
+}
+
+struct LockGraph {
+    /// Locks that have been acquired while holding the key.
+    after: BTreeMap<usize, BTreeSet<usize>>,
+    held: BTreeMap<ThreadId, Vec<HeldLock>>,
+    reported: BTreeSet<(usize, usize)>,
+}
+
+impl LockGraph {
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut stack = Vec::from([from]);
+        while let Some(addr) = stack.pop() {
+            if addr == to {
+                return true;
+            }
+            if !visited.insert(addr) {
+                continue;
+            }
+            if let Some(next) = self.after.get(&addr) {
+                stack.extend(next.iter().copied());
+            }
+        }
+        false
+    }
+}
+
+/// Tracking starts once threads exist; locks taken during early boot are ignored.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+fn tracking() -> bool {
+    ENABLED.load(Ordering::Acquire) && !percpu::in_interrupt()
+}
+
+static GRAPH: Mutex<LockGraph> = Mutex::new(LockGraph {
+    after: BTreeMap::new(),
+    held: BTreeMap::new(),
+    reported: BTreeSet::new(),
+});
+
+pub fn acquire(addr: usize, shared: bool) {
+    if !tracking() {
+        return;
+    }
+    let thread = thread::current_id();
+    let mut inversions = Vec::new();
+    let recursive = interrupts::without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        let held: Vec<usize> = match graph.held.get(&thread) {
+            Some(held) => {
+                if held
+                    .iter()
+                    .any(|lock| lock.addr == addr && !(shared && lock.shared))
+                {
+                    return true;
+                }
+                held.iter().map(|lock| lock.addr).collect()
+            }
+            None => Vec::new(),
+        };
+
+        for held_addr in held {
+            if held_addr == addr {
+                continue;
+            }
+            if graph.reachable(addr, held_addr) && graph.reported.insert((held_addr, addr)) {
+                inversions.push(held_addr);
+            }
+            graph.after.entry(held_addr).or_default().insert(addr);
+        }
+        graph
+            .held
+            .entry(thread)
+            .or_default()
+            .push(HeldLock { addr, shared });
+        false
+    });
+
+    if recursive {
+        panic!(
+            "lockdep: thread {} tried to acquire lock {:#x} it already holds",
+            thread.as_u64(),
+            addr
+        );
+    }
+    for held_addr in inversions {
+        serial_println!(
+            "lockdep: possible deadlock: thread {} acquires {:#x} while holding {:#x}, \
+             but {:#x} has been acquired while holding {:#x} before",
+            thread.as_u64(),
+            addr,
+            held_addr,
+            held_addr,
+            addr
+        );
+        if log::is_initialized() {
+            log_warn!(
+                "lockdep: possible deadlock between locks {:#x} and {:#x} (thread {})",
+                held_addr,
+                addr,
+                thread.as_u64()
+            );
+        }
+    }
+}
+
+pub fn release(addr: usize) {
+    if !tracking() {
+        return;
+    }
+    let thread = thread::current_id();
+    interrupts::without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        if let Some(held) = graph.held.get_mut(&thread) {
+            if let Some(index) = held.iter().rposition(|lock| lock.addr == addr) {
+                held.remove(index);
+            }
+            if held.is_empty() {
+                graph.held.remove(&thread);
+            }
+        }
+    });
+}
+
+pub fn forget(addr: usize) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        graph.after.remove(&addr);
+        for next in graph.after.values_mut() {
+            next.remove(&addr);
+        }
+        graph.reported.retain(|&(a, b)| a != addr && b != addr);
+    });
+}
@@ -0,0 +1,47 @@
+//! Synchronization primitives integrated with the scheduler.
+//!
+//! Unlike `spin::Mutex`, the blocking primitives here park the waiting thread
+//! instead of burning its time slice, so they must only be used from thread
+//! context. [`IrqSpinLock`] is the one lock that may also be taken inside
+//! interrupt handlers.
+
+pub mod condvar;
+#[cfg(debug_assertions)]
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
+
+pub use self::{
+    condvar::Condvar,
+    mutex::{Mutex, MutexGuard},
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    semaphore::Semaphore,
+    spinlock::{IrqSpinLock, IrqSpinLockGuard},
+    wait_queue::{Killed, WaitQueue},
+};
+
+/// Records that the current thread is about to acquire the lock at `addr`.
+///
+/// `shared` acquisitions (read locks) may be taken recursively.
+#[inline]
+fn lockdep_acquire(_addr: usize, _shared: bool) {
+    #[cfg(debug_assertions)]
+    lockdep::acquire(_addr, _shared);
+}
+
+/// Records that the current thread has released the lock at `addr`.
+#[inline]
+fn lockdep_release(_addr: usize) {
+    #[cfg(debug_assertions)]
+    lockdep::release(_addr);
+}
+
+/// Forgets everything known about the lock at `addr` once it is dropped.
+#[inline]
+fn lockdep_forget(_addr: usize) {
+    #[cfg(debug_assertions)]
+    lockdep::forget(_addr);
+}
@@ -0,0 +1,129 @@
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(not(test))]
+use x86_64::instructions::interrupts;
+
+use super::{lockdep_acquire, lockdep_forget, lockdep_release};
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// This is the only lock that is safe to share between threads and interrupt
+/// handlers: an interrupt can never arrive on the CPU holding the lock, so the
+/// handler cannot spin forever on a lock its own CPU will never release.
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    addr: usize,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        // `IrqSpinLock` implements `Drop`, so the inner lock has to be moved out manually
+        let this = ManuallyDrop::new(self);
+        lockdep_forget(this.addr());
+        unsafe { core::ptr::read(&this.inner) }.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep_acquire(self.addr(), false);
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            addr: self.addr(),
+            interrupts_were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep_acquire(self.addr(), false);
+                Some(IrqSpinLockGuard {
+                    guard: ManuallyDrop::new(guard),
+                    addr: self.addr(),
+                    interrupts_were_enabled,
+                })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLock<T> {
+    fn drop(&mut self) {
+        lockdep_forget(self.addr());
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // the lock has to be released before interrupts are enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep_release(self.addr);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Unit tests run as a user process on the host, where `cli` and `sti`
+/// fault and there are no interrupts to mask anyway.
+#[cfg(test)]
+mod interrupts {
+    pub fn are_enabled() -> bool {
+        false
+    }
+
+    pub fn disable() {}
+
+    pub fn enable() {}
+}
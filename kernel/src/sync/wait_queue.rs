@@ -0,0 +1,180 @@
+use alloc::collections::VecDeque;
+
+use super::IrqSpinLock;
+use crate::{
+    thread::{self, ThreadId},
+    time,
+};
+
+/// Returned by the waits of a [`WaitQueue`] when the waiting thread's
+/// process has been killed. The thread should unwind to the system call
+/// boundary, where it exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Killed;
+
+/// A FIFO queue of threads waiting for some event.
+///
+/// Waiters are parked and consume no CPU time until they are notified.
+/// Notifying is allowed from interrupt handlers.
+///
+/// Waits end early with [`Killed`] once the waiting thread is killed, which
+/// wakes it up. Only locks and device I/O, which finish in bounded time no
+/// matter what, wait uninterruptibly.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Parks the current thread until it is notified.
+    pub fn wait(&self) -> Result<(), Killed> {
+        self.wait_with(|| {})
+    }
+
+    /// Parks the current thread until it is notified, calling `before_sleep`
+    /// once the thread has been queued but before it gives up the CPU.
+    ///
+    /// A notification issued by `before_sleep` (or by anyone after it) is
+    /// guaranteed to wake the thread, which is what [`super::Condvar`] needs
+    /// to release its mutex atomically with going to sleep. `before_sleep`
+    /// is called even if the thread turns out to be killed.
+    pub fn wait_with<F: FnOnce()>(&self, before_sleep: F) -> Result<(), Killed> {
+        let mut waiters = self.waiters.lock();
+        let id = thread::current_id();
+        if !thread::block_current_killable() {
+            drop(waiters);
+            before_sleep();
+            return Err(Killed);
+        }
+        waiters.push_back(id);
+        before_sleep();
+        drop(waiters);
+        thread::yield_now();
+        if thread::is_killed() {
+            // woken up by the kill rather than a notification
+            self.waiters.lock().retain(|&waiter| waiter != id);
+            return Err(Killed);
+        }
+        Ok(())
+    }
+
+    /// Parks the current thread for as long as `condition` returns `true`.
+    ///
+    /// The condition is evaluated with the queue locked, so whoever makes it
+    /// false and then notifies the queue can never be missed.
+    pub fn wait_while<F: FnMut() -> bool>(&self, condition: F) -> Result<(), Killed> {
+        self.wait_while_until(true, None, condition).map(|_| ())
+    }
+
+    /// Like [`WaitQueue::wait_while`], but gives up after `ms` milliseconds.
+    /// Returns `false` if the condition still held when the time ran out.
+    pub fn wait_while_timeout<F: FnMut() -> bool>(
+        &self,
+        ms: u64,
+        condition: F,
+    ) -> Result<bool, Killed> {
+        let deadline = time::ticks() + time::ms_to_ticks(ms).max(1);
+        self.wait_while_until(true, Some(deadline), condition)
+    }
+
+    /// Like [`WaitQueue::wait_while`], but keeps waiting when the thread is
+    /// killed. Meant for locks, whose holders give them back in bounded time.
+    pub fn wait_while_uninterruptible<F: FnMut() -> bool>(&self, condition: F) {
+        let _ = self.wait_while_until(false, None, condition);
+    }
+
+    /// Like [`WaitQueue::wait_while_timeout`], but keeps waiting when the
+    /// thread is killed. Meant for device I/O, which must not be abandoned
+    /// while the device may still access its buffers.
+    pub fn wait_while_timeout_uninterruptible<F: FnMut() -> bool>(
+        &self,
+        ms: u64,
+        condition: F,
+    ) -> bool {
+        let deadline = time::ticks() + time::ms_to_ticks(ms).max(1);
+        self.wait_while_until(false, Some(deadline), condition)
+            .unwrap_or(false)
+    }
+
+    /// Parks the current thread while `condition` holds, until timer tick
+    /// `deadline` if there is one. Returns `false` if the deadline passed.
+    fn wait_while_until<F: FnMut() -> bool>(
+        &self,
+        killable: bool,
+        deadline: Option<u64>,
+        mut condition: F,
+    ) -> Result<bool, Killed> {
+        let id = thread::current_id();
+        loop {
+            let mut waiters = self.waiters.lock();
+            if !condition() {
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| time::ticks() >= deadline) {
+                return Ok(false);
+            }
+            let blocked = match (killable, deadline) {
+                (true, None) => thread::block_current_killable(),
+                (true, Some(deadline)) => thread::block_current_until_killable(deadline),
+                (false, None) => {
+                    thread::block_current();
+                    true
+                }
+                (false, Some(deadline)) => {
+                    thread::block_current_until(deadline);
+                    true
+                }
+            };
+            if !blocked {
+                // an earlier round may have been ended by the kill, not a notification
+                waiters.retain(|&waiter| waiter != id);
+                return Err(Killed);
+            }
+            waiters.push_back(id);
+            drop(waiters);
+            thread::yield_now();
+            if deadline.is_some() {
+                // nobody dequeued the thread if it woke up because of the deadline
+                self.waiters.lock().retain(|&waiter| waiter != id);
+            }
+        }
+    }
+
+    /// Wakes the longest waiting thread. Returns `false` if nobody was waiting.
+    pub fn notify_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        while let Some(id) = waiters.pop_front() {
+            if thread::unpark(id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        let mut count = 0;
+        while let Some(id) = waiters.pop_front() {
+            if thread::unpark(id) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
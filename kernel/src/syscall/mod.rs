@@ -0,0 +1,711 @@
+//! System calls through `syscall`/`sysret`.
+//!
+//! The system call number goes in `rax` and up to six arguments in `rdi`,
+//! `rsi`, `rdx`, `r10`, `r8` and `r9`. The result comes back in `rax`;
+//! failures are reported as a negated [`SyscallError`] code.
+
+use core::arch::global_asm;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+use crate::{
+    address_space::USER_END,
+    colors, gdt, graphics,
+    layer::{self, Layer, LAYER_CONTROLLER},
+    log_info, log_warn,
+    net::{
+        dns,
+        socket::{Socket, SocketKind},
+        Ipv4Address, NetError,
+    },
+    process::{self, Handle, Pid, ProcessError},
+    thread, time,
+    vfs::{self, File, FileType, OpenFlags, SeekFrom, VfsError},
+};
+
+pub mod user_ptr;
+
+pub const SYS_LOG_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_SLEEP: u64 = 2;
+pub const SYS_GET_TIME: u64 = 3;
+pub const SYS_MMAP: u64 = 4;
+pub const SYS_CREATE_WINDOW: u64 = 5;
+pub const SYS_GETPID: u64 = 6;
+pub const SYS_WAIT: u64 = 7;
+pub const SYS_KILL: u64 = 8;
+pub const SYS_CLOSE: u64 = 9;
+pub const SYS_OPEN: u64 = 10;
+pub const SYS_READ: u64 = 11;
+pub const SYS_WRITE: u64 = 12;
+pub const SYS_SEEK: u64 = 13;
+pub const SYS_STAT: u64 = 14;
+pub const SYS_READ_DIR: u64 = 15;
+pub const SYS_CREATE_DIR: u64 = 16;
+pub const SYS_REMOVE: u64 = 17;
+pub const SYS_RENAME: u64 = 18;
+pub const SYS_SYMLINK: u64 = 19;
+pub const SYS_READ_LINK: u64 = 20;
+pub const SYS_SET_PERMISSIONS: u64 = 21;
+pub const SYS_SOCKET: u64 = 22;
+pub const SYS_BIND: u64 = 23;
+pub const SYS_CONNECT: u64 = 24;
+pub const SYS_LISTEN: u64 = 25;
+pub const SYS_ACCEPT: u64 = 26;
+pub const SYS_SEND_TO: u64 = 27;
+pub const SYS_RECV_FROM: u64 = 28;
+pub const SYS_RESOLVE: u64 = 29;
+
+/// Protection bits accepted by [`SYS_MMAP`]. Mappings are always readable.
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// `whence` values of [`SYS_SEEK`].
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// `kind` values of [`SYS_SOCKET`].
+pub const SOCKET_TCP: u64 = 1;
+pub const SOCKET_UDP: u64 = 2;
+
+/// Longest message [`SYS_LOG_WRITE`] accepts.
+const MAX_LOG_WRITE: u64 = 4096;
+/// Longest path accepted by the file system calls.
+const MAX_PATH: u64 = 4096;
+/// Longest host name [`SYS_RESOLVE`] accepts.
+const MAX_HOST_NAME: u64 = 253;
+/// Most bytes a single [`SYS_READ`] or [`SYS_WRITE`] transfers.
+const MAX_IO: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    InvalidSyscall = 1,
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+    NoSuchProcess = 5,
+    BadHandle = 6,
+    NotFound = 7,
+    NotADirectory = 8,
+    IsADirectory = 9,
+    AlreadyExists = 10,
+    ReadOnly = 11,
+    NoSpace = 12,
+    Io = 13,
+    NotEmpty = 14,
+    Loop = 15,
+    AddressInUse = 16,
+    ConnectionRefused = 17,
+    ConnectionReset = 18,
+    TimedOut = 19,
+    NotConnected = 20,
+    Unreachable = 21,
+    /// The thread was killed while blocked; it exits instead of returning.
+    Interrupted = 22,
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::NoSuchProcess | ProcessError::NotAChild | ProcessError::NoChildren => {
+                SyscallError::NoSuchProcess
+            }
+            ProcessError::Load(_) => SyscallError::InvalidArgument,
+            ProcessError::File(err) => err.into(),
+            ProcessError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+impl From<VfsError> for SyscallError {
+    fn from(err: VfsError) -> Self {
+        match err {
+            VfsError::NotFound => SyscallError::NotFound,
+            VfsError::NotADirectory => SyscallError::NotADirectory,
+            VfsError::IsADirectory => SyscallError::IsADirectory,
+            VfsError::AlreadyExists => SyscallError::AlreadyExists,
+            VfsError::ReadOnly => SyscallError::ReadOnly,
+            VfsError::NoSpace => SyscallError::NoSpace,
+            VfsError::Io => SyscallError::Io,
+            VfsError::NotEmpty => SyscallError::NotEmpty,
+            VfsError::Loop => SyscallError::Loop,
+            VfsError::InvalidPath | VfsError::InvalidSeek | VfsError::NotSupported => {
+                SyscallError::InvalidArgument
+            }
+        }
+    }
+}
+
+impl From<NetError> for SyscallError {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::InvalidFrame | NetError::InvalidArgument => SyscallError::InvalidArgument,
+            NetError::Busy | NetError::LinkDown | NetError::Io => SyscallError::Io,
+            NetError::Unreachable => SyscallError::Unreachable,
+            NetError::AddressInUse => SyscallError::AddressInUse,
+            NetError::ConnectionRefused => SyscallError::ConnectionRefused,
+            NetError::ConnectionReset => SyscallError::ConnectionReset,
+            NetError::TimedOut => SyscallError::TimedOut,
+            NetError::NotConnected => SyscallError::NotConnected,
+            NetError::NotFound => SyscallError::NotFound,
+            NetError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+/// User state saved by `syscall_entry` on the kernel stack, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+type SyscallHandler = fn([u64; 6]) -> Result<u64, SyscallError>;
+
+/// Handlers indexed by system call number.
+static SYSCALL_TABLE: [SyscallHandler; 30] = [
+    sys_log_write,
+    sys_exit,
+    sys_sleep,
+    sys_get_time,
+    sys_mmap,
+    sys_create_window,
+    sys_getpid,
+    sys_wait,
+    sys_kill,
+    sys_close,
+    sys_open,
+    sys_read,
+    sys_write,
+    sys_seek,
+    sys_stat,
+    sys_read_dir,
+    sys_create_dir,
+    sys_remove,
+    sys_rename,
+    sys_symlink,
+    sys_read_link,
+    sys_set_permissions,
+    sys_socket,
+    sys_bind,
+    sys_connect,
+    sys_listen,
+    sys_accept,
+    sys_send_to,
+    sys_recv_from,
+    sys_resolve,
+];
+
+// `syscall` leaves the user stack in place and interrupts disabled (through
+// SFMASK). Switch to the kernel GS base, park the user stack pointer in the
+// per-CPU data (`gs:[16]`, `percpu::USER_RSP_OFFSET`) and continue on the
+// thread's kernel stack (`gs:[8]`, `percpu::KERNEL_STACK_OFFSET`). Once the
+// frame is saved the call runs with interrupts enabled, so it can block or be
+// preempted like any other kernel code. `syscall_dispatch` never lets a
+// non-canonical return address reach `sysretq`, which would fault in ring 0
+// with the user stack and GS base already loaded.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    swapgs",
+    "    mov qword ptr gs:[16], rsp",
+    "    mov rsp, qword ptr gs:[8]",
+    "    push qword ptr gs:[16]",
+    "    push r11",
+    "    push rcx",
+    "    push rax",
+    "    push rdi",
+    "    push rsi",
+    "    push rdx",
+    "    push r10",
+    "    push r8",
+    "    push r9",
+    "    sti",
+    "    mov rdi, rsp",
+    "    call syscall_dispatch",
+    "    cli",
+    "    pop r9",
+    "    pop r8",
+    "    pop r10",
+    "    pop rdx",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop rax",
+    "    pop rcx",
+    "    pop r11",
+    "    pop rsp",
+    "    swapgs",
+    "    sysretq",
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables `syscall` on the calling CPU. Every CPU has to call this after loading its GDT.
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not fit SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame.args()),
+        None => Err(SyscallError::InvalidSyscall),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    };
+    // the process may have been killed while the call was blocked
+    if thread::is_killed() {
+        process::exit_current_thread(None);
+    }
+    // only a `syscall` in the last bytes below `USER_END` returns there, and
+    // that page is never mapped; this keeps it that way if it ever is
+    if frame.rip >= USER_END {
+        log_warn!("System call would return to {:#x}", frame.rip);
+        process::exit(process::FAULT_STATUS);
+    }
+}
+
+/// `log_write(message, len)`: writes a UTF-8 message to the kernel log.
+fn sys_log_write([message, len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    if len > MAX_LOG_WRITE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let message = user_ptr::read_str(message, len)?;
+    let thread = thread::current_id();
+    log_info!("[thread {}] {}", thread.as_u64(), message);
+    Ok(len)
+}
+
+/// `exit(status)`: terminates the calling process.
+fn sys_exit([status, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    process::exit(status as i64);
+}
+
+/// `sleep(ms)`: blocks the calling thread for at least `ms` milliseconds.
+fn sys_sleep([ms, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::sleep(ms);
+    Ok(0)
+}
+
+/// `get_time()`: milliseconds since boot.
+fn sys_get_time(_: [u64; 6]) -> Result<u64, SyscallError> {
+    Ok(time::uptime_ms())
+}
+
+/// `mmap(len, prot)`: maps `len` bytes of zeroed memory and returns their address.
+fn sys_mmap([len, prot, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    if len == 0 || prot & !(PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let address_space = thread::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    address_space
+        .map_anonymous(len, flags)
+        .map(|addr| addr.as_u64())
+        .map_err(|_| SyscallError::OutOfMemory)
+}
+
+/// Smallest window `gui::draw_window` can draw.
+const MIN_WINDOW_WIDTH: u64 = 56;
+const MIN_WINDOW_HEIGHT: u64 = 30;
+
+/// `create_window(x, y, width, height, title, title_len)`: opens a window and returns its handle.
+fn sys_create_window(
+    [x, y, width, height, title, title_len]: [u64; 6],
+) -> Result<u64, SyscallError> {
+    let screen_width = graphics::get_width() as u64;
+    let screen_height = graphics::get_height() as u64;
+    if width < MIN_WINDOW_WIDTH
+        || height < MIN_WINDOW_HEIGHT
+        || x.saturating_add(width) > screen_width
+        || y.saturating_add(height) > screen_height
+        || title_len > MAX_LOG_WRITE
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let title = user_ptr::read_str(title, title_len)?;
+
+    let mut window = Layer::new(width as u32, height as u32, x as u32, y as u32, 1);
+    window.draw_window(&title);
+    let window = layer::add_layer(window);
+    let handle = process::with_current(|process| {
+        process.own_layer(window.clone());
+        process.add_handle(Handle::Window(window.clone()))
+    });
+
+    // the window is only drawn once a process owns it, nobody else would
+    // take it off the screen
+    let mut layer_controller = LAYER_CONTROLLER.get().unwrap().lock();
+    let Some(handle) = handle else {
+        layer_controller.remove(&window);
+        return Err(SyscallError::InvalidArgument);
+    };
+    layer_controller.render_partial(x as u32, y as u32, width as u32, height as u32);
+    Ok(handle)
+}
+
+/// `getpid()`: PID of the calling process.
+fn sys_getpid(_: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::current_process()
+        .map(|pid| pid.as_u64())
+        .ok_or(SyscallError::NoSuchProcess)
+}
+
+/// `wait(pid, status)`: waits for the child `pid` to exit, or for any child
+/// if `pid` is 0. Stores the exit status at `status` unless it is null and
+/// returns the PID of the child.
+fn sys_wait([pid, status, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    if status != 0 {
+        user_ptr::validate(status, 8, true)?;
+    }
+    let pid = (pid != 0).then(|| Pid::from_u64(pid));
+    let (child, exit_status) = process::wait(pid)?;
+    if status != 0 {
+        user_ptr::copy_to_user(status, &exit_status.to_le_bytes())?;
+    }
+    Ok(child.as_u64())
+}
+
+/// `kill(pid)`: terminates process `pid`.
+fn sys_kill([pid, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    process::kill(Pid::from_u64(pid))?;
+    Ok(0)
+}
+
+/// `close(handle)`: releases a handle. Closing a window removes it from the screen.
+fn sys_close([handle, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let handle = process::with_current(|process| process.remove_handle(handle))
+        .flatten()
+        .ok_or(SyscallError::BadHandle)?;
+    match handle {
+        Handle::Window(window) => {
+            process::with_current(|process| process.disown_layer(&window));
+            let (x, y, width, height) = {
+                let window = window.lock();
+                let (x, y) = window.get_pos_usize();
+                (x as u32, y as u32, window.get_width(), window.get_height())
+            };
+            let mut layer_controller = LAYER_CONTROLLER.get().unwrap().lock();
+            layer_controller.remove(&window);
+            layer_controller.render_partial(x, y, width, height);
+        }
+        Handle::File(_) | Handle::Socket(_) => {}
+    }
+    Ok(0)
+}
+
+/// The open file behind `handle` in the calling process.
+fn current_file(handle: u64) -> Result<Arc<dyn File>, SyscallError> {
+    process::with_current(|process| process.file(handle))
+        .flatten()
+        .ok_or(SyscallError::BadHandle)
+}
+
+fn read_path(path: u64, path_len: u64) -> Result<String, SyscallError> {
+    if path_len > MAX_PATH {
+        return Err(SyscallError::InvalidArgument);
+    }
+    user_ptr::read_str(path, path_len)
+}
+
+/// `open(path, path_len, flags)`: opens the file at `path` with
+/// [`OpenFlags`] and returns its handle.
+fn sys_open([path, path_len, flags, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let path = read_path(path, path_len)?;
+    let flags = u32::try_from(flags)
+        .ok()
+        .and_then(OpenFlags::from_bits)
+        .ok_or(SyscallError::InvalidArgument)?;
+    let file = vfs::open(&path, flags)?;
+    process::with_current(|process| process.add_handle(Handle::File(file)))
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+/// `read(handle, buf, len)`: reads up to `len` bytes and returns how many were read.
+fn sys_read([handle, buf, len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let file = current_file(handle)?;
+    let len = len.min(MAX_IO);
+    user_ptr::validate(buf, len, true)?;
+    let mut data = vec![0; len as usize];
+    let read = file.read(&mut data)?;
+    user_ptr::copy_to_user(buf, &data[..read])?;
+    Ok(read as u64)
+}
+
+/// `write(handle, buf, len)`: writes up to `len` bytes and returns how many were written.
+fn sys_write([handle, buf, len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let file = current_file(handle)?;
+    let data = user_ptr::copy_from_user(buf, len.min(MAX_IO))?;
+    Ok(file.write(&data)? as u64)
+}
+
+/// `seek(handle, offset, whence)`: moves the file position and returns the new one.
+fn sys_seek([handle, offset, whence, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let file = current_file(handle)?;
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    Ok(file.seek(pos)?)
+}
+
+/// Numbers of [`FileType`] as reported by [`SYS_STAT`] and [`SYS_READ_DIR`].
+fn file_type_number(file_type: FileType) -> u64 {
+    match file_type {
+        FileType::File => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Symlink => 5,
+    }
+}
+
+/// `stat(path, path_len, stat)`: stores the type, size, inode number,
+/// permission bits, owner and group of the file at `path` as six 64 bit
+/// words at `stat`.
+fn sys_stat([path, path_len, stat, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let path = read_path(path, path_len)?;
+    let metadata = vfs::stat(&path)?;
+    let words = [
+        file_type_number(metadata.file_type),
+        metadata.size,
+        metadata.inode,
+        metadata.mode as u64,
+        metadata.uid as u64,
+        metadata.gid as u64,
+    ];
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    user_ptr::copy_to_user(stat, &bytes)?;
+    Ok(0)
+}
+
+/// `read_dir(handle, index, buf, len)`: copies the name of entry `index` of
+/// the open directory to `buf`. Returns the length of the name in the low
+/// 32 bits and the file type above them, or 0 past the last entry.
+fn sys_read_dir([handle, index, buf, len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let file = current_file(handle)?;
+    let entries = file.read_dir()?;
+    let Some(entry) = entries.get(index as usize) else {
+        return Ok(0);
+    };
+    let name = entry.name.as_bytes();
+    if name.len() as u64 > len {
+        return Err(SyscallError::InvalidArgument);
+    }
+    user_ptr::copy_to_user(buf, name)?;
+    Ok(name.len() as u64 | file_type_number(entry.file_type) << 32)
+}
+
+/// `create_dir(path, path_len)`: creates an empty directory.
+fn sys_create_dir([path, path_len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    vfs::create_dir(&read_path(path, path_len)?)?;
+    Ok(0)
+}
+
+/// `remove(path, path_len)`: deletes a file or an empty directory.
+fn sys_remove([path, path_len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    vfs::remove(&read_path(path, path_len)?)?;
+    Ok(0)
+}
+
+/// `rename(from, from_len, to, to_len)`: moves a file or directory within a filesystem.
+fn sys_rename([from, from_len, to, to_len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    vfs::rename(&read_path(from, from_len)?, &read_path(to, to_len)?)?;
+    Ok(0)
+}
+
+/// `symlink(target, target_len, path, path_len)`: creates a symbolic link at
+/// `path` pointing at `target`.
+fn sys_symlink([target, target_len, path, path_len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    vfs::symlink(&read_path(target, target_len)?, &read_path(path, path_len)?)?;
+    Ok(0)
+}
+
+/// `read_link(path, path_len, buf, len)`: copies the target of the symbolic
+/// link at `path` to `buf` and returns its length.
+fn sys_read_link([path, path_len, buf, len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let target = vfs::read_link(&read_path(path, path_len)?)?;
+    let target = target.as_bytes();
+    if target.len() as u64 > len {
+        return Err(SyscallError::InvalidArgument);
+    }
+    user_ptr::copy_to_user(buf, target)?;
+    Ok(target.len() as u64)
+}
+
+/// `set_permissions(path, path_len, mode)`: changes the permission bits of the file at `path`.
+fn sys_set_permissions([path, path_len, mode, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let mode = u16::try_from(mode)
+        .ok()
+        .filter(|mode| mode & !0o7777 == 0)
+        .ok_or(SyscallError::InvalidArgument)?;
+    vfs::set_permissions(&read_path(path, path_len)?, mode)?;
+    Ok(0)
+}
+
+/// The socket behind `handle` in the calling process.
+fn current_socket(handle: u64) -> Result<Arc<Socket>, SyscallError> {
+    process::with_current(|process| process.socket(handle))
+        .flatten()
+        .ok_or(SyscallError::BadHandle)
+}
+
+fn add_socket(socket: Socket) -> Result<u64, SyscallError> {
+    process::with_current(|process| process.add_handle(Handle::Socket(Arc::new(socket))))
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+/// Addresses are passed as the 32 bit number of the dotted notation, e.g.
+/// `0x0A000202` for `10.0.2.2`.
+fn address_and_port(address: u64, port: u64) -> Result<(Ipv4Address, u16), SyscallError> {
+    let address = u32::try_from(address).map_err(|_| SyscallError::InvalidArgument)?;
+    let port = u16::try_from(port).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok((Ipv4Address::from_u32(address), port))
+}
+
+/// Stores `address` and `port` at `ptr` as the 64 bit word `address << 16 | port`.
+fn store_address(ptr: u64, address: Ipv4Address, port: u16) -> Result<(), SyscallError> {
+    let word = ((address.to_u32() as u64) << 16) | port as u64;
+    user_ptr::copy_to_user(ptr, &word.to_le_bytes())
+}
+
+/// `socket(kind)`: creates a TCP or UDP socket and returns its handle.
+fn sys_socket([kind, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let kind = match kind {
+        SOCKET_TCP => SocketKind::Tcp,
+        SOCKET_UDP => SocketKind::Udp,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    add_socket(Socket::new(kind))
+}
+
+/// `bind(handle, port)`: picks the local port of a socket, a free one if `port` is 0.
+fn sys_bind([handle, port, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let port = u16::try_from(port).map_err(|_| SyscallError::InvalidArgument)?;
+    current_socket(handle)?.bind(port)?;
+    Ok(0)
+}
+
+/// `connect(handle, address, port)`: connects a TCP socket, or sets where
+/// writes to a UDP socket go.
+fn sys_connect([handle, address, port, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let (address, port) = address_and_port(address, port)?;
+    current_socket(handle)?.connect(address, port)?;
+    Ok(0)
+}
+
+/// `listen(handle)`: makes a bound TCP socket accept connections.
+fn sys_listen([handle, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    current_socket(handle)?.listen()?;
+    Ok(0)
+}
+
+/// `accept(handle, peer)`: waits for a connection and returns the handle of
+/// a new socket for it. Stores the address of the peer at `peer` like
+/// [`SYS_RECV_FROM`] unless it is null.
+fn sys_accept([handle, peer, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    if peer != 0 {
+        user_ptr::validate(peer, 8, true)?;
+    }
+    let (socket, (address, port)) = current_socket(handle)?.accept()?;
+    let handle = add_socket(socket)?;
+    if peer != 0 {
+        store_address(peer, address, port)?;
+    }
+    Ok(handle)
+}
+
+/// `send_to(handle, buf, len, address, port)`: sends `len` bytes in a UDP
+/// datagram to `port` of `address`, or on the stream of a connected TCP
+/// socket. Returns how many bytes were sent.
+fn sys_send_to([handle, buf, len, address, port, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let socket = current_socket(handle)?;
+    let (address, port) = address_and_port(address, port)?;
+    let data = user_ptr::copy_from_user(buf, len.min(MAX_IO))?;
+    Ok(socket.send_to(&data, address, port)? as u64)
+}
+
+/// `recv_from(handle, buf, len, from)`: waits for data and reads up to
+/// `len` bytes of it. Stores the sender at `from` as `address << 16 | port`
+/// unless it is null, and returns how many bytes were read; 0 once the peer
+/// of a TCP socket has closed the connection.
+fn sys_recv_from([handle, buf, len, from, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    let socket = current_socket(handle)?;
+    let len = len.min(MAX_IO);
+    user_ptr::validate(buf, len, true)?;
+    if from != 0 {
+        user_ptr::validate(from, 8, true)?;
+    }
+    let mut data = vec![0; len as usize];
+    let (read, address, port) = socket.recv_from(&mut data)?;
+    user_ptr::copy_to_user(buf, &data[..read])?;
+    if from != 0 {
+        store_address(from, address, port)?;
+    }
+    Ok(read as u64)
+}
+
+/// `resolve(name, name_len)`: looks up the address of a host name, returned
+/// as the number [`SYS_CONNECT`] takes.
+fn sys_resolve([name, name_len, ..]: [u64; 6]) -> Result<u64, SyscallError> {
+    if name_len > MAX_HOST_NAME {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let name = user_ptr::read_str(name, name_len)?;
+    Ok(dns::resolve(&name)?.to_u32() as u64)
+}
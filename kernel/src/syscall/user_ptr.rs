@@ -0,0 +1,63 @@
+//! Access to user memory from system calls.
+//!
+//! Every pointer handed in by user code is checked against the page tables of
+//! the calling thread before the kernel touches it, so a bad pointer turns
+//! into an error instead of a kernel page fault.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::ptr;
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use super::SyscallError;
+use crate::{address_space::USER_END, thread};
+
+/// Checks that `len` bytes at `addr` are mapped user memory of the current
+/// thread, writable if `write` is set.
+pub fn validate(addr: u64, len: u64, write: bool) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+    let address_space = thread::current_address_space().ok_or(SyscallError::BadAddress)?;
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut page = addr & !0xfff;
+    while page < end {
+        let flags = address_space
+            .page_flags(VirtAddr::new(page))
+            .ok_or(SyscallError::BadAddress)?;
+        if !flags.contains(required) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// Copies `len` bytes of user memory at `addr` into the kernel.
+pub fn copy_from_user(addr: u64, len: u64) -> Result<Vec<u8>, SyscallError> {
+    validate(addr, len, false)?;
+    let mut buffer = vec![0; len as usize];
+    unsafe { ptr::copy_nonoverlapping(addr as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+    Ok(buffer)
+}
+
+/// Copies `data` to user memory at `addr`.
+pub fn copy_to_user(addr: u64, data: &[u8]) -> Result<(), SyscallError> {
+    validate(addr, data.len() as u64, true)?;
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len()) };
+    Ok(())
+}
+
+/// Copies a UTF-8 string of `len` bytes at `addr` into the kernel.
+pub fn read_str(addr: u64, len: u64) -> Result<String, SyscallError> {
+    let bytes = copy_from_user(addr, len)?;
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
@@ -0,0 +1,151 @@
+use core::arch::{asm, global_asm};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// Register state saved on a thread's stack when it is switched out.
+///
+/// The general purpose registers are pushed by the entry stubs below, the
+/// remaining fields form the interrupt stack frame pushed by the CPU, so
+/// resuming a thread is a matter of popping them again and `iretq`.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// x87, MMX and SSE register state in the `fxsave` layout.
+///
+/// The kernel is built without SSE, so only user code touches these
+/// registers. They are saved and restored by the scheduler on every switch
+/// rather than by the entry stubs.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state after `fninit` with all SSE exceptions masked.
+    pub fn new() -> Self {
+        let mut state = FpuState([0; 512]);
+        // FCW: all x87 exceptions masked, extended precision
+        state.0[0..2].copy_from_slice(&0x037fu16.to_le_bytes());
+        // MXCSR: all SSE exceptions masked
+        state.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        state
+    }
+
+    /// Stores the registers of the calling CPU.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags));
+        }
+    }
+
+    /// Loads the registers of the calling CPU.
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets the calling CPU execute x87 and SSE instructions and save their
+/// state with `fxsave`.
+pub fn init_fpu() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+macro_rules! switch_entry {
+    ($entry:literal, $handler:literal) => {
+        global_asm!(concat!(
+            ".global ", $entry, "\n",
+            $entry, ":\n",
+            // coming from user mode, switch to the kernel GS base
+            "    test qword ptr [rsp + 8], 3\n",
+            "    jz 1f\n",
+            "    swapgs\n",
+            "1:\n",
+            "    push rax\n",
+            "    push rbx\n",
+            "    push rcx\n",
+            "    push rdx\n",
+            "    push rsi\n",
+            "    push rdi\n",
+            "    push rbp\n",
+            "    push r8\n",
+            "    push r9\n",
+            "    push r10\n",
+            "    push r11\n",
+            "    push r12\n",
+            "    push r13\n",
+            "    push r14\n",
+            "    push r15\n",
+            "    mov rdi, rsp\n",
+            "    call ", $handler, "\n",
+            "    mov rsp, rax\n",
+            "    pop r15\n",
+            "    pop r14\n",
+            "    pop r13\n",
+            "    pop r12\n",
+            "    pop r11\n",
+            "    pop r10\n",
+            "    pop r9\n",
+            "    pop r8\n",
+            "    pop rbp\n",
+            "    pop rdi\n",
+            "    pop rsi\n",
+            "    pop rdx\n",
+            "    pop rcx\n",
+            "    pop rbx\n",
+            "    pop rax\n",
+            // the resumed thread may be in user mode even if the interrupted one was not
+            "    test qword ptr [rsp + 8], 3\n",
+            "    jz 2f\n",
+            "    swapgs\n",
+            "2:\n",
+            "    iretq\n",
+        ));
+    };
+}
+
+// All entries save the full register state of the interrupted thread, let
+// the scheduler pick the next one and resume whatever stack pointer it returns.
+switch_entry!("timer_interrupt_entry", "timer_interrupt_switch");
+switch_entry!("yield_interrupt_entry", "yield_interrupt_switch");
+switch_entry!("reschedule_interrupt_entry", "reschedule_interrupt_switch");
+
+extern "C" {
+    pub fn timer_interrupt_entry();
+    pub fn yield_interrupt_entry();
+    pub fn reschedule_interrupt_entry();
+}
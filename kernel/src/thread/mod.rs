@@ -0,0 +1,478 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    arch::asm,
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::{
+        hlt, interrupts,
+        segmentation::{Segment, CS, SS},
+    },
+    VirtAddr,
+};
+
+use self::{
+    context::{Context, FpuState},
+    scheduler::{Scheduler, SCHEDULER},
+};
+use crate::{address_space::AddressSpace, process::Pid, time, usermode};
+
+pub mod context;
+pub mod scheduler;
+
+/// Size of the kernel stack given to every spawned thread.
+pub const STACK_SIZE: usize = 4096 * 16;
+
+/// Interrupt vector used by [`yield_now`] to enter the scheduler.
+pub const YIELD_VECTOR: u8 = 0x81;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Scheduling priority. Threads of a higher priority always run before
+/// threads of a lower one; threads of equal priority share the CPU round-robin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(usize)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Sleeping,
+    Dead,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    priority: Priority,
+    state: ThreadState,
+    /// Stack pointer to the saved [`Context`] while the thread is not running.
+    rsp: u64,
+    /// `None` for the boot thread, which runs on the stack set up by the bootloader.
+    stack: Option<Vec<u8>>,
+    wake_tick: u64,
+    joiner: Option<ThreadId>,
+    /// CPU whose run queue the thread belongs to.
+    cpu: usize,
+    /// CPU the thread must run on, if any.
+    affinity: Option<usize>,
+    /// User address space the thread runs in; kernel threads have none.
+    address_space: Option<Arc<AddressSpace>>,
+    /// Process the thread belongs to; kernel threads have none.
+    process: Option<Pid>,
+    /// Set when the process is killed. The thread exits the next time it
+    /// would return to user mode.
+    killed: bool,
+    /// x87/SSE registers while the thread is not running.
+    fpu: Box<FpuState>,
+}
+
+impl Thread {
+    fn new(name: &str, priority: Priority, main: Box<dyn FnOnce() + Send>) -> Self {
+        let stack = vec![0u8; STACK_SIZE];
+        let stack_top = (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+        let rsp = unsafe {
+            write_entry_context(
+                stack_top,
+                thread_entry as *const () as u64,
+                Box::into_raw(Box::new(main)) as u64,
+            )
+        };
+
+        Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            priority,
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            wake_tick: 0,
+            joiner: None,
+            cpu: 0,
+            affinity: None,
+            address_space: None,
+            process: None,
+            killed: false,
+            fpu: Box::default(),
+        }
+    }
+
+    /// Represents code that is already running on a stack set up elsewhere,
+    /// pinned to the calling CPU.
+    fn boot(name: &str) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            priority: Priority::Normal,
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            wake_tick: 0,
+            joiner: None,
+            cpu: 0,
+            affinity: Some(crate::percpu::current().cpu_id),
+            address_space: None,
+            process: None,
+            killed: false,
+            fpu: Box::default(),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>> {
+        self.address_space.as_ref()
+    }
+
+    /// Top of the thread's kernel stack, if it owns one.
+    pub fn stack_top(&self) -> Option<u64> {
+        self.stack
+            .as_ref()
+            .map(|stack| (stack.as_ptr() as u64 + stack.len() as u64) & !0xf)
+    }
+
+    /// Makes a killed thread that is about to return to user mode run
+    /// [`killed_entry`] instead. Called only while the thread is switched out.
+    fn redirect_if_killed(&mut self) {
+        if !self.killed {
+            return;
+        }
+        let Some(stack_top) = self.stack_top() else {
+            return;
+        };
+        let context = unsafe { &*(self.rsp as *const Context) };
+        if context.cs & 3 == 3 {
+            // the user state is abandoned, so the whole kernel stack is free
+            self.rsp = unsafe { write_entry_context(stack_top, killed_entry as *const () as u64, 0) };
+        }
+    }
+}
+
+/// Prepares the stack ending at `stack_top` so that switching to the returned
+/// stack pointer calls `entry(arg)` in kernel mode.
+///
+/// ## Safety
+/// The stack must be owned by the thread and not in use.
+unsafe fn write_entry_context(stack_top: u64, entry: u64, arg: u64) -> u64 {
+    // `entry` is entered through `iretq` as if it had been called, so the
+    // stack pointer is misaligned by the return address
+    let context = Context {
+        rip: entry,
+        cs: CS::get_reg().0 as u64,
+        rflags: 0x202, // IF set
+        rsp: stack_top - 8,
+        ss: SS::get_reg().0 as u64,
+        rdi: arg,
+        ..Default::default()
+    };
+    let rsp = stack_top - 16 - size_of::<Context>() as u64;
+    (rsp as *mut Context).write(context);
+    rsp
+}
+
+extern "C" fn killed_entry(_: u64) -> ! {
+    crate::process::exit_current_thread(None);
+}
+
+extern "C" fn thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
+fn idle_main() {
+    loop {
+        hlt();
+    }
+}
+
+/// Turns the code running on the boot stack into the `main` thread and
+/// creates the idle thread of the BSP. Must be called after the heap and the
+/// per-CPU data are initialized.
+pub fn init() {
+    context::init_fpu();
+    let boot = Thread::boot("main");
+    let mut idle = Thread::new("idle", Priority::Low, Box::new(idle_main));
+    idle.affinity = Some(0);
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler::new(boot, idle));
+    });
+    #[cfg(debug_assertions)]
+    crate::sync::lockdep::enable();
+}
+
+/// Registers the calling application processor with the scheduler. The code
+/// calling this becomes the idle thread of the CPU and should halt in a loop
+/// with interrupts enabled afterwards.
+pub fn init_ap() {
+    context::init_fpu();
+    let idle = Thread::boot("idle");
+    with_scheduler(|scheduler| scheduler.add_cpu(idle));
+}
+
+/// Runs `f` with the scheduler locked and interrupts disabled.
+pub(crate) fn with_scheduler<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Scheduler) -> R,
+{
+    interrupts::without_interrupts(|| {
+        f(SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("Scheduler not initialized"))
+    })
+}
+
+/// A handle to wait for a spawned thread and collect its return value.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks the current thread until the thread has finished.
+    pub fn join(self) -> T {
+        loop {
+            if let Some(result) = self.result.lock().take() {
+                return result;
+            }
+            interrupts::without_interrupts(|| {
+                let waiting = with_scheduler(|scheduler| {
+                    let current = scheduler.current();
+                    match scheduler.get_mut(self.id) {
+                        Some(thread) if thread.state != ThreadState::Dead => {
+                            thread.joiner = Some(current);
+                            scheduler.block_current();
+                            true
+                        }
+                        _ => false,
+                    }
+                });
+                if waiting {
+                    yield_now();
+                }
+            });
+        }
+    }
+}
+
+/// Spawns a new kernel thread of normal priority running `f`.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with("kernel", Priority::Normal, f)
+}
+
+/// Spawns a new named kernel thread of the given priority running `f`.
+pub fn spawn_with<F, T>(name: &str, priority: Priority, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let thread = Thread::new(
+        name,
+        priority,
+        Box::new(move || {
+            let value = f();
+            *thread_result.lock() = Some(value);
+        }),
+    );
+    let id = thread.id;
+    with_scheduler(|scheduler| scheduler.add(thread));
+    JoinHandle { id, result }
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    // enters the scheduler through `YIELD_VECTOR`
+    unsafe { asm!("int 0x81") };
+}
+
+/// Puts the current thread to sleep for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    let wake_tick = time::ticks() + time::ms_to_ticks(ms).max(1);
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| scheduler.sleep_current(wake_tick));
+        yield_now();
+    });
+}
+
+/// Terminates the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    with_scheduler(|scheduler| scheduler.exit_current());
+    yield_now();
+    unreachable!("dead thread was scheduled again");
+}
+
+/// Moves the current thread into `address_space` and activates it. The
+/// address space is switched to whenever the thread is scheduled from now on.
+pub fn set_address_space(address_space: Arc<AddressSpace>) {
+    interrupts::without_interrupts(|| {
+        address_space.activate();
+        with_scheduler(|scheduler| {
+            scheduler.current_thread_mut().address_space = Some(address_space)
+        });
+    });
+}
+
+/// Address space of the current thread, `None` for kernel threads.
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    with_scheduler(|scheduler| scheduler.current_thread_mut().address_space.clone())
+}
+
+/// Process of the current thread, `None` for kernel threads.
+pub fn current_process() -> Option<Pid> {
+    with_scheduler(|scheduler| scheduler.current_thread_mut().process)
+}
+
+/// Whether the current thread's process has been killed.
+pub fn is_killed() -> bool {
+    with_scheduler(|scheduler| scheduler.current_thread_mut().killed)
+}
+
+/// Starts a thread of process `pid` that enters user mode at `entry`.
+pub(crate) fn spawn_user(
+    name: &str,
+    pid: Pid,
+    address_space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+) -> ThreadId {
+    let mut thread = Thread::new(
+        name,
+        Priority::Normal,
+        Box::new(move || {
+            usermode::enter(entry, stack_pointer);
+        }),
+    );
+    thread.address_space = Some(address_space);
+    thread.process = Some(pid);
+    let id = thread.id;
+    with_scheduler(|scheduler| scheduler.add(thread));
+    id
+}
+
+/// Marks a thread as killed. It exits the next time it would return to user
+/// mode; a sleeping or blocked thread is woken up for that.
+pub fn kill(id: ThreadId) -> bool {
+    with_scheduler(|scheduler| scheduler.kill(id))
+}
+
+/// Returns the ID of the thread that is currently running.
+pub fn current_id() -> ThreadId {
+    scheduler::current_thread_id()
+}
+
+/// Marks the current thread as blocked without switching away yet.
+///
+/// The thread keeps running until it calls [`yield_now`]. An [`unpark`] in
+/// between makes it runnable again, so a wake-up is never lost as long as the
+/// thread publishes its ID to the waker before blocking.
+pub fn block_current() {
+    with_scheduler(|scheduler| scheduler.block_current());
+}
+
+/// Like [`block_current`], but leaves a killed thread running and returns
+/// `false` for it. [`kill`] wakes the thread once it is blocked, so checking
+/// and blocking in one step means the kill cannot slip in between.
+pub fn block_current_killable() -> bool {
+    with_scheduler(|scheduler| {
+        if scheduler.current_thread_mut().killed {
+            return false;
+        }
+        scheduler.block_current();
+        true
+    })
+}
+
+/// Like [`block_current`], but the thread also becomes runnable again by
+/// itself at timer tick `wake_tick`.
+pub fn block_current_until(wake_tick: u64) {
+    with_scheduler(|scheduler| scheduler.sleep_current(wake_tick));
+}
+
+/// Like [`block_current_until`], but leaves a killed thread running and
+/// returns `false` for it.
+pub fn block_current_until_killable(wake_tick: u64) -> bool {
+    with_scheduler(|scheduler| {
+        if scheduler.current_thread_mut().killed {
+            return false;
+        }
+        scheduler.sleep_current(wake_tick);
+        true
+    })
+}
+
+/// Blocks the current thread until [`unpark`] is called on it.
+///
+/// Callers must make sure the wake-up cannot be missed, typically by
+/// publishing their ID somewhere the waker will find it before parking.
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| scheduler.block_current());
+        yield_now();
+    });
+}
+
+/// Makes a parked thread runnable again. Returns `false` if it was not parked.
+pub fn unpark(id: ThreadId) -> bool {
+    with_scheduler(|scheduler| scheduler.wake(id))
+}
@@ -0,0 +1,357 @@
+use alloc::{collections::BTreeMap, collections::VecDeque, vec, vec::Vec};
+
+use spin::Mutex;
+
+use super::{Priority, Thread, ThreadId, ThreadState};
+use x86_64::VirtAddr;
+
+use crate::{address_space, apic, interrupts, percpu};
+
+/// Number of scheduler timer periods a thread may run before it is preempted.
+const TIME_SLICE_TICKS: u64 = 2;
+
+pub static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// ID of the thread running on the calling CPU, readable without taking the scheduler lock.
+pub fn current_thread_id() -> ThreadId {
+    if percpu::is_initialized() {
+        ThreadId(percpu::current().current_thread())
+    } else {
+        ThreadId(0)
+    }
+}
+
+fn this_cpu() -> usize {
+    percpu::current().cpu_id
+}
+
+/// Scheduling state owned by one CPU.
+struct CpuQueue {
+    run_queues: [VecDeque<ThreadId>; Priority::COUNT],
+    current: ThreadId,
+    idle: ThreadId,
+    slice_left: u64,
+    /// Threads that exited on this CPU. Only this CPU may free them, since it
+    /// is the one that might still be running on their stacks.
+    zombies: Vec<ThreadId>,
+}
+
+impl CpuQueue {
+    fn new(current: ThreadId, idle: ThreadId) -> Self {
+        CpuQueue {
+            run_queues: Default::default(),
+            current,
+            idle,
+            slice_left: TIME_SLICE_TICKS,
+            zombies: Vec::new(),
+        }
+    }
+
+    fn has_ready(&self) -> bool {
+        self.run_queues.iter().any(|queue| !queue.is_empty())
+    }
+
+    fn load(&self) -> usize {
+        let running = (self.current != self.idle) as usize;
+        self.run_queues.iter().map(|queue| queue.len()).sum::<usize>() + running
+    }
+}
+
+/// A round-robin scheduler with one set of priority run queues per CPU.
+///
+/// Threads are assigned to the least loaded CPU when they are spawned and stay
+/// there, so a thread is never resumed by one CPU while another is still
+/// switching away from it.
+///
+/// The scheduler is only ever locked with interrupts disabled, so the timer
+/// interrupt can never find it locked by the thread it interrupted.
+pub struct Scheduler {
+    threads: BTreeMap<ThreadId, Thread>,
+    cpus: Vec<CpuQueue>,
+    sleeping: Vec<ThreadId>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler whose current thread on the BSP is `boot`, the
+    /// thread that has been running on the boot stack so far.
+    pub fn new(mut boot: Thread, mut idle: Thread) -> Self {
+        boot.cpu = 0;
+        idle.cpu = 0;
+        percpu::current().set_current_thread(boot.id.0);
+        let cpus = vec![CpuQueue::new(boot.id, idle.id)];
+        let mut threads = BTreeMap::new();
+        threads.insert(boot.id, boot);
+        threads.insert(idle.id, idle);
+        Scheduler {
+            threads,
+            cpus,
+            sleeping: Vec::new(),
+        }
+    }
+
+    /// Registers the calling application processor. `idle` represents the
+    /// code running on its startup stack, which becomes its idle thread.
+    pub fn add_cpu(&mut self, mut idle: Thread) {
+        let cpu = this_cpu();
+        assert_eq!(cpu, self.cpus.len(), "CPUs must be registered in order");
+        idle.cpu = cpu;
+        percpu::current().set_current_thread(idle.id.0);
+        self.cpus.push(CpuQueue::new(idle.id, idle.id));
+        self.threads.insert(idle.id, idle);
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.cpus[this_cpu()].current
+    }
+
+    pub fn current_thread_mut(&mut self) -> &mut Thread {
+        let current = self.current();
+        self.threads
+            .get_mut(&current)
+            .expect("current thread missing from thread table")
+    }
+
+    pub fn get(&self, id: ThreadId) -> Option<&Thread> {
+        self.threads.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.get_mut(&id)
+    }
+
+    /// Adds a freshly created thread to the least loaded CPU and makes it runnable.
+    pub fn add(&mut self, mut thread: Thread) {
+        let cpu = match thread.affinity {
+            Some(cpu) if cpu < self.cpus.len() => cpu,
+            _ => (0..self.cpus.len())
+                .min_by_key(|&cpu| self.cpus[cpu].load())
+                .unwrap_or(0),
+        };
+        thread.cpu = cpu;
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        self.enqueue(id);
+        self.kick(cpu);
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).unwrap();
+        let queue = &mut self.cpus[thread.cpu];
+        if id == queue.idle {
+            return;
+        }
+        thread.state = ThreadState::Ready;
+        queue.run_queues[thread.priority as usize].push_back(id);
+    }
+
+    /// Makes an idle remote CPU look at its run queue again.
+    fn kick(&self, cpu: usize) {
+        let queue = &self.cpus[cpu];
+        if cpu == this_cpu() || queue.current != queue.idle {
+            return;
+        }
+        if let Some(per_cpu) = percpu::get(cpu) {
+            apic::send_ipi(per_cpu.apic_id, interrupts::RESCHEDULE_VECTOR);
+        }
+    }
+
+    /// Makes a blocked or sleeping thread runnable again.
+    ///
+    /// Returns `false` if the thread does not exist or was not waiting.
+    pub fn wake(&mut self, id: ThreadId) -> bool {
+        let (state, cpu) = match self.threads.get(&id) {
+            Some(thread) => (thread.state, thread.cpu),
+            None => return false,
+        };
+        match state {
+            ThreadState::Blocked => {}
+            ThreadState::Sleeping => self.sleeping.retain(|&sleeper| sleeper != id),
+            _ => return false,
+        }
+        self.enqueue(id);
+        self.kick(cpu);
+        true
+    }
+
+    /// Marks a thread as killed and wakes it if it is sleeping or blocked.
+    /// Killable waits then fail; uninterruptible ones go back to sleep.
+    pub fn kill(&mut self, id: ThreadId) -> bool {
+        let Some(thread) = self.threads.get_mut(&id) else {
+            return false;
+        };
+        if thread.state == ThreadState::Dead {
+            return false;
+        }
+        thread.killed = true;
+        if matches!(thread.state, ThreadState::Sleeping | ThreadState::Blocked) {
+            self.wake(id);
+        }
+        true
+    }
+
+    /// Marks the current thread as blocked. It will not be scheduled again
+    /// until somebody calls [`Scheduler::wake`] on it.
+    pub fn block_current(&mut self) {
+        self.current_thread_mut().state = ThreadState::Blocked;
+    }
+
+    /// Puts the current thread to sleep until the given timer tick.
+    pub fn sleep_current(&mut self, wake_tick: u64) {
+        let current = self.current();
+        let thread = self.current_thread_mut();
+        thread.state = ThreadState::Sleeping;
+        thread.wake_tick = wake_tick;
+        self.sleeping.push(current);
+    }
+
+    /// Marks the current thread as finished and wakes whoever is joining it.
+    pub fn exit_current(&mut self) {
+        let current = self.current();
+        let thread = self.current_thread_mut();
+        thread.state = ThreadState::Dead;
+        let joiner = thread.joiner.take();
+        let cpu = thread.cpu;
+        self.cpus[cpu].zombies.push(current);
+        if let Some(joiner) = joiner {
+            self.wake(joiner);
+        }
+    }
+
+    /// Wakes every sleeping thread whose wake-up tick has passed.
+    pub fn wake_sleepers(&mut self, now: u64) {
+        let mut index = 0;
+        while index < self.sleeping.len() {
+            let id = self.sleeping[index];
+            if self.threads[&id].wake_tick <= now {
+                self.sleeping.swap_remove(index);
+                let cpu = self.threads[&id].cpu;
+                self.enqueue(id);
+                self.kick(cpu);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// Accounts one scheduler timer period on the calling CPU. Returns `true`
+    /// if its current thread should be preempted.
+    pub fn tick(&mut self) -> bool {
+        let queue = &mut self.cpus[this_cpu()];
+        queue.slice_left = queue.slice_left.saturating_sub(1);
+        queue.slice_left == 0 || (queue.current == queue.idle && queue.has_ready())
+    }
+
+    /// Whether the calling CPU is idling although it has work queued.
+    pub fn should_reschedule(&self) -> bool {
+        let queue = &self.cpus[this_cpu()];
+        queue.current == queue.idle && queue.has_ready()
+    }
+
+    /// Saves the stack pointer of the current thread, selects the next thread
+    /// to run on this CPU and returns the stack pointer to resume it from.
+    pub fn switch(&mut self, rsp: u64) -> u64 {
+        let cpu = this_cpu();
+        self.reap_zombies(cpu);
+
+        let current = self.cpus[cpu].current;
+        let thread = self.threads.get_mut(&current).unwrap();
+        thread.rsp = rsp;
+        thread.fpu.save();
+        let state = thread.state;
+        match state {
+            ThreadState::Running => self.enqueue(current),
+            // woken up again before it managed to block; it is already queued
+            ThreadState::Ready => {}
+            ThreadState::Blocked | ThreadState::Sleeping | ThreadState::Dead => {}
+        }
+
+        let queue = &mut self.cpus[cpu];
+        let idle = queue.idle;
+        let next = queue
+            .run_queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+            .unwrap_or(idle);
+        queue.current = next;
+        queue.slice_left = TIME_SLICE_TICKS;
+        percpu::current().set_current_thread(next.0);
+
+        let thread = self.threads.get_mut(&next).unwrap();
+        thread.state = ThreadState::Running;
+        thread.redirect_if_killed();
+        thread.fpu.restore();
+        if let Some(stack_top) = thread.stack_top() {
+            percpu::current().set_kernel_stack(VirtAddr::new(stack_top));
+        }
+        // kernel threads leave user address spaces too, so none stays loaded
+        // on a CPU after its owner is gone
+        match &thread.address_space {
+            Some(space) => space.activate(),
+            None => address_space::activate_kernel(),
+        }
+        thread.rsp
+    }
+
+    /// Frees the threads that have exited on this CPU, except the current one
+    /// whose stack is still in use until the switch completes.
+    fn reap_zombies(&mut self, cpu: usize) {
+        let queue = &mut self.cpus[cpu];
+        let current = queue.current;
+        let threads = &mut self.threads;
+        queue.zombies.retain(|&id| {
+            if id == current {
+                true
+            } else {
+                threads.remove(&id);
+                false
+            }
+        });
+    }
+}
+
+/// Called from the scheduler timer interrupt with the stack pointer of the interrupted thread.
+pub fn timer_tick(rsp: u64) -> u64 {
+    let mut scheduler = SCHEDULER.lock();
+    match scheduler.as_mut() {
+        Some(scheduler) => {
+            if scheduler.tick() {
+                scheduler.switch(rsp)
+            } else {
+                rsp
+            }
+        }
+        None => rsp,
+    }
+}
+
+/// Called from the reschedule IPI sent to idle CPUs when a thread becomes runnable on them.
+pub fn reschedule(rsp: u64) -> u64 {
+    let mut scheduler = SCHEDULER.lock();
+    match scheduler.as_mut() {
+        Some(scheduler) => {
+            if scheduler.should_reschedule() {
+                scheduler.switch(rsp)
+            } else {
+                rsp
+            }
+        }
+        None => rsp,
+    }
+}
+
+/// Called from the system clock interrupt.
+pub fn wake_sleepers(now: u64) {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.wake_sleepers(now);
+    }
+}
+
+#[no_mangle]
+extern "C" fn yield_interrupt_switch(rsp: u64) -> u64 {
+    let mut scheduler = SCHEDULER.lock();
+    match scheduler.as_mut() {
+        Some(scheduler) => scheduler.switch(rsp),
+        None => rsp,
+    }
+}
@@ -0,0 +1,45 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT timer interrupt, i.e. the resolution of the system clock.
+pub const TICKS_PER_SECOND: u64 = 100;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PORT_PIT_CHANNEL0: u16 = 0x0040;
+const PORT_PIT_COMMAND: u16 = 0x0043;
+const PIT_CMD_CHANNEL0_RATE_GENERATOR: u8 = 0x34; // channel 0, lobyte/hibyte, mode 2
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT so that the timer interrupt fires `TICKS_PER_SECOND` times a second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    let mut command = Port::new(PORT_PIT_COMMAND);
+    let mut channel0 = Port::new(PORT_PIT_CHANNEL0);
+    unsafe {
+        command.write(PIT_CMD_CHANNEL0_RATE_GENERATOR);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Advances the system clock by one tick. Called from the timer interrupt.
+pub fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Number of timer ticks since the PIT was initialized.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the PIT was initialized.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICKS_PER_SECOND
+}
+
+/// Converts a duration in milliseconds into timer ticks, rounding up.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICKS_PER_SECOND).div_ceil(1000)
+}
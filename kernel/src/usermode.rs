@@ -0,0 +1,54 @@
+//! Transitions from the kernel into ring 3.
+
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use crate::gdt;
+
+/// Leaves the kernel and starts executing user code at `entry` with the given
+/// stack, in the address space that is currently active.
+///
+/// Interrupts arriving while the user code runs are taken on the kernel stack
+/// of the calling thread, so the caller must be a thread with its own stack.
+pub fn enter(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let user_code = selectors.user_code_selector.0 as u64;
+    let user_data = selectors.user_data_selector.0 as u64;
+
+    unsafe {
+        asm!(
+            "cli",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",  // ss
+            "push {stack}", // rsp
+            "push 0x202",   // rflags with IF set
+            "push {code}",  // cs
+            "push {entry}", // rip
+            // the user's GS base goes live, the per-CPU pointer into KERNEL_GS_BASE
+            "swapgs",
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "iretq",
+            data = in(reg) user_data,
+            code = in(reg) user_code,
+            stack = in(reg) stack_top.as_u64(),
+            entry = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Device files. Drivers register their devices here to make them show up
+//! under the mount point of the devfs, usually `/dev`.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{DirEntry, FileType, Filesystem, Inode, Metadata, VfsError};
+
+static DEVICES: spin::Mutex<BTreeMap<String, Arc<dyn Inode>>> = spin::Mutex::new(BTreeMap::new());
+
+/// Makes `device` available as `name` in the devfs.
+pub fn register(name: &str, device: Arc<dyn Inode>) -> Result<(), VfsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    devices.insert(name.to_string(), device);
+    Ok(())
+}
+
+pub fn unregister(name: &str) -> Option<Arc<dyn Inode>> {
+    DEVICES.lock().remove(name)
+}
+
+/// Registers the devices every system has.
+pub fn init() {
+    register("null", Arc::new(Null)).unwrap();
+    register("zero", Arc::new(Zero)).unwrap();
+}
+
+pub struct DevFs;
+
+impl Filesystem for DevFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+
+    fn name(&self) -> &str {
+        "devfs"
+    }
+}
+
+struct Root;
+
+impl Inode for Root {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Ok(Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+            inode: 0,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        DEVICES.lock().get(name).cloned().ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let devices: Vec<_> = DEVICES
+            .lock()
+            .iter()
+            .map(|(name, device)| (name.clone(), device.clone()))
+            .collect();
+        Ok(devices
+            .into_iter()
+            .filter_map(|(name, device)| {
+                Some(DirEntry {
+                    name,
+                    file_type: device.stat().ok()?.file_type,
+                })
+            })
+            .collect())
+    }
+}
+
+fn char_device(inode: u64) -> Metadata {
+    Metadata {
+        file_type: FileType::CharDevice,
+        size: 0,
+        inode,
+        mode: 0o666,
+        uid: 0,
+        gid: 0,
+    }
+}
+
+/// Discards writes and reads nothing.
+struct Null;
+
+impl Inode for Null {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Ok(char_device(1))
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// Discards writes and reads zeros.
+struct Zero;
+
+impl Inode for Zero {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Ok(char_device(2))
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+//! Directory blocks: chains of variable-length entries, each giving the
+//! length of its record so that deleted entries can be skipped or reused.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::vfs::{FileType, VfsError};
+
+/// Entry header: inode, record length, name length and file type.
+const HEADER_SIZE: usize = 8;
+const MAX_NAME_LEN: usize = 255;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_SYMLINK: u8 = 7;
+
+/// Type byte of directory entries for `file_type`.
+pub fn type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::CharDevice => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+        FileType::Symlink => FT_SYMLINK,
+    }
+}
+
+/// File type from the type byte of a directory entry, if it carries one.
+pub fn file_type(code: u8) -> Option<FileType> {
+    match code {
+        FT_REG_FILE => Some(FileType::File),
+        FT_DIR => Some(FileType::Directory),
+        FT_CHRDEV => Some(FileType::CharDevice),
+        FT_BLKDEV => Some(FileType::BlockDevice),
+        FT_SYMLINK => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub inode: u32,
+    pub name: String,
+    /// Zero unless the filesystem stores types in directory entries.
+    pub type_code: u8,
+    /// Position of the entry within its block.
+    pub offset: usize,
+}
+
+/// Reads directory entries the same way with or without the file type
+/// feature, which turns the high byte of the name length into a type.
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    pub file_types: bool,
+}
+
+/// Length of the record holding a name of `name_len` bytes.
+fn record_len(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+fn read_u16(block: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([block[offset], block[offset + 1]])
+}
+
+fn read_u32(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+impl Format {
+    fn name_len(&self, block: &[u8], offset: usize) -> usize {
+        if self.file_types {
+            block[offset + 6] as usize
+        } else {
+            read_u16(block, offset + 6) as usize
+        }
+    }
+
+    /// Record lengths of the entries in `block`, checked to stay inside it.
+    fn records(&self, block: &[u8]) -> Result<Vec<(usize, usize)>, VfsError> {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < block.len() {
+            if offset + HEADER_SIZE > block.len() {
+                return Err(VfsError::Io);
+            }
+            let len = read_u16(block, offset + 4) as usize;
+            if len < HEADER_SIZE
+                || !len.is_multiple_of(4)
+                || offset + len > block.len()
+                || HEADER_SIZE + self.name_len(block, offset) > len
+            {
+                return Err(VfsError::Io);
+            }
+            records.push((offset, len));
+            offset += len;
+        }
+        Ok(records)
+    }
+
+    pub fn parse(&self, block: &[u8]) -> Result<Vec<Entry>, VfsError> {
+        Ok(self
+            .records(block)?
+            .into_iter()
+            .filter(|&(offset, _)| read_u32(block, offset) != 0)
+            .map(|(offset, _)| {
+                let name_len = self.name_len(block, offset);
+                let name = &block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len];
+                Entry {
+                    inode: read_u32(block, offset),
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    type_code: if self.file_types {
+                        block[offset + 7]
+                    } else {
+                        0
+                    },
+                    offset,
+                }
+            })
+            .collect())
+    }
+
+    fn write_entry(
+        &self,
+        block: &mut [u8],
+        offset: usize,
+        len: usize,
+        inode: u32,
+        name: &str,
+        type_code: u8,
+    ) {
+        block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+        block[offset + 4..offset + 6].copy_from_slice(&(len as u16).to_le_bytes());
+        if self.file_types {
+            block[offset + 6] = name.len() as u8;
+            block[offset + 7] = type_code;
+        } else {
+            block[offset + 6..offset + 8].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        }
+        block[offset + HEADER_SIZE..offset + HEADER_SIZE + name.len()]
+            .copy_from_slice(name.as_bytes());
+    }
+
+    /// Fills a fresh directory block with a single entry spanning all of it,
+    /// or with an unused one if `inode` is 0.
+    pub fn init_block(&self, block: &mut [u8], inode: u32, name: &str, type_code: u8) {
+        let len = block.len();
+        self.write_entry(block, 0, len, inode, name, type_code);
+    }
+
+    /// Adds an entry to `block` if there is room, splitting the record of an
+    /// entry with slack after its name.
+    pub fn insert(
+        &self,
+        block: &mut [u8],
+        inode: u32,
+        name: &str,
+        type_code: u8,
+    ) -> Result<bool, VfsError> {
+        let needed = record_len(name.len());
+        for (offset, len) in self.records(block)? {
+            let used = if read_u32(block, offset) == 0 {
+                0
+            } else {
+                record_len(self.name_len(block, offset))
+            };
+            if len - used >= needed {
+                if used != 0 {
+                    block[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                }
+                self.write_entry(block, offset + used, len - used, inode, name, type_code);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Removes entry `name` from `block`, giving its record to the entry in
+    /// front of it.
+    pub fn remove(&self, block: &mut [u8], name: &str) -> Result<bool, VfsError> {
+        let records = self.records(block)?;
+        for (i, &(offset, len)) in records.iter().enumerate() {
+            let name_len = self.name_len(block, offset);
+            if read_u32(block, offset) == 0
+                || &block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len] != name.as_bytes()
+            {
+                continue;
+            }
+            match i.checked_sub(1).map(|previous| records[previous]) {
+                Some((previous, previous_len)) => {
+                    let merged = (previous_len + len) as u16;
+                    block[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+                }
+                // the first entry of a block cannot be merged, only cleared
+                None => block[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes()),
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Points the entry at `offset` to another inode.
+    pub fn set_inode(&self, block: &mut [u8], offset: usize, inode: u32) {
+        block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.len() > MAX_NAME_LEN
+        || name.contains(['/', '\0'])
+    {
+        return Err(VfsError::InvalidPath);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: Format = Format { file_types: true };
+
+    fn records(block: &[u8]) -> Vec<(usize, usize)> {
+        FORMAT.records(block).unwrap()
+    }
+
+    fn names(block: &[u8]) -> Vec<String> {
+        FORMAT
+            .parse(block)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn dir_block() -> [u8; 64] {
+        let mut block = [0; 64];
+        FORMAT.init_block(&mut block, 2, ".", FT_DIR);
+        assert!(FORMAT.insert(&mut block, 2, "..", FT_DIR).unwrap());
+        block
+    }
+
+    #[test]
+    fn insert_splits_records_with_slack() {
+        let mut block = dir_block();
+        assert_eq!(records(&block), [(0, 12), (12, 52)]);
+
+        assert!(FORMAT.insert(&mut block, 11, "file", FT_REG_FILE).unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 12), (24, 40)]);
+        let entry = &FORMAT.parse(&block).unwrap()[2];
+        assert_eq!(
+            (entry.inode, entry.type_code, entry.offset),
+            (11, FT_REG_FILE, 24)
+        );
+
+        // 28 bytes are left behind "file"
+        assert!(!FORMAT
+            .insert(&mut block, 12, &"x".repeat(21), FT_REG_FILE)
+            .unwrap());
+        assert!(FORMAT
+            .insert(&mut block, 12, &"x".repeat(20), FT_REG_FILE)
+            .unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 12), (24, 12), (36, 28)]);
+        assert!(!FORMAT.insert(&mut block, 13, "y", FT_REG_FILE).unwrap());
+    }
+
+    #[test]
+    fn remove_merges_into_the_previous_record() {
+        let mut block = dir_block();
+        FORMAT.insert(&mut block, 11, "a", FT_REG_FILE).unwrap();
+        FORMAT.insert(&mut block, 12, "b", FT_REG_FILE).unwrap();
+        assert_eq!(records(&block), [(0, 12), (12, 12), (24, 12), (36, 28)]);
+
+        assert!(FORMAT.remove(&mut block, "a").unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 24), (36, 28)]);
+        assert_eq!(names(&block), [".", "..", "b"]);
+        assert!(!FORMAT.remove(&mut block, "a").unwrap());
+
+        // the freed space goes to the next entry that fits
+        assert!(FORMAT.insert(&mut block, 13, "c", FT_REG_FILE).unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 12), (24, 12), (36, 28)]);
+        assert_eq!(names(&block), [".", "..", "c", "b"]);
+    }
+
+    #[test]
+    fn removing_the_first_entry_clears_it() {
+        let mut block = dir_block();
+        assert!(FORMAT.remove(&mut block, ".").unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 52)]);
+        assert_eq!(names(&block), [".."]);
+
+        // an unused record is taken over whole
+        assert!(FORMAT.insert(&mut block, 11, "abcd", FT_REG_FILE).unwrap());
+        assert_eq!(records(&block), [(0, 12), (12, 52)]);
+        assert_eq!(names(&block), ["abcd", ".."]);
+    }
+
+    #[test]
+    fn records_must_stay_inside_the_block() {
+        let mut block = dir_block();
+        block[16..18].copy_from_slice(&56u16.to_le_bytes());
+        assert_eq!(FORMAT.parse(&block).err(), Some(VfsError::Io));
+        block[16..18].copy_from_slice(&6u16.to_le_bytes());
+        assert_eq!(FORMAT.parse(&block).err(), Some(VfsError::Io));
+    }
+}
@@ -0,0 +1,183 @@
+//! The on-disk inode.
+
+use crate::vfs::FileType;
+
+/// Bytes of an inode this driver understands; larger inodes only add fields
+/// at the end.
+pub const INODE_SIZE: usize = 128;
+/// Number of block pointers in an inode: 12 direct ones, then the single,
+/// double and triple indirect block.
+pub const BLOCK_POINTERS: usize = 15;
+pub const DIRECT_BLOCKS: usize = 12;
+
+const S_IFMT: u16 = 0xF000;
+pub const S_IFCHR: u16 = 0x2000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFLNK: u16 = 0xA000;
+
+/// The directory carries a hash tree index that has to be dropped when the
+/// directory is changed by a driver that does not maintain it.
+pub const INDEX_FLAG: u32 = 0x1000;
+
+/// 1980-01-01 in seconds since the Unix epoch. Used for all timestamps since
+/// there is no real-time clock yet.
+const DEFAULT_TIME: u32 = 315_532_800;
+
+/// Bytes of the block pointers, which hold the target of short symbolic links.
+pub const INLINE_SIZE: usize = BLOCK_POINTERS * 4;
+
+#[derive(Clone)]
+pub struct RawInode {
+    bytes: [u8; INODE_SIZE],
+}
+
+impl RawInode {
+    pub fn new(mode: u16) -> Self {
+        let mut inode = RawInode {
+            bytes: [0; INODE_SIZE],
+        };
+        inode.set_u16(0, mode);
+        // access, change and modification time
+        for offset in [8, 12, 16] {
+            inode.set_u32(offset, DEFAULT_TIME);
+        }
+        inode
+    }
+
+    pub fn from_bytes(bytes: [u8; INODE_SIZE]) -> Self {
+        RawInode { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; INODE_SIZE] {
+        &self.bytes
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.u16(0)
+    }
+
+    /// Permission bits.
+    pub fn permissions(&self) -> u16 {
+        self.mode() & !S_IFMT
+    }
+
+    pub fn set_permissions(&mut self, permissions: u16) {
+        self.set_u16(0, (self.mode() & S_IFMT) | (permissions & !S_IFMT));
+    }
+
+    pub fn format(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.format() == S_IFDIR
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.format() {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            // FIFOs and sockets have no contents on disk either
+            _ => FileType::File,
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.u16(2) as u32 | (self.u16(120) as u32) << 16
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.u16(24) as u32 | (self.u16(122) as u32) << 16
+    }
+
+    /// Size in bytes. Only regular files use the upper half, which holds the
+    /// ACL block on directories.
+    pub fn size(&self) -> u64 {
+        let high = if self.format() == S_IFREG {
+            self.u32(108)
+        } else {
+            0
+        };
+        self.u32(4) as u64 | (high as u64) << 32
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.set_u32(4, size as u32);
+        if self.format() == S_IFREG {
+            self.set_u32(108, (size >> 32) as u32);
+        }
+    }
+
+    /// Marks the inode as deleted; a free inode without a deletion time looks
+    /// like a half-finished operation to `e2fsck`.
+    pub fn set_deleted(&mut self) {
+        self.set_u32(20, DEFAULT_TIME);
+    }
+
+    pub fn links(&self) -> u16 {
+        self.u16(26)
+    }
+
+    pub fn set_links(&mut self, links: u16) {
+        self.set_u16(26, links);
+    }
+
+    /// Space used in 512 byte units, indirect blocks included.
+    pub fn sectors(&self) -> u32 {
+        self.u32(28)
+    }
+
+    pub fn set_sectors(&mut self, sectors: u32) {
+        self.set_u32(28, sectors);
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.u32(32)
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        self.set_u32(32, flags);
+    }
+
+    pub fn block(&self, index: usize) -> u32 {
+        self.u32(40 + index * 4)
+    }
+
+    pub fn set_block(&mut self, index: usize, block: u32) {
+        self.set_u32(40 + index * 4, block);
+    }
+
+    /// The block pointers as raw bytes, for short symbolic links.
+    pub fn inline_data(&self) -> &[u8] {
+        &self.bytes[40..40 + INLINE_SIZE]
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        self.bytes[40..40 + data.len()].copy_from_slice(data);
+    }
+
+    /// Block holding extended attributes, counted in `sectors` but not data.
+    pub fn file_acl(&self) -> u32 {
+        self.u32(104)
+    }
+}
@@ -0,0 +1,1401 @@
+//! The second extended filesystem.
+//!
+//! Like the FAT driver this works straight on the block device: inodes,
+//! bitmaps and directory blocks are read when needed and written back right
+//! away. Only the group descriptors and free counts are kept in memory, and
+//! they are written through as well.
+//!
+//! A file removed while it is still open keeps its inode and blocks, with no
+//! links left, until the last inode referring to it is dropped.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use self::{
+    dir::Format,
+    inode::{
+        RawInode, DIRECT_BLOCKS, INDEX_FLAG, INLINE_SIZE, INODE_SIZE, S_IFDIR, S_IFLNK, S_IFREG,
+    },
+};
+use super::{DirEntry, FileType, Filesystem, Inode, Metadata, VfsError};
+use crate::{
+    block::{self, BlockDevice},
+    colors, log_warn,
+    sync::Mutex,
+};
+
+mod dir;
+mod inode;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+const GROUP_DESC_SIZE: u64 = 32;
+
+const ROOT_INODE: u32 = 2;
+/// First inode of revision 0 filesystems, which have no field for it.
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+
+/// Incompatible features this driver understands; anything else cannot be mounted.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+/// Features needed for writing; with any other the volume is mounted read-only.
+const SUPPORTED_RO_COMPAT: u32 =
+    RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+/// Permission bits of new files, directories and symbolic links.
+const FILE_PERMISSIONS: u16 = 0o644;
+const DIR_PERMISSIONS: u16 = 0o755;
+const SYMLINK_PERMISSIONS: u16 = 0o777;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    first_data_block: u32,
+    block_size: u64,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    first_inode: u32,
+    group_count: u32,
+    large_file: bool,
+    format: Format,
+}
+
+impl Superblock {
+    fn parse(bytes: &[u8]) -> Result<(Self, bool), VfsError> {
+        if read_u16(bytes, 56) != EXT2_MAGIC {
+            return Err(VfsError::NotSupported);
+        }
+        let revision = read_u32(bytes, 76);
+        let (first_inode, inode_size, incompat, ro_compat) = if revision == 0 {
+            (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (
+                read_u32(bytes, 84),
+                read_u16(bytes, 88) as u64,
+                read_u32(bytes, 96),
+                read_u32(bytes, 100),
+            )
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(VfsError::NotSupported);
+        }
+
+        let log_block_size = read_u32(bytes, 24);
+        let blocks_count = read_u32(bytes, 4);
+        let first_data_block = read_u32(bytes, 20);
+        let blocks_per_group = read_u32(bytes, 32);
+        let inodes_per_group = read_u32(bytes, 40);
+        if log_block_size > 6
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || first_data_block >= blocks_count
+            || !inode_size.is_power_of_two()
+            || inode_size < INODE_SIZE as u64
+        {
+            return Err(VfsError::NotSupported);
+        }
+        let superblock = Superblock {
+            inodes_count: read_u32(bytes, 0),
+            blocks_count,
+            first_data_block,
+            block_size: 1024 << log_block_size,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            group_count: (blocks_count - first_data_block).div_ceil(blocks_per_group),
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            format: Format {
+                file_types: incompat & INCOMPAT_FILETYPE != 0,
+            },
+        };
+        let writable = ro_compat & !SUPPORTED_RO_COMPAT == 0;
+        Ok((superblock, writable))
+    }
+
+    fn group_of_inode(&self, inode: u32) -> u32 {
+        (inode - 1) / self.inodes_per_group
+    }
+
+    /// Number of blocks in `group`; the last one may be short.
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = group * self.blocks_per_group;
+        (self.blocks_count - self.first_data_block - start).min(self.blocks_per_group)
+    }
+
+    /// Block pointers in an indirect block.
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GroupDesc {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+struct State {
+    groups: Vec<GroupDesc>,
+    free_blocks: u32,
+    free_inodes: u32,
+    /// Number of [`Ext2Inode`]s alive per inode number.
+    open: BTreeMap<u32, usize>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    superblock: Superblock,
+    /// Whether the volume has features this driver cannot keep consistent.
+    read_only: bool,
+    /// Held for the duration of every operation.
+    state: Mutex<State>,
+}
+
+/// Where a block of a file is mapped: straight from the inode or through
+/// one to three levels of indirect blocks.
+struct BlockPath {
+    slot: usize,
+    indices: Vec<u64>,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), VfsError> {
+        block::read_bytes(&*self.device, offset, buf).map_err(|_| VfsError::Io)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        block::write_bytes(&*self.device, offset, data).map_err(|_| VfsError::Io)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.superblock.block_size
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, VfsError> {
+        let mut data = vec![0; self.superblock.block_size as usize];
+        self.read(self.block_offset(block), &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<(), VfsError> {
+        self.write(self.block_offset(block), data)
+    }
+
+    fn check_writable(&self) -> Result<(), VfsError> {
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn group_desc_offset(&self, group: u32) -> u64 {
+        self.block_offset(self.superblock.first_data_block + 1) + group as u64 * GROUP_DESC_SIZE
+    }
+
+    /// Writes the counters of `group` and of the superblock back to disk.
+    fn write_counts(&self, state: &State, group: u32) -> Result<(), VfsError> {
+        let desc = &state.groups[group as usize];
+        let mut counts = [0; 6];
+        counts[0..2].copy_from_slice(&desc.free_blocks.to_le_bytes());
+        counts[2..4].copy_from_slice(&desc.free_inodes.to_le_bytes());
+        counts[4..6].copy_from_slice(&desc.used_dirs.to_le_bytes());
+        self.write(self.group_desc_offset(group) + 12, &counts)?;
+
+        let mut free = [0; 8];
+        free[0..4].copy_from_slice(&state.free_blocks.to_le_bytes());
+        free[4..8].copy_from_slice(&state.free_inodes.to_le_bytes());
+        self.write(SUPERBLOCK_OFFSET + 12, &free)
+    }
+
+    fn inode_offset(&self, state: &State, inode: u32) -> Result<u64, VfsError> {
+        if inode == 0 || inode > self.superblock.inodes_count {
+            return Err(VfsError::Io);
+        }
+        let group = self.superblock.group_of_inode(inode);
+        let index = (inode - 1) % self.superblock.inodes_per_group;
+        let table = state.groups[group as usize].inode_table;
+        Ok(self.block_offset(table) + index as u64 * self.superblock.inode_size)
+    }
+
+    fn read_inode(&self, state: &State, inode: u32) -> Result<RawInode, VfsError> {
+        let mut bytes = [0; INODE_SIZE];
+        self.read(self.inode_offset(state, inode)?, &mut bytes)?;
+        Ok(RawInode::from_bytes(bytes))
+    }
+
+    fn write_inode(&self, state: &State, inode: u32, raw: &RawInode) -> Result<(), VfsError> {
+        self.write(self.inode_offset(state, inode)?, raw.as_bytes())
+    }
+
+    /// Finds a clear bit among the first `limit` bits of a bitmap.
+    fn find_clear_bit(bitmap: &[u8], limit: u32, skip: u32) -> Option<u32> {
+        (skip..limit).find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)
+    }
+
+    /// Takes a free block, preferably from group `goal`, and zeroes it.
+    fn allocate_block(&self, state: &mut State, goal: u32) -> Result<u32, VfsError> {
+        let superblock = &self.superblock;
+        for i in 0..superblock.group_count {
+            let group = (goal + i) % superblock.group_count;
+            let desc = state.groups[group as usize];
+            if desc.free_blocks == 0 {
+                continue;
+            }
+            let mut bitmap = self.read_block(desc.block_bitmap)?;
+            let Some(bit) = Self::find_clear_bit(&bitmap, superblock.blocks_in_group(group), 0)
+            else {
+                continue;
+            };
+            bitmap[bit as usize / 8] |= 1 << (bit % 8);
+            self.write_block(desc.block_bitmap, &bitmap)?;
+            state.groups[group as usize].free_blocks -= 1;
+            state.free_blocks -= 1;
+            self.write_counts(state, group)?;
+
+            let block = superblock.first_data_block + group * superblock.blocks_per_group + bit;
+            self.write_block(block, &vec![0; superblock.block_size as usize])?;
+            return Ok(block);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<(), VfsError> {
+        let superblock = &self.superblock;
+        if block < superblock.first_data_block || block >= superblock.blocks_count {
+            return Err(VfsError::Io);
+        }
+        let group = (block - superblock.first_data_block) / superblock.blocks_per_group;
+        let bit = (block - superblock.first_data_block) % superblock.blocks_per_group;
+        let bitmap_block = state.groups[group as usize].block_bitmap;
+        let mut bitmap = self.read_block(bitmap_block)?;
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)?;
+        state.groups[group as usize].free_blocks += 1;
+        state.free_blocks += 1;
+        self.write_counts(state, group)
+    }
+
+    /// Takes a free inode, preferably from group `goal`, and clears it on disk.
+    fn allocate_inode(&self, state: &mut State, goal: u32, is_dir: bool) -> Result<u32, VfsError> {
+        let superblock = &self.superblock;
+        for i in 0..superblock.group_count {
+            let group = (goal + i) % superblock.group_count;
+            let desc = state.groups[group as usize];
+            if desc.free_inodes == 0 {
+                continue;
+            }
+            let mut bitmap = self.read_block(desc.inode_bitmap)?;
+            // inodes below the first one are reserved
+            let first = group * superblock.inodes_per_group + 1;
+            let skip = superblock.first_inode.saturating_sub(first);
+            let Some(bit) = Self::find_clear_bit(&bitmap, superblock.inodes_per_group, skip) else {
+                continue;
+            };
+            bitmap[bit as usize / 8] |= 1 << (bit % 8);
+            self.write_block(desc.inode_bitmap, &bitmap)?;
+            let desc = &mut state.groups[group as usize];
+            desc.free_inodes -= 1;
+            if is_dir {
+                desc.used_dirs += 1;
+            }
+            state.free_inodes -= 1;
+            self.write_counts(state, group)?;
+
+            let inode = first + bit;
+            let offset = self.inode_offset(state, inode)?;
+            self.write(offset, &vec![0; superblock.inode_size as usize])?;
+            return Ok(inode);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&self, state: &mut State, inode: u32, is_dir: bool) -> Result<(), VfsError> {
+        let superblock = &self.superblock;
+        let group = superblock.group_of_inode(inode);
+        let bit = (inode - 1) % superblock.inodes_per_group;
+        let bitmap_block = state.groups[group as usize].inode_bitmap;
+        let mut bitmap = self.read_block(bitmap_block)?;
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(bitmap_block, &bitmap)?;
+        let desc = &mut state.groups[group as usize];
+        desc.free_inodes += 1;
+        if is_dir {
+            desc.used_dirs -= 1;
+        }
+        state.free_inodes += 1;
+        self.write_counts(state, group)
+    }
+
+    fn block_path(&self, index: u64) -> Result<BlockPath, VfsError> {
+        let pointers = self.superblock.pointers_per_block();
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(BlockPath {
+                slot: index as usize,
+                indices: Vec::new(),
+            });
+        }
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = pointers;
+        for level in 1..=3 {
+            if index < span {
+                let mut indices = vec![0; level];
+                for i in (0..level).rev() {
+                    indices[i] = index % pointers;
+                    index /= pointers;
+                }
+                return Ok(BlockPath {
+                    slot: DIRECT_BLOCKS + level - 1,
+                    indices,
+                });
+            }
+            index -= span;
+            span *= pointers;
+        }
+        // past what triple indirect blocks can address
+        Err(VfsError::NoSpace)
+    }
+
+    /// The block holding block `index` of a file, allocating it and the
+    /// indirect blocks leading to it if `allocate` is set. Holes read as
+    /// `None` otherwise.
+    fn map_block(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        goal: u32,
+        index: u64,
+        allocate: bool,
+    ) -> Result<Option<u32>, VfsError> {
+        let path = self.block_path(index)?;
+        let sectors_per_block = (self.superblock.block_size / 512) as u32;
+
+        let mut block = raw.block(path.slot);
+        if block == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            block = self.allocate_block(state, goal)?;
+            raw.set_block(path.slot, block);
+            raw.set_sectors(raw.sectors() + sectors_per_block);
+        }
+        for &i in &path.indices {
+            let pointer_offset = self.block_offset(block) + i * 4;
+            let mut pointer = [0; 4];
+            self.read(pointer_offset, &mut pointer)?;
+            let mut next = u32::from_le_bytes(pointer);
+            if next == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+                next = self.allocate_block(state, goal)?;
+                self.write(pointer_offset, &next.to_le_bytes())?;
+                raw.set_sectors(raw.sectors() + sectors_per_block);
+            }
+            block = next;
+        }
+        Ok(Some(block))
+    }
+
+    /// Frees the blocks of a file from block `keep` on.
+    fn truncate_blocks(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        keep: u64,
+    ) -> Result<(), VfsError> {
+        let sectors_per_block = (self.superblock.block_size / 512) as u32;
+        for slot in (keep.min(DIRECT_BLOCKS as u64) as usize)..DIRECT_BLOCKS {
+            let block = raw.block(slot);
+            if block != 0 {
+                self.free_block(state, block)?;
+                raw.set_block(slot, 0);
+                raw.set_sectors(raw.sectors() - sectors_per_block);
+            }
+        }
+
+        let pointers = self.superblock.pointers_per_block();
+        let mut first = DIRECT_BLOCKS as u64;
+        let mut span = pointers;
+        for level in 1..=3 {
+            let slot = DIRECT_BLOCKS + level - 1;
+            let block = raw.block(slot);
+            if block != 0 && self.free_tree(state, raw, block, level, keep.saturating_sub(first))? {
+                self.free_block(state, block)?;
+                raw.set_block(slot, 0);
+                raw.set_sectors(raw.sectors() - sectors_per_block);
+            }
+            first += span;
+            span *= pointers;
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks below indirect block `block` from its `keep`th data
+    /// block on. Returns whether the indirect block is left empty.
+    fn free_tree(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        block: u32,
+        level: usize,
+        keep: u64,
+    ) -> Result<bool, VfsError> {
+        let sectors_per_block = (self.superblock.block_size / 512) as u32;
+        let child_span = self.superblock.pointers_per_block().pow(level as u32 - 1);
+        let mut data = self.read_block(block)?;
+        let mut empty = true;
+        let mut changed = false;
+        for (i, pointer) in data.chunks_exact_mut(4).enumerate() {
+            let child = u32::from_le_bytes(pointer.try_into().unwrap());
+            if child == 0 {
+                continue;
+            }
+            let child_first = i as u64 * child_span;
+            let free = if level == 1 {
+                child_first >= keep
+            } else if keep >= child_first + child_span {
+                false
+            } else {
+                self.free_tree(
+                    state,
+                    raw,
+                    child,
+                    level - 1,
+                    keep.saturating_sub(child_first),
+                )?
+            };
+            if free {
+                self.free_block(state, child)?;
+                raw.set_sectors(raw.sectors() - sectors_per_block);
+                pointer.fill(0);
+                changed = true;
+            } else {
+                empty = false;
+            }
+        }
+        if changed && !empty {
+            self.write_block(block, &data)?;
+        }
+        Ok(empty)
+    }
+
+    fn read_data(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, VfsError> {
+        let size = raw.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = self.superblock.block_size;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % block_size;
+            let chunk = (len - done).min((block_size - within) as usize);
+            match self.map_block(state, raw, 0, pos / block_size, false)? {
+                Some(block) => self.read(
+                    self.block_offset(block) + within,
+                    &mut buf[done..done + chunk],
+                )?,
+                None => buf[done..done + chunk].fill(0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Writes `data` at `offset`, allocating blocks near inode `inode`.
+    fn write_data(
+        &self,
+        state: &mut State,
+        inode: u32,
+        raw: &mut RawInode,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), VfsError> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(VfsError::NoSpace)?;
+        if end > u32::MAX as u64 && !self.superblock.large_file {
+            return Err(VfsError::NoSpace);
+        }
+        let goal = self.superblock.group_of_inode(inode);
+        let block_size = self.superblock.block_size;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let within = pos % block_size;
+            let chunk = (data.len() - done).min((block_size - within) as usize);
+            let block = self
+                .map_block(state, raw, goal, pos / block_size, true)?
+                .ok_or(VfsError::Io)?;
+            self.write(self.block_offset(block) + within, &data[done..done + chunk])?;
+            done += chunk;
+            if pos + chunk as u64 > raw.size() {
+                raw.set_size(pos + chunk as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks of directory `raw`, in order.
+    fn dir_blocks(&self, state: &mut State, raw: &mut RawInode) -> Result<Vec<u32>, VfsError> {
+        let count = raw.size() / self.superblock.block_size;
+        (0..count)
+            .map(|index| {
+                self.map_block(state, raw, 0, index, false)?
+                    .ok_or(VfsError::Io)
+            })
+            .collect()
+    }
+
+    fn dir_entries(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+    ) -> Result<Vec<dir::Entry>, VfsError> {
+        let mut entries = Vec::new();
+        for block in self.dir_blocks(state, raw)? {
+            entries.extend(self.superblock.format.parse(&self.read_block(block)?)?);
+        }
+        Ok(entries)
+    }
+
+    fn find(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        name: &str,
+    ) -> Result<Option<dir::Entry>, VfsError> {
+        Ok(self
+            .dir_entries(state, raw)?
+            .into_iter()
+            .find(|entry| entry.name == name))
+    }
+
+    /// Adds entry `name` for `child` to directory `dir`, growing it by a
+    /// block if no block has room.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        dir: u32,
+        raw: &mut RawInode,
+        name: &str,
+        child: u32,
+        file_type: FileType,
+    ) -> Result<(), VfsError> {
+        let format = self.superblock.format;
+        let type_code = dir::type_code(file_type);
+        // the hash tree index would not know about the new entry
+        raw.set_flags(raw.flags() & !INDEX_FLAG);
+        for block in self.dir_blocks(state, raw)? {
+            let mut data = self.read_block(block)?;
+            if format.insert(&mut data, child, name, type_code)? {
+                return self.write_block(block, &data);
+            }
+        }
+        let index = raw.size() / self.superblock.block_size;
+        let goal = self.superblock.group_of_inode(dir);
+        let block = self
+            .map_block(state, raw, goal, index, true)?
+            .ok_or(VfsError::Io)?;
+        let mut data = vec![0; self.superblock.block_size as usize];
+        format.init_block(&mut data, child, name, type_code);
+        self.write_block(block, &data)?;
+        raw.set_size(raw.size() + self.superblock.block_size);
+        Ok(())
+    }
+
+    fn remove_entry(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        name: &str,
+    ) -> Result<(), VfsError> {
+        raw.set_flags(raw.flags() & !INDEX_FLAG);
+        for block in self.dir_blocks(state, raw)? {
+            let mut data = self.read_block(block)?;
+            if self.superblock.format.remove(&mut data, name)? {
+                return self.write_block(block, &data);
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    /// Points the ".." entry of directory `raw` at `parent`.
+    fn set_parent(
+        &self,
+        state: &mut State,
+        raw: &mut RawInode,
+        parent: u32,
+    ) -> Result<(), VfsError> {
+        let format = self.superblock.format;
+        for block in self.dir_blocks(state, raw)? {
+            let mut data = self.read_block(block)?;
+            if let Some(entry) = format
+                .parse(&data)?
+                .into_iter()
+                .find(|entry| entry.name == "..")
+            {
+                format.set_inode(&mut data, entry.offset, parent);
+                return self.write_block(block, &data);
+            }
+        }
+        Err(VfsError::Io)
+    }
+
+    fn is_empty_dir(&self, state: &mut State, raw: &mut RawInode) -> Result<bool, VfsError> {
+        Ok(self
+            .dir_entries(state, raw)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// Drops a link to `inode`, freeing it along with its blocks when it was
+    /// the last one and nobody has it open. Directories lose both their links
+    /// at once.
+    fn unlink(&self, state: &mut State, inode: u32, raw: &mut RawInode) -> Result<(), VfsError> {
+        let links = if raw.is_dir() {
+            0
+        } else {
+            raw.links().saturating_sub(1)
+        };
+        raw.set_links(links);
+        if links == 0 && !state.open.contains_key(&inode) {
+            self.release(state, inode, raw)?;
+        }
+        self.write_inode(state, inode, raw)
+    }
+
+    /// Frees `inode`, which has no links left, and its blocks.
+    fn release(&self, state: &mut State, inode: u32, raw: &mut RawInode) -> Result<(), VfsError> {
+        // short symbolic links keep their target where block pointers would be
+        if !self.is_inline_symlink(raw) {
+            self.truncate_blocks(state, raw, 0)?;
+        }
+        raw.set_size(0);
+        raw.set_deleted();
+        self.free_inode(state, inode, raw.is_dir())
+    }
+
+    fn is_inline_symlink(&self, raw: &RawInode) -> bool {
+        let acl_sectors = if raw.file_acl() != 0 {
+            (self.superblock.block_size / 512) as u32
+        } else {
+            0
+        };
+        raw.format() == S_IFLNK && raw.sectors() == acl_sectors
+    }
+}
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume on `device`. Volumes with features the driver
+    /// cannot update are mounted read-only.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        block::read_bytes(&*device, SUPERBLOCK_OFFSET, &mut bytes).map_err(|_| VfsError::Io)?;
+        let (superblock, writable) = Superblock::parse(&bytes)?;
+        let device_size = device.block_count() * device.block_size() as u64;
+        if superblock.blocks_count as u64 * superblock.block_size > device_size {
+            return Err(VfsError::NotSupported);
+        }
+
+        let mut table = vec![0; (superblock.group_count as u64 * GROUP_DESC_SIZE) as usize];
+        let table_offset = (superblock.first_data_block as u64 + 1) * superblock.block_size;
+        block::read_bytes(&*device, table_offset, &mut table).map_err(|_| VfsError::Io)?;
+        let groups = table
+            .chunks_exact(GROUP_DESC_SIZE as usize)
+            .map(|desc| GroupDesc {
+                block_bitmap: read_u32(desc, 0),
+                inode_bitmap: read_u32(desc, 4),
+                inode_table: read_u32(desc, 8),
+                free_blocks: read_u16(desc, 12),
+                free_inodes: read_u16(desc, 14),
+                used_dirs: read_u16(desc, 16),
+            })
+            .collect();
+
+        Ok(Ext2Fs {
+            volume: Arc::new(Volume {
+                device,
+                read_only: !writable,
+                state: Mutex::new(State {
+                    groups,
+                    free_blocks: read_u32(&bytes, 12),
+                    free_inodes: read_u32(&bytes, 16),
+                    open: BTreeMap::new(),
+                }),
+                superblock,
+            }),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.volume.read_only
+    }
+}
+
+impl Filesystem for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut state = self.volume.state.lock();
+        Ext2Inode::open(&self.volume, &mut state, ROOT_INODE)
+    }
+
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.volume.device.flush().map_err(|_| VfsError::Io)
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    inode: u32,
+}
+
+impl Ext2Inode {
+    fn open(volume: &Arc<Volume>, state: &mut State, inode: u32) -> Arc<dyn Inode> {
+        *state.open.entry(inode).or_insert(0) += 1;
+        Arc::new(Ext2Inode {
+            volume: volume.clone(),
+            inode,
+        })
+    }
+
+    /// Reads the inode, which may have lost all its links since it was
+    /// looked up but stays allocated while this inode exists.
+    fn load(&self, state: &State) -> Result<RawInode, VfsError> {
+        self.volume.read_inode(state, self.inode)
+    }
+
+    /// Reads the inode as a directory, which must not have been removed.
+    fn load_dir(&self, state: &State) -> Result<RawInode, VfsError> {
+        let raw = self.load(state)?;
+        if !raw.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        if raw.links() == 0 {
+            return Err(VfsError::NotFound);
+        }
+        Ok(raw)
+    }
+
+    fn load_file(&self, state: &State) -> Result<RawInode, VfsError> {
+        let raw = self.load(state)?;
+        match raw.format() {
+            S_IFREG => Ok(raw),
+            S_IFDIR => Err(VfsError::IsADirectory),
+            _ => Err(VfsError::NotSupported),
+        }
+    }
+
+    fn child(&self, state: &mut State, inode: u32) -> Arc<dyn Inode> {
+        Self::open(&self.volume, state, inode)
+    }
+
+    /// Creates an inode with mode `mode` and links it into this directory as `name`.
+    fn create_child(
+        &self,
+        state: &mut State,
+        name: &str,
+        mode: u16,
+        init: impl FnOnce(&Volume, &mut State, u32, &mut RawInode) -> Result<(), VfsError>,
+    ) -> Result<u32, VfsError> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        dir::validate_name(name)?;
+        let mut parent = self.load_dir(state)?;
+        if volume.find(state, &mut parent, name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let mut raw = RawInode::new(mode);
+        let is_dir = raw.is_dir();
+        let goal = volume.superblock.group_of_inode(self.inode);
+        let inode = volume.allocate_inode(state, goal, is_dir)?;
+        raw.set_links(if is_dir { 2 } else { 1 });
+        let result = init(volume, state, inode, &mut raw).and_then(|()| {
+            volume.add_entry(state, self.inode, &mut parent, name, inode, raw.file_type())
+        });
+        if let Err(err) = result {
+            volume.truncate_blocks(state, &mut raw, 0)?;
+            volume.free_inode(state, inode, is_dir)?;
+            return Err(err);
+        }
+        volume.write_inode(state, inode, &raw)?;
+        if is_dir {
+            parent.set_links(parent.links() + 1);
+        }
+        volume.write_inode(state, self.inode, &parent)?;
+        Ok(inode)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        let state = self.volume.state.lock();
+        let raw = self.load(&state)?;
+        Ok(Metadata {
+            file_type: raw.file_type(),
+            size: raw.size(),
+            inode: self.inode as u64,
+            mode: raw.permissions(),
+            uid: raw.uid(),
+            gid: raw.gid(),
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut raw = self.load_file(&state)?;
+        self.volume.read_data(&mut state, &mut raw, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.volume.state.lock();
+        self.volume.check_writable()?;
+        let mut raw = self.load_file(&state)?;
+        let result = self
+            .volume
+            .write_data(&mut state, self.inode, &mut raw, offset, buf);
+        // blocks allocated before a failure still belong to the file
+        self.volume.write_inode(&state, self.inode, &raw)?;
+        result.map(|()| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        self.volume.check_writable()?;
+        let mut raw = self.load_file(&state)?;
+        if size > u32::MAX as u64 && !self.volume.superblock.large_file {
+            return Err(VfsError::NoSpace);
+        }
+        // growing leaves a hole, which reads as zeros
+        let keep = size.div_ceil(self.volume.superblock.block_size);
+        let result = self.volume.truncate_blocks(&mut state, &mut raw, keep);
+        if size < raw.size() && !size.is_multiple_of(self.volume.superblock.block_size) {
+            // the rest of the last block has to read as zeros if the file grows again
+            if let Some(block) = self.volume.map_block(
+                &mut state,
+                &mut raw,
+                0,
+                size / self.volume.superblock.block_size,
+                false,
+            )? {
+                let within = size % self.volume.superblock.block_size;
+                let zeros = vec![0; (self.volume.superblock.block_size - within) as usize];
+                self.volume
+                    .write(self.volume.block_offset(block) + within, &zeros)?;
+            }
+        }
+        raw.set_size(size);
+        self.volume.write_inode(&state, self.inode, &raw)?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut raw = self.load_dir(&state)?;
+        let entry = self
+            .volume
+            .find(&mut state, &mut raw, name)?
+            .ok_or(VfsError::NotFound)?;
+        Ok(self.child(&mut state, entry.inode))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut raw = self.load_dir(&state)?;
+        let entries = self.volume.dir_entries(&mut state, &mut raw)?;
+        entries
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| {
+                let file_type = match dir::file_type(entry.type_code) {
+                    Some(file_type) => file_type,
+                    None => self.volume.read_inode(&state, entry.inode)?.file_type(),
+                };
+                Ok(DirEntry {
+                    name: entry.name,
+                    file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let parent = self.inode;
+        let inode = match file_type {
+            FileType::File => self.create_child(
+                &mut state,
+                name,
+                S_IFREG | FILE_PERMISSIONS,
+                |_, _, _, _| Ok(()),
+            )?,
+            FileType::Directory => self.create_child(
+                &mut state,
+                name,
+                S_IFDIR | DIR_PERMISSIONS,
+                |volume, state, inode, raw| {
+                    let block_size = volume.superblock.block_size;
+                    let goal = volume.superblock.group_of_inode(inode);
+                    let block = volume
+                        .map_block(state, raw, goal, 0, true)?
+                        .ok_or(VfsError::Io)?;
+                    let format = volume.superblock.format;
+                    let mut data = vec![0; block_size as usize];
+                    format.init_block(&mut data, inode, ".", dir::type_code(FileType::Directory));
+                    format.insert(&mut data, parent, "..", dir::type_code(FileType::Directory))?;
+                    volume.write_block(block, &data)?;
+                    raw.set_size(block_size);
+                    Ok(())
+                },
+            )?,
+            FileType::CharDevice | FileType::BlockDevice | FileType::Symlink => {
+                return Err(VfsError::NotSupported)
+            }
+        };
+        Ok(self.child(&mut state, inode))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let mut parent = self.load_dir(&state)?;
+        let entry = volume
+            .find(&mut state, &mut parent, name)?
+            .ok_or(VfsError::NotFound)?;
+        let mut raw = volume.read_inode(&state, entry.inode)?;
+        if raw.is_dir() {
+            if !volume.is_empty_dir(&mut state, &mut raw)? {
+                return Err(VfsError::NotEmpty);
+            }
+            // the child's ".." no longer links to this directory
+            parent.set_links(parent.links() - 1);
+        }
+        volume.remove_entry(&mut state, &mut parent, name)?;
+        volume.write_inode(&state, self.inode, &parent)?;
+        volume.unlink(&mut state, entry.inode, &mut raw)
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &Arc<dyn Inode>,
+        new_name: &str,
+    ) -> Result<(), VfsError> {
+        // the VFS only hands in directories of the same volume
+        let new_dir = new_parent.stat()?;
+        if new_dir.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let new_dir = new_dir.inode as u32;
+
+        let mut state = self.volume.state.lock();
+        let volume = &self.volume;
+        volume.check_writable()?;
+        dir::validate_name(new_name)?;
+        let old_dir = self.inode;
+        let mut old_raw = self.load_dir(&state)?;
+        let entry = volume
+            .find(&mut state, &mut old_raw, old_name)?
+            .ok_or(VfsError::NotFound)?;
+        if old_dir == new_dir && old_name == new_name {
+            return Ok(());
+        }
+        let mut new_raw = volume.read_inode(&state, new_dir)?;
+        if new_raw.links() == 0 {
+            return Err(VfsError::NotFound);
+        }
+        if volume.find(&mut state, &mut new_raw, new_name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let mut child = volume.read_inode(&state, entry.inode)?;
+        let moves_dir = child.is_dir() && old_dir != new_dir;
+        if moves_dir {
+            // a directory cannot move into itself
+            let mut ancestor = new_dir;
+            while ancestor != ROOT_INODE {
+                if ancestor == entry.inode {
+                    return Err(VfsError::InvalidPath);
+                }
+                let mut raw = volume.read_inode(&state, ancestor)?;
+                ancestor = volume
+                    .find(&mut state, &mut raw, "..")?
+                    .ok_or(VfsError::Io)?
+                    .inode;
+            }
+        }
+
+        volume.add_entry(
+            &mut state,
+            new_dir,
+            &mut new_raw,
+            new_name,
+            entry.inode,
+            child.file_type(),
+        )?;
+        if old_dir == new_dir {
+            // both entries live in the same directory inode
+            old_raw = new_raw.clone();
+        }
+        volume.remove_entry(&mut state, &mut old_raw, old_name)?;
+        if moves_dir {
+            volume.set_parent(&mut state, &mut child, new_dir)?;
+            volume.write_inode(&state, entry.inode, &child)?;
+            old_raw.set_links(old_raw.links() - 1);
+            new_raw.set_links(new_raw.links() + 1);
+        }
+        if old_dir != new_dir {
+            volume.write_inode(&state, new_dir, &new_raw)?;
+        }
+        volume.write_inode(&state, old_dir, &old_raw)
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut raw = self.load(&state)?;
+        if raw.format() != S_IFLNK {
+            return Err(VfsError::InvalidPath);
+        }
+        let size = raw.size() as usize;
+        let target = if self.volume.is_inline_symlink(&raw) {
+            raw.inline_data().get(..size).ok_or(VfsError::Io)?.to_vec()
+        } else {
+            let mut target = vec![0; size];
+            self.volume
+                .read_data(&mut state, &mut raw, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| VfsError::Io)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<(), VfsError> {
+        if target.is_empty() || target.len() as u64 > self.volume.superblock.block_size {
+            return Err(VfsError::InvalidPath);
+        }
+        let mut state = self.volume.state.lock();
+        self.create_child(
+            &mut state,
+            name,
+            S_IFLNK | SYMLINK_PERMISSIONS,
+            |volume, state, inode, raw| {
+                // short targets fit where the block pointers would be
+                if target.len() < INLINE_SIZE {
+                    raw.set_inline_data(target.as_bytes());
+                    raw.set_size(target.len() as u64);
+                    Ok(())
+                } else {
+                    volume.write_data(state, inode, raw, 0, target.as_bytes())
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    fn set_permissions(&self, mode: u16) -> Result<(), VfsError> {
+        let state = self.volume.state.lock();
+        self.volume.check_writable()?;
+        let mut raw = self.load(&state)?;
+        raw.set_permissions(mode & 0o7777);
+        self.volume.write_inode(&state, self.inode, &raw)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.volume.device.flush().map_err(|_| VfsError::Io)
+    }
+}
+
+impl Drop for Ext2Inode {
+    /// Frees a removed inode once the last inode referring to it is gone.
+    fn drop(&mut self) {
+        let mut state = self.volume.state.lock();
+        let Some(count) = state.open.get_mut(&self.inode) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        state.open.remove(&self.inode);
+        let volume = &self.volume;
+        let result = volume.read_inode(&state, self.inode).and_then(|mut raw| {
+            if raw.links() != 0 {
+                return Ok(());
+            }
+            volume.release(&mut state, self.inode, &mut raw)?;
+            volume.write_inode(&state, self.inode, &raw)
+        });
+        if let Err(err) = result {
+            log_warn!(
+                "Failed to free removed ext2 inode {}: {:?}",
+                self.inode,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BLOCKS: u32 = 512;
+    const INODES: u32 = 32;
+    /// Blocks 1 to 9 hold the metadata and the root directory.
+    const FREE_BLOCKS: u32 = BLOCKS - 10;
+    /// Inodes below the first one are reserved.
+    const FREE_INODES: u32 = INODES - 10;
+
+    /// Single-group volume with 1 KiB blocks: the superblock in block 1, the
+    /// group descriptors in block 2, the bitmaps in blocks 3 and 4, the inode
+    /// table in blocks 5 to 8 and the root directory in block 9.
+    fn ext2() -> Ext2Fs {
+        let mut image = vec![0; BLOCKS as usize * 1024];
+        let mut set_u32 = |offset: usize, value: u32| {
+            image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        for (field, value) in [
+            (0, INODES),
+            (4, BLOCKS),
+            (12, FREE_BLOCKS),
+            (16, FREE_INODES),
+            (20, 1),
+            (32, 8192),
+            (40, INODES),
+            (56, EXT2_MAGIC as u32),
+            (76, 1),
+            (84, GOOD_OLD_FIRST_INODE),
+            (88, INODE_SIZE as u32),
+            (96, INCOMPAT_FILETYPE),
+        ] {
+            set_u32(1024 + field, value);
+        }
+        for (field, value) in [
+            (0, 3),
+            (4, 4),
+            (8, 5),
+            (12, FREE_BLOCKS | FREE_INODES << 16),
+        ] {
+            set_u32(2048 + field, value);
+        }
+        set_u32(2048 + 16, 1);
+        image[3 * 1024..3 * 1024 + 2].copy_from_slice(&[0xFF, 0x01]);
+        image[4 * 1024..4 * 1024 + 2].copy_from_slice(&[0xFF, 0x03]);
+
+        let mut root = RawInode::new(S_IFDIR | DIR_PERMISSIONS);
+        root.set_links(2);
+        root.set_size(1024);
+        root.set_block(0, 9);
+        root.set_sectors(2);
+        let offset = 5 * 1024 + INODE_SIZE;
+        image[offset..offset + INODE_SIZE].copy_from_slice(root.as_bytes());
+        let format = Format { file_types: true };
+        let dir_type = dir::type_code(FileType::Directory);
+        let block = &mut image[9 * 1024..10 * 1024];
+        format.init_block(block, ROOT_INODE, ".", dir_type);
+        assert!(format.insert(block, ROOT_INODE, "..", dir_type).unwrap());
+
+        match Ext2Fs::new(RamDisk::new(image)) {
+            Ok(fs) => fs,
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    fn free_counts(fs: &Ext2Fs) -> (u32, u32) {
+        let state = fs.volume.state.lock();
+        (state.free_blocks, state.free_inodes)
+    }
+
+    fn names(dir: &Arc<dyn Inode>) -> Vec<String> {
+        dir.read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    /// Writes six bytes into block 0, into the first block behind the single
+    /// indirect block and into the first one behind the double indirect one.
+    fn write_every_level(file: &Arc<dyn Inode>) -> [(u64, &'static [u8]); 3] {
+        let writes: [(u64, &[u8]); 3] = [(0, b"direct"), (12, b"single"), (268, b"double")];
+        for (index, data) in writes {
+            assert_eq!(file.write_at(index * 1024 + 100, data), Ok(6));
+        }
+        writes
+    }
+
+    #[test]
+    fn block_paths_cover_every_level() {
+        let fs = ext2();
+        let path = |index| {
+            let path = fs.volume.block_path(index).unwrap();
+            (path.slot, path.indices)
+        };
+        assert_eq!(path(0), (0, vec![]));
+        assert_eq!(path(11), (11, vec![]));
+        assert_eq!(path(12), (12, vec![0]));
+        assert_eq!(path(12 + 255), (12, vec![255]));
+        assert_eq!(path(12 + 256), (13, vec![0, 0]));
+        assert_eq!(path(12 + 256 + 3 * 256 + 7), (13, vec![3, 7]));
+        assert_eq!(path(12 + 256 + 256 * 256), (14, vec![0, 0, 0]));
+        assert_eq!(
+            path(12 + 256 + 256 * 256 + 256 * 256 * 256 - 1),
+            (14, vec![255, 255, 255])
+        );
+        assert!(fs
+            .volume
+            .block_path(12 + 256 + 256 * 256 + 256 * 256 * 256)
+            .is_err());
+    }
+
+    #[test]
+    fn writes_allocate_indirect_blocks_on_the_way() {
+        let fs = ext2();
+        let file = fs.root().create("file", FileType::File).unwrap();
+        let writes = write_every_level(&file);
+
+        // three data blocks, one single indirect block and two levels of
+        // double indirect blocks
+        assert_eq!(free_counts(&fs).0, FREE_BLOCKS - 6);
+        assert_eq!(file.stat().unwrap().size, 268 * 1024 + 106);
+        for (index, data) in writes {
+            let mut buf = [0; 6];
+            assert_eq!(file.read_at(index * 1024 + 100, &mut buf), Ok(6));
+            assert_eq!(buf, data);
+        }
+        // holes read as zeros
+        let mut buf = [0xFF; 8];
+        assert_eq!(file.read_at(100 * 1024, &mut buf), Ok(8));
+        assert_eq!(buf, [0; 8]);
+    }
+
+    #[test]
+    fn truncate_frees_blocks_past_the_new_end() {
+        let fs = ext2();
+        let file = fs.root().create("file", FileType::File).unwrap();
+        write_every_level(&file);
+
+        // the double indirect blocks go, the single indirect one stays
+        file.truncate(13 * 1024).unwrap();
+        assert_eq!(free_counts(&fs).0, FREE_BLOCKS - 3);
+        let mut buf = [0; 6];
+        assert_eq!(file.read_at(12 * 1024 + 100, &mut buf), Ok(6));
+        assert_eq!(&buf, b"single");
+
+        // the cut off part of the last block reads as zeros after growing again
+        file.truncate(12 * 1024 + 103).unwrap();
+        file.truncate(13 * 1024).unwrap();
+        assert_eq!(file.read_at(12 * 1024 + 100, &mut buf), Ok(6));
+        assert_eq!(&buf, b"sin\0\0\0");
+
+        file.truncate(0).unwrap();
+        assert_eq!(free_counts(&fs).0, FREE_BLOCKS);
+        let state = fs.volume.state.lock();
+        let raw = fs.volume.read_inode(&state, 11).unwrap();
+        assert_eq!(raw.sectors(), 0);
+        assert!((0..15).all(|slot| raw.block(slot) == 0));
+    }
+
+    #[test]
+    fn directories_grow_when_their_blocks_are_full() {
+        let fs = ext2();
+        let root = fs.root();
+        // four 212-byte records fit next to "." and "..", the fifth does not
+        let long_names: Vec<String> = (0..5).map(|i| format!("{i}{}", "x".repeat(200))).collect();
+        for name in &long_names {
+            root.create(name, FileType::File).unwrap();
+        }
+        assert_eq!(root.stat().unwrap().size, 2048);
+        assert_eq!(names(&root), long_names);
+
+        for name in &long_names[..4] {
+            root.remove(name).unwrap();
+        }
+        assert_eq!(names(&root), &long_names[4..]);
+        assert!(root.lookup(&long_names[0]).is_err());
+        // the freed records are used again before the directory grows
+        root.create("short", FileType::File).unwrap();
+        assert_eq!(root.stat().unwrap().size, 2048);
+        assert_eq!(free_counts(&fs), (FREE_BLOCKS - 1, FREE_INODES - 2));
+    }
+
+    #[test]
+    fn removed_files_stay_until_closed() {
+        let fs = ext2();
+        let root = fs.root();
+        let file = root.create("file", FileType::File).unwrap();
+        file.write_at(0, b"data").unwrap();
+        root.remove("file").unwrap();
+        assert!(matches!(root.lookup("file"), Err(VfsError::NotFound)));
+
+        let mut buf = [0; 4];
+        assert_eq!(file.read_at(0, &mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+        assert_eq!(free_counts(&fs), (FREE_BLOCKS - 1, FREE_INODES - 1));
+        drop(file);
+        assert_eq!(free_counts(&fs), (FREE_BLOCKS, FREE_INODES));
+
+        // nothing holds this one open
+        root.create("other", FileType::File).unwrap();
+        root.remove("other").unwrap();
+        assert_eq!(free_counts(&fs), (FREE_BLOCKS, FREE_INODES));
+    }
+
+    #[test]
+    fn removed_directories_take_no_entries() {
+        let fs = ext2();
+        let root = fs.root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        root.remove("dir").unwrap();
+        assert!(matches!(
+            dir.create("file", FileType::File),
+            Err(VfsError::NotFound)
+        ));
+        root.create("file", FileType::File).unwrap();
+        assert_eq!(root.rename("file", &dir, "file"), Err(VfsError::NotFound));
+        assert_eq!(names(&root), ["file"]);
+        drop(dir);
+        assert_eq!(free_counts(&fs), (FREE_BLOCKS, FREE_INODES - 1));
+    }
+}
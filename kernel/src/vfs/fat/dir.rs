@@ -0,0 +1,366 @@
+//! Directory entries: 8.3 short entries and the long file name entries
+//! stored in front of them.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::vfs::VfsError;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xE5;
+/// Stands for a leading 0xE5 byte in a short name, which would mark the entry deleted.
+const KANJI_E5: u8 = 0x05;
+const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// Bits of the reserved byte of short entries telling that the name or the
+/// extension is shown in lower case.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+const CHARS_PER_LONG_ENTRY: usize = 13;
+/// Byte offsets of the UTF-16 characters within a long name entry.
+const LONG_NAME_OFFSETS: [usize; CHARS_PER_LONG_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+/// 1980-01-01, the earliest date FAT can represent. Used for all timestamps
+/// since there is no real-time clock yet.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// A file or directory as listed in its parent directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Slot of the short entry in the directory.
+    pub slot: u32,
+    /// First slot used by the entry, including its long name entries.
+    pub first_slot: u32,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.short_name == *b".          " || self.short_name == *b"..         "
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Checksum of a short name, stored in its long name entries.
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Cluster field of a short entry.
+pub fn first_cluster(entry: &[u8]) -> u32 {
+    (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32
+}
+
+pub fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub fn set_size(entry: &mut [u8], size: u32) {
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// A short entry with the default timestamps.
+pub fn short_entry(
+    short_name: &[u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0..11].copy_from_slice(short_name);
+    entry[11] = attr;
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_first_cluster(&mut entry, first_cluster);
+    set_size(&mut entry, size);
+    entry
+}
+
+fn trim_spaces(part: &[u8]) -> &[u8] {
+    let len = part
+        .iter()
+        .rposition(|&byte| byte != b' ')
+        .map_or(0, |last| last + 1);
+    &part[..len]
+}
+
+/// Name of a short entry as shown to the user.
+fn display_short_name(entry: &[u8]) -> String {
+    let lowercase = entry[12];
+    let mut base = entry[0..8].to_vec();
+    if base[0] == KANJI_E5 {
+        base[0] = DELETED;
+    }
+    let to_char = |lower: bool| {
+        move |&byte: &u8| {
+            let c = char::from(byte);
+            if lower {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            }
+        }
+    };
+
+    let mut name: String = trim_spaces(&base)
+        .iter()
+        .map(to_char(lowercase & LOWERCASE_BASE != 0))
+        .collect();
+    let extension = trim_spaces(&entry[8..11]);
+    if !extension.is_empty() {
+        name.push('.');
+        name.extend(
+            extension
+                .iter()
+                .map(to_char(lowercase & LOWERCASE_EXTENSION != 0)),
+        );
+    }
+    name
+}
+
+/// Long name being collected from the entries in front of a short entry.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the next entry; they come in descending order.
+    next: u8,
+    first_slot: u32,
+}
+
+/// Lists the entries of a directory, given its raw contents.
+pub fn parse(bytes: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (slot, raw) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        let slot = slot as u32;
+        match raw[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attr = raw[11];
+        if attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let sequence = raw[0] & 0x1F;
+            if raw[0] & LAST_LONG_ENTRY != 0 && sequence != 0 {
+                long_name = Some(LongName {
+                    units: vec![0xFFFF; sequence as usize * CHARS_PER_LONG_ENTRY],
+                    checksum: raw[13],
+                    next: sequence,
+                    first_slot: slot,
+                });
+            }
+            long_name = long_name.filter(|long| long.next == sequence && long.checksum == raw[13]);
+            if let Some(long) = long_name.as_mut() {
+                let base = (sequence as usize - 1) * CHARS_PER_LONG_ENTRY;
+                for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    long.units[base + i] = read_u16(raw, offset);
+                }
+                long.next -= 1;
+            }
+            continue;
+        }
+        if attr & ATTR_VOLUME_ID != 0 {
+            long_name = None;
+            continue;
+        }
+
+        let short_name: [u8; 11] = raw[0..11].try_into().unwrap();
+        let long = long_name
+            .take()
+            .filter(|long| long.next == 0 && long.checksum == checksum(&short_name))
+            .and_then(|long| {
+                let len = long
+                    .units
+                    .iter()
+                    .position(|&unit| unit == 0)
+                    .unwrap_or(long.units.len());
+                let name = String::from_utf16(&long.units[..len]).ok()?;
+                Some((name, long.first_slot))
+            });
+        let (name, first_slot) = long.unwrap_or_else(|| (display_short_name(raw), slot));
+        entries.push(Entry {
+            name,
+            short_name,
+            attr,
+            first_cluster: first_cluster(raw),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            slot,
+            first_slot,
+        });
+    }
+    entries
+}
+
+/// Finds `count` consecutive free slots in a directory.
+pub fn find_free_slots(bytes: &[u8], count: usize) -> Option<u32> {
+    let mut run_start = 0;
+    let mut run = 0;
+    for (slot, raw) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        if raw[0] == END_OF_DIRECTORY {
+            // everything from here on is free
+            let start = if run > 0 { run_start } else { slot };
+            let available = bytes.len() / ENTRY_SIZE - start;
+            return (available >= count).then_some(start as u32);
+        }
+        if raw[0] == DELETED {
+            if run == 0 {
+                run_start = slot;
+            }
+            run += 1;
+            if run == count {
+                return Some(run_start as u32);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+pub fn validate_name(name: &str) -> Result<(), VfsError> {
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with(['.', ' '])
+        || name.contains(invalid)
+        || name.encode_utf16().count() > MAX_NAME_LEN
+    {
+        return Err(VfsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&byte)
+}
+
+/// The name itself if it is a valid upper-case 8.3 name, which needs no long
+/// name entries.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base
+            .bytes()
+            .chain(extension.bytes())
+            .all(is_short_name_char)
+    {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// Derives a unique `BASE~N.EXT` short name for a long name.
+fn generate_short_name(name: &str, taken: &dyn Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let upper = c.to_ascii_uppercase();
+                if upper.is_ascii() && is_short_name_char(upper as u8) {
+                    upper as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (convert(&trimmed[..dot]), convert(&trimmed[dot + 1..])),
+        None => (convert(trimmed), Vec::new()),
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short_name = [b' '; 11];
+    let extension_len = extension.len().min(3);
+    short_name[8..8 + extension_len].copy_from_slice(&extension[..extension_len]);
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let base_len = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// Picks the short name for `name` and returns it along with whether long
+/// name entries are needed.
+pub fn short_name_for(name: &str, taken: &dyn Fn(&[u8; 11]) -> bool) -> Option<([u8; 11], bool)> {
+    match exact_short_name(name) {
+        Some(short_name) if !taken(&short_name) => Some((short_name, false)),
+        _ => generate_short_name(name, taken).map(|short_name| (short_name, true)),
+    }
+}
+
+/// Long name entries for `name` in the order they are stored, in front of
+/// the short entry named `short_name`.
+pub fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(CHARS_PER_LONG_ENTRY);
+    if !units.len().is_multiple_of(CHARS_PER_LONG_ENTRY) {
+        units.push(0);
+    }
+    units.resize(count * CHARS_PER_LONG_ENTRY, 0xFFFF);
+
+    let checksum = checksum(short_name);
+    let mut bytes = Vec::with_capacity(count * ENTRY_SIZE);
+    for sequence in (1..=count).rev() {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = sequence as u8;
+        if sequence == count {
+            entry[0] |= LAST_LONG_ENTRY;
+        }
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let chars = &units[(sequence - 1) * CHARS_PER_LONG_ENTRY..sequence * CHARS_PER_LONG_ENTRY];
+        for (&unit, &offset) in chars.iter().zip(LONG_NAME_OFFSETS.iter()) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        bytes.extend_from_slice(&entry);
+    }
+    bytes
+}
+
+/// Marks a directory slot as free.
+pub fn mark_deleted(entry: &mut [u8]) {
+    entry[0] = DELETED;
+}
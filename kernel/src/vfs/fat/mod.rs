@@ -0,0 +1,1200 @@
+//! FAT12, FAT16 and FAT32 filesystems with long file names.
+//!
+//! Nothing is cached here: every operation goes to the block device, and the
+//! directory entry of a file is rewritten whenever its size or first cluster
+//! changes, so the volume stays consistent after each call.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use self::dir::{Entry, ATTR_ARCHIVE, ATTR_DIRECTORY, ENTRY_SIZE};
+use super::{DirEntry, FileType, Filesystem, Inode, Metadata, VfsError};
+use crate::{
+    block::{self, BlockDevice},
+    sync::Mutex,
+};
+
+mod dir;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// First cluster number of the data region; 0 and 1 are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+/// Inode number of the root directory, which has no directory entry. Other
+/// inode numbers are the position of the short entry on the device in
+/// units of entries, which puts them past the boot sector.
+const ROOT_INODE: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Value marking the end of a cluster chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_end_of_chain(self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+}
+
+/// Layout of the volume, from the BIOS parameter block. Offsets are in bytes.
+struct Geometry {
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u32,
+    /// FAT written to when mirroring is disabled on FAT32.
+    active_fat: Option<u32>,
+    /// Fixed root directory of FAT12 and FAT16.
+    root_offset: u64,
+    root_size: u64,
+    /// First cluster of the root directory on FAT32.
+    root_cluster: u32,
+    data_offset: u64,
+    cluster_count: u32,
+    fs_info_offset: Option<u64>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Geometry {
+    fn parse(boot_sector: &[u8], device_size: u64) -> Result<Self, VfsError> {
+        let bytes_per_sector = read_u16(boot_sector, 11) as u64;
+        let sectors_per_cluster = boot_sector[13] as u64;
+        let reserved_sectors = read_u16(boot_sector, 14) as u64;
+        let fat_count = boot_sector[16] as u32;
+        let root_entries = read_u16(boot_sector, 17) as u64;
+        let total_sectors = match read_u16(boot_sector, 19) {
+            0 => read_u32(boot_sector, 32) as u64,
+            total => total as u64,
+        };
+        let sectors_per_fat = match read_u16(boot_sector, 22) {
+            0 => read_u32(boot_sector, 36) as u64,
+            size => size as u64,
+        };
+        if boot_sector[510..512] != BOOT_SIGNATURE
+            || !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || sectors_per_fat == 0
+        {
+            return Err(VfsError::NotSupported);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + fat_count as u64 * sectors_per_fat + root_sectors;
+        if total_sectors <= data_sector || total_sectors * bytes_per_sector > device_size {
+            return Err(VfsError::NotSupported);
+        }
+        let cluster_count = ((total_sectors - data_sector) / sectors_per_cluster) as u32;
+        // the allocator searches the clusters round-robin, which needs one
+        if cluster_count == 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        let mut geometry = Geometry {
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: sectors_per_fat * bytes_per_sector,
+            fat_count,
+            active_fat: None,
+            root_offset: (reserved_sectors + fat_count as u64 * sectors_per_fat) * bytes_per_sector,
+            root_size: root_sectors * bytes_per_sector,
+            root_cluster: 0,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_count,
+            fs_info_offset: None,
+        };
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err(VfsError::NotSupported);
+            }
+            let flags = read_u16(boot_sector, 40);
+            // bit 7 turns off mirroring, the low bits select the FAT in use
+            if flags & 0x80 != 0 {
+                geometry.active_fat = Some((flags & 0xF) as u32).filter(|&fat| fat < fat_count);
+            }
+            geometry.root_cluster = read_u32(boot_sector, 44);
+            if !geometry.is_valid_cluster(geometry.root_cluster) {
+                return Err(VfsError::NotSupported);
+            }
+            geometry.fs_info_offset = match read_u16(boot_sector, 48) {
+                0 | 0xFFFF => None,
+                sector => Some(sector as u64 * bytes_per_sector),
+            };
+        } else if root_entries == 0 {
+            return Err(VfsError::NotSupported);
+        }
+
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if (cluster_count as u64 + 2) * fat_bits > geometry.fat_size * 8 {
+            return Err(VfsError::NotSupported);
+        }
+        Ok(geometry)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+
+    /// Identifies the root directory the way ".." entries do: FAT12 and
+    /// FAT16 roots have no cluster and are named 0.
+    fn root_dir(&self) -> u32 {
+        self.root_cluster
+    }
+}
+
+/// Slot of a short entry within a directory, which identifies a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    /// First cluster of the directory, 0 for a FAT12 or FAT16 root.
+    dir: u32,
+    slot: u32,
+}
+
+/// State of a file or directory shared by all inodes referring to it.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    /// Where its short entry is; `None` for the root directory.
+    location: Option<Location>,
+    first_cluster: u32,
+    size: u32,
+    is_dir: bool,
+    /// Set once the entry is removed, while the node is still open.
+    removed: bool,
+}
+
+struct State {
+    /// Nodes in use, so that all inodes of a file see the same size.
+    nodes: BTreeMap<Location, Weak<spin::Mutex<Node>>>,
+    /// Cluster to start the search for a free one at.
+    next_free: u32,
+    /// Whether the FSInfo sector is out of date.
+    fs_info_dirty: bool,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    /// Held for the duration of every operation.
+    state: Mutex<State>,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), VfsError> {
+        block::read_bytes(&*self.device, offset, buf).map_err(|_| VfsError::Io)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        block::write_bytes(&*self.device, offset, data).map_err(|_| VfsError::Io)
+    }
+
+    /// Byte offset and width of the entry for `cluster` within a FAT.
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        match self.geometry.fat_type {
+            FatType::Fat12 => (cluster as u64 * 3 / 2, 2),
+            FatType::Fat16 => (cluster as u64 * 2, 2),
+            FatType::Fat32 => (cluster as u64 * 4, 4),
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, VfsError> {
+        let (offset, width) = self.fat_position(cluster);
+        let fat = self.geometry.active_fat.unwrap_or(0) as u64;
+        let mut bytes = [0; 4];
+        self.read(
+            self.geometry.fat_offset + fat * self.geometry.fat_size + offset,
+            &mut bytes[..width],
+        )?;
+        let value = u32::from_le_bytes(bytes);
+        Ok(match self.geometry.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => value >> 4,
+            FatType::Fat12 => value & 0xFFF,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Sets the entry for `cluster` in every FAT in use.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), VfsError> {
+        let (offset, width) = self.fat_position(cluster);
+        let fats = match self.geometry.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.geometry.fat_count,
+        };
+        for fat in fats {
+            let position = self.geometry.fat_offset + fat as u64 * self.geometry.fat_size + offset;
+            let mut bytes = [0; 4];
+            self.read(position, &mut bytes[..width])?;
+            let old = u32::from_le_bytes(bytes);
+            let new = match self.geometry.fat_type {
+                // FAT12 entries share a byte with their neighbor
+                FatType::Fat12 if cluster % 2 == 1 => (old & 0x000F) | (value & 0xFFF) << 4,
+                FatType::Fat12 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat16 => value & 0xFFFF,
+                // the top four bits are reserved
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            self.write(position, &new.to_le_bytes()[..width])?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, VfsError> {
+        let next = self.fat_entry(cluster)?;
+        if self.geometry.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else if self.geometry.fat_type.is_end_of_chain(next) {
+            Ok(None)
+        } else {
+            // free or bad clusters do not belong in a chain
+            Err(VfsError::Io)
+        }
+    }
+
+    fn chain(&self, first: u32) -> Result<Vec<u32>, VfsError> {
+        let mut chain = Vec::new();
+        let mut cluster = Some(first).filter(|&first| first != 0);
+        while let Some(current) = cluster {
+            if !self.geometry.is_valid_cluster(current)
+                || chain.len() > self.geometry.cluster_count as usize
+            {
+                return Err(VfsError::Io);
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    /// Takes a free cluster and appends it to the chain ending in `last`.
+    fn allocate(&self, state: &mut State, last: Option<u32>) -> Result<u32, VfsError> {
+        let count = self.geometry.cluster_count;
+        let start = state
+            .next_free
+            .clamp(FIRST_CLUSTER, count + FIRST_CLUSTER - 1)
+            - FIRST_CLUSTER;
+        for i in 0..count {
+            let cluster = (start + i) % count + FIRST_CLUSTER;
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, self.geometry.fat_type.end_of_chain())?;
+                if let Some(last) = last {
+                    self.set_fat_entry(last, cluster)?;
+                }
+                state.next_free = cluster + 1;
+                state.fs_info_dirty = true;
+                return Ok(cluster);
+            }
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), VfsError> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        state.fs_info_dirty = true;
+        Ok(())
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), VfsError> {
+        let zeros = vec![0; self.geometry.cluster_size as usize];
+        self.write(self.geometry.cluster_offset(cluster), &zeros)
+    }
+
+    /// Raw contents of directory `dir`.
+    fn read_dir_bytes(&self, dir: u32) -> Result<Vec<u8>, VfsError> {
+        if dir == 0 {
+            let mut bytes = vec![0; self.geometry.root_size as usize];
+            self.read(self.geometry.root_offset, &mut bytes)?;
+            return Ok(bytes);
+        }
+        let cluster_size = self.geometry.cluster_size as usize;
+        let chain = self.chain(dir)?;
+        let mut bytes = vec![0; chain.len() * cluster_size];
+        for (cluster, chunk) in chain.iter().zip(bytes.chunks_exact_mut(cluster_size)) {
+            self.read(self.geometry.cluster_offset(*cluster), chunk)?;
+        }
+        Ok(bytes)
+    }
+
+    fn entries(&self, dir: u32) -> Result<Vec<Entry>, VfsError> {
+        Ok(dir::parse(&self.read_dir_bytes(dir)?))
+    }
+
+    /// Looks up `name` in directory `dir`, ignoring case like FAT does.
+    fn find(&self, dir: u32, name: &str) -> Result<Option<Entry>, VfsError> {
+        Ok(self
+            .entries(dir)?
+            .into_iter()
+            .find(|entry| !entry.is_dot() && entry.name.eq_ignore_ascii_case(name)))
+    }
+
+    fn slot_offset(&self, dir: u32, slot: u32) -> Result<u64, VfsError> {
+        let offset = slot as u64 * ENTRY_SIZE as u64;
+        if dir == 0 {
+            return Ok(self.geometry.root_offset + offset);
+        }
+        let index = (offset / self.geometry.cluster_size) as usize;
+        let cluster = *self.chain(dir)?.get(index).ok_or(VfsError::Io)?;
+        Ok(self.geometry.cluster_offset(cluster) + offset % self.geometry.cluster_size)
+    }
+
+    fn read_slot(&self, location: Location) -> Result<[u8; ENTRY_SIZE], VfsError> {
+        let mut entry = [0; ENTRY_SIZE];
+        self.read(self.slot_offset(location.dir, location.slot)?, &mut entry)?;
+        Ok(entry)
+    }
+
+    /// Writes consecutive slots of directory `dir`, starting at `first`.
+    fn write_slots(&self, dir: u32, first: u32, bytes: &[u8]) -> Result<(), VfsError> {
+        for (i, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+            self.write(self.slot_offset(dir, first + i as u32)?, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an entry called `name` to directory `dir`. `short_entry` holds
+    /// everything but the short name, which is chosen here. `replacing` is
+    /// an entry of `dir` the caller deletes next, whose names may be reused.
+    fn insert(
+        &self,
+        state: &mut State,
+        dir: u32,
+        name: &str,
+        mut short_entry: [u8; ENTRY_SIZE],
+        replacing: Option<&Entry>,
+    ) -> Result<Location, VfsError> {
+        dir::validate_name(name)?;
+        let mut entries = self.entries(dir)?;
+        if let Some(replacing) = replacing {
+            entries.retain(|entry| entry.slot != replacing.slot);
+        }
+        if entries
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(VfsError::AlreadyExists);
+        }
+        let taken =
+            |short_name: &[u8; 11]| entries.iter().any(|entry| entry.short_name == *short_name);
+        let (short_name, needs_long_name) =
+            dir::short_name_for(name, &taken).ok_or(VfsError::NoSpace)?;
+        short_entry[0..11].copy_from_slice(&short_name);
+
+        let mut bytes = if needs_long_name {
+            dir::long_name_entries(name, &short_name)
+        } else {
+            Vec::new()
+        };
+        bytes.extend_from_slice(&short_entry);
+        let count = bytes.len() / ENTRY_SIZE;
+
+        let first = loop {
+            if let Some(first) = dir::find_free_slots(&self.read_dir_bytes(dir)?, count) {
+                break first;
+            }
+            // the fixed root directory cannot grow
+            if dir == 0 {
+                return Err(VfsError::NoSpace);
+            }
+            let last = self.chain(dir)?.last().copied();
+            let cluster = self.allocate(state, last)?;
+            self.zero_cluster(cluster)?;
+        };
+        self.write_slots(dir, first, &bytes)?;
+        Ok(Location {
+            dir,
+            slot: first + count as u32 - 1,
+        })
+    }
+
+    /// Frees the slots of `entry` in directory `dir`, leaving its clusters alone.
+    fn delete_slots(&self, dir: u32, entry: &Entry) -> Result<(), VfsError> {
+        for slot in entry.first_slot..=entry.slot {
+            let location = Location { dir, slot };
+            let mut raw = self.read_slot(location)?;
+            dir::mark_deleted(&mut raw);
+            self.write_slots(dir, slot, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// Inode number of the entry at `location`, or of the root directory.
+    fn inode_number(&self, location: Option<Location>) -> Result<u64, VfsError> {
+        match location {
+            Some(location) => {
+                Ok(self.slot_offset(location.dir, location.slot)? / ENTRY_SIZE as u64)
+            }
+            None => Ok(ROOT_INODE),
+        }
+    }
+
+    /// First cluster of the directory with inode number `inode`.
+    fn dir_by_inode(&self, inode: u64) -> Result<u32, VfsError> {
+        if inode == ROOT_INODE {
+            return Ok(self.geometry.root_dir());
+        }
+        let mut raw = [0; ENTRY_SIZE];
+        self.read(inode * ENTRY_SIZE as u64, &mut raw)?;
+        Ok(dir::first_cluster(&raw))
+    }
+
+    /// Stores the first cluster and size of `node` in its directory entry.
+    fn update_entry(&self, node: &Node) -> Result<(), VfsError> {
+        let Some(location) = node.location else {
+            return Ok(());
+        };
+        let mut raw = self.read_slot(location)?;
+        dir::set_first_cluster(&mut raw, node.first_cluster);
+        dir::set_size(&mut raw, if node.is_dir { 0 } else { node.size });
+        self.write_slots(location.dir, location.slot, &raw)
+    }
+
+    /// The directory `dir` is in, following its ".." entry.
+    fn parent_dir(&self, dir: u32) -> Result<u32, VfsError> {
+        let dot_dot = self.read_slot(Location { dir, slot: 1 })?;
+        Ok(match dir::first_cluster(&dot_dot) {
+            0 => self.geometry.root_dir(),
+            parent => parent,
+        })
+    }
+
+    /// Value of ".." entries pointing at `dir`.
+    fn dot_dot_cluster(&self, dir: u32) -> u32 {
+        if dir == self.geometry.root_dir() {
+            0
+        } else {
+            dir
+        }
+    }
+
+    fn node(&self, state: &mut State, dir: u32, entry: &Entry) -> Arc<spin::Mutex<Node>> {
+        let location = Location {
+            dir,
+            slot: entry.slot,
+        };
+        if let Some(node) = state.nodes.get(&location).and_then(Weak::upgrade) {
+            return node;
+        }
+        let node = Arc::new(spin::Mutex::new(Node {
+            location: Some(location),
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            is_dir: entry.is_dir(),
+            removed: false,
+        }));
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(location, Arc::downgrade(&node));
+        node
+    }
+
+    fn write_fs_info(&self, state: &mut State) -> Result<(), VfsError> {
+        let Some(offset) = self.geometry.fs_info_offset else {
+            return Ok(());
+        };
+        let mut sector = [0u8; 512];
+        self.read(offset, &mut sector)?;
+        if read_u32(&sector, 0) != FS_INFO_LEAD_SIGNATURE
+            || read_u32(&sector, 484) != FS_INFO_STRUCT_SIGNATURE
+            || read_u32(&sector, 508) != FS_INFO_TRAIL_SIGNATURE
+        {
+            return Ok(());
+        }
+        // the free count is not tracked, so it is marked unknown
+        sector[488..492].copy_from_slice(&FS_INFO_UNKNOWN.to_le_bytes());
+        sector[492..496].copy_from_slice(&state.next_free.to_le_bytes());
+        self.write(offset, &sector)?;
+        state.fs_info_dirty = false;
+        Ok(())
+    }
+}
+
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+impl FatFs {
+    /// Mounts the FAT volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        let device_size = device.block_count() * device.block_size() as u64;
+        let mut boot_sector = [0; 512];
+        block::read_bytes(&*device, 0, &mut boot_sector).map_err(|_| VfsError::Io)?;
+        let geometry = Geometry::parse(&boot_sector, device_size)?;
+
+        let mut next_free = FIRST_CLUSTER;
+        if let Some(offset) = geometry.fs_info_offset {
+            let mut sector = [0; 512];
+            block::read_bytes(&*device, offset, &mut sector).map_err(|_| VfsError::Io)?;
+            let hint = read_u32(&sector, 492);
+            if read_u32(&sector, 0) == FS_INFO_LEAD_SIGNATURE && geometry.is_valid_cluster(hint) {
+                next_free = hint;
+            }
+        }
+
+        Ok(FatFs {
+            volume: Arc::new(Volume {
+                device,
+                geometry,
+                state: Mutex::new(State {
+                    nodes: BTreeMap::new(),
+                    next_free,
+                    fs_info_dirty: false,
+                }),
+            }),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.geometry.fat_type
+    }
+}
+
+impl Filesystem for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            node: Arc::new(spin::Mutex::new(Node {
+                location: None,
+                first_cluster: self.volume.geometry.root_dir(),
+                size: 0,
+                is_dir: true,
+                removed: false,
+            })),
+        })
+    }
+
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        if state.fs_info_dirty {
+            self.volume.write_fs_info(&mut state)?;
+        }
+        self.volume.device.flush().map_err(|_| VfsError::Io)
+    }
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    node: Arc<spin::Mutex<Node>>,
+}
+
+impl FatInode {
+    fn current(&self) -> Result<Node, VfsError> {
+        let node = *self.node.lock();
+        if node.removed {
+            return Err(VfsError::NotFound);
+        }
+        Ok(node)
+    }
+
+    fn dir(&self) -> Result<u32, VfsError> {
+        let node = self.current()?;
+        if !node.is_dir {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(node.first_cluster)
+    }
+
+    fn file(&self) -> Result<Node, VfsError> {
+        let node = self.current()?;
+        if node.is_dir {
+            return Err(VfsError::IsADirectory);
+        }
+        Ok(node)
+    }
+
+    fn inode(&self, node: Arc<spin::Mutex<Node>>) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            node,
+        })
+    }
+
+    /// Writes `data` at `offset`, growing the cluster chain as needed.
+    fn write_data(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let cluster_size = volume.geometry.cluster_size;
+        let end = offset + data.len() as u64;
+        let mut chain = volume.chain(node.first_cluster)?;
+        while (chain.len() as u64) * cluster_size < end {
+            let cluster = volume.allocate(state, chain.last().copied())?;
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let cluster = chain[(pos / cluster_size) as usize];
+            let within = pos % cluster_size;
+            let len = (data.len() - done).min((cluster_size - within) as usize);
+            volume.write(
+                volume.geometry.cluster_offset(cluster) + within,
+                &data[done..done + len],
+            )?;
+            done += len;
+        }
+        node.size = node.size.max(end as u32);
+        Ok(())
+    }
+
+    /// Fills the file with zeros from its current end up to `size`.
+    fn extend(&self, state: &mut State, node: &mut Node, size: u64) -> Result<(), VfsError> {
+        let zeros = vec![0; self.volume.geometry.cluster_size as usize];
+        while (node.size as u64) < size {
+            let len = (size - node.size as u64).min(zeros.len() as u64) as usize;
+            self.write_data(state, node, node.size as u64, &zeros[..len])?;
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        let _state = self.volume.state.lock();
+        let node = self.current()?;
+        Ok(Metadata {
+            file_type: if node.is_dir {
+                FileType::Directory
+            } else {
+                FileType::File
+            },
+            size: node.size as u64,
+            // empty files share first cluster 0, so go by the entry instead
+            inode: self.volume.inode_number(node.location)?,
+            // FAT has no permissions
+            mode: if node.is_dir { 0o755 } else { 0o644 },
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let _state = self.volume.state.lock();
+        let node = self.file()?;
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let volume = &self.volume;
+        let cluster_size = volume.geometry.cluster_size;
+        let chain = volume.chain(node.first_cluster)?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let cluster = *chain
+                .get((pos / cluster_size) as usize)
+                .ok_or(VfsError::Io)?;
+            let within = pos % cluster_size;
+            let chunk = (len - done).min((cluster_size - within) as usize);
+            volume.read(
+                volume.geometry.cluster_offset(cluster) + within,
+                &mut buf[done..done + chunk],
+            )?;
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.file()?;
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= u32::MAX as u64 => {}
+            _ => return Err(VfsError::NoSpace),
+        }
+        let result = self
+            .extend(&mut state, &mut node, offset)
+            .and_then(|()| self.write_data(&mut state, &mut node, offset, buf));
+        // whatever got allocated belongs to the file, even after a failure
+        *self.node.lock() = node;
+        self.volume.update_entry(&node)?;
+        result.map(|()| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        let mut node = self.file()?;
+        if size > u32::MAX as u64 {
+            return Err(VfsError::NoSpace);
+        }
+        let result = if size > node.size as u64 {
+            self.extend(&mut state, &mut node, size)
+        } else {
+            let volume = &self.volume;
+            let keep = size.div_ceil(volume.geometry.cluster_size) as usize;
+            let chain = volume.chain(node.first_cluster)?;
+            if keep == 0 && !chain.is_empty() {
+                node.first_cluster = 0;
+                volume.free_chain(&mut state, chain[0])?;
+            } else if chain.len() > keep {
+                volume.set_fat_entry(chain[keep - 1], volume.geometry.fat_type.end_of_chain())?;
+                volume.free_chain(&mut state, chain[keep])?;
+            }
+            node.size = size as u32;
+            Ok(())
+        };
+        *self.node.lock() = node;
+        self.volume.update_entry(&node)?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let dir = self.dir()?;
+        let entry = self.volume.find(dir, name)?.ok_or(VfsError::NotFound)?;
+        Ok(self.inode(self.volume.node(&mut state, dir, &entry)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let _state = self.volume.state.lock();
+        let entries = self.volume.entries(self.dir()?)?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| DirEntry {
+                file_type: if entry.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let volume = &self.volume;
+        let dir = self.dir()?;
+        let (attr, first_cluster) = match file_type {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                // a new directory holds "." and ".." in its first cluster
+                let cluster = volume.allocate(&mut state, None)?;
+                let mut contents = vec![0; volume.geometry.cluster_size as usize];
+                contents[..ENTRY_SIZE].copy_from_slice(&dir::short_entry(
+                    b".          ",
+                    ATTR_DIRECTORY,
+                    cluster,
+                    0,
+                ));
+                contents[ENTRY_SIZE..2 * ENTRY_SIZE].copy_from_slice(&dir::short_entry(
+                    b"..         ",
+                    ATTR_DIRECTORY,
+                    volume.dot_dot_cluster(dir),
+                    0,
+                ));
+                volume.write(volume.geometry.cluster_offset(cluster), &contents)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+            FileType::CharDevice | FileType::BlockDevice | FileType::Symlink => {
+                return Err(VfsError::NotSupported)
+            }
+        };
+
+        let short_entry = dir::short_entry(&[b' '; 11], attr, first_cluster, 0);
+        match volume.insert(&mut state, dir, name, short_entry, None) {
+            Ok(location) => {
+                let entry = dir::parse(&volume.read_slot(location)?)
+                    .pop()
+                    .ok_or(VfsError::Io)?;
+                let entry = Entry {
+                    slot: location.slot,
+                    ..entry
+                };
+                Ok(self.inode(volume.node(&mut state, dir, &entry)))
+            }
+            Err(err) => {
+                if first_cluster != 0 {
+                    volume.free_chain(&mut state, first_cluster)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        let volume = &self.volume;
+        let dir = self.dir()?;
+        let entry = volume.find(dir, name)?.ok_or(VfsError::NotFound)?;
+        if entry.is_dir()
+            && (entry.first_cluster == 0
+                || volume
+                    .entries(entry.first_cluster)?
+                    .iter()
+                    .any(|child| !child.is_dot()))
+        {
+            return Err(VfsError::NotEmpty);
+        }
+        volume.delete_slots(dir, &entry)?;
+        if entry.first_cluster != 0 {
+            volume.free_chain(&mut state, entry.first_cluster)?;
+        }
+        let location = Location {
+            dir,
+            slot: entry.slot,
+        };
+        if let Some(node) = state
+            .nodes
+            .remove(&location)
+            .and_then(|node| node.upgrade())
+        {
+            node.lock().removed = true;
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &Arc<dyn Inode>,
+        new_name: &str,
+    ) -> Result<(), VfsError> {
+        // the VFS only hands in directories of the same volume
+        let new_parent = new_parent.stat()?;
+        if new_parent.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        let mut state = self.volume.state.lock();
+        let volume = &self.volume;
+        let new_dir = volume.dir_by_inode(new_parent.inode)?;
+        let old_dir = self.dir()?;
+        let entry = volume.find(old_dir, old_name)?.ok_or(VfsError::NotFound)?;
+        if old_dir == new_dir && entry.name == new_name {
+            return Ok(());
+        }
+
+        if entry.is_dir() && old_dir != new_dir {
+            // a directory cannot move into itself
+            let mut ancestor = new_dir;
+            for _ in 0..volume.geometry.cluster_count {
+                if ancestor == entry.first_cluster {
+                    return Err(VfsError::InvalidPath);
+                }
+                if ancestor == volume.geometry.root_dir() {
+                    break;
+                }
+                ancestor = volume.parent_dir(ancestor)?;
+            }
+        }
+
+        let old_location = Location {
+            dir: old_dir,
+            slot: entry.slot,
+        };
+        let short_entry = volume.read_slot(old_location)?;
+        let replacing = match volume.find(new_dir, new_name)? {
+            // only a change of case may match the entry itself
+            Some(existing) if old_dir != new_dir || existing.slot != entry.slot => {
+                return Err(VfsError::AlreadyExists);
+            }
+            Some(_) => Some(&entry),
+            None => None,
+        };
+        // the old slots go only once the new ones are written, so running out
+        // of space leaves the entry where it was
+        let new_location = volume.insert(&mut state, new_dir, new_name, short_entry, replacing)?;
+        volume.delete_slots(old_dir, &entry)?;
+
+        if entry.is_dir() && old_dir != new_dir {
+            let dot_dot = Location {
+                dir: entry.first_cluster,
+                slot: 1,
+            };
+            let mut raw = volume.read_slot(dot_dot)?;
+            dir::set_first_cluster(&mut raw, volume.dot_dot_cluster(new_dir));
+            volume.write_slots(dot_dot.dir, dot_dot.slot, &raw)?;
+        }
+
+        if let Some(node) = state.nodes.remove(&old_location) {
+            if let Some(open) = node.upgrade() {
+                open.lock().location = Some(new_location);
+            }
+            state.nodes.insert(new_location, node);
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.volume.device.flush().map_err(|_| VfsError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    /// FAT12 volume with 512-byte sectors, one reserved sector, two FATs of
+    /// one sector each and a root directory of one sector.
+    fn fat12(total_sectors: u16, sectors_per_cluster: u8) -> Arc<Volume> {
+        fat12_fs(total_sectors, sectors_per_cluster).volume
+    }
+
+    fn fat12_fs(total_sectors: u16, sectors_per_cluster: u8) -> FatFs {
+        let mut image = vec![0; total_sectors as usize * 512];
+        image[11..13].copy_from_slice(&512u16.to_le_bytes());
+        image[13] = sectors_per_cluster;
+        image[14..16].copy_from_slice(&1u16.to_le_bytes());
+        image[16] = 2;
+        image[17..19].copy_from_slice(&16u16.to_le_bytes());
+        image[19..21].copy_from_slice(&total_sectors.to_le_bytes());
+        image[22..24].copy_from_slice(&1u16.to_le_bytes());
+        image[510..512].copy_from_slice(&BOOT_SIGNATURE);
+        match FatFs::new(RamDisk::new(image)) {
+            Ok(fs) => fs,
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    fn names(dir: &Arc<dyn Inode>) -> Vec<String> {
+        dir.read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn state(next_free: u32) -> State {
+        State {
+            nodes: BTreeMap::new(),
+            next_free,
+            fs_info_dirty: false,
+        }
+    }
+
+    #[test]
+    fn clusters_follow_the_root_directory() {
+        let volume = fat12(64, 1);
+        let geometry = &volume.geometry;
+        assert_eq!(geometry.fat_type, FatType::Fat12);
+        assert_eq!(geometry.cluster_count, 60);
+        assert_eq!(geometry.cluster_offset(2), 4 * 512);
+        assert_eq!(geometry.cluster_offset(61), 63 * 512);
+        assert!(!geometry.is_valid_cluster(1));
+        assert!(geometry.is_valid_cluster(61));
+        assert!(!geometry.is_valid_cluster(62));
+    }
+
+    #[test]
+    fn fat12_entries_share_a_byte() {
+        let volume = fat12(64, 1);
+        volume.set_fat_entry(2, 0xABC).unwrap();
+        volume.set_fat_entry(3, 0x123).unwrap();
+        volume.set_fat_entry(4, 0xFFF).unwrap();
+        assert_eq!(volume.fat_entry(2), Ok(0xABC));
+        assert_eq!(volume.fat_entry(3), Ok(0x123));
+        assert_eq!(volume.fat_entry(4), Ok(0xFFF));
+        assert_eq!(volume.fat_entry(5), Ok(0));
+
+        // both FATs get the entries, packed two into three bytes
+        for fat_offset in [512, 1024] {
+            let mut bytes = [0; 5];
+            volume.read(fat_offset + 3, &mut bytes).unwrap();
+            assert_eq!(bytes, [0xBC, 0x3A, 0x12, 0xFF, 0x0F]);
+        }
+    }
+
+    #[test]
+    fn chain_follows_the_fat() {
+        let volume = fat12(64, 1);
+        volume.set_fat_entry(2, 5).unwrap();
+        volume.set_fat_entry(5, 3).unwrap();
+        volume.set_fat_entry(3, 0xFF8).unwrap();
+        assert_eq!(volume.chain(2), Ok(vec![2, 5, 3]));
+        assert_eq!(volume.chain(3), Ok(vec![3]));
+        assert_eq!(volume.chain(0), Ok(vec![]));
+    }
+
+    #[test]
+    fn chain_rejects_loops_and_free_clusters() {
+        let volume = fat12(64, 1);
+        volume.set_fat_entry(2, 3).unwrap();
+        volume.set_fat_entry(3, 2).unwrap();
+        assert_eq!(volume.chain(2), Err(VfsError::Io));
+
+        volume.set_fat_entry(4, 5).unwrap();
+        assert_eq!(volume.chain(4), Err(VfsError::Io));
+        assert_eq!(volume.chain(62), Err(VfsError::Io));
+    }
+
+    #[test]
+    fn allocate_wraps_around() {
+        let volume = fat12(64, 1);
+        let mut state = state(61);
+        assert_eq!(volume.allocate(&mut state, None), Ok(61));
+        assert_eq!(volume.allocate(&mut state, Some(61)), Ok(2));
+        assert_eq!(volume.chain(61), Ok(vec![61, 2]));
+        assert_eq!(state.next_free, 3);
+        assert!(state.fs_info_dirty);
+    }
+
+    #[test]
+    fn allocate_until_full_then_free() {
+        let volume = fat12(64, 1);
+        let mut state = state(FIRST_CLUSTER);
+        let first = volume.allocate(&mut state, None).unwrap();
+        let mut last = first;
+        for _ in 1..60 {
+            last = volume.allocate(&mut state, Some(last)).unwrap();
+        }
+        assert_eq!(volume.allocate(&mut state, None), Err(VfsError::NoSpace));
+        assert_eq!(volume.chain(first).map(|chain| chain.len()), Ok(60));
+
+        volume.free_chain(&mut state, first).unwrap();
+        assert!((2..62).all(|cluster| volume.fat_entry(cluster) == Ok(0)));
+        // the search carries on from the last cluster handed out
+        assert_eq!(volume.allocate(&mut state, None), Ok(61));
+    }
+
+    #[test]
+    fn volume_without_clusters_is_rejected() {
+        // four data sectors cannot hold a cluster of eight
+        let mut boot_sector = [0; 512];
+        boot_sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot_sector[13] = 8;
+        boot_sector[14..16].copy_from_slice(&1u16.to_le_bytes());
+        boot_sector[16] = 2;
+        boot_sector[17..19].copy_from_slice(&16u16.to_le_bytes());
+        boot_sector[19..21].copy_from_slice(&8u16.to_le_bytes());
+        boot_sector[22..24].copy_from_slice(&1u16.to_le_bytes());
+        boot_sector[510..512].copy_from_slice(&BOOT_SIGNATURE);
+        assert!(matches!(
+            Geometry::parse(&boot_sector, 8 * 512),
+            Err(VfsError::NotSupported)
+        ));
+    }
+
+    #[test]
+    fn empty_files_have_their_own_inode_numbers() {
+        let fs = fat12_fs(64, 1);
+        let root = fs.root();
+        let a = root
+            .create("A", FileType::File)
+            .unwrap()
+            .stat()
+            .unwrap()
+            .inode;
+        let b = root
+            .create("B", FileType::File)
+            .unwrap()
+            .stat()
+            .unwrap()
+            .inode;
+        assert_ne!(a, b);
+        assert_eq!(root.stat().unwrap().inode, ROOT_INODE);
+        assert_eq!(root.lookup("a").unwrap().stat().unwrap().inode, a);
+    }
+
+    #[test]
+    fn rename_moves_entries_between_directories() {
+        let fs = fat12_fs(64, 1);
+        let root = fs.root();
+        let dir = root.create("DIR", FileType::Directory).unwrap();
+        let file = root.create("FILE", FileType::File).unwrap();
+        file.write_at(0, b"data").unwrap();
+
+        root.rename("FILE", &dir, "MOVED").unwrap();
+        assert_eq!(names(&root), ["DIR"]);
+        assert_eq!(names(&dir), ["MOVED"]);
+        let mut buf = [0; 4];
+        assert_eq!(dir.lookup("MOVED").unwrap().read_at(0, &mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+        // the open inode follows the entry
+        assert_eq!(
+            file.stat().unwrap().inode,
+            dir.lookup("MOVED").unwrap().stat().unwrap().inode
+        );
+
+        dir.rename("MOVED", &root, "BACK").unwrap();
+        assert_eq!(names(&dir), Vec::<String>::new());
+        assert!(names(&root).contains(&"BACK".into()));
+    }
+
+    #[test]
+    fn case_only_rename_keeps_the_entry_without_space() {
+        let fs = fat12_fs(64, 1);
+        let root = fs.root();
+        root.create("MIXED.TXT", FileType::File).unwrap();
+        // fill the other 15 slots of the fixed root directory
+        for i in 0..15 {
+            root.create(&format!("F{i}"), FileType::File).unwrap();
+        }
+
+        // the long name needs a second slot
+        assert_eq!(
+            root.rename("MIXED.TXT", &root, "Mixed.txt"),
+            Err(VfsError::NoSpace)
+        );
+        assert!(names(&root).contains(&"MIXED.TXT".into()));
+        assert!(root.lookup("MIXED.TXT").is_ok());
+
+        root.remove("F0").unwrap();
+        root.remove("F1").unwrap();
+        root.rename("MIXED.TXT", &root, "Mixed.txt").unwrap();
+        let names = names(&root);
+        assert!(names.contains(&"Mixed.txt".into()));
+        assert!(!names.contains(&"MIXED.TXT".into()));
+        assert_eq!(names.len(), 14);
+    }
+}
@@ -0,0 +1,116 @@
+//! The initrd seen through the VFS, read-only.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{DirEntry, FileType, Filesystem, Inode, Metadata, VfsError};
+use crate::initrd::{Entry, EntryKind, Initrd};
+
+pub struct InitrdFs {
+    initrd: &'static Initrd,
+}
+
+impl InitrdFs {
+    pub fn new(initrd: &'static Initrd) -> Self {
+        InitrdFs { initrd }
+    }
+}
+
+impl Filesystem for InitrdFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path: String::new(),
+            entry: self.initrd.entry("").unwrap(),
+        })
+    }
+
+    fn name(&self) -> &str {
+        "initrd"
+    }
+}
+
+struct InitrdInode {
+    initrd: &'static Initrd,
+    /// Path within the initrd, without leading slash.
+    path: String,
+    entry: Entry,
+}
+
+impl InitrdInode {
+    fn child_path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.path, name)
+        }
+    }
+}
+
+fn file_type(kind: EntryKind) -> FileType {
+    match kind {
+        EntryKind::File => FileType::File,
+        EntryKind::Directory => FileType::Directory,
+    }
+}
+
+impl Inode for InitrdInode {
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        Ok(Metadata {
+            file_type: file_type(self.entry.kind),
+            size: self.entry.data.len() as u64,
+            inode: self.initrd.entry_number(&self.path).unwrap_or(0),
+            mode: match self.entry.kind {
+                EntryKind::File => 0o444,
+                EntryKind::Directory => 0o555,
+            },
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if self.entry.kind == EntryKind::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let data = self.entry.data;
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        if self.entry.kind != EntryKind::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let path = self.child_path(name);
+        let entry = self.initrd.entry(&path).ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path,
+            entry,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let names = self
+            .initrd
+            .read_dir(&self.path)
+            .ok_or(VfsError::NotADirectory)?;
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                let entry = self.initrd.entry(&self.child_path(name))?;
+                Some(DirEntry {
+                    name: name.to_string(),
+                    file_type: file_type(entry.kind),
+                })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,494 @@
+//! Virtual filesystem: a single tree of paths assembled from mounted filesystems.
+//!
+//! Filesystems hand out [`Inode`]s for the nodes they contain; opening an
+//! inode yields a [`File`] with its own position. Processes keep the files
+//! they opened in their handle table.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::sync::{Mutex, RwLock};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrdfs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    ReadOnly,
+    InvalidPath,
+    /// Seek to a position before the start of the file.
+    InvalidSeek,
+    NotSupported,
+    /// A directory to be removed still has entries.
+    NotEmpty,
+    NoSpace,
+    /// Too many symbolic links met while resolving a path.
+    Loop,
+    /// The underlying device failed.
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    /// Number of the inode within its filesystem.
+    pub inode: u64,
+    /// Permission bits, e.g. `0o644`.
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: Self = OpenFlags(1 << 0);
+    pub const WRITE: Self = OpenFlags(1 << 1);
+    pub const CREATE: Self = OpenFlags(1 << 2);
+    pub const TRUNCATE: Self = OpenFlags(1 << 3);
+    pub const APPEND: Self = OpenFlags(1 << 4);
+
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !0b11111 != 0 {
+            None
+        } else {
+            Some(OpenFlags(bits))
+        }
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// A node of a filesystem: a file, a directory or a device.
+///
+/// Only [`Inode::stat`] is mandatory; everything else defaults to the
+/// behavior of a read-only node that is neither file nor directory.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Result<Metadata, VfsError>;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Finds the entry called `name` in this directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Creates an entry called `name` of the given type in this directory.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Moves entry `old_name` of this directory to `new_name` in `new_parent`,
+    /// a directory of the same filesystem.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &Arc<dyn Inode>,
+        _new_name: &str,
+    ) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Target of this symbolic link.
+    fn read_link(&self) -> Result<String, VfsError> {
+        Err(VfsError::InvalidPath)
+    }
+
+    /// Creates a symbolic link called `name` pointing at `target` in this directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn set_permissions(&self, _mode: u16) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Writes cached data back to the device.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+pub trait Filesystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Short name of the filesystem type, e.g. `"fat"`.
+    fn name(&self) -> &str;
+
+    /// Writes everything cached back to the device.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// An open file: what a process holds in its handle table.
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError>;
+
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn stat(&self) -> Result<Metadata, VfsError>;
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+}
+
+/// A [`File`] reading and writing an inode at a position of its own.
+pub struct InodeFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    /// Held across the inode's I/O, which may sleep, so that reads and writes
+    /// through the same file do not interleave.
+    offset: Mutex<u64>,
+}
+
+impl InodeFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags) -> Self {
+        InodeFile {
+            inode,
+            flags,
+            offset: Mutex::new(0),
+        }
+    }
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::NotSupported);
+        }
+        let mut offset = self.offset.lock();
+        let read = self.inode.read_at(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::ReadOnly);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.inode.stat()?.size;
+        }
+        let written = self.inode.write_at(*offset, buf)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, VfsError> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.stat()?.size.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(VfsError::InvalidSeek)?;
+        Ok(*offset)
+    }
+
+    fn stat(&self) -> Result<Metadata, VfsError> {
+        self.inode.stat()
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        self.inode.read_dir()
+    }
+}
+
+/// Mounted filesystems by absolute, normalized mount point.
+static MOUNTS: RwLock<BTreeMap<String, Arc<dyn Filesystem>>> = RwLock::new(BTreeMap::new());
+
+/// Splits an absolute path into its components, resolving `.` and `..`.
+pub fn normalize(path: &str) -> Result<Vec<&str>, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+fn join<S: core::borrow::Borrow<str>>(components: &[S]) -> String {
+    let mut path = String::from("/");
+    path.push_str(&components.join("/"));
+    path
+}
+
+/// Mounts `filesystem` at `path`, hiding whatever was there before.
+pub fn mount(path: &str, filesystem: Arc<dyn Filesystem>) -> Result<(), VfsError> {
+    let mount_point = join(&normalize(path)?);
+    let mut mounts = MOUNTS.write();
+    if mounts.contains_key(&mount_point) {
+        return Err(VfsError::AlreadyExists);
+    }
+    mounts.insert(mount_point, filesystem);
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<Arc<dyn Filesystem>, VfsError> {
+    let mount_point = join(&normalize(path)?);
+    let filesystem = MOUNTS
+        .write()
+        .remove(&mount_point)
+        .ok_or(VfsError::NotFound)?;
+    filesystem.sync()?;
+    Ok(filesystem)
+}
+
+/// Mount points and the type of filesystem mounted there.
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .read()
+        .iter()
+        .map(|(path, filesystem)| (path.clone(), filesystem.name().to_string()))
+        .collect()
+}
+
+/// Writes back the caches of every mounted filesystem.
+pub fn sync_all() -> Result<(), VfsError> {
+    let filesystems: Vec<_> = MOUNTS.read().values().cloned().collect();
+    filesystems
+        .iter()
+        .try_for_each(|filesystem| filesystem.sync())
+}
+
+/// Most symbolic links followed while resolving a single path.
+const MAX_SYMLINKS: usize = 8;
+
+/// A path with its symbolic links replaced by their targets.
+struct Resolved {
+    components: Vec<String>,
+    /// Number of components naming the mount point the path is on.
+    mount_depth: usize,
+    inode: Arc<dyn Inode>,
+}
+
+/// Finds the inode at `path`, following symbolic links.
+pub fn resolve(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    resolve_components(&normalize(path)?, true).map(|resolved| resolved.inode)
+}
+
+/// Finds the inode at the path made of `components`. Symbolic links are
+/// followed except for the last component, unless `follow_last` is set.
+fn resolve_components(components: &[&str], follow_last: bool) -> Result<Resolved, VfsError> {
+    let mut components: Vec<String> = components.iter().map(|name| name.to_string()).collect();
+    for _ in 0..=MAX_SYMLINKS {
+        // the innermost mount point containing the path
+        let (mount_depth, filesystem) = {
+            let mounts = MOUNTS.read();
+            (0..=components.len())
+                .rev()
+                .find_map(|depth| {
+                    mounts
+                        .get(&join(&components[..depth]))
+                        .map(|filesystem| (depth, filesystem.clone()))
+                })
+                .ok_or(VfsError::NotFound)?
+        };
+
+        let mut inode = filesystem.root();
+        let mut link = None;
+        for (index, name) in components.iter().enumerate().skip(mount_depth) {
+            let next = inode.lookup(name)?;
+            let is_last = index + 1 == components.len();
+            if (!is_last || follow_last) && next.stat()?.file_type == FileType::Symlink {
+                link = Some((index, next.read_link()?));
+                break;
+            }
+            inode = next;
+        }
+        let Some((index, target)) = link else {
+            return Ok(Resolved {
+                components,
+                mount_depth,
+                inode,
+            });
+        };
+
+        // relative targets start from the directory holding the link
+        let mut path = if target.starts_with('/') {
+            String::new()
+        } else {
+            join(&components[..index])
+        };
+        path.push('/');
+        path.push_str(&target);
+        for name in &components[index + 1..] {
+            path.push('/');
+            path.push_str(name);
+        }
+        components = normalize(&path)?.into_iter().map(|name| name.to_string()).collect();
+    }
+    Err(VfsError::Loop)
+}
+
+/// Splits `path` into its resolved parent directory and its last component.
+fn resolve_parent(path: &str) -> Result<(Resolved, String), VfsError> {
+    let components = normalize(path)?;
+    let (name, parent) = components.split_last().ok_or(VfsError::InvalidPath)?;
+    Ok((resolve_components(parent, true)?, name.to_string()))
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<dyn File>, VfsError> {
+    let inode = match resolve(path) {
+        Ok(inode) => inode,
+        Err(VfsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = resolve_parent(path)?;
+            parent.inode.create(&name, FileType::File)?
+        }
+        Err(err) => return Err(err),
+    };
+    if flags.contains(OpenFlags::TRUNCATE) && flags.contains(OpenFlags::WRITE) {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(InodeFile::new(inode, flags)))
+}
+
+pub fn stat(path: &str) -> Result<Metadata, VfsError> {
+    resolve(path)?.stat()
+}
+
+/// Like [`stat`], but describes a symbolic link itself rather than its target.
+pub fn stat_link(path: &str) -> Result<Metadata, VfsError> {
+    resolve_components(&normalize(path)?, false)?.inode.stat()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    resolve(path)?.read_dir()
+}
+
+pub fn create_dir(path: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.inode.create(&name, FileType::Directory).map(|_| ())
+}
+
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.inode.remove(&name)
+}
+
+/// Creates a symbolic link at `path` pointing at `target`, which is not
+/// checked to exist.
+pub fn symlink(target: &str, path: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.inode.symlink(&name, target)
+}
+
+pub fn read_link(path: &str) -> Result<String, VfsError> {
+    resolve_components(&normalize(path)?, false)?.inode.read_link()
+}
+
+pub fn set_permissions(path: &str, mode: u16) -> Result<(), VfsError> {
+    resolve(path)?.set_permissions(mode)
+}
+
+/// Moves the file or directory at `from` to `to`, which has to be on the
+/// same mount.
+pub fn rename(from: &str, to: &str) -> Result<(), VfsError> {
+    let (old_parent, old_name) = resolve_parent(from)?;
+    let (new_parent, new_name) = resolve_parent(to)?;
+    let old_mount = &old_parent.components[..old_parent.mount_depth];
+    let new_mount = &new_parent.components[..new_parent.mount_depth];
+    if old_mount != new_mount {
+        return Err(VfsError::NotSupported);
+    }
+    old_parent
+        .inode
+        .rename(&old_name, &new_parent.inode, &new_name)
+}
+
+/// Reads the whole file at `path`.
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, VfsError> {
+    let inode = resolve(path)?;
+    let size = inode.stat()?.size as usize;
+    let mut data = alloc::vec![0; size];
+    let mut read = 0;
+    while read < size {
+        match inode.read_at(read as u64, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    data.truncate(read);
+    Ok(data)
+}
@@ -0,0 +1,238 @@
+//! Virtio devices behind the modern (virtio 1.0) PCI transport.
+//!
+//! The device describes where its register blocks live through vendor
+//! capabilities: the common configuration used to negotiate features and
+//! set up queues, the doorbells used to notify queues, the interrupt status
+//! and the device-specific configuration.
+
+use alloc::sync::Arc;
+use core::ptr;
+
+use x86_64::VirtAddr;
+
+use crate::pci::{PciDevice, CAP_VENDOR};
+
+mod queue;
+
+pub use self::queue::{Buffer, Virtqueue};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+// common configuration registers
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0C;
+const MSIX_CONFIG: u64 = 0x10;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const QUEUE_ENABLE: u64 = 0x1C;
+const QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+/// MSI-X entry meaning "do not interrupt".
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+fn read<T>(addr: VirtAddr) -> T {
+    unsafe { ptr::read_volatile(addr.as_ptr()) }
+}
+
+fn write<T>(addr: VirtAddr, value: T) {
+    unsafe { ptr::write_volatile(addr.as_mut_ptr(), value) }
+}
+
+/// 64-bit registers are accessed as two 32-bit halves.
+fn write_u64(addr: VirtAddr, value: u64) {
+    write(addr, value as u32);
+    write(addr + 4u64, (value >> 32) as u32);
+}
+
+/// The register blocks of a virtio PCI device.
+pub struct Transport {
+    pub device: Arc<PciDevice>,
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    config: VirtAddr,
+}
+
+impl Transport {
+    /// Finds and maps the register blocks of `device`, which must support the
+    /// modern interface.
+    pub fn new(device: &Arc<PciDevice>) -> Result<Self, &'static str> {
+        let mut bars = [None; 6];
+        let mut map = |bar: u8, offset: u32| -> Result<VirtAddr, &'static str> {
+            let slot = bars
+                .get_mut(bar as usize)
+                .ok_or("virtio capability names an invalid BAR")?;
+            if slot.is_none() {
+                *slot = Some(
+                    device
+                        .map_bar(bar as usize)
+                        .ok_or("virtio BAR is not memory")?,
+                );
+            }
+            Ok(slot.unwrap() + offset as u64)
+        };
+
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for cap in device.capabilities_with_id(CAP_VENDOR) {
+            let cfg_type = device.read_u8(cap.offset + 3);
+            let bar = device.read_u8(cap.offset + 4);
+            let offset = device.read_u32(cap.offset + 8);
+            // the first capability of each type is the preferred one
+            match cfg_type {
+                CFG_COMMON if common.is_none() => common = Some(map(bar, offset)?),
+                CFG_NOTIFY if notify.is_none() => {
+                    notify = Some(map(bar, offset)?);
+                    notify_multiplier = device.read_u32(cap.offset + 16);
+                }
+                CFG_ISR if isr.is_none() => isr = Some(map(bar, offset)?),
+                CFG_DEVICE if config.is_none() => config = Some(map(bar, offset)?),
+                _ => {}
+            }
+        }
+
+        device.enable_bus_master();
+        Ok(Transport {
+            device: device.clone(),
+            common: common.ok_or("no virtio common configuration")?,
+            notify: notify.ok_or("no virtio notification area")?,
+            notify_multiplier,
+            isr: isr.ok_or("no virtio interrupt status")?,
+            config: config.ok_or("no virtio device configuration")?,
+        })
+    }
+
+    fn status(&self) -> u8 {
+        read(self.common + DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        write(self.common + DEVICE_STATUS, status);
+    }
+
+    /// Resets the device and agrees on the features both sides support out
+    /// of `wanted`. Returns the accepted features.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut offered = 0;
+        for select in 0..2 {
+            write(self.common + DEVICE_FEATURE_SELECT, select as u32);
+            offered |= (read::<u32>(self.common + DEVICE_FEATURE) as u64) << (select * 32);
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device only speaks legacy virtio");
+        }
+        let accepted = offered & (wanted | F_VERSION_1);
+        for select in 0..2 {
+            write(self.common + DRIVER_FEATURE_SELECT, select as u32);
+            write(
+                self.common + DRIVER_FEATURE,
+                (accepted >> (select * 32)) as u32,
+            );
+        }
+
+        self.set_status(self.status() | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("device rejected the features");
+        }
+        // configuration changes are not interesting
+        write(self.common + MSIX_CONFIG, NO_VECTOR);
+        Ok(accepted)
+    }
+
+    /// Tells the device something went wrong and it should give up on us.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Allocates queue `index` with at most `max_size` entries and hands it
+    /// to the device. It stays disabled until [`Transport::enable_queue`].
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, &'static str> {
+        write(self.common + QUEUE_SELECT, index);
+        let device_size: u16 = read(self.common + QUEUE_SIZE);
+        if device_size == 0 {
+            return Err("virtqueue does not exist");
+        }
+        let size = device_size.min(max_size);
+        let notify_off: u16 = read(self.common + QUEUE_NOTIFY_OFF);
+        let notify = self.notify + notify_off as u64 * self.notify_multiplier as u64;
+        let queue = Virtqueue::new(index, size, notify).ok_or("out of memory for virtqueue")?;
+
+        write(self.common + QUEUE_SIZE, queue.size());
+        write_u64(self.common + QUEUE_DESC, queue.descriptors().as_u64());
+        write_u64(self.common + QUEUE_DRIVER, queue.available().as_u64());
+        write_u64(self.common + QUEUE_DEVICE, queue.used().as_u64());
+        Ok(queue)
+    }
+
+    /// Routes the interrupts of queue `index` to MSI-X table entry `entry`
+    /// and lets the device use the queue.
+    pub fn enable_queue(&self, index: u16, entry: u16) -> Result<(), &'static str> {
+        write(self.common + QUEUE_SELECT, index);
+        write(self.common + QUEUE_MSIX_VECTOR, entry);
+        if read::<u16>(self.common + QUEUE_MSIX_VECTOR) != entry {
+            return Err("device cannot interrupt for the virtqueue");
+        }
+        write(self.common + QUEUE_ENABLE, 1u16);
+        Ok(())
+    }
+
+    /// Finishes initialization; the device starts processing queues.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Reads and acknowledges the interrupt status, only needed for the
+    /// legacy interrupt line.
+    pub fn interrupt_status(&self) -> u8 {
+        read(self.isr)
+    }
+
+    /// Reads an 8, 16 or 32-bit field of the device-specific configuration.
+    pub fn read_config<T: Copy>(&self, offset: u64) -> T {
+        read(self.config + offset)
+    }
+
+    /// Reads a 64-bit field of the device-specific configuration, retrying
+    /// if the device changes it between the two halves.
+    pub fn read_config_u64(&self, offset: u64) -> u64 {
+        loop {
+            let generation: u8 = read(self.common + CONFIG_GENERATION);
+            let low: u32 = read(self.config + offset);
+            let high: u32 = read(self.config + offset + 4u64);
+            if read::<u8>(self.common + CONFIG_GENERATION) == generation {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Split virtqueues: a table of buffer descriptors, a ring of chains offered
+//! to the device and a ring of chains it is done with, each in a frame of
+//! its own.
+
+use alloc::vec::Vec;
+use core::{
+    mem::size_of,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{colors, log_warn, memory::DmaFrame};
+
+/// Largest queue whose descriptor table still fits in a frame.
+const MAX_SIZE: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Set by the device in the used ring when it does not need notifications.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A piece of memory handed to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes the buffer rather than reads it.
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// Doorbell of the queue.
+    notify: VirtAddr,
+    descriptors: DmaFrame,
+    available: DmaFrame,
+    used: DmaFrame,
+    free: Vec<u16>,
+    /// Our copy of the available ring index.
+    next_available: u16,
+    /// Next entry of the used ring to look at.
+    next_used: u16,
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16, notify: VirtAddr) -> Option<Self> {
+        let size = size.min(MAX_SIZE);
+        Some(Virtqueue {
+            index,
+            size,
+            notify,
+            descriptors: DmaFrame::new()?,
+            available: DmaFrame::new()?,
+            used: DmaFrame::new()?,
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            next_used: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_descriptors(&self) -> usize {
+        self.free.len()
+    }
+
+    pub(super) fn descriptors(&self) -> PhysAddr {
+        self.descriptors.phys()
+    }
+
+    pub(super) fn available(&self) -> PhysAddr {
+        self.available.phys()
+    }
+
+    pub(super) fn used(&self) -> PhysAddr {
+        self.used.phys()
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        (self.descriptors.virt() + index as u64 * size_of::<Descriptor>() as u64).as_mut_ptr()
+    }
+
+    /// Chains `buffers` into descriptors and offers them to the device.
+    /// Returns the head of the chain, or `None` if there are not enough free
+    /// descriptors. The buffers the device reads have to come before the
+    /// ones it writes.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let indices = self.free.split_off(self.free.len() - buffers.len());
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            let next = indices.get(i + 1).copied();
+            if next.is_some() {
+                flags |= DESC_F_NEXT;
+            }
+            let descriptor = Descriptor {
+                addr: buffer.addr.as_u64(),
+                len: buffer.len,
+                flags,
+                next: next.unwrap_or(0),
+            };
+            unsafe { ptr::write_volatile(self.descriptor(indices[i]), descriptor) };
+        }
+
+        let head = indices[0];
+        let ring = self.available.virt();
+        let slot = ring + 4u64 + (self.next_available % self.size) as u64 * 2;
+        unsafe { ptr::write_volatile(slot.as_mut_ptr::<u16>(), head) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // the descriptors and the entry have to be visible before the index
+        // that covers them
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile((ring + 2u64).as_mut_ptr::<u16>(), self.next_available) };
+        Some(head)
+    }
+
+    /// Tells the device there are new chains, unless it asked not to be told.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        let flags = unsafe { ptr::read_volatile(self.used.virt().as_ptr::<u16>()) };
+        if flags & USED_F_NO_NOTIFY == 0 {
+            unsafe { ptr::write_volatile(self.notify.as_mut_ptr::<u16>(), self.index) };
+        }
+    }
+
+    /// Takes the next chain the device is done with, returning its head and
+    /// how many bytes the device wrote into it. Its descriptors are free
+    /// again. Entries naming a descriptor the queue does not have are
+    /// skipped.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let ring = self.used.virt();
+        loop {
+            let used_index = unsafe { ptr::read_volatile((ring + 2u64).as_ptr::<u16>()) };
+            if used_index == self.next_used {
+                return None;
+            }
+            // the entry must not be read before the index that covers it
+            fence(Ordering::SeqCst);
+            let slot = ring + 4u64 + (self.next_used % self.size) as u64 * 8;
+            let (id, len) = unsafe {
+                (
+                    ptr::read_volatile(slot.as_ptr::<u32>()),
+                    ptr::read_volatile((slot + 4u64).as_ptr::<u32>()),
+                )
+            };
+            self.next_used = self.next_used.wrapping_add(1);
+            if id >= self.size as u32 {
+                log_warn!(
+                    "Virtqueue {} returned descriptor {} past its size {}",
+                    self.index,
+                    id,
+                    self.size
+                );
+                continue;
+            }
+
+            let head = id as u16;
+            let mut index = head;
+            loop {
+                self.free.push(index);
+                let descriptor = unsafe { ptr::read_volatile(self.descriptor(index)) };
+                if descriptor.flags & DESC_F_NEXT == 0 {
+                    break;
+                }
+                index = descriptor.next;
+            }
+            return Some((head, len));
+        }
+    }
+}
@@ -20,8 +20,25 @@ fn main() {
             .arg(format!("format=raw,file={bios_path}"));
         println!("Running BIOS image: {}", bios_path);
     }
+    // an extra disk, e.g. a FAT image made with `mkfs.fat -C disk.img 65536`
+    if let Ok(disk_image) = std::env::var("DISK_IMAGE") {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={disk_image}"));
+        println!("Attaching disk image: {}", disk_image);
+    }
+    // user-mode networking; `NIC` picks the card QEMU emulates, e.g. `e1000`,
+    // and `PCAP` names a file to record its traffic in
+    let nic = std::env::var("NIC").unwrap_or_else(|_| "virtio-net-pci".into());
+    cmd.arg("-netdev").arg("user,id=net0");
+    cmd.arg("-device").arg(format!("{nic},netdev=net0"));
+    if let Ok(pcap) = std::env::var("PCAP") {
+        cmd.arg("-object")
+            .arg(format!("filter-dump,id=dump0,netdev=net0,file={pcap}"));
+        println!("Recording network traffic in: {}", pcap);
+    }
     cmd.arg("-serial").arg("stdio");
     cmd.arg("-m").arg("512M");
+    cmd.arg("-smp").arg("4");
     let Ok(mut child) = cmd.spawn() else { return () };
     match child.wait() {
         Ok(it) => it,